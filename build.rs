@@ -0,0 +1,122 @@
+//! Creates the disk images that the tests attach to QEMU, see `test-args` in Cargo.toml: empty
+//! ones, and FAT ones made by the host's `mkfs.fat` from dosfstools. The tests write to them, so
+//! they are only made when they're missing.
+//!
+//! Also packs the files under initrd/ into a newc cpio archive for the kernel to include, see
+//! src/initrd.rs.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The names and sizes of the images, the FAT one is big enough for FAT32.
+const TEST_DISKS: [(&str, u64); 5] = [
+    ("test_disk.img", 8 << 20),
+    ("test_virtio_legacy.img", 8 << 20),
+    ("test_virtio_modern.img", 8 << 20),
+    ("test_ahci.img", 8 << 20),
+    ("test_fat.img", 40 << 20),
+];
+
+/// File systems the FAT driver didn't make itself: the name, FAT type, size and sectors per
+/// cluster of each image.
+const FAT_IMAGES: [(&str, u32, u64, u32); 3] = [
+    ("test_mkfs_fat12.img", 12, 2 << 20, 4),
+    ("test_mkfs_fat16.img", 16, 16 << 20, 1),
+    ("test_mkfs_fat32.img", 32, 40 << 20, 1),
+];
+
+fn main() {
+    let target = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("target");
+    fs::create_dir_all(&target).unwrap();
+    for &(name, size) in &TEST_DISKS {
+        let image = target.join(name);
+        if !image.exists() {
+            File::create(&image)
+                .and_then(|file| file.set_len(size))
+                .unwrap();
+        }
+    }
+    for &(name, fat_type, size, cluster_sectors) in &FAT_IMAGES {
+        let image = target.join(name);
+        if image.exists() {
+            continue;
+        }
+        let made = Command::new("mkfs.fat")
+            .arg("-C")
+            .args(["-F", &fat_type.to_string()])
+            .args(["-s", &cluster_sectors.to_string()])
+            .args(["-n", "MKFS"])
+            .arg(&image)
+            .arg((size >> 10).to_string())
+            .stdout(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !made {
+            println!("cargo:warning=couldn't make {} with mkfs.fat", name);
+        }
+    }
+
+    let root = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("initrd");
+    let mut archive = Vec::new();
+    if root.is_dir() {
+        pack(&root, "", &mut archive).unwrap();
+    }
+    add_entry(&mut archive, "TRAILER!!!", 0, &[]).unwrap();
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("initrd.cpio");
+    fs::write(out, archive).unwrap();
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=initrd");
+}
+
+/// Adds the files and directories in `dir` to `archive`, each directory before what's in it.
+fn pack(dir: &Path, prefix: &str, archive: &mut Vec<u8>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let path = entry.path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        if path.is_dir() {
+            add_entry(archive, &name, 0o040755, &[])?;
+            pack(&path, &format!("{}/", name), archive)?;
+        } else {
+            add_entry(archive, &name, 0o100644, &fs::read(&path)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn add_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) -> io::Result<()> {
+    let fields = [
+        0,
+        mode,
+        0,
+        0,
+        1,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name.len() as u32 + 1,
+        0,
+    ];
+    write!(archive, "070701")?;
+    for field in &fields {
+        write!(archive, "{:08x}", field)?;
+    }
+    write!(archive, "{}\0", name)?;
+    pad(archive);
+    archive.extend_from_slice(data);
+    pad(archive);
+    Ok(())
+}
+
+fn pad(archive: &mut Vec<u8>) {
+    archive.resize((archive.len() + 3) & !3, 0);
+}
@@ -0,0 +1,117 @@
+#!/usr/bin/env python3
+"""Runs a scripted gdb session against joel_os's gdb stub in QEMU.
+
+Boots the kernel with COM1 on a pipe and COM2 on a local TCP port, has the shell make COM2 the
+debugger port and stop for gdb, then lets gdb attach, read and write registers and memory, step,
+and stop at a breakpoint in the `help` command before detaching. Exits with 1 if gdb didn't see
+what it should have. Needs cargo-bootimage, qemu-system-x86_64 and gdb:
+
+    scripts/gdb_session.py [--port 1234] [--verbose]
+"""
+
+import argparse
+import os
+import subprocess
+import sys
+import time
+
+ROOT = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+TARGET = os.path.join(ROOT, "target", "x86_64-joel_os", "debug")
+TIMEOUT = 60
+
+
+def gdb_commands(port):
+    return [
+        "set pagination off",
+        "set confirm off",
+        "set architecture i386:x86-64",
+        f"target remote localhost:{port}",
+        "info registers rip",
+        "x/4xb $sp",
+        "stepi",
+        "info registers rip",
+        "break joel_os::shell::help",
+        # the kernel runs from here until `help` is typed into the shell
+        "continue",
+        "set var $rax = 0x1234",
+        "p/x $rax",
+        "delete",
+        "detach",
+    ]
+
+
+# what gdb has to print for the session to pass
+EXPECTED = [
+    "Breakpoint 1, joel_os::shell::help",
+    "$1 = 0x1234",
+    "Detaching from program",
+]
+
+
+def wait_for(log_path, text, start=0):
+    """Waits until `text` shows up in the serial log after offset `start`, returns its end."""
+    deadline = time.time() + TIMEOUT
+    while time.time() < deadline:
+        with open(log_path, errors="replace") as log:
+            found = log.read().find(text, start)
+        if found >= 0:
+            return found + len(text)
+        time.sleep(0.2)
+    sys.exit(f"timed out waiting for {text!r} on the serial console")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--port", type=int, default=1234, help="TCP port for COM2")
+    parser.add_argument("--verbose", action="store_true", help="print gdb's output")
+    args = parser.parse_args()
+
+    subprocess.run(["cargo", "bootimage"], cwd=ROOT, check=True)
+    log_path = os.path.join(TARGET, "gdb_session_serial.log")
+    with open(log_path, "w") as log:
+        qemu = subprocess.Popen(
+            [
+                "qemu-system-x86_64",
+                "-drive", f"format=raw,file={os.path.join(TARGET, 'bootimage-joel_os.bin')}",
+                "-display", "none",
+                "-serial", "stdio",
+                "-serial", f"tcp:127.0.0.1:{args.port},server,nowait",
+            ],
+            stdin=subprocess.PIPE,
+            stdout=log,
+        )
+    try:
+        def type_line(line):
+            qemu.stdin.write(line.encode() + b"\r")
+            qemu.stdin.flush()
+
+        offset = wait_for(log_path, "> ")
+        type_line("serial debugger com2")
+        offset = wait_for(log_path, "> ", offset)
+        type_line("gdb")
+        offset = wait_for(log_path, "waiting for gdb", offset)
+
+        command = ["gdb", "-nx", "-batch", os.path.join(TARGET, "joel_os")]
+        for line in gdb_commands(args.port):
+            command += ["-ex", line]
+        gdb = subprocess.Popen(command, stdout=subprocess.PIPE, stderr=subprocess.STDOUT, text=True)
+        # the shell prompts again once gdb has let the kernel continue
+        wait_for(log_path, "> ", offset)
+        type_line("help")
+        output, _ = gdb.communicate(timeout=TIMEOUT)
+    finally:
+        qemu.kill()
+
+    if args.verbose:
+        print(output)
+    missing = [text for text in EXPECTED if text not in output]
+    for text in missing:
+        print(f"gdb didn't print {text!r}", file=sys.stderr)
+    if missing and not args.verbose:
+        print(output, file=sys.stderr)
+    print("gdb session failed" if missing else "gdb session passed")
+    sys.exit(1 if missing else 0)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,91 @@
+#!/usr/bin/env python3
+"""Turns the screenshot frames joel_os sends over serial on PrintScreen back into text.
+
+Reads a serial log from the given file or stdin and prints every frame in it, as plain text or,
+with --ansi, with the console colours as ANSI escape codes. For example:
+
+    qemu-system-x86_64 ... -serial file:serial.log
+    scripts/screenshot.py --ansi serial.log
+"""
+
+import argparse
+import re
+import sys
+
+BEGIN = re.compile(r"-----BEGIN SCREENSHOT (\d+)x(\d+)-----")
+END = "-----END SCREENSHOT-----"
+
+# glyphs of the control characters, code page 437 decodes the rest of the bytes fine
+LOW_GLYPHS = " ☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼"
+
+# VGA colour index to ANSI colour index, the two orders swap red and blue
+ANSI_COLOURS = [0, 4, 2, 6, 1, 5, 3, 7]
+
+
+def glyph(byte):
+    if byte < 0x20:
+        return LOW_GLYPHS[byte]
+    if byte == 0x7F:
+        return "⌂"
+    return bytes([byte]).decode("cp437")
+
+
+def ansi_colour(attribute):
+    foreground = attribute & 0x0F
+    background = attribute >> 4 & 0x0F
+    fg = (90 if foreground & 8 else 30) + ANSI_COLOURS[foreground & 7]
+    bg = (100 if background & 8 else 40) + ANSI_COLOURS[background & 7]
+    return "\x1b[{};{}m".format(fg, bg)
+
+
+def render_row(row, ansi):
+    cells = [(int(row[i : i + 2], 16), int(row[i + 2 : i + 4], 16)) for i in range(0, len(row), 4)]
+    if not ansi:
+        return "".join(glyph(character) for character, _ in cells).rstrip()
+    out = []
+    current = None
+    for character, attribute in cells:
+        if attribute != current:
+            out.append(ansi_colour(attribute))
+            current = attribute
+        out.append(glyph(character))
+    out.append("\x1b[0m")
+    return "".join(out)
+
+
+def frames(lines):
+    """Yields each frame as (columns, rows, row lines), skipping frames that were cut off."""
+    frame = None
+    for line in lines:
+        line = line.strip()
+        begin = BEGIN.search(line)
+        if begin:
+            frame = (int(begin.group(1)), int(begin.group(2)), [])
+        elif frame is not None and line == END:
+            columns, rows, data = frame
+            if len(data) == rows and all(len(row) == columns * 4 for row in data):
+                yield frame
+            else:
+                print("skipping a damaged screenshot", file=sys.stderr)
+            frame = None
+        elif frame is not None:
+            frame[2].append(line)
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("log", nargs="?", help="serial log to read, stdin if not given")
+    parser.add_argument("--ansi", action="store_true", help="keep the colours as ANSI codes")
+    args = parser.parse_args()
+
+    log = open(args.log, encoding="ascii", errors="replace") if args.log else sys.stdin
+    with log:
+        for index, (_, _, rows) in enumerate(frames(log)):
+            if index > 0:
+                print()
+            for row in rows:
+                print(render_row(row, args.ansi))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,86 @@
+//! Finds the ACPI tables that the firmware leaves in memory, for what only they describe, like
+//! where PCI Express configuration space is. Only looking tables up is supported, there is no AML.
+
+use crate::memory;
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Where the BIOS keeps the segment of the extended BIOS data area.
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+const HEADER_LENGTH: u64 = 36;
+
+/// Returns the table with `signature`, like `b"MCFG"`, as the address of its header. Tables with a
+/// bad checksum are skipped. Needs `memory::init`.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let (root, entry_size) = root_table()?;
+    let length = read::<u32>(root + 4u64)?;
+    let entries = (u64::from(length).checked_sub(HEADER_LENGTH)?) / entry_size;
+    (0..entries).find_map(|i| {
+        let entry = root + HEADER_LENGTH + i * entry_size;
+        let table = if entry_size == 8 {
+            read::<u64>(entry)?
+        } else {
+            u64::from(read::<u32>(entry)?)
+        };
+        let table = PhysAddr::try_new(table).ok()?;
+        (read::<[u8; 4]>(table)? == *signature && checksum_ok(table)).then_some(table)
+    })
+}
+
+/// Reads a `T` from physical memory, if that is part of the physical memory mapping.
+pub fn read<T: Copy>(addr: PhysAddr) -> Option<T> {
+    if !memory::is_initialised() {
+        return None;
+    }
+    let virt = memory::phys_to_virt(addr);
+    let last = virt + (core::mem::size_of::<T>().max(1) - 1);
+    memory::translate(virt)?;
+    memory::translate(last)?;
+    Some(unsafe { virt.as_ptr::<T>().read_unaligned() })
+}
+
+/// The XSDT, or the RSDT on ACPI 1.0 systems, and the size of its table pointers.
+fn root_table() -> Option<(PhysAddr, u64)> {
+    let rsdp = find_rsdp()?;
+    let revision = read::<u8>(rsdp + 15u64)?;
+    if revision >= 2 {
+        let xsdt = PhysAddr::try_new(read::<u64>(rsdp + 24u64)?).ok()?;
+        if checksum_ok(xsdt) {
+            return Some((xsdt, 8));
+        }
+    }
+    let rsdt = PhysAddr::new(u64::from(read::<u32>(rsdp + 16u64)?));
+    checksum_ok(rsdt).then_some((rsdt, 4))
+}
+
+/// Looks for the root system description pointer in the first KiB of the extended BIOS data area
+/// and in the BIOS area below 1MiB, on 16 byte boundaries.
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(read::<u16>(PhysAddr::new(EBDA_POINTER))?) << 4;
+    let areas = [(ebda, ebda + 1024), (BIOS_AREA_START, BIOS_AREA_END)];
+    areas.iter().find_map(|&(start, end)| {
+        (start..end).step_by(16).map(PhysAddr::new).find(|&addr| {
+            read::<[u8; 8]>(addr) == Some(*RSDP_SIGNATURE) && bytes_sum_to_zero(addr, 20)
+        })
+    })
+}
+
+fn checksum_ok(table: PhysAddr) -> bool {
+    match read::<u32>(table + 4u64) {
+        Some(length) => bytes_sum_to_zero(table, u64::from(length)),
+        None => false,
+    }
+}
+
+fn bytes_sum_to_zero(start: PhysAddr, length: u64) -> bool {
+    let mut sum = 0u8;
+    for offset in 0..length {
+        match read::<u8>(start + offset) {
+            Some(byte) => sum = sum.wrapping_add(byte),
+            None => return false,
+        }
+    }
+    sum == 0
+}
@@ -0,0 +1,530 @@
+//! A driver for SATA disks on AHCI controllers, registered as the block devices sda, sdb and so
+//! on.
+//!
+//! Each port with a disk gets a frame with its command list, received FIS area and one command
+//! table, so commands run one at a time. They move the data by DMA through a buffer of the
+//! driver's own, with READ and WRITE DMA EXT. The controller's interrupt wakes up the code waiting
+//! for a command, which looks at the port's registers to see whether it's done.
+
+use crate::ata::Identity;
+use crate::block::{self, BlockDevice};
+use crate::interrupts::{self as irqs, STOPWATCH};
+use crate::memory;
+use crate::pci::{self, Bar, Device, Driver, Matcher};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// the controller's registers
+const CAPABILITIES: u64 = 0x00;
+const GLOBAL_CONTROL: u64 = 0x04;
+const INTERRUPT_STATUS: u64 = 0x08;
+const PORTS_IMPLEMENTED: u64 = 0x0c;
+const PORTS: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+
+/// The controller takes 64 bit addresses for its command lists, FIS areas and PRDT entries.
+const CAPABILITY_64_BIT: u32 = 1 << 31;
+
+const CONTROL_INTERRUPTS: u32 = 1 << 1;
+const CONTROL_AHCI_ENABLE: u32 = 1 << 31;
+
+// the registers of each port
+const PORT_COMMAND_LIST: u64 = 0x00;
+const PORT_FIS: u64 = 0x08;
+const PORT_INTERRUPT_STATUS: u64 = 0x10;
+const PORT_INTERRUPT_ENABLE: u64 = 0x14;
+const PORT_COMMAND: u64 = 0x18;
+const PORT_TASK_FILE: u64 = 0x20;
+const PORT_SIGNATURE: u64 = 0x24;
+const PORT_SATA_STATUS: u64 = 0x28;
+const PORT_SATA_ERROR: u64 = 0x30;
+const PORT_COMMAND_ISSUE: u64 = 0x38;
+
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_FIS_RECEIVE: u32 = 1 << 4;
+const COMMAND_FIS_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+/// Device to host register FIS, PIO setup FIS and task file error interrupts.
+const PORT_INTERRUPTS: u32 = 1 << 0 | 1 << 1 | 1 << 30;
+const TASK_FILE_ERROR: u32 = 1 << 0;
+const TASK_FILE_DATA_REQUEST: u32 = 1 << 3;
+const TASK_FILE_BUSY: u32 = 1 << 7;
+const DEVICE_PRESENT: u32 = 3;
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const FIS_HOST_TO_DEVICE: u8 = 0x27;
+const FIS_COMMAND: u8 = 1 << 7;
+const DEVICE_LBA: u8 = 1 << 6;
+const HEADER_WRITE: u32 = 1 << 6;
+
+const IDENTIFY: u8 = 0xec;
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA_EXT: u8 = 0x35;
+const FLUSH_CACHE_EXT: u8 = 0xea;
+
+// where things are in the frame each port has
+const FIS_OFFSET: u64 = 0x400;
+const TABLE_OFFSET: u64 = 0x800;
+const PRDT_OFFSET: u64 = 0x80;
+
+/// The DMA buffer each port has, in frames.
+const DATA_FRAMES: usize = 4;
+const DATA_SIZE: usize = DATA_FRAMES * 4096;
+
+const MAX_CONTROLLERS: usize = 2;
+const NAMES: [&str; 4] = ["sda", "sdb", "sdc", "sdd"];
+/// How often the registers are read before giving up when there is no interrupt.
+const POLL_LIMIT: u32 = 10_000_000;
+/// Timer ticks to wait for a command, about 3 seconds.
+const TIMEOUT_TICKS: u128 = 55;
+
+/// The registers of each controller, 0 if there isn't one.
+static CONTROLLERS: [AtomicU64; MAX_CONTROLLERS] = [AtomicU64::new(0), AtomicU64::new(0)];
+/// The ports of each controller that interrupted, which the disk on the port takes its bit from.
+static INTERRUPTED: [AtomicU32; MAX_CONTROLLERS] = [AtomicU32::new(0), AtomicU32::new(0)];
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_DISK: Once<Disk> = Once::new();
+static DISKS: [Once<Disk>; 4] = [NO_DISK; 4];
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+static INIT: Once<()> = Once::new();
+
+static DRIVER: Driver = Driver {
+    name: "ahci",
+    matchers: &[Matcher::Class {
+        class: pci::CLASS_MASS_STORAGE,
+        subclass: 0x06,
+        prog_if: Some(0x01),
+    }],
+    probe,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+pub struct Disk {
+    name: &'static str,
+    controller: usize,
+    port: u32,
+    registers: VirtAddr,
+    /// Has the command list, received FIS area and command table.
+    frame: PhysAddr,
+    buffer: PhysAddr,
+    uses_interrupts: AtomicBool,
+    identity: Identity,
+    /// Held while a command runs.
+    lock: Mutex<()>,
+}
+
+impl Disk {
+    pub fn model(&self) -> &str {
+        self.identity.model()
+    }
+
+    pub fn uses_interrupts(&self) -> bool {
+        self.uses_interrupts.load(Ordering::Relaxed)
+    }
+
+    fn read(&self, register: u64) -> u32 {
+        read(self.registers + register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        write(self.registers + register, value)
+    }
+
+    /// Runs `command` on `count` sectors at `lba`, with the data in the DMA buffer, and waits
+    /// for it to finish.
+    fn run(
+        &self,
+        command: u8,
+        lba: u64,
+        count: usize,
+        direction: Direction,
+    ) -> Result<(), &'static str> {
+        let frame = memory::phys_to_virt(self.frame);
+        let table = frame + TABLE_OFFSET;
+        let len = count * SECTOR_SIZE;
+
+        // slot 0 of the command list, with a 5 dword command FIS and a PRDT entry if there's data
+        let mut flags = 5 | u32::from(len > 0) << 16;
+        if direction == Direction::Write {
+            flags |= HEADER_WRITE;
+        }
+        let table_phys = (self.frame + TABLE_OFFSET).as_u64();
+        write(frame, flags);
+        write(frame + 4u64, 0u32);
+        write(frame + 8u64, table_phys as u32);
+        write(frame + 12u64, (table_phys >> 32) as u32);
+
+        let lba = lba.to_le_bytes();
+        let fis: [u8; 20] = [
+            FIS_HOST_TO_DEVICE,
+            FIS_COMMAND,
+            command,
+            0,
+            lba[0],
+            lba[1],
+            lba[2],
+            DEVICE_LBA,
+            lba[3],
+            lba[4],
+            lba[5],
+            0,
+            count as u8,
+            (count >> 8) as u8,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        write(table, fis);
+        if len > 0 {
+            let entry = table + PRDT_OFFSET;
+            write(entry, self.buffer.as_u64() as u32);
+            write(entry + 4u64, (self.buffer.as_u64() >> 32) as u32);
+            write(entry + 8u64, 0u32);
+            write(entry + 12u64, (len - 1) as u32 | 1 << 31);
+        }
+
+        for _ in 0..POLL_LIMIT {
+            if self.read(PORT_TASK_FILE) & (TASK_FILE_BUSY | TASK_FILE_DATA_REQUEST) == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.take_interrupt();
+        self.write(PORT_COMMAND_ISSUE, 1);
+        self.wait()
+    }
+
+    fn wait(&self) -> Result<(), &'static str> {
+        let use_interrupts = self.uses_interrupts() && interrupts::are_enabled();
+        let start = STOPWATCH.try_lock().map(|ticks| *ticks);
+        for polls in 0.. {
+            if self.read(PORT_TASK_FILE) & TASK_FILE_ERROR != 0 {
+                // the port stops on errors, and has to be restarted for the next command
+                let command = self.read(PORT_COMMAND);
+                self.write(PORT_COMMAND, command & !COMMAND_START);
+                for _ in 0..POLL_LIMIT {
+                    if self.read(PORT_COMMAND) & COMMAND_LIST_RUNNING == 0 {
+                        break;
+                    }
+                    core::hint::spin_loop();
+                }
+                self.write(PORT_SATA_ERROR, u32::MAX);
+                self.write(PORT_INTERRUPT_STATUS, u32::MAX);
+                self.write(PORT_COMMAND, command | COMMAND_START);
+                return Err("SATA disk error");
+            }
+            if self.read(PORT_COMMAND_ISSUE) & 1 == 0 {
+                return Ok(());
+            }
+            if use_interrupts {
+                // the interrupt can't come between looking for it and halting
+                interrupts::disable();
+                if self.take_interrupt() {
+                    interrupts::enable();
+                } else {
+                    interrupts::enable_and_hlt();
+                }
+                let now = STOPWATCH.try_lock().map(|ticks| *ticks);
+                if let (Some(start), Some(now)) = (start, now) {
+                    if now - start > TIMEOUT_TICKS {
+                        break;
+                    }
+                }
+            } else if polls > POLL_LIMIT {
+                break;
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+        Err("SATA disk timed out")
+    }
+
+    /// Clears the port's bit in `INTERRUPTED`, returns whether it was set.
+    fn take_interrupt(&self) -> bool {
+        let bit = 1 << self.port;
+        INTERRUPTED[self.controller].fetch_and(!bit, Ordering::SeqCst) & bit != 0
+    }
+
+    fn copy_from_buffer(&self, data: &mut [u8]) {
+        let virt = memory::phys_to_virt(self.buffer);
+        unsafe { core::ptr::copy_nonoverlapping(virt.as_ptr(), data.as_mut_ptr(), data.len()) }
+    }
+
+    fn copy_to_buffer(&self, data: &[u8]) {
+        let virt = memory::phys_to_virt(self.buffer);
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), virt.as_mut_ptr(), data.len()) }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        let _port = self.lock.lock();
+        for (i, chunk) in buf.chunks_mut(DATA_SIZE).enumerate() {
+            let lba = lba + (i * DATA_SIZE / SECTOR_SIZE) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            self.run(READ_DMA_EXT, lba, count, Direction::Read)?;
+            self.copy_from_buffer(chunk);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        let _port = self.lock.lock();
+        for (i, chunk) in buf.chunks(DATA_SIZE).enumerate() {
+            let lba = lba + (i * DATA_SIZE / SECTOR_SIZE) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            self.copy_to_buffer(chunk);
+            self.run(WRITE_DMA_EXT, lba, count, Direction::Write)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        let _port = self.lock.lock();
+        self.run(FLUSH_CACHE_EXT, 0, 0, Direction::Read)
+    }
+}
+
+/// Looks for AHCI controllers on PCI and registers the SATA disks on them as block devices.
+pub fn init() {
+    INIT.call_once(|| {
+        if let Err(err) = pci::register_driver(&DRIVER) {
+            log::warn!("no AHCI driver: {}", err);
+        }
+    });
+}
+
+/// The disk registered as `NAMES[index]`.
+pub fn disk(index: usize) -> Option<&'static Disk> {
+    DISKS.get(index)?.r#try()
+}
+
+/// Acknowledges the interrupts of all the controllers, for the IRQ they are on.
+fn handle_interrupt() {
+    for (controller, registers) in CONTROLLERS.iter().enumerate() {
+        let registers = registers.load(Ordering::Relaxed);
+        if registers == 0 {
+            continue;
+        }
+        let registers = VirtAddr::new(registers);
+        let pending: u32 = read(registers + INTERRUPT_STATUS);
+        if pending == 0 {
+            continue;
+        }
+        for port in 0..32 {
+            if pending & 1 << port != 0 {
+                let port_registers = registers + PORTS + port * PORT_SIZE;
+                let status: u32 = read(port_registers + PORT_INTERRUPT_STATUS);
+                write(port_registers + PORT_INTERRUPT_STATUS, status);
+            }
+        }
+        // the ports are cleared first, or the controller interrupts again
+        write(registers + INTERRUPT_STATUS, pending);
+        INTERRUPTED[controller].fetch_or(pending, Ordering::SeqCst);
+    }
+}
+
+fn probe(device: &'static Device) -> Result<(), &'static str> {
+    let controller = CONTROLLER_COUNT.load(Ordering::Relaxed);
+    if controller == MAX_CONTROLLERS {
+        return Err("too many AHCI controllers");
+    }
+    let (addr, size) = match device.bars[5] {
+        Some(Bar::Memory { addr, size, .. }) => (addr, size),
+        _ => return Err("AHCI controller without registers"),
+    };
+    device.enable();
+    let registers = memory::map_mmio(addr, size)?;
+    write(
+        registers + GLOBAL_CONTROL,
+        read::<u32>(registers + GLOBAL_CONTROL) | CONTROL_AHCI_ENABLE,
+    );
+    let addresses_64_bit = read::<u32>(registers + CAPABILITIES) & CAPABILITY_64_BIT != 0;
+    CONTROLLER_COUNT.store(controller + 1, Ordering::Relaxed);
+    CONTROLLERS[controller].store(registers.as_u64(), Ordering::Relaxed);
+
+    let uses_interrupts = match irqs::add_irq_handler(device.interrupt_line, handle_interrupt) {
+        Ok(()) => true,
+        Err(err) => {
+            log::info!("polling AHCI controller {}: {}", device.address, err);
+            false
+        }
+    };
+    let implemented: u32 = read(registers + PORTS_IMPLEMENTED);
+    for port in (0..32).filter(|port| implemented & 1 << port != 0) {
+        let port_registers = registers + PORTS + u64::from(port) * PORT_SIZE;
+        if read::<u32>(port_registers + PORT_SATA_STATUS) & 0xf != DEVICE_PRESENT
+            || read::<u32>(port_registers + PORT_SIGNATURE) != SIGNATURE_ATA
+        {
+            continue;
+        }
+        let added = add_disk(
+            controller,
+            port,
+            port_registers,
+            uses_interrupts,
+            addresses_64_bit,
+        );
+        if let Err(err) = added {
+            log::warn!("AHCI port {} of {}: {}", port, device.address, err);
+        }
+    }
+    write(registers + INTERRUPT_STATUS, u32::MAX);
+    if uses_interrupts {
+        write(
+            registers + GLOBAL_CONTROL,
+            read::<u32>(registers + GLOBAL_CONTROL) | CONTROL_INTERRUPTS,
+        );
+    }
+    Ok(())
+}
+
+fn add_disk(
+    controller: usize,
+    port: u32,
+    registers: VirtAddr,
+    uses_interrupts: bool,
+    addresses_64_bit: bool,
+) -> Result<(), &'static str> {
+    let index = DISK_COUNT.load(Ordering::Relaxed);
+    if index == DISKS.len() {
+        return Err("too many SATA disks");
+    }
+    let frame = memory::allocate_frame()
+        .ok_or("out of memory")?
+        .start_address();
+    let buffer = memory::allocate_frames(DATA_FRAMES)
+        .ok_or("out of memory")?
+        .start_address();
+    let buffer_end = buffer + (DATA_SIZE - 1) as u64;
+    if !addresses_64_bit && (frame.as_u64() >> 32 != 0 || buffer_end.as_u64() >> 32 != 0) {
+        return Err("controller can't reach memory above 4GiB");
+    }
+    unsafe { core::ptr::write_bytes(memory::phys_to_virt(frame).as_mut_ptr::<u8>(), 0, 4096) };
+
+    // the port has to be stopped while it's told where its memory is
+    let command: u32 = read(registers + PORT_COMMAND);
+    write(
+        registers + PORT_COMMAND,
+        command & !(COMMAND_START | COMMAND_FIS_RECEIVE),
+    );
+    let mut stopped = false;
+    for _ in 0..POLL_LIMIT {
+        if read::<u32>(registers + PORT_COMMAND) & (COMMAND_LIST_RUNNING | COMMAND_FIS_RUNNING) == 0
+        {
+            stopped = true;
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    if !stopped {
+        return Err("port doesn't stop");
+    }
+    write_u64(registers + PORT_COMMAND_LIST, frame.as_u64());
+    write_u64(registers + PORT_FIS, (frame + FIS_OFFSET).as_u64());
+    write(registers + PORT_SATA_ERROR, u32::MAX);
+    write(registers + PORT_INTERRUPT_STATUS, u32::MAX);
+    write(
+        registers + PORT_INTERRUPT_ENABLE,
+        if uses_interrupts { PORT_INTERRUPTS } else { 0 },
+    );
+    let command: u32 = read(registers + PORT_COMMAND);
+    write(registers + PORT_COMMAND, command | COMMAND_FIS_RECEIVE);
+    write(
+        registers + PORT_COMMAND,
+        command | COMMAND_FIS_RECEIVE | COMMAND_START,
+    );
+
+    let mut disk = Disk {
+        name: NAMES[index],
+        controller,
+        port,
+        registers,
+        frame,
+        buffer,
+        // the controller's interrupts aren't on yet
+        uses_interrupts: AtomicBool::new(false),
+        identity: Identity::from_sector(&[0; SECTOR_SIZE]),
+        lock: Mutex::new(()),
+    };
+    disk.run(IDENTIFY, 0, 1, Direction::Read)?;
+    let mut sector = [0; SECTOR_SIZE];
+    disk.copy_from_buffer(&mut sector);
+    disk.identity = Identity::from_sector(&sector);
+    disk.uses_interrupts = AtomicBool::new(uses_interrupts);
+
+    DISK_COUNT.store(index + 1, Ordering::Relaxed);
+    let disk = DISKS[index].call_once(|| disk);
+    log::info!(
+        "{}: {}, {} sectors",
+        disk.name,
+        disk.model(),
+        disk.identity.sectors
+    );
+    block::register(disk)
+}
+
+fn read<T: Copy>(addr: VirtAddr) -> T {
+    unsafe { addr.as_ptr::<T>().read_volatile() }
+}
+
+fn write<T: Copy>(addr: VirtAddr, value: T) {
+    unsafe { addr.as_mut_ptr::<T>().write_volatile(value) }
+}
+
+fn write_u64(addr: VirtAddr, value: u64) {
+    write(addr, value as u32);
+    write(addr + 4u64, (value >> 32) as u32);
+}
+
+#[test_case]
+fn test_read_write() {
+    init();
+    // the tests attach a disk to an ich9-ahci controller
+    let disk = disk(0).expect("no SATA disk");
+    assert!(disk.uses_interrupts());
+    let lba = disk.block_count() - 34;
+    let mut data = [0; 34 * SECTOR_SIZE];
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = (i * 5 + lba as usize) as u8;
+    }
+    disk.write_blocks(lba, &data).unwrap();
+    disk.flush().unwrap();
+
+    let mut read = [0; 34 * SECTOR_SIZE];
+    disk.read_blocks(lba, &mut read).unwrap();
+    assert!(read[..] == data[..]);
+
+    read = [0; 34 * SECTOR_SIZE];
+    interrupts::without_interrupts(|| disk.read_blocks(lba, &mut read)).unwrap();
+    assert!(read[..] == data[..]);
+    assert!(disk.read_blocks(disk.block_count(), &mut read).is_err());
+}
@@ -0,0 +1,632 @@
+//! A PIO driver for ATA disks on the two IDE channels, registered as the block devices hda to
+//! hdd: the master and slave drive of the primary channel, then of the secondary one.
+//!
+//! Channels in compatibility mode are at their ISA ports and complete commands with IRQ 14 and
+//! 15. Channels the controller has in native mode are found through their BARs and polled, as are
+//! all of them while interrupts are off. Sectors past 2^28 are reached with LBA48.
+
+use crate::block::{self, BlockDevice};
+use crate::interrupts::{PICS, STOPWATCH};
+use crate::pci::{self, Bar, Device, Driver, Matcher};
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_SELECT: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DEVICE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+const CONTROL_NO_INTERRUPTS: u8 = 1 << 1;
+
+const SELECT_LBA: u8 = 0xe0;
+const SELECT_LBA48: u8 = 0x40;
+const SELECT_SLAVE: u8 = 1 << 4;
+
+const IDENTIFY: u8 = 0xec;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const FLUSH_CACHE: u8 = 0xe7;
+const FLUSH_CACHE_EXT: u8 = 0xea;
+
+/// The signatures in LBA mid and high that packet devices, like CD drives, answer IDENTIFY with.
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xeb);
+const SATA_ATAPI_SIGNATURE: (u8, u8) = (0x69, 0x96);
+
+/// The command block and control ports of channels in compatibility mode, and their IRQs.
+const LEGACY_CHANNELS: [(u16, u16); 2] = [(0x1f0, 0x3f6), (0x170, 0x376)];
+const LEGACY_IRQS: [u8; 2] = [14, 15];
+
+const NAMES: [&str; 4] = ["hda", "hdb", "hdc", "hdd"];
+/// The most sectors one command moves, what a sector count of 0 means with LBA28.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+const LBA28_SECTORS: u64 = 1 << 28;
+/// How often the status register is read before giving up on the drive.
+const POLL_LIMIT: u32 = 1_000_000;
+/// Timer ticks to wait for an interrupt, about 3 seconds.
+const INTERRUPT_TIMEOUT: u128 = 55;
+
+static CHANNELS: [Channel; 2] = [Channel::new(), Channel::new()];
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_DRIVE: Once<Drive> = Once::new();
+static DRIVES: [Once<Drive>; 4] = [NO_DRIVE; 4];
+static INIT: Once<()> = Once::new();
+
+static DRIVER: Driver = Driver {
+    name: "ata",
+    matchers: &[Matcher::Class {
+        class: pci::CLASS_MASS_STORAGE,
+        subclass: 0x01,
+        prog_if: None,
+    }],
+    probe,
+};
+
+/// One IDE channel, which the two drives on it share.
+struct Channel {
+    /// The command block ports, 0 if the channel isn't set up.
+    io: AtomicU16,
+    /// The device control and alternate status port.
+    control: AtomicU16,
+    uses_interrupts: AtomicBool,
+    interrupted: AtomicBool,
+    /// Held while a command runs on the channel.
+    lock: Mutex<()>,
+}
+
+impl Channel {
+    const fn new() -> Channel {
+        Channel {
+            io: AtomicU16::new(0),
+            control: AtomicU16::new(0),
+            uses_interrupts: AtomicBool::new(false),
+            interrupted: AtomicBool::new(false),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        let mut port = Port::new(self.io.load(Ordering::Relaxed) + register);
+        unsafe { port.read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        let mut port = Port::new(self.io.load(Ordering::Relaxed) + register);
+        unsafe { port.write(value) }
+    }
+
+    /// Reads the status without acknowledging an interrupt.
+    fn alternate_status(&self) -> u8 {
+        let mut port = Port::new(self.control.load(Ordering::Relaxed));
+        unsafe { port.read() }
+    }
+
+    fn set_control(&self, value: u8) {
+        let mut port = Port::new(self.control.load(Ordering::Relaxed));
+        unsafe { port.write(value) }
+    }
+
+    fn select(&self, value: u8) {
+        self.write(DRIVE_SELECT, value);
+        self.pause();
+    }
+
+    /// Gives the drive the 400ns it needs to put up a new status, each read takes at least 100ns.
+    fn pause(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, &'static str> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err("drive timed out")
+    }
+
+    /// Waits until the drive is done with the command or the sector, by its interrupt if that
+    /// can come, and returns its status.
+    fn wait(&self) -> Result<u8, &'static str> {
+        if self.uses_interrupts.load(Ordering::Relaxed) && interrupts::are_enabled() {
+            let start = STOPWATCH.try_lock().map(|ticks| *ticks);
+            loop {
+                interrupts::disable();
+                if self.interrupted.swap(false, Ordering::SeqCst) {
+                    interrupts::enable();
+                    break;
+                }
+                let now = STOPWATCH.try_lock().map(|ticks| *ticks);
+                if let (Some(start), Some(now)) = (start, now) {
+                    if now - start > INTERRUPT_TIMEOUT {
+                        interrupts::enable();
+                        return Err("drive didn't interrupt");
+                    }
+                }
+                interrupts::enable_and_hlt();
+            }
+        }
+        self.pause();
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+            return Err(self.error());
+        }
+        Ok(status)
+    }
+
+    fn wait_for_data(&self) -> Result<(), &'static str> {
+        if self.wait()? & STATUS_DATA_REQUEST == 0 {
+            return Err("drive has no data ready");
+        }
+        Ok(())
+    }
+
+    fn error(&self) -> &'static str {
+        let error = self.read(ERROR);
+        if self.alternate_status() & STATUS_DEVICE_FAULT != 0 {
+            "drive fault"
+        } else if error & 1 << 6 != 0 {
+            "uncorrectable data error"
+        } else if error & 1 << 4 != 0 {
+            "sector not found"
+        } else if error & 1 << 2 != 0 {
+            "command aborted"
+        } else {
+            "drive error"
+        }
+    }
+
+    fn read_sector(&self, sector: &mut [u8]) {
+        let mut data: Port<u16> = Port::new(self.io.load(Ordering::Relaxed) + DATA);
+        for word in sector.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, sector: &[u8]) {
+        let mut data: Port<u16> = Port::new(self.io.load(Ordering::Relaxed) + DATA);
+        for word in sector.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Addressing {
+    Lba28,
+    Lba48,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// What IDENTIFY tells about a drive, which SATA drives answer the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Identity {
+    model: [u8; 40],
+    serial: [u8; 20],
+    pub(crate) sectors: u64,
+    pub(crate) lba48: bool,
+}
+
+impl Identity {
+    /// Parses the sector that IDENTIFY returns.
+    pub(crate) fn from_sector(sector: &[u8]) -> Identity {
+        let mut words = [0; 256];
+        for (word, bytes) in words.iter_mut().zip(sector.chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Identity::parse(&words)
+    }
+
+    pub(crate) fn model(&self) -> &str {
+        trimmed(&self.model)
+    }
+
+    pub(crate) fn serial(&self) -> &str {
+        trimmed(&self.serial)
+    }
+
+    fn parse(words: &[u16; 256]) -> Identity {
+        // the strings have the first character of each pair in the high byte
+        let mut model = [0; 40];
+        for (pair, &word) in model.chunks_exact_mut(2).zip(&words[27..47]) {
+            pair.copy_from_slice(&word.to_be_bytes());
+        }
+        let mut serial = [0; 20];
+        for (pair, &word) in serial.chunks_exact_mut(2).zip(&words[10..20]) {
+            pair.copy_from_slice(&word.to_be_bytes());
+        }
+        let lba48 = words[83] & 1 << 10 != 0;
+        let sectors = if lba48 {
+            words[100..104]
+                .iter()
+                .rev()
+                .fold(0, |sectors, &word| sectors << 16 | u64::from(word))
+        } else {
+            u64::from(words[61]) << 16 | u64::from(words[60])
+        };
+        Identity {
+            model,
+            serial,
+            sectors,
+            lba48,
+        }
+    }
+}
+
+pub struct Drive {
+    name: &'static str,
+    channel: &'static Channel,
+    slave: bool,
+    identity: Identity,
+}
+
+impl Drive {
+    pub fn model(&self) -> &str {
+        self.identity.model()
+    }
+
+    pub fn serial(&self) -> &str {
+        self.identity.serial()
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.identity.lba48
+    }
+
+    fn addressing(&self, lba: u64, count: u64) -> Result<Addressing, &'static str> {
+        if lba + count <= LBA28_SECTORS {
+            Ok(Addressing::Lba28)
+        } else if self.identity.lba48 {
+            Ok(Addressing::Lba48)
+        } else {
+            Err("sectors are past what LBA28 can reach")
+        }
+    }
+
+    /// Moves the sectors in `buf` at `lba`, in as many commands as it takes.
+    fn transfer(
+        &self,
+        lba: u64,
+        buf: Buffer,
+        addressing: Option<Addressing>,
+    ) -> Result<(), &'static str> {
+        let count = block::check_range(self, lba, buf.len())?;
+        let addressing = match addressing {
+            Some(addressing) => addressing,
+            None => self.addressing(lba, count)?,
+        };
+        let chunk = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+        let _channel = self.channel.lock.lock();
+        match buf {
+            Buffer::Read(buf) => {
+                for (i, chunk) in buf.chunks_mut(chunk).enumerate() {
+                    let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+                    self.command(lba, chunk.len() / SECTOR_SIZE, Direction::Read, addressing)?;
+                    for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                        self.channel.wait_for_data()?;
+                        // reading the last word starts the next sector, and its interrupt
+                        self.channel.interrupted.store(false, Ordering::SeqCst);
+                        self.channel.read_sector(sector);
+                    }
+                }
+            }
+            Buffer::Write(buf) => {
+                for (i, chunk) in buf.chunks(chunk).enumerate() {
+                    let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+                    self.command(lba, chunk.len() / SECTOR_SIZE, Direction::Write, addressing)?;
+                    // the drive asks for the first sector without interrupting
+                    if self.channel.wait_not_busy()? & STATUS_DATA_REQUEST == 0 {
+                        return Err(self.channel.error());
+                    }
+                    for (j, sector) in chunk.chunks_exact(SECTOR_SIZE).enumerate() {
+                        if j > 0 {
+                            self.channel.wait_for_data()?;
+                        }
+                        self.channel.interrupted.store(false, Ordering::SeqCst);
+                        self.channel.write_sector(sector);
+                    }
+                    self.channel.wait()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn command(
+        &self,
+        lba: u64,
+        count: usize,
+        direction: Direction,
+        addressing: Addressing,
+    ) -> Result<(), &'static str> {
+        let channel = self.channel;
+        let slave = if self.slave { SELECT_SLAVE } else { 0 };
+        let lba = lba.to_le_bytes();
+        match addressing {
+            Addressing::Lba28 => channel.select(SELECT_LBA | slave | lba[3] & 0x0f),
+            Addressing::Lba48 => channel.select(SELECT_LBA48 | slave),
+        }
+        channel.wait_not_busy()?;
+        if addressing == Addressing::Lba48 {
+            // the high bytes go first, the registers keep the previous value written to them
+            channel.write(SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(LBA_LOW, lba[3]);
+            channel.write(LBA_MID, lba[4]);
+            channel.write(LBA_HIGH, lba[5]);
+        }
+        channel.write(SECTOR_COUNT, count as u8);
+        channel.write(LBA_LOW, lba[0]);
+        channel.write(LBA_MID, lba[1]);
+        channel.write(LBA_HIGH, lba[2]);
+        channel.interrupted.store(false, Ordering::SeqCst);
+        channel.write(
+            COMMAND,
+            match (direction, addressing) {
+                (Direction::Read, Addressing::Lba28) => READ_SECTORS,
+                (Direction::Read, Addressing::Lba48) => READ_SECTORS_EXT,
+                (Direction::Write, Addressing::Lba28) => WRITE_SECTORS,
+                (Direction::Write, Addressing::Lba48) => WRITE_SECTORS_EXT,
+            },
+        );
+        channel.pause();
+        Ok(())
+    }
+}
+
+enum Buffer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl Buffer<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Buffer::Read(buf) => buf.len(),
+            Buffer::Write(buf) => buf.len(),
+        }
+    }
+}
+
+impl BlockDevice for Drive {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.transfer(lba, Buffer::Read(buf), None)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        self.transfer(lba, Buffer::Write(buf), None)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        let _channel = self.channel.lock.lock();
+        let slave = if self.slave { SELECT_SLAVE } else { 0 };
+        self.channel.select(SELECT_LBA | slave);
+        self.channel.wait_not_busy()?;
+        self.channel.interrupted.store(false, Ordering::SeqCst);
+        self.channel.write(
+            COMMAND,
+            if self.identity.lba48 {
+                FLUSH_CACHE_EXT
+            } else {
+                FLUSH_CACHE
+            },
+        );
+        self.channel.pause();
+        self.channel.wait().map(|_| ())
+    }
+}
+
+/// Looks for IDE controllers on PCI and registers the ATA drives on them as block devices. Needs
+/// interrupts set up.
+pub fn init() {
+    INIT.call_once(|| {
+        if let Err(err) = pci::register_driver(&DRIVER) {
+            log::warn!("no ATA driver: {}", err);
+        }
+    });
+}
+
+/// The drive registered as `NAMES[index]`.
+pub fn drive(index: usize) -> Option<&'static Drive> {
+    DRIVES.get(index)?.r#try()
+}
+
+/// Acknowledges the interrupt of channel `index`, called by the IRQ 14 and 15 handlers.
+pub(crate) fn handle_interrupt(index: usize) {
+    let channel = &CHANNELS[index];
+    if channel.io.load(Ordering::Relaxed) != 0 {
+        channel.read(STATUS);
+        channel.interrupted.store(true, Ordering::SeqCst);
+    }
+}
+
+fn probe(controller: &'static Device) -> Result<(), &'static str> {
+    controller.enable();
+    let mut found = 0;
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        // prog_if has a bit per channel for being in native mode
+        let native = controller.prog_if & 1 << (index * 2) != 0;
+        let (io, control) = if native {
+            match (controller.bars[index * 2], controller.bars[index * 2 + 1]) {
+                (Some(Bar::Io { port: io, .. }), Some(Bar::Io { port: control, .. })) => {
+                    (io, control + 2)
+                }
+                _ => continue,
+            }
+        } else {
+            LEGACY_CHANNELS[index]
+        };
+        channel.io.store(io, Ordering::Relaxed);
+        channel.control.store(control, Ordering::Relaxed);
+        channel.uses_interrupts.store(!native, Ordering::Relaxed);
+        channel.set_control(if native { CONTROL_NO_INTERRUPTS } else { 0 });
+        if !native {
+            unmask_irq(LEGACY_IRQS[index]);
+        }
+        // nothing drives the bus when there are no drives
+        if channel.read(STATUS) == 0xff {
+            continue;
+        }
+        for slave in [false, true] {
+            let index = index * 2 + usize::from(slave);
+            match identify(channel, slave) {
+                Ok(Some(identity)) => {
+                    let drive = DRIVES[index].call_once(|| Drive {
+                        name: NAMES[index],
+                        channel,
+                        slave,
+                        identity,
+                    });
+                    log::info!(
+                        "{}: {}, {} sectors{}",
+                        drive.name,
+                        drive.model(),
+                        drive.identity.sectors,
+                        if drive.identity.lba48 { ", LBA48" } else { "" }
+                    );
+                    block::register(drive)?;
+                    found += 1;
+                }
+                Ok(None) => {}
+                Err(err) => log::debug!("{}: {}", NAMES[index], err),
+            }
+        }
+    }
+    if found == 0 {
+        return Err("no ATA drives");
+    }
+    Ok(())
+}
+
+/// Asks the drive for its identity. Returns `None` if there is no drive, or it's not an ATA one.
+fn identify(channel: &Channel, slave: bool) -> Result<Option<Identity>, &'static str> {
+    let _channel = channel.lock.lock();
+    channel.select(SELECT_LBA | if slave { SELECT_SLAVE } else { 0 });
+    channel.write(SECTOR_COUNT, 0);
+    channel.write(LBA_LOW, 0);
+    channel.write(LBA_MID, 0);
+    channel.write(LBA_HIGH, 0);
+    channel.write(COMMAND, IDENTIFY);
+    channel.pause();
+    if channel.read(STATUS) == 0 {
+        return Ok(None);
+    }
+    channel.wait_not_busy()?;
+    let signature = (channel.read(LBA_MID), channel.read(LBA_HIGH));
+    if signature == ATAPI_SIGNATURE || signature == SATA_ATAPI_SIGNATURE {
+        return Ok(None);
+    }
+    if signature != (0, 0) {
+        return Err("not an ATA drive");
+    }
+    let status = channel.wait_not_busy()?;
+    if status & STATUS_ERROR != 0 {
+        return Ok(None);
+    }
+    if status & STATUS_DATA_REQUEST == 0 {
+        return Err("no answer to IDENTIFY");
+    }
+    let mut sector = [0; SECTOR_SIZE];
+    channel.read_sector(&mut sector);
+    Ok(Some(Identity::from_sector(&sector)))
+}
+
+fn unmask_irq(irq: u8) {
+    // IRQ 14 and 15 come in through the secondary PIC on IRQ 2
+    interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let [primary, secondary] = pics.read_masks();
+        pics.write_masks(primary & !(1 << 2), secondary & !(1 << (irq - 8)));
+    });
+}
+
+fn trimmed(text: &[u8]) -> &str {
+    core::str::from_utf8(text).unwrap_or("").trim()
+}
+
+#[test_case]
+fn test_identity() {
+    let mut words = [0; 256];
+    words[27] = u16::from_be_bytes(*b"QE");
+    words[28] = u16::from_be_bytes(*b"MU");
+    words[29] = u16::from_be_bytes(*b"  ");
+    words[60] = 0x2000;
+    words[61] = 0x0001;
+    let identity = Identity::parse(&words);
+    assert_eq!(identity.model(), "QEMU");
+    assert_eq!(identity.sectors, 0x1_2000);
+    assert!(!identity.lba48);
+
+    words[83] = 1 << 10;
+    words[100] = 0x5678;
+    words[101] = 0x1234;
+    words[102] = 0x0001;
+    let identity = Identity::parse(&words);
+    assert_eq!(identity.sectors, 0x1_1234_5678);
+    assert!(identity.lba48);
+}
+
+#[test_case]
+fn test_read_write() {
+    init();
+    // the test disk image is attached as the primary slave
+    let drive = drive(1).expect("no test disk");
+    let lba = drive.block_count() - 3;
+    let mut data = [0; 3 * SECTOR_SIZE];
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = (i * 7 + lba as usize) as u8;
+    }
+    drive.write_blocks(lba, &data).unwrap();
+    drive.flush().unwrap();
+
+    let mut read = [0; 3 * SECTOR_SIZE];
+    drive.read_blocks(lba, &mut read).unwrap();
+    assert!(read[..] == data[..]);
+
+    read = [0; 3 * SECTOR_SIZE];
+    drive
+        .transfer(lba, Buffer::Read(&mut read), Some(Addressing::Lba48))
+        .unwrap();
+    assert!(read[..] == data[..]);
+
+    // without interrupts the drive is polled
+    read = [0; 3 * SECTOR_SIZE];
+    interrupts::without_interrupts(|| drive.read_blocks(lba, &mut read)).unwrap();
+    assert!(read[..] == data[..]);
+
+    assert!(drive.read_blocks(drive.block_count(), &mut read).is_err());
+}
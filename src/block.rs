@@ -0,0 +1,145 @@
+//! Devices that store data in fixed size blocks, like disks, and the list of the ones that were
+//! found, so that they can be looked up by name.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const MAX_DEVICES: usize = 16;
+
+static DEVICES: Mutex<[Option<&'static dyn BlockDevice>; MAX_DEVICES]> =
+    Mutex::new([None; MAX_DEVICES]);
+
+pub trait BlockDevice: Sync {
+    /// Like "hda", unique among the registered devices.
+    fn name(&self) -> &str;
+
+    /// In bytes. Buffers given to `read_blocks` and `write_blocks` are whole blocks long.
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Fills `buf` with the blocks starting at block `lba`.
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+    /// Writes `buf` to the blocks starting at block `lba`.
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str>;
+
+    /// Makes sure everything written so far is stored, not just cached by the device.
+    fn flush(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/// Checks that `len` bytes at block `lba` are whole blocks that `device` has, and returns how
+/// many blocks that is.
+pub fn check_range(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, &'static str> {
+    let block_size = device.block_size();
+    let count = (len / block_size) as u64;
+    if count as usize * block_size != len {
+        return Err("buffer isn't a whole number of blocks");
+    }
+    match lba.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err("blocks are past the end of the device"),
+    }
+}
+
+/// Adds `device` to the ones `find` and `devices` know about.
+pub fn register(device: &'static dyn BlockDevice) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        if devices
+            .iter()
+            .flatten()
+            .any(|other| other.name() == device.name())
+        {
+            return Err("a block device with that name exists already");
+        }
+        let slot = devices
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("too many block devices")?;
+        *slot = Some(device);
+        Ok(())
+    })
+}
+
+pub fn find(name: &str) -> Option<&'static dyn BlockDevice> {
+    devices().find(|device| device.name() == name)
+}
+
+/// The registered devices, in the order they were registered in.
+pub fn devices() -> impl Iterator<Item = &'static dyn BlockDevice> {
+    let devices = interrupts::without_interrupts(|| *DEVICES.lock());
+    IntoIterator::into_iter(devices).flatten()
+}
+
+/// A small disk in memory for the tests of code on top of block devices.
+#[cfg(test)]
+pub(crate) struct RamDisk {
+    pub(crate) name: &'static str,
+    pub(crate) blocks: spin::Mutex<[u8; 64 * 512]>,
+}
+
+#[cfg(test)]
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn block_count(&self) -> u64 {
+        64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        let start = lba as usize * 512;
+        buf.copy_from_slice(&self.blocks.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(self, lba, buf.len())?;
+        let start = lba as usize * 512;
+        self.blocks.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_check_range() {
+    struct Blocks;
+
+    impl BlockDevice for Blocks {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn block_count(&self) -> u64 {
+            4
+        }
+
+        fn read_blocks(&self, _lba: u64, _buf: &mut [u8]) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn write_blocks(&self, _lba: u64, _buf: &[u8]) -> Result<(), &'static str> {
+            Ok(())
+        }
+    }
+
+    assert_eq!(check_range(&Blocks, 0, 2048), Ok(4));
+    assert_eq!(check_range(&Blocks, 3, 512), Ok(1));
+    assert_eq!(check_range(&Blocks, 2, 0), Ok(0));
+    assert!(check_range(&Blocks, 3, 1024).is_err());
+    assert!(check_range(&Blocks, 0, 100).is_err());
+    assert!(check_range(&Blocks, u64::MAX, 512).is_err());
+}
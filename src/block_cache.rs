@@ -0,0 +1,180 @@
+//! Keeps recently used blocks of a block device in memory, so that file systems can read and
+//! write a few bytes at a time without going to the disk for each. Writes stay in the cache until
+//! their block is evicted or the cache is flushed.
+
+use crate::block::BlockDevice;
+
+/// The size of the blocks that can be cached.
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    block: u64,
+    valid: bool,
+    dirty: bool,
+    /// When the entry was last used, for evicting the least recently used one.
+    used: u64,
+    data: [u8; BLOCK_SIZE],
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        block: 0,
+        valid: false,
+        dirty: false,
+        used: 0,
+        data: [0; BLOCK_SIZE],
+    };
+}
+
+pub struct BlockCache<const N: usize> {
+    device: &'static dyn BlockDevice,
+    entries: [Entry; N],
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<const N: usize> BlockCache<N> {
+    pub fn new(device: &'static dyn BlockDevice) -> Result<Self, &'static str> {
+        if device.block_size() != BLOCK_SIZE {
+            return Err("the block cache only works with 512 byte blocks");
+        }
+        Ok(BlockCache {
+            device,
+            entries: [Entry::EMPTY; N],
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        })
+    }
+
+    pub fn device(&self) -> &'static dyn BlockDevice {
+        self.device
+    }
+
+    /// How many blocks were found in the cache and how many had to be read from the device.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Fills `buf` with the bytes at byte `pos` of the device.
+    pub fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let offset = (at % BLOCK_SIZE as u64) as usize;
+            let len = (BLOCK_SIZE - offset).min(buf.len() - done);
+            let entry = self.entry(at / BLOCK_SIZE as u64, true)?;
+            buf[done..done + len].copy_from_slice(&entry.data[offset..offset + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to byte `pos` of the device.
+    pub fn write_at(&mut self, pos: u64, data: &[u8]) -> Result<(), &'static str> {
+        self.modify(pos, data.len(), |done, bytes| {
+            bytes.copy_from_slice(&data[done..done + bytes.len()])
+        })
+    }
+
+    /// Sets `len` bytes at byte `pos` of the device to `value`.
+    pub fn fill_at(&mut self, pos: u64, len: usize, value: u8) -> Result<(), &'static str> {
+        self.modify(pos, len, |_, bytes| bytes.fill(value))
+    }
+
+    /// Writes the changed blocks to the device and flushes it.
+    pub fn flush(&mut self) -> Result<(), &'static str> {
+        for i in 0..N {
+            self.write_back(i)?;
+        }
+        self.device.flush()
+    }
+
+    /// Calls `f` with the part of each block that the `len` bytes at `pos` cover and how many
+    /// bytes come before it. Blocks that are covered completely aren't read first.
+    fn modify(
+        &mut self,
+        pos: u64,
+        len: usize,
+        mut f: impl FnMut(usize, &mut [u8]),
+    ) -> Result<(), &'static str> {
+        let mut done = 0;
+        while done < len {
+            let at = pos + done as u64;
+            let offset = (at % BLOCK_SIZE as u64) as usize;
+            let part = (BLOCK_SIZE - offset).min(len - done);
+            let entry = self.entry(at / BLOCK_SIZE as u64, part != BLOCK_SIZE)?;
+            f(done, &mut entry.data[offset..offset + part]);
+            entry.dirty = true;
+            done += part;
+        }
+        Ok(())
+    }
+
+    /// The entry for `block`, which is read from the device if it isn't cached and `load` is set.
+    fn entry(&mut self, block: u64, load: bool) -> Result<&mut Entry, &'static str> {
+        self.clock += 1;
+        if let Some(i) = self
+            .entries
+            .iter()
+            .position(|entry| entry.valid && entry.block == block)
+        {
+            self.hits += 1;
+            self.entries[i].used = self.clock;
+            return Ok(&mut self.entries[i]);
+        }
+
+        self.misses += 1;
+        let i = (0..N)
+            .min_by_key(|&i| (self.entries[i].valid, self.entries[i].used))
+            .ok_or("the block cache has no entries")?;
+        self.write_back(i)?;
+        let entry = &mut self.entries[i];
+        entry.valid = false;
+        if load {
+            self.device.read_blocks(block, &mut entry.data)?;
+        }
+        entry.block = block;
+        entry.valid = true;
+        entry.used = self.clock;
+        Ok(entry)
+    }
+
+    fn write_back(&mut self, i: usize) -> Result<(), &'static str> {
+        let entry = &mut self.entries[i];
+        if entry.valid && entry.dirty {
+            self.device.write_blocks(entry.block, &entry.data)?;
+            entry.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_cache() {
+    use crate::block::RamDisk;
+
+    static DISK: RamDisk = RamDisk {
+        name: "cachetest",
+        blocks: spin::Mutex::new([0; 64 * 512]),
+    };
+    DISK.blocks.lock()[1000] = 7;
+    let mut cache = BlockCache::<2>::new(&DISK).unwrap();
+    let mut byte = [0];
+    cache.read_at(1000, &mut byte).unwrap();
+    assert_eq!(byte, [7]);
+
+    // across a block boundary, and not written until the blocks are evicted
+    cache.write_at(510, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(DISK.blocks.lock()[510..514], [0; 4]);
+    cache.read_at(1000, &mut byte).unwrap();
+    cache.fill_at(40 * 512, 512, 9).unwrap();
+    assert_eq!(DISK.blocks.lock()[510..512], [1, 2]);
+    assert_eq!(cache.stats(), (2, 3));
+
+    cache.flush().unwrap();
+    assert_eq!(DISK.blocks.lock()[510..514], [1, 2, 3, 4]);
+    assert_eq!(DISK.blocks.lock()[40 * 512 + 511], 9);
+}
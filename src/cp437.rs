@@ -0,0 +1,79 @@
+//! Mapping between Unicode and code page 437, the character set built into the VGA text mode font.
+
+/// Shown for characters that have no glyph in code page 437.
+pub const REPLACEMENT: u8 = 0xfe;
+
+/// The glyphs of the control range 0x01..=0x1f, index 0 is 0x01.
+const LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕', '‼',
+    '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// The glyphs of 0x80..=0xff, index 0 is 0x80.
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ', 'Æ',
+    'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', 'á', 'í', 'ó', 'ú', 'ñ', 'Ñ',
+    'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕',
+    '╣', '║', '╗', '╝', '╜', '╛', '┐', '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦',
+    '╠', '═', '╬', '╧', '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐',
+    '▀', 'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', '≡', '±',
+    '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Characters that aren't in the table but look the same as one of its glyphs.
+const ALIASES: [(char, u8); 5] = [
+    ('\u{3b2}', 0xe1),  // Greek beta drawn as sharp s
+    ('\u{3bc}', 0xe6),  // Greek mu drawn as micro sign
+    ('\u{2211}', 0xe4), // n-ary summation drawn as capital sigma
+    ('\u{2126}', 0xea), // ohm sign drawn as capital omega
+    ('\u{2208}', 0xee), // element of drawn as epsilon
+];
+
+/// Returns the code page 437 byte that displays `c`, or `None` if there is no such glyph.
+pub fn encode(c: char) -> Option<u8> {
+    match c {
+        ' '..='~' => Some(c as u8),
+        '⌂' => Some(0x7f),
+        _ => LOW
+            .iter()
+            .position(|&glyph| glyph == c)
+            .map(|i| i as u8 + 0x01)
+            .or_else(|| {
+                HIGH.iter()
+                    .position(|&glyph| glyph == c)
+                    .map(|i| i as u8 + 0x80)
+            })
+            .or_else(|| {
+                ALIASES
+                    .iter()
+                    .find(|&&(alias, _)| alias == c)
+                    .map(|&(_, byte)| byte)
+            }),
+    }
+}
+
+/// Returns the character drawn for code page 437 byte `byte`.
+pub fn decode(byte: u8) -> char {
+    match byte {
+        0x00 => ' ',
+        0x01..=0x1f => LOW[byte as usize - 0x01],
+        0x7f => '⌂',
+        0x20..=0x7e => byte as char,
+        _ => HIGH[byte as usize - 0x80],
+    }
+}
+
+#[test_case]
+fn test_cp437_round_trip() {
+    for byte in 0x01..=0xff {
+        assert_eq!(encode(decode(byte)), Some(byte));
+    }
+}
+
+#[test_case]
+fn test_cp437_unmappable() {
+    assert_eq!(encode('£'), Some(0x9c));
+    assert_eq!(encode('╬'), Some(0xce));
+    assert_eq!(encode('€'), None);
+    assert_eq!(encode('\t'), None);
+}
@@ -0,0 +1,267 @@
+//! The devices as files, mounted at /dev: the consoles tty0 to tty3, the serial ports ttyS0 to
+//! ttyS3 that are there, console for wherever `print!` goes, null, zero, and the block devices
+//! under their own names.
+//!
+//! Reading a console or serial port gives what was typed so far without waiting, which may be
+//! nothing. Block devices can be read and written at any byte offset.
+
+use crate::block::{self, BlockDevice};
+use crate::keyboard;
+use crate::serial::{self, ComPort};
+use crate::vfs::{DirEntry, FileSystem, Inode, Kind, Stat};
+use crate::vga_buffer::{self, CONSOLE_COUNT};
+
+pub static DEVFS: DevFs = DevFs;
+
+const ROOT: Inode = 0;
+const NULL: Inode = 1;
+const ZERO: Inode = 2;
+const CONSOLE: Inode = 3;
+const FIRST_TTY: Inode = 0x10;
+const FIRST_SERIAL: Inode = 0x20;
+const FIRST_BLOCK: Inode = 0x100;
+
+const TTY_NAMES: [&str; CONSOLE_COUNT] = ["tty0", "tty1", "tty2", "tty3"];
+const SERIAL_NAMES: [&str; 4] = ["ttyS0", "ttyS1", "ttyS2", "ttyS3"];
+/// The largest block size of the block devices that can be used as files.
+const MAX_BLOCK_SIZE: usize = 4096;
+
+#[derive(Clone, Copy)]
+enum Device {
+    Null,
+    Zero,
+    Console,
+    Tty(usize),
+    Serial(ComPort),
+    Block(&'static dyn BlockDevice),
+}
+
+impl Device {
+    fn from_inode(inode: Inode) -> Option<Device> {
+        match inode {
+            NULL => Some(Device::Null),
+            ZERO => Some(Device::Zero),
+            CONSOLE => Some(Device::Console),
+            _ if (FIRST_TTY..FIRST_TTY + CONSOLE_COUNT as Inode).contains(&inode) => {
+                Some(Device::Tty((inode - FIRST_TTY) as usize))
+            }
+            _ if (FIRST_SERIAL..FIRST_SERIAL + 4).contains(&inode) => {
+                let port = ComPort::ALL[(inode - FIRST_SERIAL) as usize];
+                Some(Device::Serial(port)).filter(|_| serial::is_present(port))
+            }
+            _ if inode >= FIRST_BLOCK => block::devices()
+                .nth((inode - FIRST_BLOCK) as usize)
+                .map(Device::Block),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match *self {
+            Device::Null => "null",
+            Device::Zero => "zero",
+            Device::Console => "console",
+            Device::Tty(index) => TTY_NAMES[index],
+            Device::Serial(port) => SERIAL_NAMES[port as usize],
+            Device::Block(device) => device.name(),
+        }
+    }
+}
+
+/// The inodes in the order they are listed in.
+fn inodes() -> impl Iterator<Item = Inode> {
+    let fixed = IntoIterator::into_iter([NULL, ZERO, CONSOLE]);
+    let ttys = (0..CONSOLE_COUNT as Inode).map(|i| FIRST_TTY + i);
+    let serials = (0..4).map(|i| FIRST_SERIAL + i);
+    let blocks = (0..block::devices().count() as Inode).map(|i| FIRST_BLOCK + i);
+    fixed
+        .chain(ttys)
+        .chain(serials)
+        .chain(blocks)
+        .filter(|&inode| Device::from_inode(inode).is_some())
+}
+
+/// Puts the characters typed so far into `buf`, as many as fit whole.
+fn read_typed(buf: &mut [u8], mut next: impl FnMut() -> Option<char>) -> usize {
+    let mut len = 0;
+    while buf.len() - len >= 4 {
+        match next() {
+            Some(c) => len += c.encode_utf8(&mut buf[len..]).len(),
+            None => break,
+        }
+    }
+    len
+}
+
+/// Reads or writes the bytes at `offset` of a block device a block at a time.
+fn block_transfer(
+    device: &dyn BlockDevice,
+    offset: u64,
+    len: usize,
+    mut f: impl FnMut(usize, &mut [u8]) -> bool,
+) -> Result<usize, &'static str> {
+    let block_size = device.block_size();
+    if block_size > MAX_BLOCK_SIZE {
+        return Err("the block size is too big");
+    }
+    let size = device.block_count() * block_size as u64;
+    let len = len.min(size.saturating_sub(offset) as usize);
+    let mut block = [0; MAX_BLOCK_SIZE];
+    let block = &mut block[..block_size];
+    let mut done = 0;
+    while done < len {
+        let at = offset + done as u64;
+        let lba = at / block_size as u64;
+        let in_block = (at % block_size as u64) as usize;
+        let part = (block_size - in_block).min(len - done);
+        device.read_blocks(lba, block)?;
+        if f(done, &mut block[in_block..in_block + part]) {
+            device.write_blocks(lba, block)?;
+        }
+        done += part;
+    }
+    Ok(len)
+}
+
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn type_name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Inode {
+        ROOT
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, &'static str> {
+        let (kind, size) = match inode {
+            ROOT => (Kind::Directory, 0),
+            _ => match Device::from_inode(inode).ok_or("no such device")? {
+                Device::Block(device) => (
+                    Kind::BlockDevice,
+                    device.block_count() * device.block_size() as u64,
+                ),
+                _ => (Kind::CharDevice, 0),
+            },
+        };
+        Ok(Stat { kind, size, inode })
+    }
+
+    fn lookup(&self, dir: Inode, name: &str) -> Result<Inode, &'static str> {
+        if dir != ROOT {
+            return Err("not a directory");
+        }
+        inodes()
+            .find(|&inode| Device::from_inode(inode).is_some_and(|d| d.name() == name))
+            .ok_or("no such device")
+    }
+
+    fn read_dir(&self, dir: Inode, index: &mut u64) -> Result<Option<DirEntry>, &'static str> {
+        if dir != ROOT {
+            return Err("not a directory");
+        }
+        let inode = match inodes().nth(*index as usize) {
+            Some(inode) => inode,
+            None => return Ok(None),
+        };
+        *index += 1;
+        let device = Device::from_inode(inode).ok_or("no such device")?;
+        let stat = self.stat(inode)?;
+        DirEntry::new(device.name(), inode, stat.kind).map(Some)
+    }
+
+    fn read(&self, inode: Inode, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        match Device::from_inode(inode).ok_or("no such device")? {
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            Device::Console => Ok(read_typed(buf, || {
+                keyboard::read_char().or_else(serial::read_char)
+            })),
+            Device::Tty(index) if index == vga_buffer::active_console() => {
+                Ok(read_typed(buf, keyboard::read_char))
+            }
+            Device::Tty(_) => Ok(0),
+            Device::Serial(port) => {
+                let mut len = 0;
+                while let Some(byte) = buf.get_mut(len) {
+                    match serial::read_byte(port) {
+                        Some(received) => *byte = received,
+                        None => break,
+                    }
+                    len += 1;
+                }
+                Ok(len)
+            }
+            Device::Block(device) => block_transfer(device, offset, buf.len(), |done, bytes| {
+                let len = bytes.len();
+                buf[done..done + len].copy_from_slice(bytes);
+                false
+            }),
+        }
+    }
+
+    fn write(&self, inode: Inode, offset: u64, data: &[u8]) -> Result<usize, &'static str> {
+        match Device::from_inode(inode).ok_or("no such device")? {
+            Device::Null | Device::Zero => {}
+            Device::Console => vga_buffer::write_console(vga_buffer::output_console(), data),
+            Device::Tty(index) => vga_buffer::write_console(index, data),
+            Device::Serial(port) => serial::write_bytes(port, data)?,
+            Device::Block(device) => {
+                return block_transfer(device, offset, data.len(), |done, bytes| {
+                    let len = bytes.len();
+                    bytes.copy_from_slice(&data[done..done + len]);
+                    true
+                });
+            }
+        }
+        Ok(data.len())
+    }
+
+    /// Character devices have no size to change, so opening them to truncate works.
+    fn truncate(&self, inode: Inode, _size: u64) -> Result<(), &'static str> {
+        match self.stat(inode)?.kind {
+            Kind::CharDevice => Ok(()),
+            _ => Err("can't change the size of a device"),
+        }
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        block::devices().try_for_each(|device| device.flush())
+    }
+}
+
+#[test_case]
+fn test_devices() {
+    let null = DEVFS.lookup(ROOT, "null").unwrap();
+    assert_eq!(DEVFS.write(null, 0, b"gone"), Ok(4));
+    assert_eq!(DEVFS.read(null, 0, &mut [0; 4]), Ok(0));
+    assert_eq!(DEVFS.stat(null).unwrap().kind, Kind::CharDevice);
+    assert!(DEVFS.lookup(ROOT, "tty0").is_ok());
+    assert!(DEVFS.lookup(ROOT, "tty9").is_err());
+
+    let mut index = 0;
+    let mut names = 0;
+    while let Some(entry) = DEVFS.read_dir(ROOT, &mut index).unwrap() {
+        assert_eq!(DEVFS.lookup(ROOT, entry.name()), Ok(entry.inode));
+        names += 1;
+    }
+    assert!(names >= 3 + CONSOLE_COUNT);
+
+    static DISK: block::RamDisk = block::RamDisk {
+        name: "devtest",
+        blocks: spin::Mutex::new([0; 64 * 512]),
+    };
+    block::register(&DISK).unwrap();
+    let disk = DEVFS.lookup(ROOT, "devtest").unwrap();
+    let stat = DEVFS.stat(disk).unwrap();
+    assert_eq!((stat.kind, stat.size), (Kind::BlockDevice, 64 * 512));
+    assert_eq!(DEVFS.write(disk, 1020, b"across"), Ok(6));
+    let mut buf = [0; 8];
+    assert_eq!(DEVFS.read(disk, 1020, &mut buf), Ok(8));
+    assert_eq!(&buf[..6], b"across");
+    assert_eq!(DEVFS.read(disk, stat.size - 2, &mut buf), Ok(2));
+}
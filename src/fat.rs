@@ -0,0 +1,1646 @@
+//! A driver for FAT12, FAT16 and FAT32 file systems on block devices, with long file names. It
+//! reads and writes through a block cache, so changes are only certain to be on the device after
+//! `FileSystem::flush`.
+//!
+//! Files and directories are `Node`s, which remember where their directory entry is so that it
+//! can be updated when they change. A node doesn't see changes made through other copies of it.
+
+use crate::block::BlockDevice;
+use crate::block_cache::{BlockCache, BLOCK_SIZE};
+use crate::cp437;
+use crate::vfs::{self, Inode, Kind, Stat};
+use core::fmt;
+use spin::{Mutex, Once};
+
+/// How many blocks each mounted file system keeps in memory.
+const CACHE_BLOCKS: usize = 16;
+
+const ENTRY_SIZE: u32 = 32;
+/// The most entries a directory can have.
+const MAX_DIR_ENTRIES: u32 = 65536;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// The first name byte of a deleted entry, an entry starting with 0 ends the directory.
+const DELETED: u8 = 0xe5;
+/// Stands for a first name byte of 0xe5, which would mean deleted.
+const KANJI_E5: u8 = 0x05;
+/// Flags in the reserved byte of an entry for a short name with a lowercase base or extension.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+/// Marks the last long name entry, which comes first in the directory.
+const LONG_NAME_LAST: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+/// Where the UTF-16 characters are in a long name entry.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_UNITS: usize = 255;
+/// In UTF-8, long enough for any long name.
+pub const MAX_NAME_LENGTH: usize = MAX_NAME_UNITS * 3;
+
+/// The date files are given, 1980-01-01, as there is no clock to get a better one from.
+const DATE: u16 = (1 << 5) | 1;
+
+const ROOT_INODE: u64 = u64::MAX;
+const MAX_MOUNTED: usize = 4;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_MOUNTED: Once<FileSystem> = Once::new();
+static MOUNTED: [Once<FileSystem>; MAX_MOUNTED] = [NOT_MOUNTED; MAX_MOUNTED];
+static MOUNTED_COUNT: Mutex<usize> = Mutex::new(0);
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const FSINFO_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_FREE_COUNT: u64 = 488;
+const FSINFO_NEXT_FREE: u64 = 492;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// The cluster counts file systems of this type have.
+    fn cluster_range(self) -> (u64, u64) {
+        match self {
+            FatType::Fat12 => (1, 4084),
+            FatType::Fat16 => (4085, 65524),
+            FatType::Fat32 => (65525, 0x0fff_fff4),
+        }
+    }
+
+    fn bits(self) -> u64 {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+}
+
+impl fmt::Display for FatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FAT{}", self.bits())
+    }
+}
+
+/// A file name, decoded from a long name or a short one.
+#[derive(Clone, Copy)]
+pub struct Name {
+    bytes: [u8; MAX_NAME_LENGTH],
+    len: usize,
+}
+
+impl Name {
+    fn new() -> Self {
+        Name {
+            bytes: [0; MAX_NAME_LENGTH],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        let len = c.len_utf8();
+        if self.len + len <= MAX_NAME_LENGTH {
+            c.encode_utf8(&mut self.bytes[self.len..]);
+            self.len += len;
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// Where the entries of a node are: the first cluster of the directory (0 for the root directory
+/// of FAT12 and FAT16), the index of its first long name entry and of its short entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    dir: u32,
+    first: u32,
+    index: u32,
+}
+
+/// A file or directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    /// The first cluster, 0 for empty files and the root directory of FAT12 and FAT16.
+    cluster: u32,
+    size: u32,
+    attributes: u8,
+    /// `None` for the root directory.
+    slot: Option<Slot>,
+}
+
+impl Node {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.attributes & ATTR_READ_ONLY != 0
+    }
+
+    /// In bytes, always 0 for directories.
+    pub fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    /// A number for the node that stays the same while it exists, made from where its entry is.
+    /// `FileSystem::node` turns it back into the node.
+    pub fn inode(&self) -> u64 {
+        match self.slot {
+            Some(slot) => (slot.dir as u64) << 32 | slot.index as u64,
+            None => ROOT_INODE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DirEntry {
+    pub name: Name,
+    pub node: Node,
+    short_name: [u8; 11],
+}
+
+/// Collects the long name entries that come before a short entry.
+struct LongName {
+    units: [u16; 20 * LONG_NAME_CHARS],
+    /// How many entries the name has, 0 while none are collected.
+    count: usize,
+    /// The number of the entry that should come next, they count down to 1.
+    next: usize,
+    checksum: u8,
+    first: u32,
+}
+
+impl LongName {
+    fn new() -> Self {
+        LongName {
+            units: [0; 20 * LONG_NAME_CHARS],
+            count: 0,
+            next: 0,
+            checksum: 0,
+            first: 0,
+        }
+    }
+
+    fn add(&mut self, index: u32, entry: &[u8; 32]) {
+        let number = (entry[0] & !LONG_NAME_LAST) as usize;
+        if entry[0] & LONG_NAME_LAST != 0 {
+            self.count = number;
+            self.next = number;
+            self.checksum = entry[13];
+            self.first = index;
+        }
+        if self.count == 0
+            || number == 0
+            || number > 20
+            || number != self.next
+            || entry[13] != self.checksum
+        {
+            self.count = 0;
+            return;
+        }
+        for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+            self.units[(number - 1) * LONG_NAME_CHARS + i] = u16_at(entry, offset);
+        }
+        self.next -= 1;
+    }
+
+    /// The name and its first entry, if one was collected for the short name with `checksum`.
+    fn take(&mut self, checksum: u8) -> Option<(Name, u32)> {
+        let complete = self.count != 0 && self.next == 0 && self.checksum == checksum;
+        let count = core::mem::replace(&mut self.count, 0);
+        if !complete {
+            return None;
+        }
+        let units = &self.units[..count * LONG_NAME_CHARS];
+        let len = units
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(units.len());
+        let mut name = Name::new();
+        for c in char::decode_utf16(units[..len].iter().copied()) {
+            name.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+        Some((name, self.first))
+    }
+}
+
+/// What `Volume::transfer` does with the bytes of a file.
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+    Zero(usize),
+}
+
+impl Transfer<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Transfer::Read(buf) => buf.len(),
+            Transfer::Write(data) => data.len(),
+            Transfer::Zero(len) => *len,
+        }
+    }
+}
+
+/// The layout of a mounted file system, positions are in bytes from the start of the device.
+struct Volume {
+    cache: BlockCache<CACHE_BLOCKS>,
+    fat_type: FatType,
+    cluster_size: u32,
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u32,
+    /// The root directory of FAT12 and FAT16, which isn't in a cluster.
+    root_start: u64,
+    root_entries: u32,
+    /// The root directory cluster of FAT32, 0 otherwise.
+    root_cluster: u32,
+    data_start: u64,
+    cluster_count: u32,
+    fsinfo: Option<u64>,
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+    allocated: bool,
+    /// The last cluster found by `walk`: the first cluster of the chain, how far along the chain
+    /// it is and the cluster, so that walking further along the same chain starts from there.
+    cursor: (u32, u32, u32),
+}
+
+impl Volume {
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - 2) as u64 * self.cluster_size as u64
+    }
+
+    fn root(&self) -> Node {
+        Node {
+            cluster: self.root_cluster,
+            size: 0,
+            attributes: ATTR_DIRECTORY,
+            slot: None,
+        }
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<u32, &'static str> {
+        if cluster < 2 || cluster > self.cluster_count + 1 {
+            return Err("broken cluster chain");
+        }
+        Ok(cluster)
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, &'static str> {
+        let cluster = self.check_cluster(cluster)?;
+        match self.fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.cache
+                    .read_at(self.fat_start + (cluster + cluster / 2) as u64, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes);
+                Ok(if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                } as u32)
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.cache
+                    .read_at(self.fat_start + cluster as u64 * 2, &mut bytes)?;
+                Ok(u16::from_le_bytes(bytes) as u32)
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.cache
+                    .read_at(self.fat_start + cluster as u64 * 4, &mut bytes)?;
+                Ok(u32::from_le_bytes(bytes) & 0x0fff_ffff)
+            }
+        }
+    }
+
+    /// Sets the entry for `cluster` in every copy of the FAT.
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), &'static str> {
+        let cluster = self.check_cluster(cluster)?;
+        for i in 0..self.fat_count as u64 {
+            let fat = self.fat_start + i * self.fat_size;
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let pos = fat + (cluster + cluster / 2) as u64;
+                    let mut bytes = [0; 2];
+                    self.cache.read_at(pos, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let value = value as u16 & 0xfff;
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000f) | (value << 4)
+                    } else {
+                        (old & 0xf000) | value
+                    };
+                    self.cache.write_at(pos, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => {
+                    let pos = fat + cluster as u64 * 2;
+                    self.cache.write_at(pos, &(value as u16).to_le_bytes())?;
+                }
+                FatType::Fat32 => {
+                    // the top four bits are reserved and kept
+                    let pos = fat + cluster as u64 * 4;
+                    let mut bytes = [0; 4];
+                    self.cache.read_at(pos, &mut bytes)?;
+                    let new = (u32::from_le_bytes(bytes) & 0xf000_0000) | (value & 0x0fff_ffff);
+                    self.cache.write_at(pos, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, `None` at the end.
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, &'static str> {
+        let value = self.fat_entry(cluster)?;
+        if value >= self.fat_type.end_of_chain() - 7 {
+            return Ok(None);
+        }
+        self.check_cluster(value).map(Some)
+    }
+
+    /// Goes up to `n` clusters along the chain starting at `first`, returns the cluster it got to
+    /// and how many it went, which is less than `n` if the chain is shorter.
+    fn walk(&mut self, first: u32, n: u32) -> Result<(u32, u32), &'static str> {
+        let (mut cluster, mut i) = match self.cursor {
+            (chain, i, cluster) if chain == first && i <= n => (cluster, i),
+            _ => (first, 0),
+        };
+        while i < n {
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => break,
+            }
+            i += 1;
+        }
+        self.cursor = (first, i, cluster);
+        Ok((cluster, i))
+    }
+
+    fn nth_cluster(&mut self, first: u32, n: u32) -> Result<Option<u32>, &'static str> {
+        let (cluster, i) = self.walk(first, n)?;
+        Ok(if i == n { Some(cluster) } else { None })
+    }
+
+    /// Takes a free cluster, fills it with zeroes and adds it to the chain after `previous`.
+    fn allocate(&mut self, previous: Option<u32>) -> Result<u32, &'static str> {
+        for i in 0..self.cluster_count {
+            let cluster = 2 + (self.next_free - 2 + i) % self.cluster_count;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+            self.set_fat_entry(cluster, self.fat_type.end_of_chain())?;
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster)?;
+            }
+            let pos = self.cluster_pos(cluster);
+            self.cache.fill_at(pos, self.cluster_size as usize, 0)?;
+            self.next_free = if cluster == self.cluster_count + 1 {
+                2
+            } else {
+                cluster + 1
+            };
+            self.allocated = true;
+            return Ok(cluster);
+        }
+        Err("the file system is full")
+    }
+
+    fn free_chain(&mut self, first: u32) -> Result<(), &'static str> {
+        self.cursor = (0, 0, 0);
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(current, 0)?;
+            self.next_free = self.next_free.min(current);
+        }
+        self.allocated = true;
+        Ok(())
+    }
+
+    /// How many clusters `size` bytes take up, worked out in 64 bits as sizes go up to `u32::MAX`.
+    fn clusters_for(&self, size: u32) -> u32 {
+        let cluster_size = u64::from(self.cluster_size);
+        ((u64::from(size) + cluster_size - 1) / cluster_size) as u32
+    }
+
+    /// Makes the chain of `node` long enough for `size` bytes.
+    fn reserve(&mut self, node: &mut Node, size: u32) -> Result<(), &'static str> {
+        let clusters = self.clusters_for(size);
+        if clusters == 0 {
+            return Ok(());
+        }
+        if node.cluster == 0 {
+            node.cluster = self.allocate(None)?;
+        }
+        let (mut cluster, mut i) = self.walk(node.cluster, clusters - 1)?;
+        while i < clusters - 1 {
+            cluster = self.allocate(Some(cluster))?;
+            i += 1;
+        }
+        self.cursor = (node.cluster, i, cluster);
+        Ok(())
+    }
+
+    /// Reads, writes or zeroes the bytes at `offset` in the chain starting at `first`, which has
+    /// to be long enough.
+    fn transfer(
+        &mut self,
+        first: u32,
+        offset: u32,
+        mut data: Transfer,
+    ) -> Result<(), &'static str> {
+        let len = data.len();
+        let mut done = 0;
+        while done < len {
+            let at = offset + done as u32;
+            let cluster = self
+                .nth_cluster(first, at / self.cluster_size)?
+                .ok_or("broken cluster chain")?;
+            let in_cluster = at % self.cluster_size;
+            let part = ((self.cluster_size - in_cluster) as usize).min(len - done);
+            let pos = self.cluster_pos(cluster) + in_cluster as u64;
+            match &mut data {
+                Transfer::Read(buf) => self.cache.read_at(pos, &mut buf[done..done + part])?,
+                Transfer::Write(bytes) => self.cache.write_at(pos, &bytes[done..done + part])?,
+                Transfer::Zero(_) => self.cache.fill_at(pos, part, 0)?,
+            }
+            done += part;
+        }
+        Ok(())
+    }
+
+    /// Changes the size of `node`, freeing the clusters it doesn't need anymore or zeroing the
+    /// bytes it gets. Doesn't update its entry.
+    fn resize(&mut self, node: &mut Node, size: u32) -> Result<(), &'static str> {
+        if size > node.size {
+            self.reserve(node, size)?;
+            let zeroes = (size - node.size) as usize;
+            self.transfer(node.cluster, node.size, Transfer::Zero(zeroes))?;
+        } else {
+            let clusters = self.clusters_for(size);
+            if clusters == 0 {
+                if node.cluster != 0 {
+                    self.free_chain(node.cluster)?;
+                    node.cluster = 0;
+                }
+            } else {
+                let last = self
+                    .nth_cluster(node.cluster, clusters - 1)?
+                    .ok_or("broken cluster chain")?;
+                if let Some(rest) = self.next_cluster(last)? {
+                    self.set_fat_entry(last, self.fat_type.end_of_chain())?;
+                    self.free_chain(rest)?;
+                }
+            }
+        }
+        node.size = size;
+        Ok(())
+    }
+
+    /// Where entry `index` of the directory starting at cluster `dir` is, `None` past the end of
+    /// the directory. With `grow`, a directory that ends just before the entry gets a cluster more.
+    fn entry_pos(&mut self, dir: u32, index: u32, grow: bool) -> Result<Option<u64>, &'static str> {
+        if dir == 0 {
+            if index >= self.root_entries {
+                return Ok(None);
+            }
+            return Ok(Some(self.root_start + (index * ENTRY_SIZE) as u64));
+        }
+        let per_cluster = self.cluster_size / ENTRY_SIZE;
+        let n = index / per_cluster;
+        let (last, i) = self.walk(dir, n)?;
+        let cluster = if i == n {
+            last
+        } else if grow && i + 1 == n && index < MAX_DIR_ENTRIES {
+            let cluster = self.allocate(Some(last))?;
+            self.cursor = (dir, n, cluster);
+            cluster
+        } else {
+            return Ok(None);
+        };
+        let offset = (index % per_cluster) * ENTRY_SIZE;
+        Ok(Some(self.cluster_pos(cluster) + offset as u64))
+    }
+
+    fn read_entry(&mut self, pos: u64) -> Result<[u8; 32], &'static str> {
+        let mut entry = [0; 32];
+        self.cache.read_at(pos, &mut entry)?;
+        Ok(entry)
+    }
+
+    /// The first entry from `*index` on in the directory starting at cluster `dir`, moving
+    /// `*index` past it. Deleted entries, long name entries and volume labels are skipped.
+    fn next_entry(&mut self, dir: u32, index: &mut u32) -> Result<Option<DirEntry>, &'static str> {
+        let mut long_name = LongName::new();
+        loop {
+            let i = *index;
+            let entry = match self.entry_pos(dir, i, false)? {
+                Some(pos) => self.read_entry(pos)?,
+                None => return Ok(None),
+            };
+            if entry[0] == 0 {
+                return Ok(None);
+            }
+            *index += 1;
+            if entry[0] == DELETED {
+                long_name.count = 0;
+                continue;
+            }
+            if entry[11] & 0x3f == ATTR_LONG_NAME {
+                long_name.add(i, &entry);
+                continue;
+            }
+            if entry[11] & ATTR_VOLUME_ID != 0 {
+                long_name.count = 0;
+                continue;
+            }
+
+            let mut short_name = [0; 11];
+            short_name.copy_from_slice(&entry[..11]);
+            let (name, first) = long_name
+                .take(checksum(&short_name))
+                .unwrap_or_else(|| (format_short_name(&short_name, entry[12]), i));
+            let node = self.parse_entry(
+                &entry,
+                Slot {
+                    dir,
+                    first,
+                    index: i,
+                },
+            );
+            return Ok(Some(DirEntry {
+                name,
+                node,
+                short_name,
+            }));
+        }
+    }
+
+    fn parse_entry(&self, entry: &[u8; 32], slot: Slot) -> Node {
+        let mut cluster = u16_at(entry, 26) as u32;
+        if self.fat_type == FatType::Fat32 {
+            cluster |= (u16_at(entry, 20) as u32) << 16;
+        }
+        let node = Node {
+            cluster,
+            size: u32_at(entry, 28),
+            attributes: entry[11],
+            slot: Some(slot),
+        };
+        match (node.is_dir(), cluster) {
+            // ".." in a directory below the root
+            (true, 0) => self.root(),
+            (true, _) => Node { size: 0, ..node },
+            (false, _) => node,
+        }
+    }
+
+    /// The node whose short entry is entry `index` of the directory starting at cluster `dir`.
+    fn node_at(&mut self, dir: u32, index: u32) -> Result<Node, &'static str> {
+        let pos = self
+            .entry_pos(dir, index, false)?
+            .ok_or("the file doesn't exist anymore")?;
+        let entry = self.read_entry(pos)?;
+        if entry[0] == 0
+            || entry[0] == DELETED
+            || entry[11] & 0x3f == ATTR_LONG_NAME
+            || entry[11] & ATTR_VOLUME_ID != 0
+        {
+            return Err("the file doesn't exist anymore");
+        }
+        let slot = Slot {
+            dir,
+            first: index,
+            index,
+        };
+        Ok(self.parse_entry(&entry, slot))
+    }
+
+    /// The entry called `name` in the directory starting at cluster `dir`, by its long or short
+    /// name, ignoring case.
+    fn find(&mut self, dir: u32, name: &str) -> Result<Option<DirEntry>, &'static str> {
+        let mut index = 0;
+        while let Some(entry) = self.next_entry(dir, &mut index)? {
+            if entry.name.as_str().eq_ignore_ascii_case(name)
+                || format_short_name(&entry.short_name, 0)
+                    .as_str()
+                    .eq_ignore_ascii_case(name)
+            {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    fn short_name_used(&mut self, dir: u32, short_name: &[u8; 11]) -> Result<bool, &'static str> {
+        let mut index = 0;
+        while let Some(entry) = self.next_entry(dir, &mut index)? {
+            if entry.short_name == *short_name {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Makes a short name for `name` like "LONGNA~1.TXT" that isn't in the directory yet.
+    fn unique_short_name(&mut self, dir: u32, name: &str) -> Result<[u8; 11], &'static str> {
+        let (base, base_len, extension) = short_name_basis(name);
+        for n in 1..1_000_000u32 {
+            let mut tail = [b'~'; 7];
+            let mut digits = 0;
+            let mut rest = n;
+            while rest > 0 {
+                digits += 1;
+                rest /= 10;
+            }
+            let mut rest = n;
+            for i in (1..=digits).rev() {
+                tail[i] = b'0' + (rest % 10) as u8;
+                rest /= 10;
+            }
+            let keep = base_len.min(8 - (digits + 1));
+            let mut short_name = [b' '; 11];
+            short_name[..keep].copy_from_slice(&base[..keep]);
+            short_name[keep..keep + digits + 1].copy_from_slice(&tail[..digits + 1]);
+            short_name[8..].copy_from_slice(&extension);
+            if !self.short_name_used(dir, &short_name)? {
+                return Ok(short_name);
+            }
+        }
+        Err("no short name left for the file")
+    }
+
+    /// Finds `count` unused entries in a row in the directory starting at cluster `dir`, making
+    /// it bigger if there aren't any, and returns the index of the first.
+    fn free_entries(&mut self, dir: u32, count: u32) -> Result<u32, &'static str> {
+        let mut run = 0;
+        for index in 0..MAX_DIR_ENTRIES {
+            let pos = self
+                .entry_pos(dir, index, true)?
+                .ok_or("the directory is full")?;
+            let mut first = [0];
+            self.cache.read_at(pos, &mut first)?;
+            if first[0] == 0 || first[0] == DELETED {
+                run += 1;
+                if run == count {
+                    return Ok(index + 1 - count);
+                }
+            } else {
+                run = 0;
+            }
+        }
+        Err("the directory is full")
+    }
+
+    fn write_entry(&mut self, dir: u32, index: u32, entry: &[u8; 32]) -> Result<(), &'static str> {
+        let pos = self
+            .entry_pos(dir, index, false)?
+            .ok_or("broken directory")?;
+        self.cache.write_at(pos, entry)
+    }
+
+    /// Writes the first cluster and size of `node` to its entry.
+    fn update_entry(&mut self, node: &Node) -> Result<(), &'static str> {
+        let slot = match node.slot {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+        let pos = self
+            .entry_pos(slot.dir, slot.index, false)?
+            .ok_or("broken directory")?;
+        let mut entry = self.read_entry(pos)?;
+        set_entry_cluster(&mut entry, node.cluster);
+        entry[24..26].copy_from_slice(&DATE.to_le_bytes());
+        entry[28..32].copy_from_slice(&node.size.to_le_bytes());
+        self.cache.write_at(pos, &entry)
+    }
+
+    fn create(&mut self, dir: &Node, name: &str, directory: bool) -> Result<Node, &'static str> {
+        let mut units = [0; MAX_NAME_UNITS];
+        let len = check_name(name, &mut units)?;
+        if self.find(dir.cluster, name)?.is_some() {
+            return Err("a file with that name exists already");
+        }
+        let (short_name, long_entries) = match plain_short_name(name) {
+            Some(short_name) => (short_name, 0),
+            None => (
+                self.unique_short_name(dir.cluster, name)?,
+                ((len + LONG_NAME_CHARS - 1) / LONG_NAME_CHARS) as u32,
+            ),
+        };
+        let first = self.free_entries(dir.cluster, long_entries + 1)?;
+
+        let mut node = Node {
+            cluster: 0,
+            size: 0,
+            attributes: if directory {
+                ATTR_DIRECTORY
+            } else {
+                ATTR_ARCHIVE
+            },
+            slot: Some(Slot {
+                dir: dir.cluster,
+                first,
+                index: first + long_entries,
+            }),
+        };
+        if directory {
+            node.cluster = self.allocate(None)?;
+            let pos = self.cluster_pos(node.cluster);
+            let parent = if dir.slot.is_none() { 0 } else { dir.cluster };
+            self.cache
+                .write_at(pos, &short_entry(b".          ", &node, node.cluster))?;
+            self.cache.write_at(
+                pos + ENTRY_SIZE as u64,
+                &short_entry(b"..         ", &node, parent),
+            )?;
+        }
+
+        let checksum = checksum(&short_name);
+        for number in 1..=long_entries {
+            let mut entry = [0; 32];
+            entry[0] = number as u8;
+            if number == long_entries {
+                entry[0] |= LONG_NAME_LAST;
+            }
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let at = (number as usize - 1) * LONG_NAME_CHARS + i;
+                let unit = match at.cmp(&len) {
+                    core::cmp::Ordering::Less => units[at],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            self.write_entry(dir.cluster, first + long_entries - number, &entry)?;
+        }
+        let mut stored_name = short_name;
+        if stored_name[0] == DELETED {
+            stored_name[0] = KANJI_E5;
+        }
+        let entry = short_entry(&stored_name, &node, node.cluster);
+        self.write_entry(dir.cluster, first + long_entries, &entry)?;
+        Ok(node)
+    }
+
+    fn remove(&mut self, dir: &Node, name: &str) -> Result<(), &'static str> {
+        if name == "." || name == ".." {
+            return Err("can't remove . or ..");
+        }
+        let entry = self.find(dir.cluster, name)?.ok_or("no such file")?;
+        let slot = entry.node.slot.ok_or("can't remove the root directory")?;
+        if entry.node.is_dir() {
+            let mut index = 0;
+            while let Some(child) = self.next_entry(entry.node.cluster, &mut index)? {
+                if child.name.as_str() != "." && child.name.as_str() != ".." {
+                    return Err("the directory isn't empty");
+                }
+            }
+        }
+        for index in slot.first..=slot.index {
+            let pos = self
+                .entry_pos(slot.dir, index, false)?
+                .ok_or("broken directory")?;
+            self.cache.write_at(pos, &[DELETED])?;
+        }
+        if entry.node.cluster != 0 {
+            self.free_chain(entry.node.cluster)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), &'static str> {
+        if let (Some(fsinfo), true) = (self.fsinfo, self.allocated) {
+            let mut signature = [0; 4];
+            self.cache.read_at(fsinfo, &mut signature)?;
+            if u32::from_le_bytes(signature) == FSINFO_SIGNATURE {
+                // the free count isn't kept up to date, so it's marked as unknown
+                self.cache
+                    .write_at(fsinfo + FSINFO_FREE_COUNT, &u32::MAX.to_le_bytes())?;
+                self.cache
+                    .write_at(fsinfo + FSINFO_NEXT_FREE, &self.next_free.to_le_bytes())?;
+            }
+            self.allocated = false;
+        }
+        self.cache.flush()
+    }
+}
+
+/// A mounted FAT file system.
+pub struct FileSystem {
+    volume: Mutex<Volume>,
+}
+
+impl FileSystem {
+    /// Reads the boot sector of `device` and checks that it has a FAT file system.
+    pub fn mount(device: &'static dyn BlockDevice) -> Result<FileSystem, &'static str> {
+        let mut cache = BlockCache::new(device)?;
+        let mut boot = [0; BLOCK_SIZE];
+        cache.read_at(0, &mut boot)?;
+        if boot[510..512] != BOOT_SIGNATURE {
+            return Err("no FAT boot sector");
+        }
+        if u16_at(&boot, 11) as usize != BLOCK_SIZE {
+            return Err("only FAT file systems with 512 byte sectors are supported");
+        }
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = u16_at(&boot, 17) as u64;
+        let total = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = match u16_at(&boot, 22) {
+            0 => u32_at(&boot, 36) as u64,
+            sectors => sectors as u64,
+        };
+        if !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+            || total > device.block_count()
+        {
+            return Err("not a FAT file system");
+        }
+
+        let block = BLOCK_SIZE as u64;
+        let root_sectors = (root_entries * ENTRY_SIZE as u64 + block - 1) / block;
+        let data_start = reserved + fat_count * fat_sectors + root_sectors;
+        let clusters = total
+            .checked_sub(data_start)
+            .ok_or("not a FAT file system")?
+            / sectors_per_cluster;
+        let fat_type = if clusters <= FatType::Fat12.cluster_range().1 {
+            FatType::Fat12
+        } else if clusters <= FatType::Fat16.cluster_range().1 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        // the FAT may be too small for all the clusters
+        let fat_clusters = (fat_sectors * block * 8 / fat_type.bits()).saturating_sub(2);
+        let cluster_count = clusters.min(fat_clusters) as u32;
+        if cluster_count == 0 {
+            return Err("not a FAT file system");
+        }
+
+        let (root_cluster, fsinfo) = if fat_type == FatType::Fat32 {
+            if root_entries != 0 {
+                return Err("not a FAT file system");
+            }
+            let fsinfo = match u16_at(&boot, 48) as u64 {
+                0 | 0xffff => None,
+                sector => Some(sector * block),
+            };
+            (u32_at(&boot, 44), fsinfo)
+        } else {
+            (0, None)
+        };
+        let volume = Volume {
+            cache,
+            fat_type,
+            cluster_size: (sectors_per_cluster * block) as u32,
+            fat_start: reserved * block,
+            fat_size: fat_sectors * block,
+            fat_count: fat_count as u32,
+            root_start: (reserved + fat_count * fat_sectors) * block,
+            root_entries: root_entries as u32,
+            root_cluster,
+            data_start: data_start * block,
+            cluster_count,
+            fsinfo,
+            next_free: 2,
+            allocated: false,
+            cursor: (0, 0, 0),
+        };
+        if fat_type == FatType::Fat32 {
+            volume.check_cluster(root_cluster)?;
+        }
+        log::info!(
+            "{}: {} with {} clusters of {} bytes",
+            device.name(),
+            fat_type,
+            cluster_count,
+            volume.cluster_size
+        );
+        Ok(FileSystem {
+            volume: Mutex::new(volume),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.volume.lock().fat_type
+    }
+
+    pub fn device(&self) -> &'static dyn BlockDevice {
+        self.volume.lock().cache.device()
+    }
+
+    pub fn root(&self) -> Node {
+        self.volume.lock().root()
+    }
+
+    /// The node with `inode`, as it is on the device now.
+    pub fn node(&self, inode: u64) -> Result<Node, &'static str> {
+        let mut volume = self.volume.lock();
+        if inode == ROOT_INODE {
+            return Ok(volume.root());
+        }
+        volume.node_at((inode >> 32) as u32, inode as u32)
+    }
+
+    /// The entry in directory `dir` called `name`, ignoring case.
+    pub fn lookup(&self, dir: &Node, name: &str) -> Result<Node, &'static str> {
+        if !dir.is_dir() {
+            return Err("not a directory");
+        }
+        let mut volume = self.volume.lock();
+        Ok(volume.find(dir.cluster, name)?.ok_or("no such file")?.node)
+    }
+
+    /// Looks up each part of an absolute `path` like "/games/snake.txt" from the root.
+    pub fn open(&self, path: &str) -> Result<Node, &'static str> {
+        let mut node = self.root();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            node = self.lookup(&node, name)?;
+        }
+        Ok(node)
+    }
+
+    /// The first entry of directory `dir` from `*index` on, "." and ".." included, and moves
+    /// `*index` past it. Start with 0 to list the whole directory.
+    pub fn read_dir(&self, dir: &Node, index: &mut u32) -> Result<Option<DirEntry>, &'static str> {
+        if !dir.is_dir() {
+            return Err("not a directory");
+        }
+        self.volume.lock().next_entry(dir.cluster, index)
+    }
+
+    /// Reads from byte `offset` of `file` into `buf`, returns how many bytes were read, which is
+    /// less than asked for at the end of the file.
+    pub fn read(&self, file: &Node, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if file.is_dir() {
+            return Err("is a directory");
+        }
+        if offset >= file.size() {
+            return Ok(0);
+        }
+        let len = buf.len().min((file.size() - offset) as usize);
+        let mut volume = self.volume.lock();
+        volume.transfer(file.cluster, offset as u32, Transfer::Read(&mut buf[..len]))?;
+        Ok(len)
+    }
+
+    /// Writes `data` at byte `offset` of `file`, making it bigger if needed. Writing past the
+    /// end leaves zeroes in between.
+    pub fn write(&self, file: &mut Node, offset: u64, data: &[u8]) -> Result<usize, &'static str> {
+        if file.is_dir() {
+            return Err("is a directory");
+        }
+        if file.is_read_only() {
+            return Err("the file is read only");
+        }
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or("FAT files can't be bigger than 4GiB")? as u32;
+        let mut volume = self.volume.lock();
+        if offset > file.size() {
+            volume.resize(file, offset as u32)?;
+        }
+        volume.reserve(file, end)?;
+        volume.transfer(file.cluster, offset as u32, Transfer::Write(data))?;
+        file.size = file.size.max(end);
+        volume.update_entry(file)?;
+        Ok(data.len())
+    }
+
+    /// Makes `file` `size` bytes long, cutting it off or adding zeroes.
+    pub fn truncate(&self, file: &mut Node, size: u64) -> Result<(), &'static str> {
+        if file.is_dir() {
+            return Err("is a directory");
+        }
+        if size > u32::MAX as u64 {
+            return Err("FAT files can't be bigger than 4GiB");
+        }
+        let mut volume = self.volume.lock();
+        volume.resize(file, size as u32)?;
+        volume.update_entry(file)
+    }
+
+    /// Makes an empty file in directory `dir`, or an empty directory.
+    pub fn create(&self, dir: &Node, name: &str, directory: bool) -> Result<Node, &'static str> {
+        if !dir.is_dir() {
+            return Err("not a directory");
+        }
+        self.volume.lock().create(dir, name, directory)
+    }
+
+    /// Deletes the file or empty directory `name` in directory `dir`.
+    pub fn remove(&self, dir: &Node, name: &str) -> Result<(), &'static str> {
+        if !dir.is_dir() {
+            return Err("not a directory");
+        }
+        self.volume.lock().remove(dir, name)
+    }
+
+    /// How many clusters aren't used, and how big they are.
+    pub fn free_space(&self) -> Result<(u32, u32), &'static str> {
+        let mut volume = self.volume.lock();
+        let mut free = 0;
+        for cluster in 2..volume.cluster_count + 2 {
+            if volume.fat_entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        Ok((free, volume.cluster_size))
+    }
+
+    /// Writes everything that is only in the cache to the device.
+    pub fn flush(&self) -> Result<(), &'static str> {
+        self.volume.lock().flush()
+    }
+}
+
+/// Mounts the file system on `device` for good, so that it can be given to the VFS. Mounting a
+/// device again gives the same file system.
+pub fn mount(device: &'static dyn BlockDevice) -> Result<&'static FileSystem, &'static str> {
+    let mut count = MOUNTED_COUNT.lock();
+    if let Some(fs) = MOUNTED[..*count]
+        .iter()
+        .filter_map(Once::r#try)
+        .find(|fs| fs.device().name() == device.name())
+    {
+        return Ok(fs);
+    }
+    if *count == MAX_MOUNTED {
+        return Err("too many FAT file systems");
+    }
+    let fs = FileSystem::mount(device)?;
+    *count += 1;
+    Ok(MOUNTED[*count - 1].call_once(|| fs))
+}
+
+impl vfs::FileSystem for FileSystem {
+    fn type_name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(&self) -> Inode {
+        ROOT_INODE
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, &'static str> {
+        let node = self.node(inode)?;
+        Ok(Stat {
+            kind: if node.is_dir() {
+                Kind::Directory
+            } else {
+                Kind::File
+            },
+            size: node.size(),
+            inode,
+        })
+    }
+
+    fn lookup(&self, dir: Inode, name: &str) -> Result<Inode, &'static str> {
+        Ok(FileSystem::lookup(self, &self.node(dir)?, name)?.inode())
+    }
+
+    fn read_dir(&self, dir: Inode, index: &mut u64) -> Result<Option<vfs::DirEntry>, &'static str> {
+        let dir = self.node(dir)?;
+        let mut position = *index as u32;
+        // the VFS works out "." and ".." from the path
+        let entry = loop {
+            match FileSystem::read_dir(self, &dir, &mut position)? {
+                Some(entry) if matches!(entry.name.as_str(), "." | "..") => {}
+                entry => break entry,
+            }
+        };
+        *index = position as u64;
+        entry
+            .map(|entry| {
+                let kind = if entry.node.is_dir() {
+                    Kind::Directory
+                } else {
+                    Kind::File
+                };
+                vfs::DirEntry::new(entry.name.as_str(), entry.node.inode(), kind)
+            })
+            .transpose()
+    }
+
+    fn read(&self, inode: Inode, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        FileSystem::read(self, &self.node(inode)?, offset, buf)
+    }
+
+    fn write(&self, inode: Inode, offset: u64, data: &[u8]) -> Result<usize, &'static str> {
+        FileSystem::write(self, &mut self.node(inode)?, offset, data)
+    }
+
+    fn truncate(&self, inode: Inode, size: u64) -> Result<(), &'static str> {
+        FileSystem::truncate(self, &mut self.node(inode)?, size)
+    }
+
+    fn create(&self, dir: Inode, name: &str, kind: Kind) -> Result<Inode, &'static str> {
+        let directory = match kind {
+            Kind::File => false,
+            Kind::Directory => true,
+            _ => return Err("FAT only has files and directories"),
+        };
+        let node = FileSystem::create(self, &self.node(dir)?, name, directory)?;
+        Ok(node.inode())
+    }
+
+    fn remove(&self, dir: Inode, name: &str) -> Result<(), &'static str> {
+        FileSystem::remove(self, &self.node(dir)?, name)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        FileSystem::flush(self)
+    }
+}
+
+/// Makes a new, empty file system of `fat_type` on all of `device`, with the smallest clusters
+/// that work for that type. `label` can be empty.
+pub fn format(
+    device: &'static dyn BlockDevice,
+    fat_type: FatType,
+    label: &str,
+) -> Result<(), &'static str> {
+    if device.block_size() != BLOCK_SIZE {
+        return Err("only FAT file systems with 512 byte sectors are supported");
+    }
+    if label.len() > 11 || !label.is_ascii() {
+        return Err("labels are up to 11 ASCII characters");
+    }
+    let total = device.block_count();
+    if total > u32::MAX as u64 {
+        return Err("the device is too big for FAT");
+    }
+    let block = BLOCK_SIZE as u64;
+    let (reserved, root_entries): (u64, u64) = match fat_type {
+        FatType::Fat32 => (32, 0),
+        _ => (1, 512),
+    };
+    let root_sectors = root_entries * ENTRY_SIZE as u64 / block;
+    let (min, max) = fat_type.cluster_range();
+    let mut layout = None;
+    for shift in 0..8 {
+        let sectors_per_cluster = 1 << shift;
+        let mut fat_sectors = 1;
+        let clusters = loop {
+            let data = total.saturating_sub(reserved + root_sectors + 2 * fat_sectors);
+            let clusters = data / sectors_per_cluster;
+            let bytes = ((clusters + 2) * fat_type.bits() + 7) / 8;
+            let needed = (bytes + block - 1) / block;
+            if needed <= fat_sectors {
+                break clusters;
+            }
+            fat_sectors = needed;
+        };
+        if (min..=max).contains(&clusters) {
+            layout = Some((sectors_per_cluster, fat_sectors));
+            break;
+        }
+    }
+    let (sectors_per_cluster, fat_sectors) =
+        layout.ok_or("the device is the wrong size for that FAT type")?;
+
+    let mut boot = [0; BLOCK_SIZE];
+    boot[..3].copy_from_slice(if fat_type == FatType::Fat32 {
+        &[0xeb, 0x58, 0x90]
+    } else {
+        &[0xeb, 0x3c, 0x90]
+    });
+    boot[3..11].copy_from_slice(b"JOEL_OS ");
+    boot[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+    boot[13] = sectors_per_cluster as u8;
+    boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+    boot[16] = 2;
+    boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+    if total <= u16::MAX as u64 && fat_type != FatType::Fat32 {
+        boot[19..21].copy_from_slice(&(total as u16).to_le_bytes());
+    } else {
+        boot[32..36].copy_from_slice(&(total as u32).to_le_bytes());
+    }
+    boot[21] = 0xf8;
+    boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+    let extended = if fat_type == FatType::Fat32 {
+        boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+        boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+        boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+        boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+        64
+    } else {
+        boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+        36
+    };
+    boot[extended] = 0x80;
+    boot[extended + 2] = 0x29;
+    let serial = unsafe { core::arch::x86_64::_rdtsc() } as u32;
+    boot[extended + 3..extended + 7].copy_from_slice(&serial.to_le_bytes());
+    let mut volume_label = [b' '; 11];
+    for (byte, c) in volume_label.iter_mut().zip(label.bytes()) {
+        *byte = c.to_ascii_uppercase();
+    }
+    boot[extended + 7..extended + 18].copy_from_slice(&volume_label);
+    boot[extended + 18..extended + 26].copy_from_slice(match fat_type {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    });
+    boot[510..512].copy_from_slice(&BOOT_SIGNATURE);
+
+    // everything up to the end of the root directory starts out as zeroes
+    let root_start = reserved + 2 * fat_sectors;
+    let end = root_start
+        + if fat_type == FatType::Fat32 {
+            sectors_per_cluster
+        } else {
+            root_sectors
+        };
+    let zeroes = [0; 16 * BLOCK_SIZE];
+    let mut sector = 1;
+    while sector < end {
+        let count = (end - sector).min(16);
+        device.write_blocks(sector, &zeroes[..(count * block) as usize])?;
+        sector += count;
+    }
+    device.write_blocks(0, &boot)?;
+
+    if fat_type == FatType::Fat32 {
+        let mut fsinfo = [0; BLOCK_SIZE];
+        fsinfo[..4].copy_from_slice(&FSINFO_SIGNATURE.to_le_bytes());
+        fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        fsinfo[488..492].copy_from_slice(&u32::MAX.to_le_bytes());
+        fsinfo[492..496].copy_from_slice(&u32::MAX.to_le_bytes());
+        fsinfo[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+        device.write_blocks(1, &fsinfo)?;
+        device.write_blocks(6, &boot)?;
+        device.write_blocks(7, &fsinfo)?;
+    }
+
+    // the first two FAT entries hold the media byte and an end of chain, FAT32 has the root
+    // directory in cluster 2
+    let mut fat = [0; BLOCK_SIZE];
+    match fat_type {
+        FatType::Fat12 => fat[..3].copy_from_slice(&[0xf8, 0xff, 0xff]),
+        FatType::Fat16 => fat[..4].copy_from_slice(&[0xf8, 0xff, 0xff, 0xff]),
+        FatType::Fat32 => {
+            fat[..4].copy_from_slice(&0x0fff_fff8u32.to_le_bytes());
+            fat[4..8].copy_from_slice(&0x0fff_ffffu32.to_le_bytes());
+            fat[8..12].copy_from_slice(&0x0fff_ffffu32.to_le_bytes());
+        }
+    }
+    device.write_blocks(reserved, &fat)?;
+    device.write_blocks(reserved + fat_sectors, &fat)?;
+
+    if !label.is_empty() {
+        let mut root = [0; BLOCK_SIZE];
+        root[..11].copy_from_slice(&volume_label);
+        root[11] = ATTR_VOLUME_ID;
+        root[24..26].copy_from_slice(&DATE.to_le_bytes());
+        device.write_blocks(root_start, &root)?;
+    }
+    device.flush()
+}
+
+/// Checks that `name` can be a file name, and puts it in `units` as UTF-16. Returns how many
+/// units that is.
+fn check_name(name: &str, units: &mut [u16; MAX_NAME_UNITS]) -> Result<usize, &'static str> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err("invalid file name");
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Err("file names can't end with a dot or space");
+    }
+    if name
+        .chars()
+        .any(|c| c < ' ' || "\"*/:<>?\\|\u{7f}".contains(c))
+    {
+        return Err("invalid character in file name");
+    }
+    let mut len = 0;
+    for unit in name.encode_utf16() {
+        *units.get_mut(len).ok_or("file name too long")? = unit;
+        len += 1;
+    }
+    Ok(len)
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// The short name for `name` if it is one already, like "README.TXT", so needs no long name.
+fn plain_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    if !base
+        .bytes()
+        .chain(extension.bytes())
+        .all(is_short_name_char)
+    {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short_name)
+}
+
+/// The start of a generated short name: up to 8 characters of the base of `name`, how many
+/// there are, and its extension.
+fn short_name_basis(name: &str) -> ([u8; 8], usize, [u8; 3]) {
+    let convert = |c: char| {
+        let c = c.to_ascii_uppercase();
+        if c.is_ascii() && is_short_name_char(c as u8) {
+            c as u8
+        } else {
+            b'_'
+        }
+    };
+    let name = name.trim_start_matches('.');
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let mut short_base = [b' '; 8];
+    let mut len = 0;
+    for c in base.chars().filter(|&c| c != ' ' && c != '.').take(8) {
+        short_base[len] = convert(c);
+        len += 1;
+    }
+    if len == 0 {
+        short_base[0] = b'_';
+        len = 1;
+    }
+    let mut short_extension = [b' '; 3];
+    for (byte, c) in short_extension
+        .iter_mut()
+        .zip(extension.chars().filter(|&c| c != ' '))
+    {
+        *byte = convert(c);
+    }
+    (short_base, len, short_extension)
+}
+
+/// Turns an 11 byte short name like "README  TXT" into "README.TXT", lowercasing the parts that
+/// `case` says are lowercase.
+fn format_short_name(short_name: &[u8; 11], case: u8) -> Name {
+    let mut name = Name::new();
+    let base_len = short_name[..8]
+        .iter()
+        .rposition(|&c| c != b' ')
+        .map_or(0, |i| i + 1);
+    let extension_len = short_name[8..]
+        .iter()
+        .rposition(|&c| c != b' ')
+        .map_or(0, |i| i + 1);
+    let push = |name: &mut Name, bytes: &[u8], lowercase: bool| {
+        for &byte in bytes {
+            let c = cp437::decode(byte);
+            name.push(if lowercase { c.to_ascii_lowercase() } else { c });
+        }
+    };
+    let mut base = [0; 8];
+    base.copy_from_slice(&short_name[..8]);
+    if base[0] == KANJI_E5 {
+        base[0] = DELETED;
+    }
+    push(&mut name, &base[..base_len], case & LOWERCASE_BASE != 0);
+    if extension_len > 0 {
+        name.push('.');
+        push(
+            &mut name,
+            &short_name[8..8 + extension_len],
+            case & LOWERCASE_EXTENSION != 0,
+        );
+    }
+    name
+}
+
+/// The checksum of a short name that its long name entries have.
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+fn short_entry(short_name: &[u8; 11], node: &Node, cluster: u32) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[..11].copy_from_slice(short_name);
+    entry[11] = node.attributes;
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&DATE.to_le_bytes());
+    }
+    set_entry_cluster(&mut entry, cluster);
+    entry[28..32].copy_from_slice(&node.size.to_le_bytes());
+    entry
+}
+
+fn set_entry_cluster(entry: &mut [u8; 32], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+#[test_case]
+fn test_names() {
+    assert_eq!(plain_short_name("README.TXT"), Some(*b"README  TXT"));
+    assert_eq!(plain_short_name("KERNEL"), Some(*b"KERNEL     "));
+    assert_eq!(plain_short_name("readme.txt"), None);
+    assert_eq!(plain_short_name("LONGFILENAME"), None);
+    assert_eq!(
+        short_name_basis("high scores.text"),
+        (*b"HIGHSCOR", 8, *b"TEX")
+    );
+    assert_eq!(short_name_basis(".bashrc"), (*b"BASHRC  ", 6, *b"   "));
+    assert_eq!(
+        format_short_name(b"README  TXT", LOWERCASE_BASE).as_str(),
+        "readme.TXT"
+    );
+    assert_eq!(format_short_name(b"..         ", 0).as_str(), "..");
+    assert_eq!(checksum(b"HIGHSC~1TXT"), 0xf6);
+    let mut units = [0; MAX_NAME_UNITS];
+    assert_eq!(check_name("snake.rs", &mut units), Ok(8));
+    assert!(check_name("a/b", &mut units).is_err());
+    assert!(check_name("dot.", &mut units).is_err());
+}
+
+#[test_case]
+fn test_file_systems() {
+    crate::ahci::init();
+    // the tests attach a second SATA disk for this
+    let device = crate::block::find("sdb").expect("no disk for FAT tests");
+    for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+        format(device, fat_type, "test").unwrap();
+        let fs = FileSystem::mount(device).unwrap();
+        assert_eq!(fs.fat_type(), fat_type);
+        let (free, cluster_size) = fs.free_space().unwrap();
+        let root = fs.root();
+
+        let dir = fs.create(&root, "Games", true).unwrap();
+        let mut file = fs.create(&dir, "High scores.txt", false).unwrap();
+        assert!(fs.create(&dir, "high SCORES.txt", false).is_err());
+        // a few clusters, written in pieces that don't line up with them
+        let len = cluster_size as usize * 3 + 100;
+        let mut data = [0; 1000];
+        let mut offset = 0;
+        while offset < len {
+            let part = (len - offset).min(data.len());
+            for (i, byte) in data[..part].iter_mut().enumerate() {
+                *byte = ((offset + i) % 251) as u8;
+            }
+            fs.write(&mut file, offset as u64, &data[..part]).unwrap();
+            offset += part;
+        }
+        assert_eq!(file.size(), len as u64);
+
+        // enough entries to need more than one cluster of the directory
+        let mut name = *b"file 00";
+        for i in 0..40 {
+            name[5] = b'0' + i / 10;
+            name[6] = b'0' + i % 10;
+            let name = core::str::from_utf8(&name).unwrap();
+            fs.create(&dir, name, false).unwrap();
+        }
+        fs.flush().unwrap();
+
+        let fs = FileSystem::mount(device).unwrap();
+        let dir = fs.open("/GAMES").unwrap();
+        assert!(dir.is_dir());
+        let mut file = fs.open("/Games/high scores.TXT").unwrap();
+        assert_eq!(file.size(), len as u64);
+        let mut read = [0; 1000];
+        let mut offset = 0;
+        while offset < len {
+            let count = fs.read(&file, offset as u64, &mut read).unwrap();
+            for (i, &byte) in read[..count].iter().enumerate() {
+                assert_eq!(byte, ((offset + i) % 251) as u8);
+            }
+            offset += count;
+        }
+        assert_eq!(fs.read(&file, len as u64, &mut read), Ok(0));
+        let mut index = 0;
+        let mut names = 0;
+        let mut found = false;
+        while let Some(entry) = fs.read_dir(&dir, &mut index).unwrap() {
+            found |= entry.name.as_str() == "High scores.txt";
+            names += 1;
+        }
+        assert!(found);
+        assert_eq!(names, 2 + 1 + 40);
+        assert!(fs.lookup(&dir, "file 39").is_ok());
+        assert!(fs.lookup(&dir, "HIGHSC~1.TXT").is_ok());
+
+        // cutting off and growing again leaves zeroes
+        fs.truncate(&mut file, 10).unwrap();
+        fs.write(&mut file, 20, b"end").unwrap();
+        assert_eq!(fs.read(&file, 0, &mut read), Ok(23));
+        assert_eq!(read[9], 9);
+        assert_eq!(read[10..20], [0; 10]);
+        assert_eq!(&read[20..23], b"end");
+
+        assert!(fs.remove(&root, "games").is_err());
+        fs.remove(&dir, "high scores.txt").unwrap();
+        for i in 0..40 {
+            name[5] = b'0' + i / 10;
+            name[6] = b'0' + i % 10;
+            fs.remove(&dir, core::str::from_utf8(&name).unwrap())
+                .unwrap();
+        }
+        fs.remove(&root, "games").unwrap();
+        assert!(fs.open("/games").is_err());
+        assert_eq!(fs.free_space().unwrap().0, free);
+        fs.flush().unwrap();
+    }
+}
+
+#[test_case]
+fn test_mkfs_images() {
+    crate::ata::init();
+    crate::ahci::init();
+    // made by the host's mkfs.fat, see build.rs
+    for &(name, fat_type) in &[
+        ("hdc", FatType::Fat12),
+        ("hdd", FatType::Fat16),
+        ("sdc", FatType::Fat32),
+    ] {
+        let device = crate::block::find(name).expect("no disk made by mkfs.fat");
+        let fs = FileSystem::mount(device).unwrap();
+        assert_eq!(fs.fat_type(), fat_type);
+        let root = fs.root();
+        // left behind if an earlier run failed
+        let _ = fs.remove(&root, "Written here.txt");
+        // the volume label is the only entry in the root, and isn't listed
+        assert!(fs.read_dir(&root, &mut 0).unwrap().is_none());
+        let (free, _) = fs.free_space().unwrap();
+
+        let mut file = fs.create(&root, "Written here.txt", false).unwrap();
+        assert_eq!(fs.write(&mut file, 0, b"from the kernel"), Ok(15));
+        fs.flush().unwrap();
+
+        let fs = FileSystem::mount(device).unwrap();
+        let file = fs.open("/WRITTE~1.TXT").unwrap();
+        let mut read = [0; 32];
+        assert_eq!(fs.read(&file, 0, &mut read), Ok(15));
+        assert_eq!(&read[..15], b"from the kernel");
+        fs.remove(&fs.root(), "written here.txt").unwrap();
+        assert_eq!(fs.free_space().unwrap().0, free);
+        fs.flush().unwrap();
+    }
+}
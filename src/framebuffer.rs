@@ -0,0 +1,247 @@
+//! Text console rendered into a linear framebuffer.
+//!
+//! The bootloader we use can't set VBE modes, so the framebuffer comes from the Bochs VBE
+//! extensions ("DISPI") that QEMU, Bochs and VirtualBox graphics cards implement. The text
+//! consoles keep working exactly as before, `vga_buffer` just draws their rows here instead of
+//! into the text buffer at 0xb8000. Without such a card everything stays in VGA text mode.
+
+use crate::memory;
+use crate::pci;
+use crate::vga::FONT_GLYPHS;
+use crate::vga_buffer;
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+const DISPI_INDEX_PORT: u16 = 0x01ce;
+const DISPI_DATA_PORT: u16 = 0x01cf;
+
+const DISPI_INDEX_ID: u16 = 0;
+const DISPI_INDEX_XRES: u16 = 1;
+const DISPI_INDEX_YRES: u16 = 2;
+const DISPI_INDEX_BPP: u16 = 3;
+const DISPI_INDEX_ENABLE: u16 = 4;
+
+/// The first interface version with 32 bit colour and a linear framebuffer.
+const DISPI_ID_MIN: u16 = 0xb0c2;
+const DISPI_ID_MAX: u16 = 0xb0cf;
+const DISPI_ENABLED: u16 = 0x01;
+const DISPI_LFB_ENABLED: u16 = 0x40;
+
+/// PCI vendor and device ids of graphics cards with the DISPI interface, whose first BAR is the
+/// linear framebuffer.
+const DISPI_DEVICES: [(u16, u16); 2] = [(0x1234, 0x1111), (0x80ee, 0xbeef)];
+
+pub const GLYPH_WIDTH: usize = 8;
+pub const GLYPH_HEIGHT: usize = 16;
+
+/// The text mode colours as 24 bit RGB.
+const TEXT_COLOURS: [u32; 16] = [
+    0x000000, 0x0000aa, 0x00aa00, 0x00aaaa, 0xaa0000, 0xaa00aa, 0xaa5500, 0xaaaaaa, 0x555555,
+    0x5555ff, 0x55ff55, 0x55ffff, 0xff5555, 0xff55ff, 0xffff55, 0xffffff,
+];
+
+static CONSOLE: Once<FrameBuffer> = Once::new();
+/// The 8x16 code page 437 font, copied out of the VGA font memory before it gets overwritten.
+static FONT: Once<[u8; FONT_GLYPHS * GLYPH_HEIGHT]> = Once::new();
+
+/// A linear framebuffer with 24 or 32 bit pixels stored as blue, green, red.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameBuffer {
+    base: usize,
+    width: usize,
+    height: usize,
+    /// Bytes from the start of one line to the next.
+    stride: usize,
+    bytes_per_pixel: usize,
+}
+
+impl FrameBuffer {
+    /// # Safety
+    ///
+    /// `stride * height` bytes at `base` must be mapped and not used for anything else while the
+    /// framebuffer exists.
+    pub unsafe fn new(
+        base: *mut u8,
+        width: usize,
+        height: usize,
+        stride: usize,
+        bytes_per_pixel: usize,
+    ) -> FrameBuffer {
+        assert!(bytes_per_pixel == 3 || bytes_per_pixel == 4);
+        FrameBuffer {
+            base: base as usize,
+            width,
+            height,
+            stride,
+            bytes_per_pixel,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Sets the pixel at `x`, `y` to the 24 bit colour `rgb`, pixels off the screen are ignored.
+    pub fn set_pixel(&self, x: usize, y: usize, rgb: u32) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let pixel = (self.base + y * self.stride + x * self.bytes_per_pixel) as *mut u8;
+        unsafe {
+            pixel.write_volatile(rgb as u8);
+            pixel.add(1).write_volatile((rgb >> 8) as u8);
+            pixel.add(2).write_volatile((rgb >> 16) as u8);
+        }
+    }
+
+    pub fn fill_rect(&self, x: usize, y: usize, width: usize, height: usize, rgb: u32) {
+        for row in y..y + height {
+            for col in x..x + width {
+                self.set_pixel(col, row, rgb);
+            }
+        }
+    }
+
+    /// Text columns and rows that fit on the screen.
+    pub fn text_size(&self) -> (usize, usize) {
+        (self.width / GLYPH_WIDTH, self.height / GLYPH_HEIGHT)
+    }
+
+    /// Draws `glyph` with its top left corner at `x`, `y`, set bits in `foreground` and clear bits
+    /// in `background`.
+    pub fn draw_glyph(&self, x: usize, y: usize, glyph: &[u8], foreground: u32, background: u32) {
+        for (row, &bits) in glyph.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                let colour = if bits & (0x80 >> col) != 0 {
+                    foreground
+                } else {
+                    background
+                };
+                self.set_pixel(x + col, y + row, colour);
+            }
+        }
+    }
+
+    /// Draws code page 437 `character` into text cell `col`, `row` with the colours of the VGA
+    /// text attribute `attribute`.
+    pub(crate) fn draw_cell(&self, col: usize, row: usize, character: u8, attribute: u8) {
+        let font = match FONT.r#try() {
+            Some(font) => font,
+            None => return,
+        };
+        let start = character as usize * GLYPH_HEIGHT;
+        self.draw_glyph(
+            col * GLYPH_WIDTH,
+            row * GLYPH_HEIGHT,
+            &font[start..start + GLYPH_HEIGHT],
+            TEXT_COLOURS[(attribute & 0x0f) as usize],
+            TEXT_COLOURS[(attribute >> 4 & 0x0f) as usize],
+        );
+    }
+}
+
+/// The framebuffer the consoles are drawn into, if `init` managed to set one up.
+pub fn console() -> Option<&'static FrameBuffer> {
+    CONSOLE.r#try()
+}
+
+/// Switches to a `width` by `height` 32 bit framebuffer and moves the text consoles onto it.
+/// Needs `memory::init` to have run. If no card with a linear framebuffer is found the consoles
+/// stay in VGA text mode and an error is returned.
+pub fn init(width: u16, height: u16) -> Result<&'static FrameBuffer, &'static str> {
+    if let Some(framebuffer) = console() {
+        return Ok(framebuffer);
+    }
+    if !dispi_present() {
+        return Err("no Bochs VBE compatible graphics card");
+    }
+    let address = find_framebuffer().ok_or("graphics card has no linear framebuffer")?;
+    let stride = width as usize * 4;
+    let base = memory::map_mmio(address, (stride * height as usize) as u64)?;
+
+    // the font lives in VGA memory, which the new mode reuses
+    FONT.call_once(|| {
+        let mut font = [0; FONT_GLYPHS * GLYPH_HEIGHT];
+        vga_buffer::copy_text_font(&mut font);
+        font
+    });
+
+    dispi_write(DISPI_INDEX_ENABLE, 0);
+    dispi_write(DISPI_INDEX_XRES, width);
+    dispi_write(DISPI_INDEX_YRES, height);
+    dispi_write(DISPI_INDEX_BPP, 32);
+    dispi_write(DISPI_INDEX_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED);
+
+    let framebuffer = CONSOLE.call_once(|| unsafe {
+        FrameBuffer::new(
+            base.as_mut_ptr(),
+            width as usize,
+            height as usize,
+            stride,
+            4,
+        )
+    });
+    framebuffer.fill_rect(0, 0, framebuffer.width, framebuffer.height, 0);
+    let (columns, rows) = framebuffer.text_size();
+    vga_buffer::resize_consoles(columns, rows);
+    Ok(framebuffer)
+}
+
+fn dispi_write(index: u16, value: u16) {
+    let mut index_port: Port<u16> = Port::new(DISPI_INDEX_PORT);
+    let mut data_port: Port<u16> = Port::new(DISPI_DATA_PORT);
+    unsafe {
+        index_port.write(index);
+        data_port.write(value);
+    }
+}
+
+fn dispi_read(index: u16) -> u16 {
+    let mut index_port: Port<u16> = Port::new(DISPI_INDEX_PORT);
+    let mut data_port: Port<u16> = Port::new(DISPI_DATA_PORT);
+    unsafe {
+        index_port.write(index);
+        data_port.read()
+    }
+}
+
+fn dispi_present() -> bool {
+    let id = dispi_read(DISPI_INDEX_ID);
+    (DISPI_ID_MIN..=DISPI_ID_MAX).contains(&id)
+}
+
+/// Looks for the graphics card on PCI and returns the address of its first memory BAR.
+fn find_framebuffer() -> Option<PhysAddr> {
+    let card = pci::devices()
+        .find(|device| DISPI_DEVICES.contains(&(device.vendor_id, device.device_id)))?;
+    match card.bars[0]? {
+        pci::Bar::Memory { addr, .. } => Some(addr),
+        pci::Bar::Io { .. } => None,
+    }
+}
+
+#[test_case]
+fn test_draw_glyph() {
+    const WIDTH: usize = 16;
+    let mut pixels = [0u8; WIDTH * GLYPH_HEIGHT * 4];
+
+    let framebuffer =
+        unsafe { FrameBuffer::new(pixels.as_mut_ptr(), WIDTH, GLYPH_HEIGHT, WIDTH * 4, 4) };
+    let mut glyph = [0; GLYPH_HEIGHT];
+    glyph[3] = 0b1000_0001;
+    framebuffer.draw_glyph(8, 0, &glyph, 0x12_34_56, 0x00_00_01);
+
+    let pixel = |x: usize, y: usize| {
+        let offset = (y * WIDTH + x) * 4;
+        [pixels[offset + 2], pixels[offset + 1], pixels[offset]]
+    };
+    assert_eq!(pixel(8, 3), [0x12, 0x34, 0x56]);
+    assert_eq!(pixel(15, 3), [0x12, 0x34, 0x56]);
+    assert_eq!(pixel(9, 3), [0x00, 0x00, 0x01]);
+    assert_eq!(pixel(7, 3), [0x00, 0x00, 0x00]);
+}
@@ -0,0 +1,596 @@
+//! A stub for the GDB remote serial protocol on the serial port with `Role::Debugger`, so the
+//! kernel can be debugged with gdb on real hardware and in tests, not only through QEMU's `-s`:
+//!
+//!     (gdb) target remote /dev/ttyUSB0
+//!
+//! The kernel stops for gdb on `int3`, which includes the breakpoints gdb sets, after a single
+//! step and when gdb sends Ctrl+C. While it is stopped interrupts stay off and the stub answers
+//! gdb's packets, which read and write registers and memory and continue or step the kernel.
+//! `scripts/gdb_session.py` runs a scripted gdb session against the kernel in QEMU.
+
+use crate::interrupts::{self, TrapFrame};
+use crate::memory;
+use crate::serial::{self, Role};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// Longest packet in either direction, told to gdb in the `qSupported` reply.
+const PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+/// The registers of gdb's amd64 description up to gs: rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp,
+/// r8 to r15 and rip with 8 bytes each, then eflags, cs, ss, ds, es, fs and gs with 4.
+const REGISTER_COUNT: usize = 24;
+
+/// Set when gdb sent Ctrl+C, so the debug exception that follows is reported as SIGINT.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Whether gdb is waiting to hear that the kernel stopped, after it continued or stepped it.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+    Mutex::new([None; MAX_BREAKPOINTS]);
+
+/// A software breakpoint set with a `Z0` packet, and the byte the `int3` replaced.
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Send the reply and wait for the next packet.
+    Reply,
+    /// Let the kernel run until it stops again, which gdb is told about.
+    Resume,
+    /// Send the reply, if there is one, and let the kernel run without gdb.
+    Detach,
+}
+
+/// The data of a packet, without the framing and checksum.
+struct Packet {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    const fn new() -> Packet {
+        Packet {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Appends `byte`, unless the packet is full.
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.data[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        self.push(DIGITS[usize::from(byte >> 4)]);
+        self.push(DIGITS[usize::from(byte & 0xf)]);
+    }
+
+    /// Appends the low `size` bytes of `value` in target byte order, which is how gdb wants
+    /// register values.
+    fn push_le(&mut self, value: u64, size: usize) {
+        for &byte in &value.to_le_bytes()[..size] {
+            self.push_hex(byte);
+        }
+    }
+
+    fn checksum(&self) -> u8 {
+        self.as_bytes()
+            .iter()
+            .fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+    }
+}
+
+impl fmt::Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+/// The packets last received and sent on the debugger port.
+struct Connection {
+    input: Packet,
+    output: Packet,
+}
+
+impl Connection {
+    fn read_byte(&self) -> u8 {
+        loop {
+            // bytes that came in before the kernel stopped were buffered by the interrupt handler
+            let port = serial::role_port(Role::Debugger);
+            if let Some(byte) = port.and_then(serial::read_byte) {
+                return byte;
+            }
+            if let Some(Some(byte)) = serial::with_role(Role::Debugger, |uart| uart.try_receive()) {
+                return byte;
+            }
+        }
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        serial::with_role(Role::Debugger, |uart| {
+            for &byte in bytes {
+                uart.send(byte);
+            }
+        });
+    }
+
+    /// Waits for a packet with a good checksum and acknowledges it. A `-` from gdb, asking for
+    /// the last packet again, is answered on the way.
+    fn receive(&mut self) {
+        loop {
+            match self.read_byte() {
+                b'$' => {}
+                b'-' => {
+                    self.send();
+                    continue;
+                }
+                // acknowledgements, and a Ctrl+C that came too late
+                _ => continue,
+            }
+            self.input.clear();
+            let mut byte = self.read_byte();
+            while byte != b'#' {
+                self.input.push(byte);
+                byte = self.read_byte();
+            }
+            let checksum = [self.read_byte(), self.read_byte()];
+            if parse_hex(&checksum) == Some(u64::from(self.input.checksum())) {
+                self.write(b"+");
+                return;
+            }
+            self.write(b"-");
+        }
+    }
+
+    /// Sends the output packet. It is kept for gdb to ask for again.
+    fn send(&self) {
+        let mut checksum = Packet::new();
+        checksum.push_hex(self.output.checksum());
+        self.write(b"$");
+        self.write(self.output.as_bytes());
+        self.write(b"#");
+        self.write(checksum.as_bytes());
+    }
+}
+
+/// Whether there is a debugger port for gdb, i.e. a breakpoint stops the kernel for gdb.
+pub fn is_attached() -> bool {
+    serial::role_port(Role::Debugger).is_some_and(serial::is_present)
+}
+
+/// Stops the kernel in the stub, to give gdb the chance to attach.
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Makes the code interrupted with `stack_frame` stop for gdb after its next instruction, through
+/// the trap flag and the debug exception. Used when gdb sends Ctrl+C.
+pub(crate) fn request_stop(stack_frame: &mut InterruptStackFrame) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+    interrupts::set_trap_flag(stack_frame);
+}
+
+/// Serves gdb until it continues the kernel, called from the breakpoint and debug exception
+/// handlers with the registers of the code that stopped.
+pub(crate) fn handle_trap(frame: &mut TrapFrame) {
+    let signal = if frame.vector == 1 && STOP_REQUESTED.swap(false, Ordering::SeqCst) {
+        SIGINT
+    } else {
+        SIGTRAP
+    };
+    // int3 leaves rip after itself, but gdb expects to be stopped at its breakpoint
+    if frame.vector == 3 && is_breakpoint(frame.rip.wrapping_sub(1)) {
+        frame.rip -= 1;
+    }
+
+    let mut connection = Connection {
+        input: Packet::new(),
+        output: Packet::new(),
+    };
+    // when gdb isn't waiting for this it will ask with `?` once it attaches
+    if RUNNING.swap(false, Ordering::SeqCst) {
+        stop_reply(signal, &mut connection.output);
+        connection.send();
+    }
+    loop {
+        connection.receive();
+        connection.output.clear();
+        match handle_packet(
+            connection.input.as_bytes(),
+            frame,
+            signal,
+            &mut connection.output,
+        ) {
+            Action::Reply => connection.send(),
+            Action::Resume => {
+                RUNNING.store(true, Ordering::SeqCst);
+                return;
+            }
+            Action::Detach => {
+                if connection.output.len > 0 {
+                    connection.send();
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Carries out the command in `packet` on the stopped kernel and puts the answer in `reply`.
+fn handle_packet(packet: &[u8], frame: &mut TrapFrame, signal: u8, reply: &mut Packet) -> Action {
+    use core::fmt::Write;
+
+    let (&command, args) = match packet.split_first() {
+        Some(split) => split,
+        None => return Action::Reply,
+    };
+    let result = match command {
+        b'?' => {
+            stop_reply(signal, reply);
+            Ok(())
+        }
+        b'g' => {
+            for n in 0..REGISTER_COUNT {
+                reply.push_le(register(frame, n).unwrap_or(0), register_size(n));
+            }
+            Ok(())
+        }
+        b'G' => write_registers(frame, args).map(|()| reply_ok(reply)),
+        b'p' => parse_hex(args)
+            .and_then(|n| register(frame, n as usize).map(|value| (n as usize, value)))
+            .map(|(n, value)| reply.push_le(value, register_size(n)))
+            .ok_or(()),
+        b'P' => {
+            let (n, value) = split(args, b'=');
+            match (parse_hex(n), decode_le(value)) {
+                (Some(n), Some(value)) if set_register(frame, n as usize, value) => {
+                    reply_ok(reply);
+                    Ok(())
+                }
+                _ => Err(()),
+            }
+        }
+        b'm' => read_memory(args, reply),
+        b'M' => write_memory(args).map(|()| reply_ok(reply)),
+        b'c' | b's' => {
+            if !args.is_empty() {
+                match parse_hex(args) {
+                    Some(addr) => frame.rip = addr,
+                    None => return error(reply),
+                }
+            }
+            if command == b's' {
+                frame.rflags |= RFlags::TRAP_FLAG.bits();
+            } else {
+                frame.rflags &= !RFlags::TRAP_FLAG.bits();
+            }
+            return Action::Resume;
+        }
+        b'Z' | b'z' if args.first() == Some(&b'0') => {
+            let (addr, _kind) = split(args.get(2..).unwrap_or(&[]), b',');
+            let addr = parse_hex(addr).ok_or(());
+            addr.and_then(|addr| {
+                if command == b'Z' {
+                    insert_breakpoint(addr)
+                } else {
+                    remove_breakpoint(addr)
+                }
+            })
+            .map(|()| reply_ok(reply))
+        }
+        b'D' => {
+            reply_ok(reply);
+            frame.rflags &= !RFlags::TRAP_FLAG.bits();
+            return Action::Detach;
+        }
+        b'k' => return Action::Detach,
+        b'H' | b'T' => {
+            reply_ok(reply);
+            Ok(())
+        }
+        b'q' if args.starts_with(b"Supported") => {
+            let _ = write!(reply, "PacketSize={:x}", PACKET_SIZE);
+            Ok(())
+        }
+        b'q' if args == b"Attached" => {
+            reply.push(b'1');
+            Ok(())
+        }
+        // an empty reply tells gdb the packet isn't supported
+        _ => Ok(()),
+    };
+    match result {
+        Ok(()) => Action::Reply,
+        Err(()) => error(reply),
+    }
+}
+
+fn stop_reply(signal: u8, reply: &mut Packet) {
+    reply.push(b'S');
+    reply.push_hex(signal);
+}
+
+fn reply_ok(reply: &mut Packet) {
+    reply.push(b'O');
+    reply.push(b'K');
+}
+
+fn error(reply: &mut Packet) -> Action {
+    reply.clear();
+    reply.push(b'E');
+    reply.push_hex(1);
+    Action::Reply
+}
+
+fn register_size(n: usize) -> usize {
+    if n <= 16 {
+        8
+    } else {
+        4
+    }
+}
+
+fn register_mut(frame: &mut TrapFrame, n: usize) -> Option<&mut u64> {
+    Some(match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18 => &mut frame.cs,
+        19 => &mut frame.ss,
+        _ => return None,
+    })
+}
+
+fn register(frame: &mut TrapFrame, n: usize) -> Option<u64> {
+    match n {
+        20 => Some(DS::get_reg().0.into()),
+        21 => Some(ES::get_reg().0.into()),
+        22 => Some(FS::get_reg().0.into()),
+        23 => Some(GS::get_reg().0.into()),
+        _ => register_mut(frame, n).map(|value| *value),
+    }
+}
+
+/// Changes register `n` when the stopped code resumes. The segment registers are left alone,
+/// gdb writes them back unchanged along with the others.
+fn set_register(frame: &mut TrapFrame, n: usize, value: u64) -> bool {
+    match n {
+        18..=23 => true,
+        _ => match register_mut(frame, n) {
+            Some(register) => {
+                *register = value;
+                true
+            }
+            None => false,
+        },
+    }
+}
+
+fn write_registers(frame: &mut TrapFrame, mut hex: &[u8]) -> Result<(), ()> {
+    for n in 0..REGISTER_COUNT {
+        if hex.is_empty() {
+            break;
+        }
+        let size = register_size(n) * 2;
+        let value = decode_le(hex.get(..size).ok_or(())?).ok_or(())?;
+        set_register(frame, n, value);
+        hex = &hex[size..];
+    }
+    Ok(())
+}
+
+/// Goes through the physical memory mapping, so that breakpoints can be put in read-only code.
+fn physical(addr: u64) -> Option<*mut u8> {
+    let alias = memory::physical_alias(VirtAddr::try_new(addr).ok()?)?;
+    Some(alias.as_mut_ptr())
+}
+
+fn read_memory(args: &[u8], reply: &mut Packet) -> Result<(), ()> {
+    let (addr, len) = split(args, b',');
+    let (addr, len) = (parse_hex(addr).ok_or(())?, parse_hex(len).ok_or(())?);
+    for offset in 0..len.min(PACKET_SIZE as u64 / 2) {
+        match physical(addr.wrapping_add(offset)) {
+            Some(byte) => reply.push_hex(unsafe { byte.read_volatile() }),
+            // gdb takes a short read as the part before the unmapped address
+            None if offset > 0 => break,
+            None => return Err(()),
+        }
+    }
+    Ok(())
+}
+
+fn write_memory(args: &[u8]) -> Result<(), ()> {
+    let (range, data) = split(args, b':');
+    let (addr, len) = split(range, b',');
+    let (addr, len) = (parse_hex(addr).ok_or(())?, parse_hex(len).ok_or(())?);
+    if data.len() as u64 != len * 2 {
+        return Err(());
+    }
+    for (offset, hex) in data.chunks(2).enumerate() {
+        let value = parse_hex(hex).ok_or(())? as u8;
+        let byte = physical(addr.wrapping_add(offset as u64)).ok_or(())?;
+        unsafe { byte.write_volatile(value) };
+    }
+    Ok(())
+}
+
+fn is_breakpoint(addr: u64) -> bool {
+    BREAKPOINTS
+        .lock()
+        .iter()
+        .flatten()
+        .any(|breakpoint| breakpoint.addr == addr)
+}
+
+fn insert_breakpoint(addr: u64) -> Result<(), ()> {
+    if is_breakpoint(addr) {
+        return Ok(());
+    }
+    let mut breakpoints = BREAKPOINTS.lock();
+    let slot = breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(())?;
+    let byte = physical(addr).ok_or(())?;
+    unsafe {
+        *slot = Some(Breakpoint {
+            addr,
+            original: byte.read_volatile(),
+        });
+        byte.write_volatile(INT3);
+    }
+    Ok(())
+}
+
+fn remove_breakpoint(addr: u64) -> Result<(), ()> {
+    let mut breakpoints = BREAKPOINTS.lock();
+    let slot = breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|breakpoint| breakpoint.addr == addr))
+        .ok_or(())?;
+    if let (Some(breakpoint), Some(byte)) = (slot.take(), physical(addr)) {
+        unsafe { byte.write_volatile(breakpoint.original) };
+    }
+    Ok(())
+}
+
+/// Splits `bytes` at the first `separator`, which belongs to neither half.
+fn split(bytes: &[u8], separator: u8) -> (&[u8], &[u8]) {
+    match bytes.iter().position(|&byte| byte == separator) {
+        Some(index) => (&bytes[..index], &bytes[index + 1..]),
+        None => (bytes, &[]),
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, &digit| {
+        let digit = (digit as char).to_digit(16)?;
+        Some(value << 4 | u64::from(digit))
+    })
+}
+
+/// Parses a value sent in target byte order, like register values.
+fn decode_le(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 || hex.len() & 1 == 1 {
+        return None;
+    }
+    hex.chunks(2)
+        .rev()
+        .try_fold(0, |value, byte| Some(value << 8 | parse_hex(byte)?))
+}
+
+#[cfg(test)]
+fn run(packet: &[u8], frame: &mut TrapFrame) -> (Action, Packet) {
+    let mut reply = Packet::new();
+    let action = handle_packet(packet, frame, SIGTRAP, &mut reply);
+    (action, reply)
+}
+
+#[test_case]
+fn test_registers() {
+    let mut frame = TrapFrame {
+        rax: 0x1122_3344_5566_7788,
+        rip: 0x1000,
+        ..TrapFrame::default()
+    };
+    assert_eq!(run(b"?", &mut frame).1.as_bytes(), b"S05");
+    let (_, registers) = run(b"g", &mut frame);
+    assert_eq!(registers.len, 17 * 16 + 7 * 8);
+    assert!(registers.as_bytes().starts_with(b"8877665544332211"));
+    assert_eq!(run(b"p10", &mut frame).1.as_bytes(), b"0010000000000000");
+    assert_eq!(run(b"P10=0020000000000000", &mut frame).1.as_bytes(), b"OK");
+    assert_eq!(frame.rip, 0x2000);
+    assert_eq!(run(b"P63=00", &mut frame).1.as_bytes(), b"E01");
+
+    assert_eq!(run(b"s", &mut frame).0, Action::Resume);
+    assert_ne!(frame.rflags & RFlags::TRAP_FLAG.bits(), 0);
+    assert_eq!(run(b"c3000", &mut frame).0, Action::Resume);
+    assert_eq!(frame.rflags & RFlags::TRAP_FLAG.bits(), 0);
+    assert_eq!(frame.rip, 0x3000);
+}
+
+#[test_case]
+fn test_memory_and_breakpoints() {
+    use core::fmt::Write;
+
+    let mut frame = TrapFrame::default();
+    let mut bytes = [1u8, 2, 3, 4];
+    let ptr = bytes.as_mut_ptr();
+    let addr = ptr as u64;
+    let mut packet = Packet::new();
+    let mut send = |args: fmt::Arguments, frame: &mut TrapFrame| {
+        packet.clear();
+        packet.write_fmt(args).unwrap();
+        run(packet.as_bytes(), frame).1
+    };
+
+    assert_eq!(
+        send(format_args!("m{:x},4", addr), &mut frame).as_bytes(),
+        b"01020304"
+    );
+    assert_eq!(
+        send(format_args!("M{:x},2:aabb", addr), &mut frame).as_bytes(),
+        b"OK"
+    );
+    assert_eq!(
+        send(format_args!("Z0,{:x},1", addr + 2), &mut frame).as_bytes(),
+        b"OK"
+    );
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0xaa);
+        assert_eq!(ptr.add(1).read_volatile(), 0xbb);
+        assert_eq!(ptr.add(2).read_volatile(), INT3);
+    }
+    assert!(is_breakpoint(addr + 2));
+    assert_eq!(
+        send(format_args!("z0,{:x},1", addr + 2), &mut frame).as_bytes(),
+        b"OK"
+    );
+    assert_eq!(unsafe { ptr.add(2).read_volatile() }, 3);
+    assert_eq!(send(format_args!("m0,1"), &mut frame).as_bytes(), b"E01");
+}
@@ -0,0 +1,361 @@
+//! Pixel graphics in the VGA's 320x200x256 (mode 13h) and 640x480x16 (mode 12h) modes.
+//!
+//! ```ignore
+//! let mut screen = graphics::enter(GraphicsMode::Vga320x200x256)?;
+//! screen.fill_rect(10, 10, 50, 20, 4);
+//! screen.circle(160, 100, 40, 15);
+//! drop(screen); // back to text mode
+//! ```
+
+use crate::framebuffer;
+use crate::vga::{self, ModeRegisters, GRAPHICS_INDEX, PALETTE_SIZE};
+use crate::vga_buffer;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Both modes map their video memory at the start of the 64K graphics window.
+const FRAMEBUFFER: usize = 0xa0000;
+
+const GC_SET_RESET: u8 = 0x00;
+const GC_READ_MAP: u8 = 0x04;
+const GC_BIT_MASK: u8 = 0x08;
+
+const MODE_13H: ModeRegisters = ModeRegisters {
+    misc: 0x63,
+    sequencer: [0x03, 0x01, 0x0f, 0x00, 0x0e],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x54, 0x80, 0xbf, 0x1f, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x9c, 0x0e, 0x8f, 0x28, 0x40, 0x96, 0xb9, 0xa3, 0xff,
+    ],
+    graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0f, 0xff],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x41, 0x00, 0x0f, 0x00, 0x00,
+    ],
+};
+
+/// Mode 12h, with set/reset enabled on every plane so a pixel is drawn by loading its colour into
+/// the set/reset register and its bit into the bit mask. The attribute palette maps colour n to
+/// DAC entry n so both modes are coloured through `vga::set_palette` the same way.
+const MODE_12H: ModeRegisters = ModeRegisters {
+    misc: 0xe3,
+    sequencer: [0x03, 0x01, 0x0f, 0x00, 0x06],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x54, 0x80, 0x0b, 0x3e, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0xea, 0x0c, 0xdf, 0x28, 0x00, 0xe7, 0x04, 0xe3, 0xff,
+    ],
+    graphics: [0x00, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x05, 0x0f, 0xff],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x01, 0x00, 0x0f, 0x00, 0x00,
+    ],
+};
+
+/// The 16 text mode colours as 6 bit DAC levels, so `vga_buffer::Colour as u8` works as a pixel
+/// colour in both modes.
+const STANDARD_COLOURS: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (0, 0, 42),
+    (0, 42, 0),
+    (0, 42, 42),
+    (42, 0, 0),
+    (42, 0, 42),
+    (42, 21, 0),
+    (42, 42, 42),
+    (21, 21, 21),
+    (21, 21, 63),
+    (21, 63, 21),
+    (21, 63, 63),
+    (63, 21, 21),
+    (63, 21, 63),
+    (63, 63, 21),
+    (63, 63, 63),
+];
+
+static IN_GRAPHICS_MODE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphicsMode {
+    /// Mode 13h, one byte per pixel.
+    Vga320x200x256,
+    /// Mode 12h, four bit planes.
+    Vga640x480x16,
+}
+
+impl GraphicsMode {
+    pub fn width(self) -> usize {
+        match self {
+            GraphicsMode::Vga320x200x256 => 320,
+            GraphicsMode::Vga640x480x16 => 640,
+        }
+    }
+
+    pub fn height(self) -> usize {
+        match self {
+            GraphicsMode::Vga320x200x256 => 200,
+            GraphicsMode::Vga640x480x16 => 480,
+        }
+    }
+
+    pub fn colours(self) -> usize {
+        match self {
+            GraphicsMode::Vga320x200x256 => 256,
+            GraphicsMode::Vga640x480x16 => 16,
+        }
+    }
+
+    fn registers(self) -> &'static ModeRegisters {
+        match self {
+            GraphicsMode::Vga320x200x256 => &MODE_13H,
+            GraphicsMode::Vga640x480x16 => &MODE_12H,
+        }
+    }
+}
+
+/// Switches the VGA into `mode`. The returned `Screen` owns the display until it is dropped,
+/// which puts the text consoles back.
+pub fn enter(mode: GraphicsMode) -> Result<Screen, &'static str> {
+    if framebuffer::console().is_some() {
+        return Err("the display belongs to the framebuffer console");
+    }
+    if IN_GRAPHICS_MODE.swap(true, Ordering::SeqCst) {
+        return Err("already in a graphics mode");
+    }
+
+    let mut saved_palette = [0; PALETTE_SIZE * 3];
+    vga::read_palette(&mut saved_palette);
+    vga_buffer::save_text_font();
+    vga::set_registers(mode.registers());
+    load_default_palette(mode);
+
+    let mut screen = Screen {
+        mode,
+        saved_palette,
+    };
+    screen.clear(0);
+    Ok(screen)
+}
+
+/// The 16 standard colours, then for 256 colour modes a 6x6x6 colour cube and a grey ramp.
+fn load_default_palette(mode: GraphicsMode) {
+    for (index, &(red, green, blue)) in STANDARD_COLOURS.iter().enumerate() {
+        vga::set_palette(index as u8, red, green, blue);
+    }
+    if mode.colours() < 256 {
+        return;
+    }
+    const LEVELS: [u8; 6] = [0, 12, 25, 38, 51, 63];
+    for index in 0..216 {
+        vga::set_palette(
+            16 + index as u8,
+            LEVELS[index / 36],
+            LEVELS[index / 6 % 6],
+            LEVELS[index % 6],
+        );
+    }
+    for index in 0..24 {
+        let level = (index * 63 / 23) as u8;
+        vga::set_palette(232 + index as u8, level, level, level);
+    }
+}
+
+/// The display while it is in a graphics mode. Drawing outside of the screen is clipped.
+pub struct Screen {
+    mode: GraphicsMode,
+    saved_palette: [u8; PALETTE_SIZE * 3],
+}
+
+impl Screen {
+    pub fn mode(&self) -> GraphicsMode {
+        self.mode
+    }
+
+    pub fn width(&self) -> usize {
+        self.mode.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.mode.height()
+    }
+
+    pub fn set_pixel(&mut self, x: i32, y: i32, colour: u8) {
+        if x < 0 || y < 0 || x as usize >= self.width() || y as usize >= self.height() {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        match self.mode {
+            GraphicsMode::Vga320x200x256 => unsafe {
+                (FRAMEBUFFER as *mut u8)
+                    .add(y * self.width() + x)
+                    .write_volatile(colour)
+            },
+            GraphicsMode::Vga640x480x16 => {
+                vga::write_register(GRAPHICS_INDEX, GC_SET_RESET, colour & 0x0f);
+                vga::write_register(GRAPHICS_INDEX, GC_BIT_MASK, 0x80 >> (x % 8));
+                let byte = (FRAMEBUFFER as *mut u8).wrapping_add(y * self.width() / 8 + x / 8);
+                unsafe {
+                    // the read loads the latches so the other pixels of the byte are kept
+                    byte.read_volatile();
+                    byte.write_volatile(0xff);
+                }
+            }
+        }
+    }
+
+    /// Returns the colour at `x`, `y`, or `None` if that is off the screen.
+    pub fn pixel(&self, x: i32, y: i32) -> Option<u8> {
+        if x < 0 || y < 0 || x as usize >= self.width() || y as usize >= self.height() {
+            return None;
+        }
+        let (x, y) = (x as usize, y as usize);
+        match self.mode {
+            GraphicsMode::Vga320x200x256 => unsafe {
+                Some(
+                    (FRAMEBUFFER as *const u8)
+                        .add(y * self.width() + x)
+                        .read_volatile(),
+                )
+            },
+            GraphicsMode::Vga640x480x16 => {
+                let byte = (FRAMEBUFFER as *const u8).wrapping_add(y * self.width() / 8 + x / 8);
+                let mut colour = 0;
+                for plane in 0..4 {
+                    vga::write_register(GRAPHICS_INDEX, GC_READ_MAP, plane);
+                    if unsafe { byte.read_volatile() } & (0x80 >> (x % 8)) != 0 {
+                        colour |= 1 << plane;
+                    }
+                }
+                vga::write_register(GRAPHICS_INDEX, GC_READ_MAP, 0);
+                Some(colour)
+            }
+        }
+    }
+
+    pub fn clear(&mut self, colour: u8) {
+        match self.mode {
+            GraphicsMode::Vga320x200x256 => {
+                for offset in 0..self.width() * self.height() {
+                    unsafe { (FRAMEBUFFER as *mut u8).add(offset).write_volatile(colour) };
+                }
+            }
+            GraphicsMode::Vga640x480x16 => {
+                vga::write_register(GRAPHICS_INDEX, GC_SET_RESET, colour & 0x0f);
+                vga::write_register(GRAPHICS_INDEX, GC_BIT_MASK, 0xff);
+                for offset in 0..self.width() * self.height() / 8 {
+                    unsafe { (FRAMEBUFFER as *mut u8).add(offset).write_volatile(0xff) };
+                }
+            }
+        }
+    }
+
+    /// Draws a line from `x0`, `y0` to `x1`, `y1` including both ends.
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, colour: u8) {
+        // Bresenham's algorithm, generalised to every octant
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            self.set_pixel(x, y, colour);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draws the outline of a `width` by `height` rectangle with its top left corner at `x`, `y`.
+    pub fn rect(&mut self, x: i32, y: i32, width: i32, height: i32, colour: u8) {
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        let (right, bottom) = (x + width - 1, y + height - 1);
+        self.line(x, y, right, y, colour);
+        self.line(x, bottom, right, bottom, colour);
+        self.line(x, y, x, bottom, colour);
+        self.line(right, y, right, bottom, colour);
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, colour: u8) {
+        for row in y..y + height {
+            for col in x..x + width {
+                self.set_pixel(col, row, colour);
+            }
+        }
+    }
+
+    /// Draws the outline of a circle centred on `cx`, `cy`.
+    pub fn circle(&mut self, cx: i32, cy: i32, radius: i32, colour: u8) {
+        // midpoint circle algorithm, each step plots one point in all eight octants
+        let (mut x, mut y) = (radius, 0);
+        let mut error = 1 - radius;
+        while x >= y {
+            for &(px, py) in &[(x, y), (y, x), (-y, x), (-x, y)] {
+                self.set_pixel(cx + px, cy + py, colour);
+                self.set_pixel(cx - px, cy - py, colour);
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Copies an image stored as rows of `width` colours to `x`, `y`. Pixels of the `transparent`
+    /// colour are skipped so sprites can have holes.
+    pub fn blit(&mut self, x: i32, y: i32, width: usize, pixels: &[u8], transparent: Option<u8>) {
+        if width == 0 {
+            return;
+        }
+        for (row, line) in pixels.chunks(width).enumerate() {
+            for (col, &colour) in line.iter().enumerate() {
+                if Some(colour) != transparent {
+                    self.set_pixel(x + col as i32, y + row as i32, colour);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        vga_buffer::restore_text_mode();
+        vga::write_palette(&self.saved_palette);
+        IN_GRAPHICS_MODE.store(false, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn test_graphics_primitives() {
+    for &mode in &[GraphicsMode::Vga320x200x256, GraphicsMode::Vga640x480x16] {
+        let mut screen = enter(mode).expect("failed to enter graphics mode");
+        assert!(enter(mode).is_err());
+
+        screen.line(0, 0, 10, 10, 4);
+        assert_eq!(screen.pixel(5, 5), Some(4));
+        assert_eq!(screen.pixel(5, 6), Some(0));
+
+        screen.fill_rect(20, 20, 3, 3, 9);
+        screen.blit(20, 20, 2, &[1, 0, 0, 1], Some(0));
+        assert_eq!(screen.pixel(20, 20), Some(1));
+        assert_eq!(screen.pixel(21, 20), Some(9));
+        assert_eq!(screen.pixel(22, 22), Some(9));
+
+        screen.circle(50, 50, 10, 14);
+        assert_eq!(screen.pixel(60, 50), Some(14));
+        assert_eq!(screen.pixel(50, 50), Some(0));
+        assert_eq!(screen.pixel(-1, 0), None);
+    }
+}
@@ -0,0 +1,303 @@
+//! Unpacks an initrd, a ustar or newc cpio archive, into a directory of the VFS. The kernel image
+//! has one built in from the files under initrd/ in the source tree, which `init` unpacks into
+//! /tmp, so config files, game levels and programs can be there without a disk.
+//!
+//! The bootloader doesn't pass modules, but `load` takes any archive in memory, so one that does
+//! can be unpacked the same way.
+
+use crate::vfs::{self, FdTable, Kind, OpenFlags, MAX_PATH_LENGTH};
+
+/// The archive built from initrd/ by build.rs.
+pub static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.cpio"));
+
+const USTAR_BLOCK: usize = 512;
+const NEWC_HEADER: usize = 110;
+
+/// Unpacks `EMBEDDED` into /tmp.
+pub fn init() {
+    match load(EMBEDDED, "/tmp") {
+        Ok(files) => log::info!("initrd: {} files in /tmp", files),
+        Err(err) => log::error!("initrd: {}", err),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Ustar,
+    Newc,
+}
+
+struct Entry<'a> {
+    /// Relative to the root of the archive, without a leading "./" or "/".
+    name: [u8; MAX_PATH_LENGTH],
+    len: usize,
+    /// Directory or file, anything else is skipped.
+    kind: Option<Kind>,
+    data: &'a [u8],
+}
+
+impl Entry<'_> {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("")
+    }
+}
+
+/// Puts the parts of a name together into `entry`, dropping the leading "./" or "/". Names with
+/// ".." in them are refused, they could reach outside the directory the archive is unpacked in.
+fn set_name(entry: &mut Entry, parts: &[&[u8]]) -> Result<(), &'static str> {
+    entry.len = 0;
+    for part in parts {
+        let end = entry.len + part.len();
+        if end > MAX_PATH_LENGTH {
+            return Err("a name in the archive is too long");
+        }
+        entry.name[entry.len..end].copy_from_slice(part);
+        entry.len = end;
+    }
+    let name = core::str::from_utf8(&entry.name[..entry.len])
+        .map_err(|_| "a name in the archive isn't UTF-8")?;
+    let trimmed = name.trim_start_matches("./").trim_start_matches('/');
+    if trimmed.split('/').any(|part| part == "..") {
+        return Err("a name in the archive goes up a directory");
+    }
+    let skip = name.len() - trimmed.len();
+    entry.name.copy_within(skip..entry.len, 0);
+    entry.len -= skip;
+    Ok(())
+}
+
+fn parse_number(field: &[u8], radix: u32) -> Result<usize, &'static str> {
+    let digits = core::str::from_utf8(field)
+        .map_err(|_| "bad number in the archive")?
+        .trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(digits, radix).map_err(|_| "bad number in the archive")
+}
+
+/// The part of a field up to the first NUL.
+fn field(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+fn format_of(archive: &[u8]) -> Option<Format> {
+    if archive.starts_with(b"070701") || archive.starts_with(b"070702") {
+        Some(Format::Newc)
+    } else if archive.get(257..262) == Some(b"ustar") {
+        Some(Format::Ustar)
+    } else {
+        None
+    }
+}
+
+/// The entries of an archive, one at a time.
+struct Entries<'a> {
+    archive: &'a [u8],
+    pos: usize,
+    format: Format,
+}
+
+impl<'a> Entries<'a> {
+    fn new(archive: &'a [u8]) -> Result<Self, &'static str> {
+        let format = format_of(archive).ok_or("not a ustar or newc cpio archive")?;
+        Ok(Entries {
+            archive,
+            pos: 0,
+            format,
+        })
+    }
+
+    fn bytes(&self, start: usize, len: usize) -> Result<&'a [u8], &'static str> {
+        start
+            .checked_add(len)
+            .and_then(|end| self.archive.get(start..end))
+            .ok_or("the archive is cut off")
+    }
+
+    /// The next entry, or `None` after the last one.
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, &'static str> {
+        let mut entry = Entry {
+            name: [0; MAX_PATH_LENGTH],
+            len: 0,
+            kind: None,
+            data: &[],
+        };
+        match self.format {
+            Format::Ustar => {
+                let header = match self.archive.get(self.pos..self.pos + USTAR_BLOCK) {
+                    // the archive ends with zero blocks, but they are sometimes left out
+                    None => return Ok(None),
+                    Some(header) if header.iter().all(|&b| b == 0) => return Ok(None),
+                    Some(header) => header,
+                };
+                let sum = header
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b })
+                    .map(usize::from)
+                    .sum::<usize>();
+                if parse_number(field(&header[148..156]), 8)? != sum {
+                    return Err("bad checksum in the archive");
+                }
+                let size = parse_number(field(&header[124..136]), 8)?;
+                let prefix = field(&header[345..500]);
+                let name = field(&header[..100]);
+                if prefix.is_empty() {
+                    set_name(&mut entry, &[name])?;
+                } else {
+                    set_name(&mut entry, &[prefix, b"/", name])?;
+                }
+                entry.kind = match header[156] {
+                    b'0' | 0 => Some(Kind::File),
+                    b'5' => Some(Kind::Directory),
+                    _ => None,
+                };
+                entry.data = self.bytes(self.pos + USTAR_BLOCK, size)?;
+                self.pos += USTAR_BLOCK + (size + USTAR_BLOCK - 1) / USTAR_BLOCK * USTAR_BLOCK;
+            }
+            Format::Newc => {
+                let header = self.bytes(self.pos, NEWC_HEADER)?;
+                if format_of(header) != Some(Format::Newc) {
+                    return Err("bad header in the archive");
+                }
+                let number = |i: usize| parse_number(&header[6 + i * 8..14 + i * 8], 16);
+                let mode = number(1)?;
+                let size = number(6)?;
+                let name_size = number(11)?;
+                let name = self.bytes(self.pos + NEWC_HEADER, name_size)?;
+                let name = field(name);
+                if name == b"TRAILER!!!" {
+                    return Ok(None);
+                }
+                set_name(&mut entry, &[name])?;
+                entry.kind = match mode & 0o170000 {
+                    0o100000 => Some(Kind::File),
+                    0o040000 => Some(Kind::Directory),
+                    _ => None,
+                };
+                let data = (self.pos + NEWC_HEADER + name_size + 3) & !3;
+                entry.data = self.bytes(data, size)?;
+                self.pos = (data + size + 3) & !3;
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+/// Makes the directories and files of `archive` in directory `dir`, replacing files that are
+/// there already. Returns how many files there were.
+pub fn load(archive: &[u8], dir: &str) -> Result<usize, &'static str> {
+    if archive.is_empty() {
+        return Ok(0);
+    }
+    let mut entries = Entries::new(archive)?;
+    let mut files = FdTable::new();
+    let mut count = 0;
+    let mut path = [0; MAX_PATH_LENGTH];
+    while let Some(entry) = entries.next_entry()? {
+        if entry.name().is_empty() || entry.name() == "." {
+            continue;
+        }
+        let len = dir.len() + 1 + entry.len;
+        if len > MAX_PATH_LENGTH {
+            return Err("a name in the archive is too long");
+        }
+        path[..dir.len()].copy_from_slice(dir.as_bytes());
+        path[dir.len()] = b'/';
+        path[dir.len() + 1..len].copy_from_slice(&entry.name[..entry.len]);
+        let path = core::str::from_utf8(&path[..len]).map_err(|_| "bad path")?;
+        match entry.kind {
+            Some(Kind::Directory) => match vfs::stat(path) {
+                Ok(stat) if stat.kind == Kind::Directory => {}
+                _ => {
+                    vfs::create(path, Kind::Directory)?;
+                }
+            },
+            Some(_) => {
+                let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+                let fd = files.open(path, flags)?;
+                let written = files.write(fd, entry.data);
+                files.close(fd)?;
+                if written? != entry.data.len() {
+                    return Err("out of space for the files of the archive");
+                }
+                count += 1;
+            }
+            None => log::warn!("initrd: skipping {}, it isn't a file", entry.name()),
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+fn ustar_header(block: &mut [u8], name: &str, size: usize, kind: u8) {
+    use core::fmt::Write;
+
+    struct Field<'a>(&'a mut [u8]);
+    impl Write for Field<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            self.0[..s.len()].copy_from_slice(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    write!(Field(&mut block[124..136]), "{:011o}", size).unwrap();
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[148..156].fill(b' ');
+    let sum: usize = block[..USTAR_BLOCK].iter().map(|&b| b as usize).sum();
+    write!(Field(&mut block[148..156]), "{:06o}\0", sum).unwrap();
+}
+
+#[test_case]
+fn test_ustar() {
+    vfs::init();
+    let mut archive = [0; 4 * USTAR_BLOCK];
+    ustar_header(&mut archive, "./levels/", 0, b'5');
+    ustar_header(&mut archive[USTAR_BLOCK..], "levels/1", 6, b'0');
+    archive[2 * USTAR_BLOCK..2 * USTAR_BLOCK + 6].copy_from_slice(b"#.@.$#");
+
+    vfs::create("/tmp/ustar", Kind::Directory).unwrap();
+    assert_eq!(load(&archive, "/tmp/ustar"), Ok(1));
+    // again, replacing the file
+    assert_eq!(load(&archive, "/tmp/ustar"), Ok(1));
+    let mut files = FdTable::new();
+    let fd = files.open("/tmp/ustar/levels/1", OpenFlags::READ).unwrap();
+    let mut buf = [0; 8];
+    assert_eq!(files.read(fd, &mut buf), Ok(6));
+    assert_eq!(&buf[..6], b"#.@.$#");
+    files.close(fd).unwrap();
+
+    archive[100] ^= 1;
+    assert!(load(&archive, "/tmp/ustar").is_err());
+    assert!(load(b"not an archive", "/tmp/ustar").is_err());
+    vfs::remove("/tmp/ustar/levels/1").unwrap();
+    vfs::remove("/tmp/ustar/levels").unwrap();
+    vfs::remove("/tmp/ustar").unwrap();
+}
+
+#[test_case]
+fn test_parent_dir() {
+    vfs::init();
+    let mut archive = [0; 3 * USTAR_BLOCK];
+    ustar_header(&mut archive, "levels/../../escaped", 1, b'0');
+    archive[USTAR_BLOCK] = b'!';
+
+    vfs::create("/tmp/parent", Kind::Directory).unwrap();
+    assert!(load(&archive, "/tmp/parent").is_err());
+    assert!(vfs::stat("/tmp/escaped").is_err());
+    vfs::remove("/tmp/parent").unwrap();
+}
+
+#[test_case]
+fn test_newc() {
+    vfs::init();
+    vfs::create("/tmp/newc", Kind::Directory).unwrap();
+    let files = load(EMBEDDED, "/tmp/newc").unwrap();
+    assert!(files >= 1);
+    assert!(vfs::stat("/tmp/newc/motd.txt").unwrap().size > 0);
+    assert!(load(&EMBEDDED[..EMBEDDED.len() / 2], "/tmp/newc").is_err());
+}
@@ -1,26 +1,143 @@
+use crate::ata;
+use crate::gdb;
 use crate::gdt;
-use crate::println;
+use crate::keyboard;
+use crate::monitor;
+use crate::mouse;
+use crate::ps2;
+use crate::serial;
+use crate::vga_buffer::{self, CONSOLES};
 use lazy_static::lazy_static;
-use pc_keyboard::DecodedKey;
+use pc_keyboard::{DecodedKey, KeyCode, KeyEvent, KeyState};
 use pic8259::ChainedPics;
 use spin;
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
+            idt.debug
+                .set_handler_addr(VirtAddr::from_ptr(debug_entry as *const ()));
+            idt.breakpoint
+                .set_handler_addr(VirtAddr::from_ptr(breakpoint_entry as *const ()));
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Com1.as_usize()].set_handler_fn(com1_interrupt_handler);
+        idt[InterruptIndex::Com2.as_usize()].set_handler_fn(com2_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[InterruptIndex::Irq5.as_usize()].set_handler_fn(irq5_interrupt_handler);
+        idt[InterruptIndex::Irq9.as_usize()].set_handler_fn(irq9_interrupt_handler);
+        idt[InterruptIndex::Irq10.as_usize()].set_handler_fn(irq10_interrupt_handler);
+        idt[InterruptIndex::Irq11.as_usize()].set_handler_fn(irq11_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()]
+            .set_handler_fn(secondary_ata_interrupt_handler);
         idt
     };
 }
 
+/// The registers of the code that was interrupted by a breakpoint or debug exception, as saved by
+/// `trap_common`. Changes are written back to the registers when the handler returns.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+extern "C" {
+    fn debug_entry();
+    fn breakpoint_entry();
+}
+
+// The x86-interrupt ABI only gives handlers the stack frame pushed by the CPU, a debugger also
+// needs to see and change the general purpose registers.
+core::arch::global_asm!(
+    ".global debug_entry",
+    "debug_entry:",
+    "push 1",
+    "jmp trap_common",
+    ".global breakpoint_entry",
+    "breakpoint_entry:",
+    "push 3",
+    "jmp trap_common",
+    "trap_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    // rbp is saved above and preserved by the handler, so it can hold the unaligned stack pointer
+    "mov rbp, rsp",
+    "and rsp, -16",
+    "cld",
+    "call {handler}",
+    "mov rsp, rbp",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // the vector number
+    "add rsp, 8",
+    "iretq",
+    handler = sym trap_handler,
+);
+
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    match frame.vector {
+        1 => debug_handler(frame),
+        _ => breakpoint_handler(frame),
+    }
+}
+
 pub static LASTPRESSED: spin::Mutex<DecodedKey> = spin::Mutex::new(DecodedKey::Unicode('2'));
 pub static STOPWATCH: spin::Mutex<u128> = spin::Mutex::new(0);
 
@@ -28,34 +145,249 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
+extern "x86-interrupt" fn keyboard_interrupt_handler(mut stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Uk105Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Uk105Key, ScancodeSet1, HandleControl::Ignore)
-        );
+    // device commands poll for their answers, which can leave an interrupt with nothing to read
+    let status = ps2::status();
+    if status & ps2::STATUS_OUTPUT_FULL != 0 && status & ps2::STATUS_SECOND_PORT_DATA == 0 {
+        let mut port = Port::new(0x60);
+        let scancode: u8 = unsafe { port.read() };
+        handle_scancode(scancode);
+    }
+    monitor::stop_if_requested(&mut stack_frame);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
+    }
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    let status = ps2::status();
+    if status & ps2::STATUS_OUTPUT_FULL != 0 && status & ps2::STATUS_SECOND_PORT_DATA != 0 {
+        let mut port = Port::new(0x60);
+        let byte: u8 = unsafe { port.read() };
+        mouse::add_byte(byte);
+    }
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8())
+    }
+}
+
+extern "x86-interrupt" fn com1_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    if serial::receive_pending() {
+        gdb::request_stop(&mut stack_frame);
     }
 
-    let mut keyboard = KEYBOARD.lock();
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Com1.as_u8())
+    }
+}
+
+extern "x86-interrupt" fn com2_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    if serial::receive_pending() {
+        gdb::request_stop(&mut stack_frame);
+    }
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Com2.as_u8())
+    }
+}
+
+extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    ata::handle_interrupt(0);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8())
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    ata::handle_interrupt(1);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondaryAta.as_u8())
+    }
+}
+
+extern "x86-interrupt" fn irq5_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handle_shared_irq(InterruptIndex::Irq5);
+}
+
+extern "x86-interrupt" fn irq9_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handle_shared_irq(InterruptIndex::Irq9);
+}
+
+extern "x86-interrupt" fn irq10_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handle_shared_irq(InterruptIndex::Irq10);
+}
+
+extern "x86-interrupt" fn irq11_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    handle_shared_irq(InterruptIndex::Irq11);
+}
+
+/// Runs the handlers added for the IRQ with `add_irq_handler`.
+fn handle_shared_irq(index: InterruptIndex) {
+    let irq = index.as_u8() - PIC_1_OFFSET;
+    if let Some(handlers) = IRQ_HANDLERS.try_lock() {
+        handlers[usize::from(irq)]
+            .iter()
+            .flatten()
+            .for_each(|handler| handler());
+    }
+
+    unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) }
+}
+
+/// Has `handler` called on `irq`, one of the lines the firmware routes PCI interrupts to. PCI
+/// devices share lines, so the handler has to check whether its device interrupted, and stop it
+/// from interrupting before returning.
+pub fn add_irq_handler(irq: u8, handler: fn()) -> Result<(), &'static str> {
+    if !PCI_IRQS.contains(&irq) {
+        return Err("IRQ can't have handlers added");
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = handlers[usize::from(irq)]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("too many handlers for the IRQ")?;
+        *slot = Some(handler);
+        // the secondary PIC's lines come in through IRQ 2
+        unsafe {
+            let mut pics = PICS.lock();
+            let [primary, secondary] = pics.read_masks();
+            if irq < 8 {
+                pics.write_masks(primary & !(1 << irq), secondary);
+            } else {
+                pics.write_masks(primary & !(1 << 2), secondary & !(1 << (irq - 8)));
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Reads what the keyboard or the mouse sent and handles it like their interrupt handlers, for
+/// code that runs with interrupts off.
+pub(crate) fn poll_ps2() {
+    use x86_64::instructions::port::Port;
+
+    let status = ps2::status();
+    if status & ps2::STATUS_OUTPUT_FULL == 0 {
+        return;
+    }
     let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+    if status & ps2::STATUS_SECOND_PORT_DATA != 0 {
+        mouse::add_byte(byte);
+    } else {
+        handle_scancode(byte);
+    }
+}
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            *LASTPRESSED.lock() = key;
+pub(crate) fn handle_scancode(scancode: u8) {
+    if let Some(key_event) = keyboard::add_scancode(scancode) {
+        if handle_console_keys(&key_event) {
+            keyboard::consume_event(&key_event);
+        } else {
+            keyboard::process_event(key_event);
         }
     }
+}
 
+/// Makes the code that was interrupted with `stack_frame` stop with a debug exception after its
+/// next instruction, which gets it into `debug_handler` with all of its registers.
+pub(crate) fn set_trap_flag(stack_frame: &mut InterruptStackFrame) {
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.cpu_flags |= RFlags::TRAP_FLAG.bits());
+    }
+}
+
+/// Handles the keys that control the console itself rather than the running program. Returns
+/// true if the event was consumed.
+fn handle_console_keys(key_event: &KeyEvent) -> bool {
+    let pressed = key_event.state == KeyState::Down;
+    let modifiers = keyboard::modifiers();
+    match key_event.code {
+        KeyCode::F1 | KeyCode::F2 | KeyCode::F3 | KeyCode::F4
+            if modifiers.alt() || modifiers.alt_gr() =>
+        {
+            if pressed {
+                let console = match key_event.code {
+                    KeyCode::F1 => 0,
+                    KeyCode::F2 => 1,
+                    KeyCode::F3 => 2,
+                    _ => 3,
+                };
+                vga_buffer::switch_console(console);
+            }
+            true
+        }
+        KeyCode::PageUp | KeyCode::PageDown if modifiers.shift() => {
+            if pressed {
+                scroll_console(key_event.code == KeyCode::PageUp);
+            }
+            true
+        }
+        KeyCode::PrintScreen if modifiers.alt() => {
+            if pressed {
+                monitor::request();
+            }
+            true
+        }
+        KeyCode::PrintScreen => {
+            if pressed {
+                vga_buffer::screenshot_to_serial();
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Shift+PageUp/PageDown moves the active console's view through the scrollback a page at a time.
+fn scroll_console(up: bool) {
+    // the interrupted code may be holding the writer, drop the keypress rather than deadlock
+    if let Some(mut writer) = CONSOLES[vga_buffer::active_console()].try_lock() {
+        let page = writer.height() - 1;
+        if up {
+            writer.scroll_up(page);
+        } else {
+            writer.scroll_down(page);
+        }
+        writer.present();
     }
 }
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+
+fn breakpoint_handler(frame: &mut TrapFrame) {
+    if monitor::take_request() || !gdb::is_attached() {
+        monitor::run(frame);
+    } else {
+        gdb::handle_trap(frame);
+    }
+}
+
+/// Single steps and the stops asked for with `set_trap_flag` end up here.
+fn debug_handler(frame: &mut TrapFrame) {
+    frame.rflags &= !RFlags::TRAP_FLAG.bits();
+    if monitor::take_request() {
+        monitor::run(frame);
+    } else if gdb::is_attached() {
+        gdb::handle_trap(frame);
+    } else {
+        log::warn!("EXCEPTION: DEBUG\n{:#x?}", frame);
+    }
 }
 
 extern "x86-interrupt" fn double_fault_handler(
@@ -73,8 +405,33 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     }
 }
 
+#[test_case]
+fn test_print_screen() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for &(scancodes, state) in &[
+            (&[0xe0, 0x2a, 0xe0, 0x37][..], KeyState::Down),
+            (&[0xe0, 0xb7, 0xe0, 0xaa][..], KeyState::Up),
+            (&[0x54][..], KeyState::Down),
+            (&[0xd4][..], KeyState::Up),
+        ] {
+            let mut events = scancodes
+                .iter()
+                .filter_map(|&scancode| keyboard::add_scancode(scancode));
+            let key_event = events.next().expect("no PrintScreen event");
+            assert_eq!(key_event, KeyEvent::new(KeyCode::PrintScreen, state));
+            assert_eq!(events.next(), None);
+            // takes the screenshot on the way down
+            assert!(handle_console_keys(&key_event));
+            keyboard::consume_event(&key_event);
+        }
+    });
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
+    // the monitor takes its commands from the serial terminal here
+    let port = serial::role_port(serial::Role::Console).expect("no serial console");
+    serial::feed_input(port, b"regs\rc\r");
     x86_64::instructions::interrupts::int3();
 }
 
@@ -84,11 +441,31 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// The lines the PCI interrupts are routed to on the PC, which `add_irq_handler` can be used on.
+const PCI_IRQS: [u8; 4] = [5, 9, 10, 11];
+const MAX_SHARED_HANDLERS: usize = 4;
+
+type SharedHandlers = [Option<fn()>; MAX_SHARED_HANDLERS];
+
+static IRQ_HANDLERS: spin::Mutex<[SharedHandlers; 16]> =
+    spin::Mutex::new([[None; MAX_SHARED_HANDLERS]; 16]);
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// COM2 and COM4.
+    Com2 = PIC_1_OFFSET + 3,
+    /// COM1 and COM3.
+    Com1 = PIC_1_OFFSET + 4,
+    Irq5 = PIC_1_OFFSET + 5,
+    Irq9 = PIC_2_OFFSET + 1,
+    Irq10,
+    Irq11,
+    Mouse = PIC_2_OFFSET + 4,
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta = PIC_2_OFFSET + 7,
 }
 
 impl InterruptIndex {
@@ -0,0 +1,593 @@
+//! Keyboard state for programs: which keys are held, the modifiers, and a queue of key presses and
+//! releases that carry both the raw `KeyCode` and the character it decoded to. The layout and
+//! scancode set used for decoding can be changed at runtime.
+
+use crate::interrupts::LASTPRESSED;
+use crate::ps2;
+use crate::ring_buffer::RingBuffer;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, KeyboardLayout,
+    ScancodeSet1, ScancodeSet2,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Events that haven't been read yet, further ones are dropped until there is room again.
+const QUEUE_SIZE: usize = 64;
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<Decoder> = Mutex::new(Decoder::new(
+        Layout::Uk105,
+        ScancodeSet::Set1,
+        HandleControl::Ignore
+    ));
+}
+
+/// One bit per `KeyCode`, set while the key is held down.
+static PRESSED: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static MODIFIERS: AtomicU16 = AtomicU16::new(0);
+static EVENTS: Mutex<RingBuffer<KeyboardEvent, QUEUE_SIZE>> = Mutex::new(RingBuffer::new());
+
+const LEFT_SHIFT: u16 = 1 << 0;
+const RIGHT_SHIFT: u16 = 1 << 1;
+const LEFT_CTRL: u16 = 1 << 2;
+const RIGHT_CTRL: u16 = 1 << 3;
+const ALT: u16 = 1 << 4;
+const ALT_GR: u16 = 1 << 5;
+const CAPS_LOCK: u16 = 1 << 6;
+const NUM_LOCK: u16 = 1 << 7;
+const SCROLL_LOCK: u16 = 1 << 8;
+
+/// The modifier keys held down and the lock keys switched on when an event happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers(u16);
+
+impl Modifiers {
+    pub fn shift(self) -> bool {
+        self.0 & (LEFT_SHIFT | RIGHT_SHIFT) != 0
+    }
+
+    pub fn ctrl(self) -> bool {
+        self.0 & (LEFT_CTRL | RIGHT_CTRL) != 0
+    }
+
+    /// The left Alt key, the right one is `alt_gr`.
+    pub fn alt(self) -> bool {
+        self.0 & ALT != 0
+    }
+
+    pub fn alt_gr(self) -> bool {
+        self.0 & ALT_GR != 0
+    }
+
+    pub fn caps_lock(self) -> bool {
+        self.0 & CAPS_LOCK != 0
+    }
+
+    pub fn num_lock(self) -> bool {
+        self.0 & NUM_LOCK != 0
+    }
+
+    pub fn scroll_lock(self) -> bool {
+        self.0 & SCROLL_LOCK != 0
+    }
+}
+
+/// A key going down or coming back up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardEvent {
+    pub code: KeyCode,
+    /// `KeyState::Down` for presses, including the repeats of a held key, and `KeyState::Up` for
+    /// releases.
+    pub state: KeyState,
+    /// True if the key was already held, i.e. this is the typematic repeat of a press.
+    pub repeat: bool,
+    /// What the key typed, if anything. Releases never type anything.
+    pub key: Option<DecodedKey>,
+    pub modifiers: Modifiers,
+}
+
+impl KeyboardEvent {
+    pub fn is_press(&self) -> bool {
+        self.state != KeyState::Up
+    }
+}
+
+/// The keyboard layouts scancodes can be decoded with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    De105,
+    Dvorak104,
+    Azerty,
+    Jis109,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 6] = [
+        Layout::Us104,
+        Layout::Uk105,
+        Layout::De105,
+        Layout::Dvorak104,
+        Layout::Azerty,
+        Layout::Jis109,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::De105 => "de",
+            Layout::Dvorak104 => "dvorak",
+            Layout::Azerty => "azerty",
+            Layout::Jis109 => "jis",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL
+            .iter()
+            .copied()
+            .find(|layout| layout.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+impl ScancodeSet {
+    pub fn name(self) -> &'static str {
+        match self {
+            ScancodeSet::Set1 => "set1",
+            ScancodeSet::Set2 => "set2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ScancodeSet> {
+        match name {
+            "set1" | "1" => Some(ScancodeSet::Set1),
+            "set2" | "2" => Some(ScancodeSet::Set2),
+            _ => None,
+        }
+    }
+}
+
+/// The German layout, which `pc_keyboard` doesn't have. Keys that are the same as on a US keyboard
+/// are left to `Us104Key`, and so is the key next to left shift, which it doesn't decode.
+pub struct De105Key;
+
+impl KeyboardLayout for De105Key {
+    fn map_keycode(
+        keycode: KeyCode,
+        modifiers: &pc_keyboard::Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        let us = |keycode| layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl);
+        // the character typed alone, with shift, and with AltGr
+        let (plain, shifted, alt_gr) = match keycode {
+            KeyCode::Y => return us(KeyCode::Z),
+            KeyCode::Z => return us(KeyCode::Y),
+            KeyCode::Q if modifiers.alt_gr => return DecodedKey::Unicode('@'),
+            KeyCode::E if modifiers.alt_gr => return DecodedKey::Unicode('€'),
+            KeyCode::BracketSquareLeft | KeyCode::SemiColon | KeyCode::Quote => {
+                let (lower, upper) = match keycode {
+                    KeyCode::BracketSquareLeft => ('ü', 'Ü'),
+                    KeyCode::SemiColon => ('ö', 'Ö'),
+                    _ => ('ä', 'Ä'),
+                };
+                let umlaut = if modifiers.is_caps() { upper } else { lower };
+                return DecodedKey::Unicode(umlaut);
+            }
+            KeyCode::BackTick => ('^', '°', None),
+            KeyCode::Key2 => ('2', '"', Some('²')),
+            KeyCode::Key3 => ('3', '§', Some('³')),
+            KeyCode::Key6 => ('6', '&', None),
+            KeyCode::Key7 => ('7', '/', Some('{')),
+            KeyCode::Key8 => ('8', '(', Some('[')),
+            KeyCode::Key9 => ('9', ')', Some(']')),
+            KeyCode::Key0 => ('0', '=', Some('}')),
+            KeyCode::Minus => ('ß', '?', Some('\\')),
+            KeyCode::Equals => ('´', '`', None),
+            KeyCode::BracketSquareRight => ('+', '*', Some('~')),
+            KeyCode::BackSlash => ('#', '\'', None),
+            KeyCode::Comma => (',', ';', None),
+            KeyCode::Fullstop => ('.', ':', None),
+            KeyCode::Slash => ('-', '_', None),
+            _ => return us(keycode),
+        };
+        match alt_gr {
+            Some(alt_gr) if modifiers.alt_gr => DecodedKey::Unicode(alt_gr),
+            _ if modifiers.is_shifted() => DecodedKey::Unicode(shifted),
+            _ => DecodedKey::Unicode(plain),
+        }
+    }
+}
+
+/// `pc_keyboard` picks the layout at compile time, so there is a variant for every layout.
+enum LayoutKeyboard<S: pc_keyboard::ScancodeSet> {
+    Us104(Keyboard<layouts::Us104Key, S>),
+    Uk105(Keyboard<layouts::Uk105Key, S>),
+    De105(Keyboard<De105Key, S>),
+    Dvorak104(Keyboard<layouts::Dvorak104Key, S>),
+    Azerty(Keyboard<layouts::Azerty, S>),
+    Jis109(Keyboard<layouts::Jis109Key, S>),
+}
+
+/// Runs `$body` with `$keyboard` bound to whichever `pc_keyboard::Keyboard` is in use.
+macro_rules! with_keyboard {
+    ($decoder:expr, $keyboard:ident => $body:expr) => {
+        match $decoder {
+            LayoutKeyboard::Us104($keyboard) => $body,
+            LayoutKeyboard::Uk105($keyboard) => $body,
+            LayoutKeyboard::De105($keyboard) => $body,
+            LayoutKeyboard::Dvorak104($keyboard) => $body,
+            LayoutKeyboard::Azerty($keyboard) => $body,
+            LayoutKeyboard::Jis109($keyboard) => $body,
+        }
+    };
+}
+
+impl<S: pc_keyboard::ScancodeSet> LayoutKeyboard<S> {
+    fn new(layout: Layout, set: S, handle_ctrl: HandleControl) -> Self {
+        match layout {
+            Layout::Us104 => {
+                LayoutKeyboard::Us104(Keyboard::new(layouts::Us104Key, set, handle_ctrl))
+            }
+            Layout::Uk105 => {
+                LayoutKeyboard::Uk105(Keyboard::new(layouts::Uk105Key, set, handle_ctrl))
+            }
+            Layout::De105 => LayoutKeyboard::De105(Keyboard::new(De105Key, set, handle_ctrl)),
+            Layout::Dvorak104 => {
+                LayoutKeyboard::Dvorak104(Keyboard::new(layouts::Dvorak104Key, set, handle_ctrl))
+            }
+            Layout::Azerty => {
+                LayoutKeyboard::Azerty(Keyboard::new(layouts::Azerty, set, handle_ctrl))
+            }
+            Layout::Jis109 => {
+                LayoutKeyboard::Jis109(Keyboard::new(layouts::Jis109Key, set, handle_ctrl))
+            }
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, pc_keyboard::Error> {
+        with_keyboard!(self, keyboard => keyboard.add_byte(byte))
+    }
+
+    fn process_keyevent(&mut self, key_event: KeyEvent) -> Option<DecodedKey> {
+        with_keyboard!(self, keyboard => keyboard.process_keyevent(key_event))
+    }
+
+    fn set_ctrl_handling(&mut self, handle_ctrl: HandleControl) {
+        with_keyboard!(self, keyboard => keyboard.set_ctrl_handling(handle_ctrl))
+    }
+}
+
+enum SetKeyboard {
+    Set1(LayoutKeyboard<ScancodeSet1>),
+    Set2(LayoutKeyboard<ScancodeSet2>),
+}
+
+/// Turns scancodes into key events and characters for the selected layout and scancode set.
+struct Decoder {
+    layout: Layout,
+    scancode_set: ScancodeSet,
+    handle_ctrl: HandleControl,
+    keyboard: SetKeyboard,
+    /// The last byte was E0, or E0 F0 in set 2.
+    extended: bool,
+    /// The last byte was F0, in set 2.
+    release: bool,
+}
+
+impl Decoder {
+    fn new(layout: Layout, scancode_set: ScancodeSet, handle_ctrl: HandleControl) -> Decoder {
+        let keyboard = match scancode_set {
+            ScancodeSet::Set1 => {
+                SetKeyboard::Set1(LayoutKeyboard::new(layout, ScancodeSet1, handle_ctrl))
+            }
+            ScancodeSet::Set2 => {
+                SetKeyboard::Set2(LayoutKeyboard::new(layout, ScancodeSet2, handle_ctrl))
+            }
+        };
+        Decoder {
+            layout,
+            scancode_set,
+            handle_ctrl,
+            keyboard,
+            extended: false,
+            release: false,
+        }
+    }
+
+    /// Fills in PrintScreen, which `pc_keyboard` doesn't know. Set 1 sends E0 2A E0 37 for it and
+    /// E0 B7 E0 AA when it is released, set 2 sends E0 12 E0 7C and E0 F0 7C E0 F0 12. While Alt is
+    /// held the key is SysRq, which is 54 and D4 in set 1 and 84 and F0 84 in set 2. The fake shifts
+    /// around it decode to nothing.
+    fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, pc_keyboard::Error> {
+        let (extended, release) = (self.extended, self.release);
+        self.extended = byte == 0xE0 || (extended && byte == 0xF0);
+        self.release = byte == 0xF0;
+        let decoded = match &mut self.keyboard {
+            SetKeyboard::Set1(keyboard) => keyboard.add_byte(byte),
+            SetKeyboard::Set2(keyboard) => keyboard.add_byte(byte),
+        };
+        let state = match (self.scancode_set, extended, release, byte) {
+            (ScancodeSet::Set1, true, _, 0x37) | (ScancodeSet::Set1, false, _, 0x54) => {
+                KeyState::Down
+            }
+            (ScancodeSet::Set1, true, _, 0xB7) | (ScancodeSet::Set1, false, _, 0xD4) => {
+                KeyState::Up
+            }
+            (ScancodeSet::Set2, true, false, 0x7C) | (ScancodeSet::Set2, false, false, 0x84) => {
+                KeyState::Down
+            }
+            (ScancodeSet::Set2, true, true, 0x7C) | (ScancodeSet::Set2, false, true, 0x84) => {
+                KeyState::Up
+            }
+            _ => return decoded,
+        };
+        Ok(Some(KeyEvent::new(KeyCode::PrintScreen, state)))
+    }
+
+    fn process_keyevent(&mut self, key_event: KeyEvent) -> Option<DecodedKey> {
+        match &mut self.keyboard {
+            SetKeyboard::Set1(keyboard) => keyboard.process_keyevent(key_event),
+            SetKeyboard::Set2(keyboard) => keyboard.process_keyevent(key_event),
+        }
+    }
+
+    fn set_ctrl_handling(&mut self, handle_ctrl: HandleControl) {
+        self.handle_ctrl = handle_ctrl;
+        match &mut self.keyboard {
+            SetKeyboard::Set1(keyboard) => keyboard.set_ctrl_handling(handle_ctrl),
+            SetKeyboard::Set2(keyboard) => keyboard.set_ctrl_handling(handle_ctrl),
+        }
+    }
+}
+
+/// The layout and scancode set keys are decoded with.
+pub fn layout() -> (Layout, ScancodeSet) {
+    interrupts::without_interrupts(|| {
+        let keyboard = KEYBOARD.lock();
+        (keyboard.layout, keyboard.scancode_set)
+    })
+}
+
+/// Decodes keys with `layout` from now on.
+pub fn set_layout(layout: Layout) {
+    interrupts::without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        *keyboard = Decoder::new(layout, keyboard.scancode_set, keyboard.handle_ctrl);
+    });
+}
+
+/// Switches the keyboard over to scancode `set`. The keyboard itself always sends set 2, set 1 is
+/// what the controller makes of it when translation is on, so this turns translation on or off.
+pub fn set_scancode_set(set: ScancodeSet) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        let config = ps2::read_config()?;
+        let config = match set {
+            ScancodeSet::Set1 => config | ps2::CONFIG_TRANSLATE,
+            ScancodeSet::Set2 => config & !ps2::CONFIG_TRANSLATE,
+        };
+        ps2::write_config(config)?;
+        *keyboard = Decoder::new(keyboard.layout, set, keyboard.handle_ctrl);
+        Ok(())
+    })
+}
+
+/// Whether `code` is held down right now.
+pub fn is_pressed(code: KeyCode) -> bool {
+    let index = code as usize;
+    PRESSED[index / 64].load(Ordering::Relaxed) & 1 << (index % 64) != 0
+}
+
+pub fn modifiers() -> Modifiers {
+    Modifiers(MODIFIERS.load(Ordering::Relaxed))
+}
+
+/// Takes the oldest key event that hasn't been read yet.
+pub fn next_event() -> Option<KeyboardEvent> {
+    interrupts::without_interrupts(|| EVENTS.lock().pop())
+}
+
+/// Takes the oldest key press, skipping releases. Keys that don't type anything, like the arrows,
+/// come as `DecodedKey::RawKey`.
+pub fn read_key() -> Option<DecodedKey> {
+    while let Some(event) = next_event() {
+        if event.key.is_some() {
+            return event.key;
+        }
+    }
+    None
+}
+
+/// Takes the oldest typed character, skipping releases and keys that don't type anything.
+pub fn read_char() -> Option<char> {
+    while let Some(key) = read_key() {
+        if let DecodedKey::Unicode(c) = key {
+            return Some(c);
+        }
+    }
+    None
+}
+
+/// Throws away the events that haven't been read yet, e.g. the keys typed before a game started.
+pub fn clear_events() {
+    interrupts::without_interrupts(|| EVENTS.lock().clear());
+}
+
+/// Chooses whether Ctrl+letter types the matching control character (`MapLettersToUnicode`) or
+/// the plain letter (`Ignore`, the default).
+pub fn set_ctrl_handling(handle_ctrl: HandleControl) {
+    interrupts::without_interrupts(|| KEYBOARD.lock().set_ctrl_handling(handle_ctrl));
+}
+
+/// Feeds a byte from the keyboard into the scancode decoder. Returns the key event once a whole
+/// scancode has arrived, after updating the pressed keys and the modifiers.
+pub(crate) fn add_scancode(scancode: u8) -> Option<KeyEvent> {
+    // only called from the keyboard interrupt, or with interrupts off
+    let key_event = KEYBOARD.try_lock()?.add_byte(scancode).ok()??;
+    let pressed = key_event.state == KeyState::Down;
+    let bit = match key_event.code {
+        KeyCode::ShiftLeft => LEFT_SHIFT,
+        KeyCode::ShiftRight => RIGHT_SHIFT,
+        KeyCode::ControlLeft => LEFT_CTRL,
+        KeyCode::ControlRight => RIGHT_CTRL,
+        KeyCode::AltLeft => ALT,
+        KeyCode::AltRight => ALT_GR,
+        KeyCode::CapsLock => CAPS_LOCK,
+        KeyCode::NumpadLock => NUM_LOCK,
+        KeyCode::ScrollLock => SCROLL_LOCK,
+        _ => 0,
+    };
+    if bit & (CAPS_LOCK | NUM_LOCK | SCROLL_LOCK) != 0 {
+        if pressed && !is_pressed(key_event.code) {
+            MODIFIERS.fetch_xor(bit, Ordering::Relaxed);
+            let modifiers = modifiers();
+            // the lights are only cosmetic, so a keyboard without them is fine
+            let _ = ps2::set_leds(
+                modifiers.scroll_lock(),
+                modifiers.num_lock(),
+                modifiers.caps_lock(),
+            );
+        }
+    } else if pressed {
+        MODIFIERS.fetch_or(bit, Ordering::Relaxed);
+    } else if key_event.state == KeyState::Up {
+        MODIFIERS.fetch_and(!bit, Ordering::Relaxed);
+    }
+    Some(key_event)
+}
+
+/// Decodes `key_event` into a character and queues it for programs to read.
+pub(crate) fn process_event(key_event: KeyEvent) {
+    let code = key_event.code;
+    let state = key_event.state;
+    let repeat = state == KeyState::Down && is_pressed(code);
+    let key = match KEYBOARD.try_lock() {
+        Some(mut keyboard) => keyboard.process_keyevent(key_event),
+        None => None,
+    };
+    set_pressed(code, state == KeyState::Down);
+
+    if let Some(key) = key {
+        *LASTPRESSED.lock() = key;
+    }
+    if let Some(mut events) = EVENTS.try_lock() {
+        events.push(KeyboardEvent {
+            code,
+            state,
+            repeat,
+            key,
+            modifiers: modifiers(),
+        });
+    }
+}
+
+/// Records the key state of an event that the console took for itself, so that it doesn't look
+/// held down forever to programs.
+pub(crate) fn consume_event(key_event: &KeyEvent) {
+    set_pressed(key_event.code, key_event.state == KeyState::Down);
+}
+
+fn set_pressed(code: KeyCode, pressed: bool) {
+    let index = code as usize;
+    let bit = 1 << (index % 64);
+    if pressed {
+        PRESSED[index / 64].fetch_or(bit, Ordering::Relaxed);
+    } else {
+        PRESSED[index / 64].fetch_and(!bit, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+fn feed(scancodes: &[u8]) {
+    for &scancode in scancodes {
+        if let Some(key_event) = add_scancode(scancode) {
+            process_event(key_event);
+        }
+    }
+}
+
+#[test_case]
+fn test_key_events() {
+    interrupts::without_interrupts(|| {
+        clear_events();
+        // left shift, then A pressed twice (a repeat) and released, then shift released
+        feed(&[0x2a, 0x1e, 0x1e]);
+        assert!(modifiers().shift());
+        assert!(is_pressed(KeyCode::A));
+        feed(&[0x9e, 0xaa]);
+        assert!(!modifiers().shift());
+        assert!(!is_pressed(KeyCode::A));
+
+        let shift = next_event().expect("no shift event");
+        assert_eq!(shift.code, KeyCode::ShiftLeft);
+        let press = next_event().expect("no press event");
+        assert_eq!(press.key, Some(DecodedKey::Unicode('A')));
+        assert!(press.modifiers.shift() && !press.repeat);
+        assert!(next_event().expect("no repeat event").repeat);
+        let release = next_event().expect("no release event");
+        assert_eq!((release.code, release.state), (KeyCode::A, KeyState::Up));
+        assert_eq!(release.key, None);
+        assert_eq!(
+            next_event().map(|event| event.code),
+            Some(KeyCode::ShiftLeft)
+        );
+        assert_eq!(next_event(), None);
+    });
+}
+
+#[test_case]
+fn test_set_layout() {
+    interrupts::without_interrupts(|| {
+        clear_events();
+        // the key right of T is Y on a UK keyboard and Z on a German one
+        set_layout(Layout::De105);
+        feed(&[0x15, 0x95]);
+        set_layout(Layout::Uk105);
+        feed(&[0x15, 0x95]);
+        assert_eq!(layout(), (Layout::Uk105, ScancodeSet::Set1));
+
+        let keys = [next_event(), next_event(), next_event(), next_event()];
+        assert_eq!(
+            keys[0].and_then(|event| event.key),
+            Some(DecodedKey::Unicode('z'))
+        );
+        assert_eq!(
+            keys[2].and_then(|event| event.key),
+            Some(DecodedKey::Unicode('y'))
+        );
+        assert_eq!(next_event(), None);
+    });
+}
+
+#[test_case]
+fn test_print_screen_set2() {
+    let mut decoder = Decoder::new(Layout::Us104, ScancodeSet::Set2, HandleControl::Ignore);
+    let mut events = [
+        0xe0, 0x12, 0xe0, 0x7c, 0xe0, 0xf0, 0x7c, 0xe0, 0xf0, 0x12, 0x84, 0xf0, 0x84,
+    ]
+    .iter()
+    .filter_map(|&byte| decoder.add_byte(byte).ok().flatten());
+    for &state in &[KeyState::Down, KeyState::Up, KeyState::Down, KeyState::Up] {
+        assert_eq!(
+            events.next(),
+            Some(KeyEvent::new(KeyCode::PrintScreen, state))
+        );
+    }
+    assert_eq!(events.next(), None);
+}
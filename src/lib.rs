@@ -5,19 +5,59 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
 
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
+pub mod acpi;
+pub mod ahci;
+pub mod ata;
+pub mod block;
+pub mod block_cache;
+pub mod cp437;
+pub mod devfs;
+pub mod fat;
+pub mod framebuffer;
+pub mod gdb;
 pub mod gdt;
+pub mod graphics;
+pub mod initrd;
 pub mod interrupts;
+pub mod keyboard;
+pub mod logger;
+pub mod memory;
+pub mod monitor;
+pub mod mouse;
+pub mod partition;
+pub mod pci;
 pub mod program;
+pub mod ps2;
+pub mod ramfs;
+pub mod ring_buffer;
 pub mod serial;
+pub mod shell;
 pub mod snake;
+pub mod tmpfs;
+pub mod vfs;
+pub mod vga;
 pub mod vga_buffer;
+pub mod virtio;
+pub mod virtio_blk;
 
 pub fn init() {
+    logger::init();
     interrupts::init_idt();
     gdt::init();
     unsafe { interrupts::PICS.lock().initialize() };
+    match ps2::init() {
+        Ok(()) => {
+            if let Err(err) = mouse::init() {
+                log::warn!("no mouse: {}", err);
+            }
+        }
+        Err(err) => log::warn!("no PS/2 keyboard: {}", err),
+    }
+    serial::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -57,11 +97,14 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
 /// Entry point for `cargo test`
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    memory::init(boot_info);
     test_main();
     hlt_loop();
 }
@@ -0,0 +1,370 @@
+//! The kernel's backend for the `log` crate, so modules log with `log::info!` and friends.
+//!
+//! Each record is stamped with the timer ticks since boot and the module it came from, then goes
+//! to the sinks: the serial port carrying the log, the kernel console and the `dmesg` buffer that
+//! keeps the latest records in memory. A record is kept if its level passes the filter of its
+//! module, the longest `set_module_level` prefix of its target or else the default level, and
+//! each sink can be limited to a level of its own.
+
+use crate::interrupts::STOPWATCH;
+use crate::ring_buffer::RingBuffer;
+use crate::{serial, vga_buffer};
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Records kept for `dmesg`, older ones are dropped to make room.
+const DMESG_SIZE: usize = 128;
+/// Longest target and message kept in the `dmesg` buffer, in bytes.
+const TARGET_LENGTH: usize = 32;
+const MESSAGE_LENGTH: usize = 120;
+const MAX_FILTERS: usize = 16;
+
+/// Targets are module paths, which are shown and filtered without the crate name in front.
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+static LOGGER: KernelLogger = KernelLogger;
+static DEFAULT_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static FILTERS: Mutex<[Option<Filter>; MAX_FILTERS]> = Mutex::new([None; MAX_FILTERS]);
+static SINK_LEVELS: [AtomicUsize; 3] = [
+    AtomicUsize::new(LevelFilter::Trace as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Trace as usize),
+];
+static DMESG: Mutex<RingBuffer<Entry, DMESG_SIZE>> = Mutex::new(RingBuffer::new());
+/// The last timestamp read, for when the timer interrupted the code holding `STOPWATCH`.
+static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    /// The serial port with `serial::Role::Log`.
+    Serial,
+    /// The kernel messages console, console 0.
+    Vga,
+    /// The in-memory buffer read by `dmesg`.
+    Dmesg,
+}
+
+impl Sink {
+    pub const ALL: [Sink; 3] = [Sink::Serial, Sink::Vga, Sink::Dmesg];
+
+    pub fn name(self) -> &'static str {
+        ["serial", "vga", "dmesg"][self as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<Sink> {
+        Sink::ALL.iter().copied().find(|sink| sink.name() == name)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Filter {
+    module: Text<TARGET_LENGTH>,
+    level: LevelFilter,
+}
+
+impl Filter {
+    /// Whether `module` is the filter's module or inside it.
+    fn matches(&self, module: &str) -> bool {
+        let prefix = self.module.as_str();
+        module
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    }
+}
+
+/// A string of at most `N` bytes, longer ones are cut at a character boundary.
+#[derive(Clone, Copy)]
+struct Text<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Text<N> {
+    const fn new() -> Self {
+        Text {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // only whole characters are ever stored
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> fmt::Write for Text<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.len + c.len_utf8() > N {
+                break;
+            }
+            c.encode_utf8(&mut self.bytes[self.len..]);
+            self.len += c.len_utf8();
+        }
+        Ok(())
+    }
+}
+
+/// A record as kept in the `dmesg` buffer.
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub ticks: u64,
+    pub level: Level,
+    target: Text<TARGET_LENGTH>,
+    message: Text<MESSAGE_LENGTH>,
+}
+
+impl Entry {
+    pub fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line = Line {
+            ticks: self.ticks,
+            level: self.level,
+            target: self.target(),
+            message: &self.message(),
+        };
+        line.fmt(f)
+    }
+}
+
+/// How every sink shows a record: `[ticks] LEVEL module: message`.
+struct Line<'a> {
+    ticks: u64,
+    level: Level,
+    target: &'a str,
+    message: &'a dyn fmt::Display,
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>8}] {:<5} {}: {}",
+            self.ticks, self.level, self.target, self.message
+        )
+    }
+}
+
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= module_level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = Line {
+            ticks: ticks(),
+            level: record.level(),
+            target: short_target(record.target()),
+            message: record.args(),
+        };
+        if line.level <= sink_level(Sink::Serial) {
+            serial::_print(format_args!("{}\n", line));
+        }
+        if line.level <= sink_level(Sink::Vga) {
+            vga_buffer::try_print_console(0, format_args!("{}\n", line));
+        }
+        if line.level <= sink_level(Sink::Dmesg) {
+            let mut entry = Entry {
+                ticks: line.ticks,
+                level: line.level,
+                target: Text::new(),
+                message: Text::new(),
+            };
+            let _ = entry.target.write_str(line.target);
+            let _ = write!(entry.message, "{}", line.message);
+            interrupts::without_interrupts(|| DMESG.lock().push_overwrite(entry));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Makes this the logger behind the `log` macros. Records logged before are lost.
+pub fn init() {
+    // fails only if it was already set, by an earlier call
+    let _ = log::set_logger(&LOGGER);
+    update_max_level();
+}
+
+/// The level of modules without a filter of their own.
+pub fn level() -> LevelFilter {
+    level_from_usize(DEFAULT_LEVEL.load(Ordering::SeqCst))
+}
+
+pub fn set_level(level: LevelFilter) {
+    DEFAULT_LEVEL.store(level as usize, Ordering::SeqCst);
+    update_max_level();
+}
+
+/// The level that applies to records from `target`.
+pub fn module_level(target: &str) -> LevelFilter {
+    let module = short_target(target);
+    interrupts::without_interrupts(|| {
+        FILTERS
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|filter| filter.matches(module))
+            .max_by_key(|filter| filter.module.len)
+            .map(|filter| filter.level)
+    })
+    .unwrap_or_else(level)
+}
+
+/// Sets the level of `module` and the modules inside it, which is given without the crate name,
+/// like `ps2` or `interrupts`. `None` removes the filter so the default level applies again.
+pub fn set_module_level(module: &str, level: Option<LevelFilter>) -> Result<(), &'static str> {
+    let module = short_target(module);
+    if module.is_empty() {
+        return Err("which module?");
+    }
+    if module.len() > TARGET_LENGTH {
+        return Err("module name too long");
+    }
+    interrupts::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        let existing = filters
+            .iter()
+            .position(|filter| filter.is_some_and(|filter| filter.module.as_str() == module));
+        match (existing, level) {
+            (Some(index), Some(level)) => {
+                if let Some(filter) = &mut filters[index] {
+                    filter.level = level;
+                }
+            }
+            (Some(index), None) => filters[index] = None,
+            (None, Some(level)) => {
+                let slot = filters
+                    .iter_mut()
+                    .find(|filter| filter.is_none())
+                    .ok_or("too many module filters")?;
+                let mut filter = Filter {
+                    module: Text::new(),
+                    level,
+                };
+                let _ = filter.module.write_str(module);
+                *slot = Some(filter);
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    })?;
+    update_max_level();
+    Ok(())
+}
+
+/// Calls `f` with each module that has a filter and its level.
+pub fn module_levels(mut f: impl FnMut(&str, LevelFilter)) {
+    let filters = interrupts::without_interrupts(|| *FILTERS.lock());
+    for filter in filters.iter().flatten() {
+        f(filter.module.as_str(), filter.level);
+    }
+}
+
+pub fn sink_level(sink: Sink) -> LevelFilter {
+    level_from_usize(SINK_LEVELS[sink as usize].load(Ordering::SeqCst))
+}
+
+/// Limits what `sink` gets, on top of the module filters.
+pub fn set_sink_level(sink: Sink, level: LevelFilter) {
+    SINK_LEVELS[sink as usize].store(level as usize, Ordering::SeqCst);
+}
+
+/// Calls `f` with the records in the `dmesg` buffer, oldest first. The buffer is locked meanwhile,
+/// so `f` must not log.
+pub fn dmesg(mut f: impl FnMut(&Entry)) {
+    interrupts::without_interrupts(|| {
+        for entry in DMESG.lock().iter() {
+            f(&entry);
+        }
+    });
+}
+
+pub fn clear_dmesg() {
+    interrupts::without_interrupts(|| DMESG.lock().clear());
+}
+
+/// Parses a level name like `debug` or `off`, in any case.
+pub fn parse_level(name: &str) -> Result<LevelFilter, &'static str> {
+    LevelFilter::from_str(name)
+        .map_err(|_| "unknown level, try off, error, warn, info, debug or trace")
+}
+
+/// Lets the `log` macros skip records that no filter would keep without calling the logger.
+fn update_max_level() {
+    let mut max = level();
+    module_levels(|_, level| max = max.max(level));
+    log::set_max_level(max);
+}
+
+fn level_from_usize(level: usize) -> LevelFilter {
+    LevelFilter::iter().nth(level).unwrap_or(LevelFilter::Trace)
+}
+
+fn short_target(target: &str) -> &str {
+    target.strip_prefix(CRATE_PREFIX).unwrap_or(target)
+}
+
+fn ticks() -> u64 {
+    // the timer interrupt may have come in while the stopwatch was being read
+    match STOPWATCH.try_lock() {
+        Some(ticks) => {
+            LAST_TICKS.store(*ticks as u64, Ordering::Relaxed);
+            *ticks as u64
+        }
+        None => LAST_TICKS.load(Ordering::Relaxed),
+    }
+}
+
+#[test_case]
+fn test_module_filters() {
+    let default = level();
+    set_level(LevelFilter::Info);
+    set_module_level("ps2", Some(LevelFilter::Debug)).unwrap();
+    assert_eq!(module_level("joel_os::ps2"), LevelFilter::Debug);
+    assert_eq!(module_level("joel_os::ps2::mouse"), LevelFilter::Debug);
+    assert_eq!(module_level("joel_os::ps2x"), LevelFilter::Info);
+    set_module_level("ps2", None).unwrap();
+    assert_eq!(module_level("joel_os::ps2"), LevelFilter::Info);
+
+    set_sink_level(Sink::Serial, LevelFilter::Off);
+    set_module_level("logger", Some(LevelFilter::Trace)).unwrap();
+    log::trace!("test_module_filters {}", 'é');
+    log::trace!(target: "joel_os::ps2", "filtered out");
+    let mut last = None;
+    dmesg(|entry| last = Some(*entry));
+    let last = last.expect("nothing in dmesg");
+    assert_eq!(last.level, Level::Trace);
+    assert_eq!(last.target(), "logger");
+    assert_eq!(last.message(), "test_module_filters é");
+    set_module_level("logger", None).unwrap();
+    set_sink_level(Sink::Serial, LevelFilter::Trace);
+    set_level(default);
+}
+
+#[test_case]
+fn test_truncated_text() {
+    let mut text: Text<4> = Text::new();
+    write!(text, "abé").unwrap();
+    write!(text, "c").unwrap();
+    assert_eq!(text.as_str(), "abé");
+}
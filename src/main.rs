@@ -4,20 +4,37 @@
 #![test_runner(joel_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use joel_os::println;
 use joel_os::program::program_handler;
-use joel_os::snake::SnakeGame;
+use joel_os::shell::Shell;
+use joel_os::vga_buffer;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("hello");
     joel_os::init();
+    joel_os::memory::init(boot_info);
+    joel_os::pci::init();
+    joel_os::ata::init();
+    joel_os::ahci::init();
+    joel_os::virtio_blk::init();
+    joel_os::partition::scan_all();
+    joel_os::vfs::init();
+    joel_os::initrd::init();
+    if let Err(err) = joel_os::framebuffer::init(1024, 768) {
+        log::warn!("staying in VGA text mode: {}", err);
+    }
 
     #[cfg(test)]
     test_main();
 
-    program_handler(&mut SnakeGame).unwrap();
+    // the shell gets its own console, Alt+F1 goes back to the kernel messages
+    vga_buffer::set_output_console(1);
+    vga_buffer::switch_console(1);
+    program_handler(&mut Shell).unwrap();
     joel_os::hlt_loop();
 }
 
@@ -25,6 +42,8 @@ pub extern "C" fn _start() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    vga_buffer::output().lock().set_auto_present(true);
+    vga_buffer::switch_console(vga_buffer::output_console());
     println!("{:?}", info);
     joel_os::hlt_loop();
 }
@@ -0,0 +1,205 @@
+//! Access to physical memory through the bootloader's complete physical memory mapping, plus a
+//! frame allocator for new page mappings.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Virtual address that physical address 0 is mapped to, 0 until `init` has run.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+pub fn init(boot_info: &'static BootInfo) {
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET.store(offset.as_u64(), Ordering::SeqCst);
+    unsafe {
+        let level_4_table = active_level_4_table(offset);
+        *MAPPER.lock() = Some(OffsetPageTable::new(level_4_table, offset));
+        *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator::init(&boot_info.memory_map));
+    }
+}
+
+pub fn is_initialised() -> bool {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) != 0
+}
+
+pub fn physical_memory_offset() -> VirtAddr {
+    assert!(is_initialised(), "memory::init hasn't been called");
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst))
+}
+
+/// Returns where physical address `addr` can be accessed in the physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Translates `addr` through the active page tables.
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    MAPPER.lock().as_ref()?.translate_addr(addr)
+}
+
+/// Calls `f` with the level (4 to 1) and entry for `addr` in each of the active page tables, from
+/// the level 4 table down to the entry that maps it or isn't present. Returns the physical address
+/// that `addr` translates to.
+///
+/// The tables are read directly instead of through the mapper, so this works while the mapper is
+/// locked, e.g. from a debugger that stopped the kernel anywhere.
+pub fn walk_page_tables(
+    addr: VirtAddr,
+    mut f: impl FnMut(usize, &PageTableEntry),
+) -> Option<PhysAddr> {
+    if !is_initialised() {
+        return None;
+    }
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table_addr = Cr3::read().0.start_address();
+    for (i, &index) in indices.iter().enumerate() {
+        let table: &PageTable = unsafe { &*phys_to_virt(table_addr).as_ptr() };
+        let entry = &table[index];
+        f(4 - i, entry);
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        // a level 4 entry maps 512GiB, each level below an entry maps 512 times less
+        let entry_size = 1u64 << (39 - 9 * i);
+        if i == indices.len() - 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Some(entry.addr() + (addr.as_u64() & (entry_size - 1)));
+        }
+        table_addr = entry.addr();
+    }
+    None
+}
+
+/// Translates `addr` through the active page tables like `virt_to_phys`, but without the mapper,
+/// see `walk_page_tables`.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    walk_page_tables(addr, |_, _| {})
+}
+
+/// Where `addr` can also be accessed through the physical memory mapping, which is writable even
+/// where `addr` itself is read-only, like the kernel's code. `None` if `addr` isn't mapped or is
+/// device memory that the physical memory mapping doesn't cover.
+pub fn physical_alias(addr: VirtAddr) -> Option<VirtAddr> {
+    let alias = phys_to_virt(translate(addr)?);
+    translate(alias)?;
+    Some(alias)
+}
+
+/// Makes `size` bytes of device memory at `addr` accessible and returns where. The bootloader only
+/// maps physical memory up to the end of the memory map, so holes above it (like PCI BARs) are
+/// mapped here on demand, uncached.
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, &'static str> {
+    let virt = phys_to_virt(addr);
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or("memory::init hasn't been called")?;
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator
+        .as_mut()
+        .ok_or("memory::init hasn't been called")?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let first: Page<Size4KiB> = Page::containing_address(virt);
+    let last: Page<Size4KiB> = Page::containing_address(virt + size.max(1) - 1u64);
+    for page in Page::range_inclusive(first, last) {
+        if mapper.translate_addr(page.start_address()).is_some() {
+            continue;
+        }
+        let frame = PhysFrame::containing_address(PhysAddr::new(
+            page.start_address() - physical_memory_offset(),
+        ));
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, allocator)
+                .map_err(|_| "failed to map device memory")?
+                .flush();
+        }
+    }
+    Ok(virt)
+}
+
+/// Takes an unused 4KiB frame of RAM, e.g. for DMA buffers. Frames are never given back.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+}
+
+/// Takes `count` unused frames that follow each other in physical memory, for DMA buffers bigger
+/// than a frame, and returns the first. Frames skipped to find them are lost.
+pub fn allocate_frames(count: usize) -> Option<PhysFrame> {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut()?;
+    let mut first = allocator.allocate_frame()?;
+    let mut len = 1;
+    while len < count {
+        let frame = allocator.allocate_frame()?;
+        if frame == first + len as u64 {
+            len += 1;
+        } else {
+            first = frame;
+            len = 1;
+        }
+    }
+    Some(first)
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// This function is unsafe because the caller must guarantee that the complete physical memory is
+/// mapped to virtual memory at the passed `physical_memory_offset`, and it must only be called
+/// once to avoid aliasing `&mut` references.
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+/// A frame allocator that hands out the usable frames of the bootloader's memory map in order.
+struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// This function is unsafe because the caller must guarantee that the passed memory map is
+    /// valid, in particular that all frames marked as `USABLE` are really unused.
+    unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator {
+            memory_map,
+            next: 0,
+        }
+    }
+
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.start_addr()..r.range.end_addr())
+            .flat_map(|r| r.step_by(4096))
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
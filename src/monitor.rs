@@ -0,0 +1,469 @@
+//! A built-in monitor that takes over the stopped kernel: on `int3` while gdb isn't attached,
+//! with the `monitor` shell command, or with Alt+SysRq (Alt+PrintScreen) at any time.
+//!
+//! It runs in the breakpoint or debug exception handler with interrupts off, so it polls the
+//! keyboard and the serial console for its commands. It has a console of its own, and shows the
+//! registers of the stopped code, memory, page table walks, the running programs and the IDT and
+//! GDT, until it is told to continue or step.
+
+use crate::interrupts::{self, TrapFrame};
+use crate::program;
+use crate::serial;
+use crate::vga_buffer::{self, CONSOLES, CONSOLE_COUNT};
+use crate::{keyboard, memory};
+use core::fmt::{self, Write};
+use core::str::SplitWhitespace;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::tables::{sgdt, sidt};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// Like `print!`, but to the monitor console and the serial terminal.
+macro_rules! out {
+    ($($arg:tt)*) => ($crate::monitor::print(format_args!($($arg)*)));
+}
+
+/// Like `println!`, but to the monitor console and the serial terminal.
+macro_rules! outln {
+    () => (out!("\n"));
+    ($($arg:tt)*) => (out!("{}\n", format_args!($($arg)*)));
+}
+
+const MONITOR_CONSOLE: usize = CONSOLE_COUNT - 1;
+const LINE_LENGTH: usize = 80;
+/// Bytes shown by `mem` when no length is given, and the most it shows at once.
+const DEFAULT_DUMP: u64 = 64;
+const MAX_DUMP: u64 = 1024;
+
+/// Set by the hotkey and `enter`, so that the next stop goes to the monitor even if gdb is
+/// attached.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Set by `step`, so the debug exception after the instruction comes back here.
+static STEPPING: AtomicBool = AtomicBool::new(false);
+
+enum Flow {
+    Stay,
+    Resume,
+}
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&mut TrapFrame, SplitWhitespace) -> Result<Flow, &'static str>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "lists the commands",
+        run: help,
+    },
+    Command {
+        name: "regs",
+        help: "shows the registers of the stopped code",
+        run: regs,
+    },
+    Command {
+        name: "mem",
+        help: "<address> [length], dumps memory, addresses can be registers like rsp",
+        run: mem,
+    },
+    Command {
+        name: "pt",
+        help: "<address>, walks the page tables for an address",
+        run: page_tables,
+    },
+    Command {
+        name: "tasks",
+        help: "lists the running programs",
+        run: tasks,
+    },
+    Command {
+        name: "idt",
+        help: "[vector], shows the IDT's present entries or one entry",
+        run: idt,
+    },
+    Command {
+        name: "gdt",
+        help: "shows the GDT entries",
+        run: gdt,
+    },
+    Command {
+        name: "step",
+        help: "runs one instruction and comes back",
+        run: step,
+    },
+    Command {
+        name: "c",
+        help: "continues the kernel",
+        run: continue_kernel,
+    },
+];
+
+/// Stops the kernel here and opens the monitor.
+pub fn enter() {
+    REQUESTED.store(true, Ordering::SeqCst);
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Asks for the monitor to be opened once the current interrupt handler returns, for the hotkey.
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes the code interrupted with `stack_frame` stop for the monitor after its next instruction,
+/// if it was asked for.
+pub(crate) fn stop_if_requested(stack_frame: &mut InterruptStackFrame) {
+    if REQUESTED.load(Ordering::SeqCst) {
+        interrupts::set_trap_flag(stack_frame);
+    }
+}
+
+/// Whether this stop is for the monitor rather than the debugger.
+pub(crate) fn take_request() -> bool {
+    let stepping = STEPPING.swap(false, Ordering::SeqCst);
+    REQUESTED.swap(false, Ordering::SeqCst) || stepping
+}
+
+/// Runs the monitor until it is told to continue, with the registers of the code that stopped.
+pub(crate) fn run(frame: &mut TrapFrame) {
+    let previous = vga_buffer::active_console();
+    vga_buffer::switch_console(MONITOR_CONSOLE);
+    if frame.vector == 3 {
+        outln!("breakpoint at {:#x}, try help", frame.rip);
+    } else {
+        outln!("stopped at {:#x}, try help", frame.rip);
+    }
+
+    let mut line = [0; LINE_LENGTH];
+    loop {
+        out!("monitor> ");
+        let len = read_line(&mut line);
+        let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+        match execute(frame, line) {
+            Ok(Flow::Stay) => {}
+            Ok(Flow::Resume) => break,
+            Err(err) => outln!("error: {}", err),
+        }
+    }
+    // the hotkey may have been pressed again meanwhile
+    REQUESTED.store(false, Ordering::SeqCst);
+    if !STEPPING.load(Ordering::SeqCst) {
+        vga_buffer::switch_console(previous);
+    }
+}
+
+fn execute(frame: &mut TrapFrame, line: &str) -> Result<Flow, &'static str> {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return Ok(Flow::Stay),
+    };
+    let command = COMMANDS
+        .iter()
+        .find(|command| command.name == name)
+        .ok_or("unknown command, try help")?;
+    (command.run)(frame, words)
+}
+
+/// Prints to the monitor console and to the serial terminal. The monitor console is only ever
+/// locked by the monitor, but it gives up rather than deadlock all the same.
+fn print(args: fmt::Arguments) {
+    if let Some(mut writer) = CONSOLES[MONITOR_CONSOLE].try_lock() {
+        let _ = writer.write_fmt(args);
+        writer.present();
+    }
+    serial::print_terminal(args);
+}
+
+/// Reads a line of ASCII into `line`, polling the keyboard and serial console since interrupts
+/// are off, and returns its length.
+fn read_line(line: &mut [u8; LINE_LENGTH]) -> usize {
+    let mut len = 0;
+    loop {
+        interrupts::poll_ps2();
+        serial::receive_pending();
+        let c = match keyboard::read_char().or_else(serial::read_char) {
+            Some(c) => c,
+            None => continue,
+        };
+        match c {
+            '\n' => {
+                outln!();
+                return len;
+            }
+            '\u{8}' if len > 0 => {
+                len -= 1;
+                out!("\u{8}");
+            }
+            c if c.is_ascii() && !c.is_control() && len < LINE_LENGTH => {
+                line[len] = c as u8;
+                len += 1;
+                out!("{}", c);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn registers(frame: &TrapFrame) -> [(&'static str, u64); 18] {
+    [
+        ("rax", frame.rax),
+        ("rbx", frame.rbx),
+        ("rcx", frame.rcx),
+        ("rdx", frame.rdx),
+        ("rsi", frame.rsi),
+        ("rdi", frame.rdi),
+        ("rbp", frame.rbp),
+        ("rsp", frame.rsp),
+        ("r8", frame.r8),
+        ("r9", frame.r9),
+        ("r10", frame.r10),
+        ("r11", frame.r11),
+        ("r12", frame.r12),
+        ("r13", frame.r13),
+        ("r14", frame.r14),
+        ("r15", frame.r15),
+        ("rip", frame.rip),
+        ("rflags", frame.rflags),
+    ]
+}
+
+/// Parses a hexadecimal number, with or without `0x`, or the name of a register for its value.
+fn parse_value(frame: &TrapFrame, word: &str) -> Result<u64, &'static str> {
+    if let Some(&(_, value)) = registers(frame).iter().find(|(name, _)| *name == word) {
+        return Ok(value);
+    }
+    let digits = word.strip_prefix("0x").unwrap_or(word);
+    u64::from_str_radix(digits, 16).map_err(|_| "not a hex number or register")
+}
+
+fn help(_frame: &mut TrapFrame, _args: SplitWhitespace) -> Result<Flow, &'static str> {
+    for command in COMMANDS {
+        outln!("{:6} {}", command.name, command.help);
+    }
+    Ok(Flow::Stay)
+}
+
+fn regs(frame: &mut TrapFrame, _args: SplitWhitespace) -> Result<Flow, &'static str> {
+    for row in registers(frame).chunks(3) {
+        for (name, value) in row {
+            out!("{:>6} {:016x} ", name, value);
+        }
+        outln!();
+    }
+    outln!(
+        "    cs {:04x}   ss {:04x}   cr2 {:#x}   cr3 {:#x}",
+        frame.cs,
+        frame.ss,
+        Cr2::read(),
+        Cr3::read().0.start_address()
+    );
+    Ok(Flow::Stay)
+}
+
+fn mem(frame: &mut TrapFrame, mut args: SplitWhitespace) -> Result<Flow, &'static str> {
+    let start = parse_value(frame, args.next().ok_or("which address?")?)?;
+    let len = match args.next() {
+        Some(len) => parse_value(frame, len)?.min(MAX_DUMP),
+        None => DEFAULT_DUMP,
+    };
+    for line in (0..len).step_by(16) {
+        let addr = start.wrapping_add(line);
+        out!("{:016x} ", addr);
+        let mut ascii = [b' '; 16];
+        for (i, ascii) in ascii.iter_mut().enumerate().take((len - line) as usize) {
+            match read_byte(addr.wrapping_add(i as u64)) {
+                Some(byte) => {
+                    out!(" {:02x}", byte);
+                    if byte.is_ascii_graphic() {
+                        *ascii = byte;
+                    } else {
+                        *ascii = b'.';
+                    }
+                }
+                None => out!(" ??"),
+            }
+        }
+        outln!("  {}", core::str::from_utf8(&ascii).unwrap_or(""));
+    }
+    Ok(Flow::Stay)
+}
+
+/// Reads a byte without faulting on addresses that aren't mapped.
+fn read_byte(addr: u64) -> Option<u8> {
+    let alias = memory::physical_alias(VirtAddr::try_new(addr).ok()?)?;
+    Some(unsafe { alias.as_ptr::<u8>().read_volatile() })
+}
+
+fn page_tables(frame: &mut TrapFrame, mut args: SplitWhitespace) -> Result<Flow, &'static str> {
+    let addr = parse_value(frame, args.next().ok_or("which address?")?)?;
+    let addr = VirtAddr::try_new(addr).map_err(|_| "not a canonical address")?;
+    let phys = memory::walk_page_tables(addr, |level, entry| {
+        let index = (addr.as_u64() >> (12 + 9 * (level - 1))) & 0x1ff;
+        outln!(
+            "P{} [{:3}] {:#x} {:?}",
+            level,
+            index,
+            entry.addr(),
+            entry.flags()
+        );
+    });
+    match phys {
+        Some(phys) => outln!("{:#x} -> {:#x}", addr, phys),
+        None => outln!("{:#x} isn't mapped", addr),
+    }
+    Ok(Flow::Stay)
+}
+
+fn tasks(frame: &mut TrapFrame, _args: SplitWhitespace) -> Result<Flow, &'static str> {
+    // there is no scheduler, programs run inside the program that started them
+    outln!("  0 kernel");
+    let mut count = 0;
+    let listed = program::running_programs(|name| {
+        count += 1;
+        outln!("{:3} {}", count, name);
+    });
+    if !listed {
+        outln!("    (the program list is being changed)");
+    }
+    outln!(
+        "the last one was stopped at rip {:#x} rsp {:#x}",
+        frame.rip,
+        frame.rsp
+    );
+    Ok(Flow::Stay)
+}
+
+fn idt(frame: &mut TrapFrame, mut args: SplitWhitespace) -> Result<Flow, &'static str> {
+    let only = args
+        .next()
+        .map(|vector| parse_value(frame, vector))
+        .transpose()?;
+    let table = sidt();
+    let count = (u64::from(table.limit) + 1) / 16;
+    for vector in 0..count {
+        if only.is_some_and(|only| only != vector) {
+            continue;
+        }
+        let entry: [u8; 16] = unsafe { *(table.base + vector * 16).as_ptr() };
+        let present = entry[5] & 0x80 != 0;
+        if !present && only.is_none() {
+            continue;
+        }
+        let word = |i: usize| u64::from(u16::from_le_bytes([entry[i], entry[i + 1]]));
+        let high = u64::from(u32::from_le_bytes([
+            entry[8], entry[9], entry[10], entry[11],
+        ]));
+        let handler = word(0) | word(6) << 16 | high << 32;
+        let kind = match entry[5] & 0xf {
+            0xe => "interrupt gate",
+            0xf => "trap gate",
+            _ => "?",
+        };
+        outln!(
+            "{:3} {:#018x} selector {:#x} ist {} dpl {} {}{}",
+            vector,
+            handler,
+            word(2),
+            entry[4] & 0x7,
+            entry[5] >> 5 & 0x3,
+            kind,
+            if present { "" } else { ", not present" }
+        );
+    }
+    Ok(Flow::Stay)
+}
+
+fn gdt(_frame: &mut TrapFrame, _args: SplitWhitespace) -> Result<Flow, &'static str> {
+    let table = sgdt();
+    let count = (u64::from(table.limit) + 1) / 8;
+    let read = |index: u64| unsafe { *(table.base + index * 8).as_ptr::<u64>() };
+    let mut index = 0;
+    while index < count {
+        let descriptor = read(index);
+        out!("{:2} {:016x} ", index, descriptor);
+        let present = descriptor & 1 << 47 != 0;
+        let system = descriptor & 1 << 44 == 0;
+        let dpl = descriptor >> 45 & 0x3;
+        let kind = descriptor >> 40 & 0xf;
+        if descriptor == 0 {
+            outln!("null");
+        } else if system && (kind == 0x9 || kind == 0xb) {
+            // a TSS descriptor takes two entries, for its 64 bit base
+            let base = (descriptor >> 16 & 0xff_ffff)
+                | (descriptor >> 56 & 0xff) << 24
+                | (read(index + 1) & 0xffff_ffff) << 32;
+            let limit = (descriptor & 0xffff) | (descriptor >> 48 & 0xf) << 16;
+            outln!(
+                "tss base {:#x} limit {:#x} present {}",
+                base,
+                limit,
+                present
+            );
+            index += 1;
+        } else if descriptor & 1 << 43 != 0 {
+            let long = descriptor & 1 << 53 != 0;
+            outln!("code dpl {} long {} present {}", dpl, long, present);
+        } else {
+            outln!("data dpl {} present {}", dpl, present);
+        }
+        index += 1;
+    }
+    Ok(Flow::Stay)
+}
+
+fn step(frame: &mut TrapFrame, _args: SplitWhitespace) -> Result<Flow, &'static str> {
+    frame.rflags |= RFlags::TRAP_FLAG.bits();
+    STEPPING.store(true, Ordering::SeqCst);
+    Ok(Flow::Resume)
+}
+
+fn continue_kernel(frame: &mut TrapFrame, _args: SplitWhitespace) -> Result<Flow, &'static str> {
+    frame.rflags &= !RFlags::TRAP_FLAG.bits();
+    Ok(Flow::Resume)
+}
+
+#[test_case]
+fn test_parse_value() {
+    let frame = TrapFrame {
+        rsp: 0x1234,
+        ..TrapFrame::default()
+    };
+    assert_eq!(parse_value(&frame, "rsp"), Ok(0x1234));
+    assert_eq!(parse_value(&frame, "0xb8000"), Ok(0xb8000));
+    assert_eq!(parse_value(&frame, "ff"), Ok(0xff));
+    assert!(parse_value(&frame, "rzp").is_err());
+}
+
+#[test_case]
+fn test_monitor() {
+    // the monitor takes its commands from the serial terminal here
+    let port = serial::role_port(serial::Role::Console).expect("no serial console");
+    serial::feed_input(port, b"regs\rmem rsp 16\rnonsense\rc\r");
+    enter();
+    assert_eq!(serial::read_char(), None);
+    assert!(!take_request());
+}
+
+#[test_case]
+fn test_sysrq() {
+    use x86_64::registers::rflags;
+
+    let port = serial::role_port(serial::Role::Console).expect("no serial console");
+    serial::feed_input(port, b"c\r");
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // Alt+SysRq, pressed and released
+        for &scancode in &[0x38, 0x54, 0xd4, 0xb8] {
+            interrupts::handle_scancode(scancode);
+        }
+        assert!(REQUESTED.load(Ordering::SeqCst));
+        // the keyboard interrupt sets the trap flag on its way out, which stops after the next
+        // instruction
+        unsafe { rflags::write(rflags::read() | RFlags::TRAP_FLAG) };
+    });
+    assert_eq!(serial::read_char(), None);
+    assert!(!REQUESTED.load(Ordering::SeqCst));
+}
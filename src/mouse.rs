@@ -0,0 +1,227 @@
+//! PS/2 mouse on the controller's second port. Packets arriving on IRQ 12 are decoded into
+//! `MouseEvent`s for programs to read, and move a pointer drawn as an inverted text cell.
+
+use crate::framebuffer::{GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::interrupts::PICS;
+use crate::ps2::{self, Device, Ps2Port};
+use crate::ring_buffer::RingBuffer;
+use crate::vga_buffer::{self, CONSOLES};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const LEFT_BUTTON: u8 = 1 << 0;
+pub const RIGHT_BUTTON: u8 = 1 << 1;
+pub const MIDDLE_BUTTON: u8 = 1 << 2;
+pub const FOURTH_BUTTON: u8 = 1 << 3;
+pub const FIFTH_BUTTON: u8 = 1 << 4;
+
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const ENABLE_DATA_REPORTING: u8 = 0xf4;
+
+const FLAGS_ALWAYS_SET: u8 = 1 << 3;
+const FLAGS_X_SIGN: u8 = 1 << 4;
+const FLAGS_Y_SIGN: u8 = 1 << 5;
+const FLAGS_X_OVERFLOW: u8 = 1 << 6;
+const FLAGS_Y_OVERFLOW: u8 = 1 << 7;
+
+/// Events that haven't been read yet, further ones are dropped until there is room again.
+const QUEUE_SIZE: usize = 64;
+
+static DECODER: Mutex<PacketDecoder> = Mutex::new(PacketDecoder::new(Device::Mouse));
+static EVENTS: Mutex<RingBuffer<MouseEvent, QUEUE_SIZE>> = Mutex::new(RingBuffer::new());
+/// Pointer position in pixels of the text console.
+static POINTER_X: AtomicUsize = AtomicUsize::new(0);
+static POINTER_Y: AtomicUsize = AtomicUsize::new(0);
+
+/// Movement since the previous event and the buttons held, `LEFT_BUTTON` and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    /// Positive when the mouse moves towards the user, i.e. down the screen.
+    pub dy: i16,
+    /// Positive when the wheel is turned towards the user.
+    pub wheel: i8,
+    pub buttons: u8,
+}
+
+/// Collects the bytes of a packet, which is 3 bytes long or 4 for mice with a wheel.
+struct PacketDecoder {
+    device: Device,
+    packet: [u8; 4],
+    len: usize,
+}
+
+impl PacketDecoder {
+    const fn new(device: Device) -> PacketDecoder {
+        PacketDecoder {
+            device,
+            packet: [0; 4],
+            len: 0,
+        }
+    }
+
+    fn packet_size(&self) -> usize {
+        match self.device {
+            Device::ScrollMouse | Device::FiveButtonMouse => 4,
+            _ => 3,
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // a first byte without bit 3 means a byte got lost, wait for the next packet to start
+        if self.len == 0 && byte & FLAGS_ALWAYS_SET == 0 {
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_size() {
+            return None;
+        }
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> MouseEvent {
+        let flags = self.packet[0];
+        let movement = |value: u8, sign: u8, overflow: u8| {
+            if flags & overflow != 0 {
+                0
+            } else if flags & sign != 0 {
+                value as i16 - 0x100
+            } else {
+                value as i16
+            }
+        };
+        let dx = movement(self.packet[1], FLAGS_X_SIGN, FLAGS_X_OVERFLOW);
+        let dy = movement(self.packet[2], FLAGS_Y_SIGN, FLAGS_Y_OVERFLOW);
+
+        let mut buttons = flags & (LEFT_BUTTON | RIGHT_BUTTON | MIDDLE_BUTTON);
+        let extra = self.packet[3];
+        let wheel = match self.device {
+            Device::ScrollMouse => extra as i8,
+            Device::FiveButtonMouse => {
+                // a 4 bit wheel movement with the fourth and fifth buttons above it
+                buttons |= extra >> 1 & (FOURTH_BUTTON | FIFTH_BUTTON);
+                ((extra << 4) as i8) >> 4
+            }
+            _ => 0,
+        };
+        MouseEvent {
+            dx,
+            dy: -dy,
+            wheel,
+            buttons,
+        }
+    }
+}
+
+/// Turns on the wheel and extra buttons if the mouse has them, and starts the stream of packets.
+/// Needs `ps2::init` to have found a mouse.
+pub fn init() -> Result<(), &'static str> {
+    if !ps2::device(Ps2Port::Second).is_some_and(Device::is_mouse) {
+        return Err("no PS/2 mouse");
+    }
+    // the magic sample rate sequences that IntelliMouse compatible mice answer by changing id
+    let mut device = Device::Mouse;
+    for &unlock in &[[200, 100, 80], [200, 200, 80]] {
+        ps2::send_command(
+            Ps2Port::Second,
+            &[
+                SET_SAMPLE_RATE,
+                unlock[0],
+                SET_SAMPLE_RATE,
+                unlock[1],
+                SET_SAMPLE_RATE,
+                unlock[2],
+            ],
+        )?;
+        let id = ps2::identify(Ps2Port::Second)?;
+        if !id.is_mouse() || id == device {
+            break;
+        }
+        device = id;
+    }
+    ps2::send_command(
+        Ps2Port::Second,
+        &[SET_SAMPLE_RATE, 100, ENABLE_DATA_REPORTING],
+    )?;
+    log::info!("mouse identifies as {:?}", device);
+    interrupts::without_interrupts(|| *DECODER.lock() = PacketDecoder::new(device));
+    ps2::enable_interrupt(Ps2Port::Second)?;
+
+    // IRQ 12 comes in through the secondary PIC on IRQ 2
+    interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let [primary, secondary] = pics.read_masks();
+        pics.write_masks(primary & !(1 << 2), secondary & !(1 << 4));
+    });
+    Ok(())
+}
+
+/// Takes the oldest mouse event that hasn't been read yet.
+pub fn next_event() -> Option<MouseEvent> {
+    interrupts::without_interrupts(|| EVENTS.lock().pop())
+}
+
+/// Feeds a byte from the mouse into the packet decoder, called from the IRQ 12 handler.
+pub(crate) fn add_byte(byte: u8) {
+    let event = match DECODER.try_lock() {
+        Some(mut decoder) => decoder.add_byte(byte),
+        None => None,
+    };
+    if let Some(event) = event {
+        if let Some(mut events) = EVENTS.try_lock() {
+            events.push(event);
+        }
+        move_pointer(&event);
+    }
+}
+
+fn move_pointer(event: &MouseEvent) {
+    // the interrupted code may be holding the writer, the pointer catches up on the next move
+    if let Some(mut writer) = CONSOLES[vga_buffer::active_console()].try_lock() {
+        let moved = |position: &AtomicUsize, delta: i16, cells: usize, cell_size: usize| {
+            let limit = (cells * cell_size - 1) as isize;
+            let moved =
+                (position.load(Ordering::Relaxed) as isize + delta as isize).clamp(0, limit);
+            position.store(moved as usize, Ordering::Relaxed);
+            moved as usize / cell_size
+        };
+        let col = moved(&POINTER_X, event.dx, writer.width(), GLYPH_WIDTH);
+        let row = moved(&POINTER_Y, event.dy, writer.height(), GLYPH_HEIGHT);
+        writer.show_pointer(Some((row, col)));
+    }
+}
+
+#[test_case]
+fn test_decode_packets() {
+    let mut decoder = PacketDecoder::new(Device::Mouse);
+    // a stray byte without the always set bit is skipped
+    assert_eq!(decoder.add_byte(0x00), None);
+    assert_eq!(decoder.add_byte(0x19), None);
+    assert_eq!(decoder.add_byte(0xfe), None);
+    assert_eq!(
+        decoder.add_byte(0x05),
+        Some(MouseEvent {
+            dx: -2,
+            dy: -5,
+            wheel: 0,
+            buttons: LEFT_BUTTON,
+        })
+    );
+
+    let mut decoder = PacketDecoder::new(Device::FiveButtonMouse);
+    for &byte in &[0x0a, 0x03, 0x00] {
+        assert_eq!(decoder.add_byte(byte), None);
+    }
+    assert_eq!(
+        decoder.add_byte(0x1f),
+        Some(MouseEvent {
+            dx: 3,
+            dy: 0,
+            wheel: -1,
+            buttons: RIGHT_BUTTON | FOURTH_BUTTON,
+        })
+    );
+}
@@ -0,0 +1,540 @@
+//! Finds the partitions on block devices, in an MBR, its extended partitions included, or a GPT,
+//! and registers each as a block device of its own, named after the device like "sda1". MBR
+//! partitions are numbered by their slot, logical ones from 5 on. GPT partitions are numbered by
+//! their entry.
+
+#[cfg(test)]
+use crate::block::RamDisk;
+use crate::block::{self, BlockDevice};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// How many logical partitions are looked for, which also stops a chain that loops.
+const MAX_LOGICAL: u32 = 32;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// The most entries that are looked at, what tools make by default.
+const GPT_MAX_ENTRIES: u32 = 128;
+
+/// The largest block size partition tables are read from.
+const MAX_BLOCK_SIZE: usize = 4096;
+const MAX_PARTITIONS: usize = 32;
+const NAME_LENGTH: usize = 12;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PARTITION: Once<Partition> = Once::new();
+static PARTITIONS: [Once<Partition>; MAX_PARTITIONS] = [NO_PARTITION; MAX_PARTITIONS];
+static PARTITION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// With the partition type byte.
+    Mbr(u8),
+    Gpt {
+        type_guid: [u8; 16],
+        unique_guid: [u8; 16],
+    },
+}
+
+/// A part of a block device, which is a block device itself.
+pub struct Partition {
+    name: [u8; NAME_LENGTH],
+    name_len: usize,
+    device: &'static dyn BlockDevice,
+    start: u64,
+    blocks: u64,
+    kind: Kind,
+}
+
+impl Partition {
+    /// The device the partition is on.
+    pub fn device(&self) -> &'static dyn BlockDevice {
+        self.device
+    }
+
+    /// The first block of the partition on its device.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        self.device.read_blocks(self.start + lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        self.device.write_blocks(self.start + lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        self.device.flush()
+    }
+}
+
+/// Looks for partitions on all the block devices that aren't partitions themselves.
+pub fn scan_all() {
+    for device in block::devices() {
+        if partitions().any(|partition| partition.name() == device.name()) {
+            continue;
+        }
+        match scan(device) {
+            Ok(0) => {}
+            Ok(count) => log::info!("{}: {} partitions", device.name(), count),
+            Err(err) => log::warn!("{}: {}", device.name(), err),
+        }
+    }
+}
+
+/// Registers the partitions on `device` and returns how many there are. A device without a
+/// partition table has none.
+pub fn scan(device: &'static dyn BlockDevice) -> Result<usize, &'static str> {
+    let block_size = device.block_size();
+    if !(512..=MAX_BLOCK_SIZE).contains(&block_size) {
+        return Err("block size not supported for partition tables");
+    }
+    let mut sector = [0; MAX_BLOCK_SIZE];
+    let sector = &mut sector[..block_size];
+    device.read_blocks(0, sector)?;
+    if sector[510..512] != MBR_SIGNATURE {
+        return Ok(0);
+    }
+    let entries = mbr_entries(sector);
+    if entries
+        .iter()
+        .flatten()
+        .any(|entry| entry.kind == MBR_TYPE_PROTECTIVE)
+    {
+        return scan_gpt(device);
+    }
+
+    let mut count = 0;
+    for (slot, entry) in entries.iter().enumerate() {
+        let entry = match entry {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if MBR_TYPES_EXTENDED.contains(&entry.kind) {
+            count += scan_extended(device, u64::from(entry.start))?;
+        } else {
+            add(
+                device,
+                slot as u32 + 1,
+                u64::from(entry.start),
+                u64::from(entry.blocks),
+                Kind::Mbr(entry.kind),
+            )?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// The partitions found so far.
+pub fn partitions() -> impl Iterator<Item = &'static Partition> {
+    let count = PARTITION_COUNT.load(Ordering::SeqCst);
+    PARTITIONS[..count]
+        .iter()
+        .filter_map(|partition| partition.r#try())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MbrEntry {
+    kind: u8,
+    start: u32,
+    blocks: u32,
+}
+
+/// The four entries of an MBR or EBR, the unused ones `None`.
+fn mbr_entries(sector: &[u8]) -> [Option<MbrEntry>; 4] {
+    let mut entries = [None; 4];
+    for (slot, entry) in entries.iter_mut().enumerate() {
+        let bytes = &sector[MBR_ENTRIES + slot * 16..][..16];
+        let kind = bytes[4];
+        let start = u32_at(bytes, 8);
+        let blocks = u32_at(bytes, 12);
+        if kind != 0 && blocks != 0 {
+            *entry = Some(MbrEntry {
+                kind,
+                start,
+                blocks,
+            });
+        }
+    }
+    entries
+}
+
+/// Follows the chain of extended boot records from the extended partition at `base`. The
+/// logical partition in each is relative to it, the link to the next is relative to `base`.
+fn scan_extended(device: &'static dyn BlockDevice, base: u64) -> Result<usize, &'static str> {
+    let mut sector = [0; MAX_BLOCK_SIZE];
+    let sector = &mut sector[..device.block_size()];
+    let mut ebr = base;
+    let mut count = 0;
+    for number in 5..5 + MAX_LOGICAL {
+        device.read_blocks(ebr, sector)?;
+        if sector[510..512] != MBR_SIGNATURE {
+            return Err("extended boot record without signature");
+        }
+        let entries = mbr_entries(sector);
+        if let Some(logical) = entries[0] {
+            add(
+                device,
+                number,
+                ebr + u64::from(logical.start),
+                u64::from(logical.blocks),
+                Kind::Mbr(logical.kind),
+            )?;
+            count += 1;
+        }
+        match entries[1] {
+            Some(next) if MBR_TYPES_EXTENDED.contains(&next.kind) => {
+                ebr = base + u64::from(next.start)
+            }
+            _ => break,
+        }
+    }
+    Ok(count)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GptHeader {
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GptEntry {
+    number: u32,
+    type_guid: [u8; 16],
+    unique_guid: [u8; 16],
+    first: u64,
+    last: u64,
+}
+
+/// Registers the partitions in the GPT, from the backup at the end of the device if the one at
+/// the start is damaged.
+fn scan_gpt(device: &'static dyn BlockDevice) -> Result<usize, &'static str> {
+    let last = device.block_count().saturating_sub(1);
+    let mut found = [None; GPT_MAX_ENTRIES as usize];
+    let mut result = Err("no GPT header");
+    for &lba in &[1, last] {
+        result =
+            gpt_header(device, lba).and_then(|header| gpt_entries(device, &header, &mut found));
+        match result {
+            Ok(_) => break,
+            Err(err) => log::warn!("{}: GPT at block {}: {}", device.name(), lba, err),
+        }
+    }
+    let count = result?;
+    for entry in found.iter().flatten() {
+        if entry.first > entry.last || entry.last > last {
+            log::warn!(
+                "{}: GPT entry {} is out of bounds",
+                device.name(),
+                entry.number
+            );
+            continue;
+        }
+        add(
+            device,
+            entry.number,
+            entry.first,
+            entry.last - entry.first + 1,
+            Kind::Gpt {
+                type_guid: entry.type_guid,
+                unique_guid: entry.unique_guid,
+            },
+        )?;
+    }
+    Ok(count)
+}
+
+fn gpt_header(device: &dyn BlockDevice, lba: u64) -> Result<GptHeader, &'static str> {
+    let block_size = device.block_size();
+    let mut sector = [0; MAX_BLOCK_SIZE];
+    let sector = &mut sector[..block_size];
+    device.read_blocks(lba, sector)?;
+    if &sector[..8] != GPT_SIGNATURE {
+        return Err("no GPT signature");
+    }
+    let header_size = u32_at(sector, 12) as usize;
+    if !(GPT_HEADER_MIN_SIZE..=block_size).contains(&header_size) {
+        return Err("bad GPT header size");
+    }
+    let header_crc = u32_at(sector, 16);
+    sector[16..20].fill(0);
+    if crc32(&sector[..header_size]) != header_crc {
+        return Err("bad GPT header checksum");
+    }
+    if u64_at(sector, 24) != lba {
+        return Err("GPT header is at the wrong block");
+    }
+    let entry_size = u32_at(sector, 84) as usize;
+    // sizes are 128 times a power of two, so entries don't straddle blocks
+    if entry_size < GPT_ENTRY_MIN_SIZE || !entry_size.is_power_of_two() || entry_size > block_size {
+        return Err("GPT entry size not supported");
+    }
+    Ok(GptHeader {
+        entries_lba: u64_at(sector, 72),
+        entry_count: u32_at(sector, 80),
+        entry_size,
+        entries_crc: u32_at(sector, 88),
+    })
+}
+
+/// Reads the used entries into `found` and checks the checksum of all of them.
+fn gpt_entries(
+    device: &dyn BlockDevice,
+    header: &GptHeader,
+    found: &mut [Option<GptEntry>],
+) -> Result<usize, &'static str> {
+    if header.entry_count > GPT_MAX_ENTRIES {
+        return Err("too many GPT entries");
+    }
+    found.iter_mut().for_each(|entry| *entry = None);
+    let block_size = device.block_size();
+    let per_block = block_size / header.entry_size;
+    let mut sector = [0; MAX_BLOCK_SIZE];
+    let sector = &mut sector[..block_size];
+    let mut crc = CRC_INIT;
+    let mut count = 0;
+    for (index, slot) in found
+        .iter_mut()
+        .enumerate()
+        .take(header.entry_count as usize)
+    {
+        let offset = index % per_block * header.entry_size;
+        if offset == 0 {
+            device.read_blocks(header.entries_lba + (index / per_block) as u64, sector)?;
+        }
+        let bytes = &sector[offset..offset + header.entry_size];
+        crc = crc32_update(crc, bytes);
+        let mut type_guid = [0; 16];
+        type_guid.copy_from_slice(&bytes[..16]);
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let mut unique_guid = [0; 16];
+        unique_guid.copy_from_slice(&bytes[16..32]);
+        *slot = Some(GptEntry {
+            number: index as u32 + 1,
+            type_guid,
+            unique_guid,
+            first: u64_at(bytes, 32),
+            last: u64_at(bytes, 40),
+        });
+        count += 1;
+    }
+    if !crc != header.entries_crc {
+        return Err("bad GPT entries checksum");
+    }
+    Ok(count)
+}
+
+fn add(
+    device: &'static dyn BlockDevice,
+    number: u32,
+    start: u64,
+    blocks: u64,
+    kind: Kind,
+) -> Result<(), &'static str> {
+    match start.checked_add(blocks) {
+        Some(end) if end <= device.block_count() => {}
+        _ => return Err("partition is past the end of the device"),
+    }
+    let index = PARTITION_COUNT.load(Ordering::SeqCst);
+    if index == MAX_PARTITIONS {
+        return Err("too many partitions");
+    }
+    let mut name = Name::default();
+    let separator = match device.name().bytes().last() {
+        Some(last) if last.is_ascii_digit() => "p",
+        _ => "",
+    };
+    write!(name, "{}{}{}", device.name(), separator, number)
+        .map_err(|_| "partition name too long")?;
+    let partition = PARTITIONS[index].call_once(|| Partition {
+        name: name.bytes,
+        name_len: name.len,
+        device,
+        start,
+        blocks,
+        kind,
+    });
+    PARTITION_COUNT.store(index + 1, Ordering::SeqCst);
+    block::register(partition)
+}
+
+#[derive(Default)]
+struct Name {
+    bytes: [u8; NAME_LENGTH],
+    len: usize,
+}
+
+impl fmt::Write for Name {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > NAME_LENGTH {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+const CRC_INIT: u32 = !0;
+
+/// The CRC-32 that GPT and zip use.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(CRC_INIT, bytes)
+}
+
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+#[cfg(test)]
+fn set_mbr_entry(sector: &mut [u8], slot: usize, kind: u8, start: u32, blocks: u32) {
+    let entry = &mut sector[MBR_ENTRIES + slot * 16..][..16];
+    entry[4] = kind;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+}
+
+#[test_case]
+fn test_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b""), 0);
+}
+
+#[test_case]
+fn test_mbr() {
+    static DISK: RamDisk = RamDisk {
+        name: "mbrtest",
+        blocks: spin::Mutex::new([0; 64 * 512]),
+    };
+    {
+        let mut blocks = DISK.blocks.lock();
+        set_mbr_entry(&mut blocks[..512], 0, 0x83, 1, 7);
+        set_mbr_entry(&mut blocks[..512], 2, 0x05, 16, 48);
+        // two logical partitions, the second EBR 32 blocks into the extended one
+        set_mbr_entry(&mut blocks[16 * 512..17 * 512], 0, 0x0c, 2, 10);
+        set_mbr_entry(&mut blocks[16 * 512..17 * 512], 1, 0x05, 32, 16);
+        set_mbr_entry(&mut blocks[48 * 512..49 * 512], 0, 0x83, 1, 15);
+        blocks[50 * 512] = 0xab;
+    }
+    assert_eq!(scan(&DISK), Ok(3));
+
+    let find = |name| partitions().find(|partition| partition.name() == name);
+    let first = find("mbrtest1").unwrap();
+    assert_eq!((first.start(), first.block_count()), (1, 7));
+    assert_eq!(first.kind(), Kind::Mbr(0x83));
+    assert_eq!(find("mbrtest5").unwrap().start(), 18);
+    let last = find("mbrtest6").unwrap();
+    assert_eq!((last.start(), last.block_count()), (49, 15));
+    let mut sector = [0; 512];
+    last.read_blocks(1, &mut sector).unwrap();
+    assert_eq!(sector[0], 0xab);
+    assert!(last.read_blocks(15, &mut sector).is_err());
+    assert!(block::find("mbrtest6").is_some());
+}
+
+#[test_case]
+fn test_gpt() {
+    static DISK: RamDisk = RamDisk {
+        name: "gpt0",
+        blocks: spin::Mutex::new([0; 64 * 512]),
+    };
+    {
+        let mut blocks = DISK.blocks.lock();
+        set_mbr_entry(&mut blocks[..512], 0, MBR_TYPE_PROTECTIVE, 1, 63);
+        // four entries in block 2, the second one used
+        let entries = &mut blocks[2 * 512..3 * 512];
+        entries[128..144].copy_from_slice(&[0xaf; 16]);
+        entries[144..160].copy_from_slice(&[0x01; 16]);
+        entries[160..168].copy_from_slice(&10u64.to_le_bytes());
+        entries[168..176].copy_from_slice(&19u64.to_le_bytes());
+        let entries_crc = crc32(entries);
+
+        // only the backup header is intact
+        let header = &mut blocks[63 * 512..64 * 512];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&63u64.to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let header_crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        blocks[512..520].copy_from_slice(GPT_SIGNATURE);
+    }
+    assert_eq!(scan(&DISK), Ok(1));
+    let partition = partitions()
+        .find(|partition| partition.name() == "gpt0p2")
+        .unwrap();
+    assert_eq!((partition.start(), partition.block_count()), (10, 10));
+    assert_eq!(
+        partition.kind(),
+        Kind::Gpt {
+            type_guid: [0xaf; 16],
+            unique_guid: [0x01; 16]
+        }
+    );
+
+    // a damaged entry array is noticed
+    DISK.blocks.lock()[2 * 512 + 160] = 11;
+    assert!(scan(&DISK).is_err());
+}
@@ -0,0 +1,601 @@
+//! PCI device discovery. The buses are scanned once, starting from the host bridges on bus 0 and
+//! following PCI-to-PCI bridges, and each function found is decoded into a `Device` with its
+//! ids, class, BARs, capabilities and interrupt.
+//!
+//! Configuration space is read through the memory mapped ECAM area when ACPI describes one in its
+//! MCFG table, and through the legacy 0xCF8/0xCFC ports otherwise. Drivers register a `Driver`
+//! with the devices it handles and have its `probe` called for each of them.
+
+use crate::acpi;
+use crate::memory;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const VENDOR_ID: u16 = 0x00;
+const COMMAND: u16 = 0x04;
+const CLASS: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0c;
+const BAR0: u16 = 0x10;
+const BUS_NUMBERS: u16 = 0x18;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT: u16 = 0x3c;
+
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_BRIDGE: u8 = 0x01;
+const NO_DEVICE: u16 = 0xffff;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_64_BIT: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSI_X: u8 = 0x11;
+
+const MAX_DEVICES: usize = 64;
+const MAX_CAPABILITIES: usize = 8;
+const MAX_DRIVERS: usize = 16;
+
+static ECAM: Once<Option<Ecam>> = Once::new();
+static CONFIG_PORTS: Mutex<()> = Mutex::new(());
+static DEVICES: Once<Registry> = Once::new();
+static DRIVERS: Mutex<[Option<&'static Driver>; MAX_DRIVERS]> = Mutex::new([None; MAX_DRIVERS]);
+/// The name of the driver that took each device in `DEVICES`, by index.
+static BOUND: Mutex<[Option<&'static str>; MAX_DEVICES]> = Mutex::new([None; MAX_DEVICES]);
+
+/// Where a function's configuration space is, `bus:device.function` in lspci's notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// The configuration space of one PCI segment mapped into memory, 4KiB per function.
+#[derive(Debug, Clone, Copy)]
+struct Ecam {
+    base: PhysAddr,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        addr: PhysAddr,
+        size: u64,
+        prefetchable: bool,
+        is_64_bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where it starts in configuration space.
+    pub offset: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// Indexed by BAR number, the upper half of a 64 bit BAR is `None`. Bridges only have two.
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: [Option<Capability>; MAX_CAPABILITIES],
+    /// The legacy PIC line the firmware routed the interrupt to, 0xff if none.
+    pub interrupt_line: u8,
+    /// INTA# to INTD# as 1 to 4, 0 if the function doesn't interrupt.
+    pub interrupt_pin: u8,
+}
+
+impl Device {
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities
+            .iter()
+            .flatten()
+            .copied()
+            .find(|capability| capability.id == id)
+    }
+
+    pub fn read_config(&self, offset: u16) -> u32 {
+        read_config(self.address, offset)
+    }
+
+    pub fn write_config(&self, offset: u16, value: u32) {
+        write_config(self.address, offset, value)
+    }
+
+    /// Lets the device decode its BARs and master the bus, for DMA.
+    pub fn enable(&self) {
+        let command = self.read_config(COMMAND) & 0xffff;
+        self.write_config(
+            COMMAND,
+            command | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+}
+
+/// What a `Driver` handles, a driver can list several.
+#[derive(Debug, Clone, Copy)]
+pub enum Matcher {
+    Id {
+        vendor_id: u16,
+        device_id: u16,
+    },
+    /// `prog_if` of `None` matches any programming interface.
+    Class {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+}
+
+impl Matcher {
+    pub fn matches(&self, device: &Device) -> bool {
+        match *self {
+            Matcher::Id {
+                vendor_id,
+                device_id,
+            } => device.vendor_id == vendor_id && device.device_id == device_id,
+            Matcher::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matchers: &'static [Matcher],
+    /// Sets up a device that matched, which belongs to the driver if this returns `Ok`.
+    pub probe: fn(&'static Device) -> Result<(), &'static str>,
+}
+
+struct Registry {
+    devices: [Option<Device>; MAX_DEVICES],
+    len: usize,
+}
+
+impl Registry {
+    fn add(&mut self, device: Device) {
+        if self.len < MAX_DEVICES {
+            self.devices[self.len] = Some(device);
+            self.len += 1;
+        } else {
+            log::warn!("no room for PCI device {}", device.address);
+        }
+    }
+}
+
+/// Scans the buses, if that hasn't happened yet. Should run after `memory::init`, or the ECAM
+/// area can't be used.
+pub fn init() {
+    DEVICES.call_once(|| {
+        let mut registry = Registry {
+            devices: [None; MAX_DEVICES],
+            len: 0,
+        };
+        let ecam = ECAM.call_once(find_ecam);
+        if let Some(ecam) = ecam {
+            log::info!("PCI Express configuration space at {:#x}", ecam.base);
+        }
+        // a multi-function host bridge means there is a host bridge, and a bus, per function
+        let host = Address {
+            bus: 0,
+            device: 0,
+            function: 0,
+        };
+        if header_type(host) & HEADER_MULTIFUNCTION == 0 {
+            scan_bus(0, &mut registry);
+        } else {
+            for function in 0..8 {
+                let host = Address { function, ..host };
+                if vendor_id(host) != NO_DEVICE {
+                    scan_bus(function, &mut registry);
+                }
+            }
+        }
+        registry
+    });
+}
+
+/// The devices found, in the order they were found in.
+pub fn devices() -> impl Iterator<Item = &'static Device> {
+    init();
+    let registry = DEVICES.r#try().expect("PCI scan failed");
+    registry.devices[..registry.len].iter().flatten()
+}
+
+/// The first device that `matcher` matches.
+pub fn find(matcher: &Matcher) -> Option<&'static Device> {
+    devices().find(|device| matcher.matches(device))
+}
+
+/// The name of the driver that took the device at `address`.
+pub fn driver_of(address: Address) -> Option<&'static str> {
+    let index = devices().position(|device| device.address == address)?;
+    interrupts::without_interrupts(|| BOUND.lock()[index])
+}
+
+/// Adds `driver` to the registry and probes it with the devices it matches that no other driver
+/// has taken yet.
+pub fn register_driver(driver: &'static Driver) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut drivers = DRIVERS.lock();
+        let slot = drivers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("too many PCI drivers")?;
+        *slot = Some(driver);
+        Ok(())
+    })?;
+    for (index, device) in devices().enumerate() {
+        let free = interrupts::without_interrupts(|| BOUND.lock()[index].is_none());
+        if !free
+            || !driver
+                .matchers
+                .iter()
+                .any(|matcher| matcher.matches(device))
+        {
+            continue;
+        }
+        // probes can take a while and use other devices, so the lock isn't held meanwhile
+        match (driver.probe)(device) {
+            Ok(()) => {
+                log::info!(
+                    "{} {} taken by {}",
+                    device.address,
+                    name(device),
+                    driver.name
+                );
+                interrupts::without_interrupts(|| BOUND.lock()[index] = Some(driver.name));
+            }
+            Err(err) => log::warn!("{} on {} failed: {}", driver.name, device.address, err),
+        }
+    }
+    Ok(())
+}
+
+/// Reads the aligned dword at `offset` in the configuration space of `address`. Beyond 256 bytes
+/// this needs the ECAM area, without it those read as all ones.
+pub fn read_config(address: Address, offset: u16) -> u32 {
+    if let Some(config) = ecam_address(address, offset) {
+        return unsafe { config.read_volatile() };
+    }
+    if offset >= 256 {
+        return u32::MAX;
+    }
+    interrupts::without_interrupts(|| {
+        let _ports = CONFIG_PORTS.lock();
+        let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS);
+        let mut data_port: Port<u32> = Port::new(CONFIG_DATA);
+        unsafe {
+            address_port.write(port_address(address, offset));
+            data_port.read()
+        }
+    })
+}
+
+pub fn write_config(address: Address, offset: u16, value: u32) {
+    if let Some(config) = ecam_address(address, offset) {
+        unsafe { config.write_volatile(value) };
+        return;
+    }
+    if offset >= 256 {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let _ports = CONFIG_PORTS.lock();
+        let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS);
+        let mut data_port: Port<u32> = Port::new(CONFIG_DATA);
+        unsafe {
+            address_port.write(port_address(address, offset));
+            data_port.write(value);
+        }
+    })
+}
+
+/// What lspci would call the kind of device.
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "mass storage controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x09, _) => "input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "serial bus controller",
+        _ => "unknown device",
+    }
+}
+
+pub fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "power management",
+        CAPABILITY_MSI => "MSI",
+        CAPABILITY_VENDOR => "vendor specific",
+        CAPABILITY_PCI_EXPRESS => "PCI Express",
+        CAPABILITY_MSI_X => "MSI-X",
+        0x12 => "SATA",
+        _ => "unknown",
+    }
+}
+
+fn name(device: &Device) -> &'static str {
+    class_name(device.class, device.subclass)
+}
+
+fn find_ecam() -> Option<Ecam> {
+    let mcfg = acpi::find_table(b"MCFG")?;
+    let length = acpi::read::<u32>(mcfg + 4u64)?;
+    // the table header and 8 reserved bytes, then 16 byte entries
+    (44..u64::from(length))
+        .step_by(16)
+        .find_map(|offset| {
+            let entry = mcfg + offset;
+            let segment = acpi::read::<u16>(entry + 8u64)?;
+            (segment == 0).then_some(entry)
+        })
+        .and_then(|entry| {
+            Some(Ecam {
+                base: PhysAddr::try_new(acpi::read::<u64>(entry)?).ok()?,
+                start_bus: acpi::read::<u8>(entry + 10u64)?,
+                end_bus: acpi::read::<u8>(entry + 11u64)?,
+            })
+        })
+}
+
+fn ecam_address(address: Address, offset: u16) -> Option<*mut u32> {
+    let ecam = (*ECAM.r#try()?)?;
+    if !(ecam.start_bus..=ecam.end_bus).contains(&address.bus) {
+        return None;
+    }
+    let function = ecam.base
+        + (u64::from(address.bus - ecam.start_bus) << 20
+            | u64::from(address.device) << 15
+            | u64::from(address.function) << 12);
+    // mapped a function at a time, the whole area can be 256MiB
+    let mut virt = memory::phys_to_virt(function);
+    if memory::translate(virt).is_none() {
+        virt = memory::map_mmio(function, 4096).ok()?;
+    }
+    Some((virt + u64::from(offset & 0xffc)).as_mut_ptr())
+}
+
+fn port_address(address: Address, offset: u16) -> u32 {
+    CONFIG_ENABLE
+        | u32::from(address.bus) << 16
+        | u32::from(address.device) << 11
+        | u32::from(address.function) << 8
+        | u32::from(offset & 0xfc)
+}
+
+fn vendor_id(address: Address) -> u16 {
+    read_config(address, VENDOR_ID) as u16
+}
+
+fn header_type(address: Address) -> u8 {
+    (read_config(address, HEADER_TYPE) >> 16) as u8
+}
+
+fn scan_bus(bus: u8, registry: &mut Registry) {
+    for device in 0..32 {
+        let address = Address {
+            bus,
+            device,
+            function: 0,
+        };
+        if vendor_id(address) == NO_DEVICE {
+            continue;
+        }
+        let functions = if header_type(address) & HEADER_MULTIFUNCTION != 0 {
+            8
+        } else {
+            1
+        };
+        for function in 0..functions {
+            let address = Address {
+                function,
+                ..address
+            };
+            if vendor_id(address) != NO_DEVICE {
+                scan_function(address, registry);
+            }
+        }
+    }
+}
+
+fn scan_function(address: Address, registry: &mut Registry) {
+    let ids = read_config(address, VENDOR_ID);
+    let class = read_config(address, CLASS);
+    let header_type = header_type(address) & !HEADER_MULTIFUNCTION;
+    let interrupt = read_config(address, INTERRUPT);
+    let mut device = Device {
+        address,
+        vendor_id: ids as u16,
+        device_id: (ids >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        header_type,
+        bars: [None; 6],
+        capabilities: [None; MAX_CAPABILITIES],
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+    };
+    read_bars(&mut device);
+    read_capabilities(&mut device);
+    log::debug!(
+        "{} {:04x}:{:04x} {}",
+        address,
+        device.vendor_id,
+        device.device_id,
+        name(&device)
+    );
+    registry.add(device);
+
+    if device.class == CLASS_BRIDGE
+        && device.subclass == SUBCLASS_PCI_BRIDGE
+        && header_type == HEADER_BRIDGE
+    {
+        let secondary = (read_config(address, BUS_NUMBERS) >> 8) as u8;
+        // a bridge the firmware didn't number leads back to bus 0
+        if secondary > address.bus {
+            scan_bus(secondary, registry);
+        }
+    }
+}
+
+/// Finds the size of each BAR by writing all ones and seeing which address bits stick, with the
+/// device's decoding off meanwhile so it doesn't claim the bogus addresses.
+fn read_bars(device: &mut Device) {
+    let count = match device.header_type {
+        0 => 6,
+        HEADER_BRIDGE => 2,
+        _ => 0,
+    };
+    let command = device.read_config(COMMAND);
+    device.write_config(
+        COMMAND,
+        command & 0xffff & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+    );
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + index as u16 * 4;
+        let size_mask = |offset: u16| {
+            let original = device.read_config(offset);
+            device.write_config(offset, u32::MAX);
+            let mask = device.read_config(offset);
+            device.write_config(offset, original);
+            (original, mask)
+        };
+        let (low, low_mask) = size_mask(offset);
+        if low & BAR_IO != 0 {
+            let mask = low_mask & !0x3;
+            if mask != 0 {
+                device.bars[index] = Some(Bar::Io {
+                    port: (low & !0x3) as u16,
+                    size: (!mask | 0xffff_0000).wrapping_add(1),
+                });
+            }
+        } else {
+            let is_64_bit = low & 0b110 == BAR_64_BIT;
+            let (high, high_mask) = if is_64_bit && index + 1 < count {
+                size_mask(offset + 4)
+            } else {
+                (0, u32::MAX)
+            };
+            let addr = u64::from(high) << 32 | u64::from(low & !0xf);
+            let mask = u64::from(high_mask) << 32 | u64::from(low_mask & !0xf);
+            if mask != 0 {
+                device.bars[index] = Some(Bar::Memory {
+                    addr: PhysAddr::new_truncate(addr),
+                    size: (!mask).wrapping_add(1),
+                    prefetchable: low & BAR_PREFETCHABLE != 0,
+                    is_64_bit,
+                });
+            }
+            if is_64_bit {
+                index += 1;
+            }
+        }
+        index += 1;
+    }
+    device.write_config(COMMAND, command & 0xffff);
+}
+
+fn read_capabilities(device: &mut Device) {
+    if device.read_config(COMMAND) & STATUS_CAPABILITIES == 0 {
+        return;
+    }
+    let mut offset = device.read_config(CAPABILITIES_POINTER) as u8 & !0x3;
+    let address = device.address;
+    // only the first few are kept, which also stops a list that loops
+    for slot in device.capabilities.iter_mut() {
+        if offset == 0 {
+            break;
+        }
+        let header = read_config(address, u16::from(offset));
+        *slot = Some(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> 8) as u8 & !0x3;
+    }
+}
+
+#[test_case]
+fn test_scan() {
+    let host = devices().next().expect("no PCI devices found");
+    assert_eq!(host.address.bus, 0);
+    assert_eq!(host.class, CLASS_BRIDGE);
+    assert!(Matcher::Class {
+        class: CLASS_BRIDGE,
+        subclass: 0x00,
+        prog_if: None,
+    }
+    .matches(host));
+    assert!(!Matcher::Id {
+        vendor_id: host.vendor_id,
+        device_id: !host.device_id,
+    }
+    .matches(host));
+    let address = Address {
+        bus: 1,
+        device: 0x1f,
+        function: 2,
+    };
+    assert_eq!(port_address(address, 0x3e), 0x8001_fa3c);
+}
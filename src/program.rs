@@ -1,7 +1,54 @@
+use crate::vfs::FdTable;
+use spin::Mutex;
+
+/// How deeply programs can start other programs while still being listed by `running_programs`.
+const MAX_DEPTH: usize = 8;
+
+/// The type names of the programs started by `program_handler` that haven't returned yet.
+static RUNNING: Mutex<([&str; MAX_DEPTH], usize)> = Mutex::new(([""; MAX_DEPTH], 0));
+/// The open files of each running program by depth, the first table is the kernel's own. Programs
+/// started deeper than `MAX_DEPTH` share the last one.
+static FILES: Mutex<[FdTable; MAX_DEPTH + 1]> = Mutex::new([NO_FILES; MAX_DEPTH + 1]);
+const NO_FILES: FdTable = FdTable::new();
+
 pub trait Program {
     fn run(&mut self) -> Result<(), &'static str>;
 }
 
-pub fn program_handler(prog: &mut impl Program) -> Result<(), &'static str> {
-    prog.run()
+pub fn program_handler<P: Program>(prog: &mut P) -> Result<(), &'static str> {
+    with_running(|(names, depth)| {
+        if let Some(name) = names.get_mut(*depth) {
+            *name = core::any::type_name::<P>();
+        }
+        *depth += 1;
+    });
+    let result = prog.run();
+    with_files(FdTable::close_all);
+    with_running(|(_, depth)| *depth -= 1);
+    result
+}
+
+/// Calls `f` with the file descriptor table of the program that runs now, which closes the files
+/// left open when the program returns.
+pub fn with_files<R>(f: impl FnOnce(&mut FdTable) -> R) -> R {
+    let depth = with_running(|(_, depth)| *depth).min(MAX_DEPTH);
+    f(&mut FILES.lock()[depth])
+}
+
+/// Calls `f` with the names of the running programs, from the first one started to the one
+/// that runs now, which the others are waiting for. Gives up and returns false if the list is
+/// being changed, as it can be when the kernel was stopped for the monitor.
+pub fn running_programs(mut f: impl FnMut(&'static str)) -> bool {
+    match RUNNING.try_lock() {
+        Some(running) => {
+            let (names, depth) = *running;
+            names.iter().take(depth).for_each(|&name| f(name));
+            true
+        }
+        None => false,
+    }
+}
+
+fn with_running<R>(f: impl FnOnce(&mut ([&'static str; MAX_DEPTH], usize)) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut RUNNING.lock()))
 }
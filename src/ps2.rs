@@ -0,0 +1,336 @@
+//! Driver for the i8042 PS/2 controller and the devices plugged into its two ports.
+//!
+//! The keyboard interrupt handler reads scancodes straight from the data port, this module sets
+//! the controller up and talks to the devices: self-tests, detection, keyboard LEDs and repeat
+//! rate. Every device command is acknowledged with 0xfa, or 0xfe when it has to be sent again.
+
+use crate::keyboard::{self, ScancodeSet};
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+pub(crate) const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer came from the second port.
+pub(crate) const STATUS_SECOND_PORT_DATA: u8 = 1 << 5;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND: u8 = 0xa7;
+const COMMAND_ENABLE_SECOND: u8 = 0xa8;
+const COMMAND_TEST_SECOND: u8 = 0xa9;
+const COMMAND_TEST_CONTROLLER: u8 = 0xaa;
+const COMMAND_TEST_FIRST: u8 = 0xab;
+const COMMAND_DISABLE_FIRST: u8 = 0xad;
+const COMMAND_ENABLE_FIRST: u8 = 0xae;
+const COMMAND_WRITE_SECOND: u8 = 0xd4;
+
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_FIRST_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+/// Makes the controller translate the keyboard's set 2 scancodes to set 1.
+pub(crate) const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+const CONTROLLER_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_IDENTIFY: u8 = 0xf2;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_DISABLE_SCANNING: u8 = 0xf5;
+const KEYBOARD_SET_LEDS: u8 = 0xed;
+const KEYBOARD_SCANCODE_SET: u8 = 0xf0;
+const KEYBOARD_TYPEMATIC: u8 = 0xf3;
+
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const SELF_TEST_PASSED: u8 = 0xaa;
+
+const RETRIES: usize = 3;
+/// Status polls before giving up on the controller or a device.
+const TIMEOUT: usize = 100_000;
+/// Devices can take a good while over their self-test after a reset.
+const RESET_TIMEOUT: usize = 1_000_000;
+
+static DEVICES: Once<[Option<Device>; 2]> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+/// What a device said it was when asked to identify itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    /// Old AT keyboards don't answer the identify command at all.
+    AtKeyboard,
+    Mf2Keyboard,
+    Mouse,
+    ScrollMouse,
+    FiveButtonMouse,
+    Unknown(u8, u8),
+}
+
+impl Device {
+    fn from_id(id: &[u8]) -> Device {
+        match id {
+            [] => Device::AtKeyboard,
+            [0xab, _] => Device::Mf2Keyboard,
+            [0x00] => Device::Mouse,
+            [0x03] => Device::ScrollMouse,
+            [0x04] => Device::FiveButtonMouse,
+            [first] => Device::Unknown(*first, 0),
+            [first, second, ..] => Device::Unknown(*first, *second),
+        }
+    }
+
+    pub fn is_keyboard(self) -> bool {
+        matches!(self, Device::AtKeyboard | Device::Mf2Keyboard)
+    }
+
+    pub fn is_mouse(self) -> bool {
+        matches!(
+            self,
+            Device::Mouse | Device::ScrollMouse | Device::FiveButtonMouse
+        )
+    }
+}
+
+/// Brings the controller into a known state, tests it and both ports, and resets and identifies
+/// the devices. The keyboard on the first port is left scanning with its interrupt on.
+pub fn init() -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        controller_command(COMMAND_DISABLE_FIRST)?;
+        controller_command(COMMAND_DISABLE_SECOND)?;
+        flush();
+
+        let mut config = read_config()?;
+        let mut dual_channel = config & CONFIG_SECOND_CLOCK_DISABLED != 0;
+        config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATE);
+        write_config(config)?;
+
+        if controller_read(COMMAND_TEST_CONTROLLER)? != CONTROLLER_TEST_PASSED {
+            return Err("PS/2 controller failed its self-test");
+        }
+        // the self-test resets some controllers
+        write_config(config)?;
+
+        // the second port's clock only comes on if there is a second port
+        if dual_channel {
+            controller_command(COMMAND_ENABLE_SECOND)?;
+            dual_channel = read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+            controller_command(COMMAND_DISABLE_SECOND)?;
+        }
+
+        let first_works = controller_read(COMMAND_TEST_FIRST)? == PORT_TEST_PASSED;
+        let second_works =
+            dual_channel && controller_read(COMMAND_TEST_SECOND)? == PORT_TEST_PASSED;
+        if !first_works {
+            return Err("PS/2 keyboard port failed its self-test");
+        }
+
+        controller_command(COMMAND_ENABLE_FIRST)?;
+        let first = detect(Ps2Port::First);
+        let second = if second_works {
+            controller_command(COMMAND_ENABLE_SECOND)?;
+            detect(Ps2Port::Second)
+        } else {
+            None
+        };
+        DEVICES.call_once(|| [first, second]);
+        log::info!("found {:?} and {:?}", first, second);
+
+        if first.is_some_and(Device::is_keyboard) {
+            // the controller turns set 2 into set 1 if that is what we decode
+            send_command(Ps2Port::First, &[KEYBOARD_SCANCODE_SET, 2])?;
+            send_command(Ps2Port::First, &[DEVICE_ENABLE_SCANNING])?;
+        }
+        // disabling the ports during setup shows up in the configuration, undo that
+        config &= !CONFIG_FIRST_CLOCK_DISABLED;
+        if second_works {
+            config &= !CONFIG_SECOND_CLOCK_DISABLED;
+        }
+        config |= CONFIG_FIRST_IRQ;
+        if keyboard::layout().1 == ScancodeSet::Set1 {
+            config |= CONFIG_TRANSLATE;
+        }
+        write_config(config)
+    })
+}
+
+/// The device found on `port` by `init`.
+pub fn device(port: Ps2Port) -> Option<Device> {
+    DEVICES.r#try()?[port as usize]
+}
+
+/// Sends `bytes` to the device on `port`, waiting for each to be acknowledged.
+pub fn send_command(port: Ps2Port, bytes: &[u8]) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        for &byte in bytes {
+            send_byte(port, byte)?;
+        }
+        Ok(())
+    })
+}
+
+/// Switches the keyboard's Scroll, Num and Caps Lock lights.
+pub fn set_leds(scroll_lock: bool, num_lock: bool, caps_lock: bool) -> Result<(), &'static str> {
+    if !device(Ps2Port::First).is_some_and(Device::is_keyboard) {
+        return Err("no PS/2 keyboard");
+    }
+    let leds = scroll_lock as u8 | (num_lock as u8) << 1 | (caps_lock as u8) << 2;
+    send_command(Ps2Port::First, &[KEYBOARD_SET_LEDS, leds])
+}
+
+/// Sets how long a key has to be held before it repeats, 250 to 1000ms in steps of 250ms, and the
+/// repeat rate from 0 (30 per second) to 31 (2 per second).
+pub fn set_typematic(delay_ms: u16, rate: u8) -> Result<(), &'static str> {
+    if !(250..=1000).contains(&delay_ms) || rate > 31 {
+        return Err("typematic delay or rate out of range");
+    }
+    if !device(Ps2Port::First).is_some_and(Device::is_keyboard) {
+        return Err("no PS/2 keyboard");
+    }
+    let delay = (delay_ms / 250 - 1) as u8;
+    send_command(Ps2Port::First, &[KEYBOARD_TYPEMATIC, delay << 5 | rate])
+}
+
+pub(crate) fn read_config() -> Result<u8, &'static str> {
+    controller_read(COMMAND_READ_CONFIG)
+}
+
+pub(crate) fn write_config(config: u8) -> Result<(), &'static str> {
+    controller_command(COMMAND_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+pub(crate) fn status() -> u8 {
+    let mut status: Port<u8> = Port::new(STATUS_COMMAND_PORT);
+    unsafe { status.read() }
+}
+
+/// Resets and identifies the device on `port`, leaving it with scanning disabled.
+fn detect(port: Ps2Port) -> Option<Device> {
+    send_byte(port, DEVICE_RESET).ok()?;
+    if read_byte(RESET_TIMEOUT)? != SELF_TEST_PASSED {
+        return None;
+    }
+    // mice follow the self-test result with their id
+    while read_byte(TIMEOUT).is_some() {}
+
+    send_byte(port, DEVICE_DISABLE_SCANNING).ok()?;
+    identify(port).ok()
+}
+
+/// Asks the device on `port` what it is. It has to have scanning disabled, or its data gets mixed
+/// up with the answer.
+pub fn identify(port: Ps2Port) -> Result<Device, &'static str> {
+    interrupts::without_interrupts(|| {
+        send_byte(port, DEVICE_IDENTIFY)?;
+        let mut id = [0; 2];
+        let mut len = 0;
+        while len < id.len() {
+            match read_byte(TIMEOUT) {
+                Some(byte) => {
+                    id[len] = byte;
+                    len += 1;
+                }
+                None => break,
+            }
+        }
+        Ok(Device::from_id(&id[..len]))
+    })
+}
+
+/// Lets the device on `port` raise its interrupt, IRQ 1 for the first port and IRQ 12 for the
+/// second.
+pub fn enable_interrupt(port: Ps2Port) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let bit = match port {
+            Ps2Port::First => CONFIG_FIRST_IRQ,
+            Ps2Port::Second => CONFIG_SECOND_IRQ,
+        };
+        write_config(read_config()? | bit)
+    })
+}
+
+fn send_byte(port: Ps2Port, byte: u8) -> Result<(), &'static str> {
+    for _ in 0..RETRIES {
+        if port == Ps2Port::Second {
+            controller_command(COMMAND_WRITE_SECOND)?;
+        }
+        write_data(byte)?;
+        // keys pressed meanwhile can come before the answer, they are lost
+        loop {
+            match read_byte(TIMEOUT) {
+                Some(ACK) => return Ok(()),
+                Some(RESEND) => break,
+                Some(_) => continue,
+                None => return Err("PS/2 device didn't answer"),
+            }
+        }
+    }
+    Err("PS/2 device kept asking for a resend")
+}
+
+fn controller_command(command: u8) -> Result<(), &'static str> {
+    wait_for_input_empty()?;
+    let mut port: Port<u8> = Port::new(STATUS_COMMAND_PORT);
+    unsafe { port.write(command) };
+    Ok(())
+}
+
+/// Sends a controller command that answers with a byte.
+fn controller_read(command: u8) -> Result<u8, &'static str> {
+    // a key that hasn't been picked up yet would look like the answer
+    flush();
+    controller_command(command)?;
+    read_byte(TIMEOUT).ok_or("PS/2 controller didn't answer")
+}
+
+fn write_data(byte: u8) -> Result<(), &'static str> {
+    wait_for_input_empty()?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    unsafe { port.write(byte) };
+    Ok(())
+}
+
+fn read_byte(timeout: usize) -> Option<u8> {
+    for _ in 0..timeout {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            let mut port: Port<u8> = Port::new(DATA_PORT);
+            return Some(unsafe { port.read() });
+        }
+    }
+    None
+}
+
+/// Throws away whatever is waiting in the output buffer.
+fn flush() {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        unsafe { port.read() };
+    }
+}
+
+/// Waits until the controller has taken the last byte written to it.
+fn wait_for_input_empty() -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err("PS/2 controller timed out")
+}
+
+#[test_case]
+fn test_keyboard_detected() {
+    assert_eq!(device(Ps2Port::First), Some(Device::Mf2Keyboard));
+    assert_eq!(set_typematic(500, 11), Ok(()));
+    assert!(set_typematic(100, 11).is_err());
+}
@@ -0,0 +1,262 @@
+//! A small file system in memory, with room for `MAX_NODES` files and directories of up to
+//! `MAX_FILE_SIZE` bytes each. It is what / is, so that there are directories to mount the other
+//! file systems on.
+
+use crate::vfs::{DirEntry, FileSystem, Inode, Kind, Stat};
+use spin::Mutex;
+
+const MAX_NODES: usize = 64;
+pub const MAX_FILE_SIZE: usize = 2048;
+const MAX_NAME_LENGTH: usize = 32;
+/// The root directory isn't in the table, node `i` has inode `i + 1`.
+const ROOT: Inode = 0;
+
+pub static ROOT_FS: RamFs = RamFs::new();
+
+#[derive(Clone, Copy)]
+struct Node {
+    used: bool,
+    parent: Inode,
+    directory: bool,
+    name: [u8; MAX_NAME_LENGTH],
+    name_len: usize,
+    size: usize,
+    data: [u8; MAX_FILE_SIZE],
+}
+
+impl Node {
+    const EMPTY: Node = Node {
+        used: false,
+        parent: ROOT,
+        directory: false,
+        name: [0; MAX_NAME_LENGTH],
+        name_len: 0,
+        size: 0,
+        data: [0; MAX_FILE_SIZE],
+    };
+
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    fn kind(&self) -> Kind {
+        if self.directory {
+            Kind::Directory
+        } else {
+            Kind::File
+        }
+    }
+}
+
+pub struct RamFs {
+    nodes: Mutex<[Node; MAX_NODES]>,
+}
+
+impl RamFs {
+    pub const fn new() -> Self {
+        RamFs {
+            nodes: Mutex::new([Node::EMPTY; MAX_NODES]),
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The node with `inode`, if it is a file or directory that exists.
+fn node(nodes: &mut [Node; MAX_NODES], inode: Inode) -> Result<&mut Node, &'static str> {
+    inode
+        .checked_sub(1)
+        .and_then(move |i| nodes.get_mut(i as usize))
+        .filter(|node| node.used)
+        .ok_or("no such file")
+}
+
+fn is_dir(nodes: &mut [Node; MAX_NODES], inode: Inode) -> bool {
+    inode == ROOT || node(nodes, inode).is_ok_and(|node| node.directory)
+}
+
+fn find(nodes: &[Node; MAX_NODES], dir: Inode, name: &str) -> Option<Inode> {
+    nodes
+        .iter()
+        .position(|node| node.used && node.parent == dir && node.name() == name)
+        .map(|i| i as Inode + 1)
+}
+
+impl FileSystem for RamFs {
+    fn type_name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Inode {
+        ROOT
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, &'static str> {
+        if inode == ROOT {
+            return Ok(Stat {
+                kind: Kind::Directory,
+                size: 0,
+                inode,
+            });
+        }
+        let mut nodes = self.nodes.lock();
+        let node = node(&mut nodes, inode)?;
+        Ok(Stat {
+            kind: node.kind(),
+            size: node.size as u64,
+            inode,
+        })
+    }
+
+    fn lookup(&self, dir: Inode, name: &str) -> Result<Inode, &'static str> {
+        let mut nodes = self.nodes.lock();
+        if !is_dir(&mut nodes, dir) {
+            return Err("not a directory");
+        }
+        find(&nodes, dir, name).ok_or("no such file")
+    }
+
+    fn read_dir(&self, dir: Inode, index: &mut u64) -> Result<Option<DirEntry>, &'static str> {
+        let mut nodes = self.nodes.lock();
+        if !is_dir(&mut nodes, dir) {
+            return Err("not a directory");
+        }
+        let start = (*index as usize).min(MAX_NODES);
+        match nodes[start..]
+            .iter()
+            .position(|node| node.used && node.parent == dir)
+        {
+            Some(i) => {
+                let i = start + i;
+                *index = i as u64 + 1;
+                let node = &nodes[i];
+                DirEntry::new(node.name(), i as Inode + 1, node.kind()).map(Some)
+            }
+            None => {
+                *index = MAX_NODES as u64;
+                Ok(None)
+            }
+        }
+    }
+
+    fn read(&self, inode: Inode, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let mut nodes = self.nodes.lock();
+        let node = node(&mut nodes, inode)?;
+        if node.directory {
+            return Err("is a directory");
+        }
+        let start = (offset as usize).min(node.size);
+        let len = buf.len().min(node.size - start);
+        buf[..len].copy_from_slice(&node.data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write(&self, inode: Inode, offset: u64, data: &[u8]) -> Result<usize, &'static str> {
+        let mut nodes = self.nodes.lock();
+        let node = node(&mut nodes, inode)?;
+        if node.directory {
+            return Err("is a directory");
+        }
+        let end = (offset as usize)
+            .checked_add(data.len())
+            .filter(|&end| offset as usize <= MAX_FILE_SIZE && end <= MAX_FILE_SIZE)
+            .ok_or("ramfs files are at most 2KiB")?;
+        let start = offset as usize;
+        // a gap left by writing past the end reads as zeroes
+        if start > node.size {
+            node.data[node.size..start].fill(0);
+        }
+        node.data[start..end].copy_from_slice(data);
+        node.size = node.size.max(end);
+        Ok(data.len())
+    }
+
+    fn truncate(&self, inode: Inode, size: u64) -> Result<(), &'static str> {
+        let mut nodes = self.nodes.lock();
+        let node = node(&mut nodes, inode)?;
+        if node.directory {
+            return Err("is a directory");
+        }
+        if size > MAX_FILE_SIZE as u64 {
+            return Err("ramfs files are at most 2KiB");
+        }
+        let size = size as usize;
+        if size > node.size {
+            node.data[node.size..size].fill(0);
+        }
+        node.size = size;
+        Ok(())
+    }
+
+    fn create(&self, dir: Inode, name: &str, kind: Kind) -> Result<Inode, &'static str> {
+        let directory = match kind {
+            Kind::File => false,
+            Kind::Directory => true,
+            _ => return Err("ramfs only has files and directories"),
+        };
+        if name.len() > MAX_NAME_LENGTH {
+            return Err("ramfs names are at most 32 bytes");
+        }
+        let mut nodes = self.nodes.lock();
+        if !is_dir(&mut nodes, dir) {
+            return Err("not a directory");
+        }
+        if find(&nodes, dir, name).is_some() {
+            return Err("the file exists already");
+        }
+        let i = nodes
+            .iter()
+            .position(|node| !node.used)
+            .ok_or("ramfs is full")?;
+        let node = &mut nodes[i];
+        node.used = true;
+        node.parent = dir;
+        node.directory = directory;
+        node.name[..name.len()].copy_from_slice(name.as_bytes());
+        node.name_len = name.len();
+        node.size = 0;
+        Ok(i as Inode + 1)
+    }
+
+    fn remove(&self, dir: Inode, name: &str) -> Result<(), &'static str> {
+        let mut nodes = self.nodes.lock();
+        let inode = find(&nodes, dir, name).ok_or("no such file")?;
+        if nodes.iter().any(|node| node.used && node.parent == inode) {
+            return Err("the directory isn't empty");
+        }
+        node(&mut nodes, inode)?.used = false;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_ramfs() {
+    static FS: RamFs = RamFs::new();
+    let dir = FS.create(ROOT, "levels", Kind::Directory).unwrap();
+    let file = FS.create(dir, "1", Kind::File).unwrap();
+    assert!(FS.create(dir, "1", Kind::Directory).is_err());
+    assert_eq!(FS.lookup(ROOT, "levels"), Ok(dir));
+    assert!(FS.lookup(file, "x").is_err());
+
+    assert_eq!(FS.write(file, 4, b"wall"), Ok(4));
+    let mut buf = [1; 16];
+    assert_eq!(FS.read(file, 0, &mut buf), Ok(8));
+    assert_eq!(&buf[..8], b"\0\0\0\0wall");
+    assert!(FS.write(file, MAX_FILE_SIZE as u64 - 1, b"ab").is_err());
+    FS.truncate(file, 2).unwrap();
+    assert_eq!(FS.stat(file).unwrap().size, 2);
+
+    let mut index = 0;
+    let entry = FS.read_dir(dir, &mut index).unwrap().unwrap();
+    assert_eq!((entry.name(), entry.inode), ("1", file));
+    assert!(FS.read_dir(dir, &mut index).unwrap().is_none());
+
+    assert!(FS.remove(ROOT, "levels").is_err());
+    FS.remove(dir, "1").unwrap();
+    FS.remove(ROOT, "levels").unwrap();
+    assert!(FS.stat(file).is_err());
+}
@@ -0,0 +1,86 @@
+//! A fixed size FIFO queue for handing data from interrupt handlers to the rest of the kernel.
+
+pub struct RingBuffer<T: Copy, const N: usize> {
+    items: [Option<T>; N],
+    next: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            items: [None; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Adds `item` at the back, unless the buffer is full. Returns whether it was added.
+    pub fn push(&mut self, item: T) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.items[(self.next + self.len) % N] = Some(item);
+        self.len += 1;
+        true
+    }
+
+    /// Adds `item` at the back, dropping the oldest item if the buffer is full.
+    pub fn push_overwrite(&mut self, item: T) {
+        if self.len == N {
+            self.pop();
+        }
+        self.push(item);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.items[self.next].take();
+        self.next = (self.next + 1) % N;
+        self.len -= 1;
+        item
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    /// The items from oldest to newest, without taking them.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.len).filter_map(move |i| self.items[(self.next + i) % N])
+    }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_ring_buffer() {
+    let mut buffer: RingBuffer<u8, 3> = RingBuffer::new();
+    assert!(buffer.push(1) && buffer.push(2) && buffer.push(3));
+    assert!(!buffer.push(4));
+    assert_eq!(buffer.pop(), Some(1));
+    buffer.push(4);
+    buffer.push_overwrite(5);
+    assert_eq!(buffer.len(), 3);
+    let mut items = [0; 3];
+    for (slot, item) in items.iter_mut().zip(buffer.iter()) {
+        *slot = item;
+    }
+    assert_eq!(items, [3, 4, 5]);
+    buffer.clear();
+    assert!(buffer.is_empty());
+}
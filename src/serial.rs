@@ -1,29 +1,474 @@
-use lazy_static::lazy_static;
-use spin::Mutex;
-use uart_16550::SerialPort;
-
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
+//! Driver for 16550 compatible UARTs on the four standard COM ports.
+//!
+//! Ports are probed before they are used and can be set to any line settings. What they receive
+//! is buffered from their interrupt. Which port carries the kernel log, the shell console and the
+//! debugger is chosen with `set_role`, by default COM1 carries the log and the console.
+
+use crate::interrupts::PICS;
+use crate::ring_buffer::RingBuffer;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+/// The divisor latch takes the place of the data and interrupt enable registers while the
+/// `LINE_CONTROL_DIVISOR_LATCH` bit is set.
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+const INTERRUPT_DATA_RECEIVED: u8 = 1 << 0;
+const FIFO_ENABLE: u8 = 1 << 0;
+const FIFO_CLEAR_RECEIVE: u8 = 1 << 1;
+const FIFO_CLEAR_TRANSMIT: u8 = 1 << 2;
+const LINE_CONTROL_DIVISOR_LATCH: u8 = 1 << 7;
+/// DTR, RTS and OUT2, which connects the interrupt line.
+const MODEM_CONTROL_NORMAL: u8 = 0x0b;
+/// RTS, OUT1, OUT2 and loopback, which feeds sent bytes straight back.
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1e;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// The UART clock divided by 16, the fastest baud rate.
+const MAX_BAUD_RATE: u32 = 115_200;
+/// Status polls before giving up on sending a byte.
+const TIMEOUT: usize = 100_000;
+
+/// Bytes received that haven't been read yet, further ones are dropped until there is room again.
+const INPUT_SIZE: usize = 256;
+/// What gdb sends to stop the kernel while it runs.
+const BREAK: u8 = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    pub const fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        ["com1", "com2", "com3", "com4"][self as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<ComPort> {
+        ComPort::ALL
+            .iter()
+            .copied()
+            .find(|port| port.name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// How full the receive FIFO gets before the UART interrupts. It also interrupts when bytes have
+/// sat in the FIFO for a while, so a lone keypress still arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoTrigger {
+    Disabled,
+    Bytes1,
+    Bytes4,
+    Bytes8,
+    Bytes14,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    pub baud_rate: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub fifo_trigger: FifoTrigger,
+}
+
+impl LineConfig {
+    /// 115200 baud, 8 data bits, no parity, 1 stop bit.
+    pub const DEFAULT: LineConfig = LineConfig {
+        baud_rate: MAX_BAUD_RATE,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+        fifo_trigger: FifoTrigger::Bytes14,
     };
+
+    fn line_control(&self) -> Result<u8, &'static str> {
+        if !(5..=8).contains(&self.data_bits) {
+            return Err("UARTs only do 5 to 8 data bits");
+        }
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1,
+        };
+        Ok((self.data_bits - 5) | stop_bits << 2 | parity << 3)
+    }
+
+    fn divisor(&self) -> Result<u16, &'static str> {
+        match MAX_BAUD_RATE.checked_div(self.baud_rate) {
+            Some(divisor) if divisor * self.baud_rate == MAX_BAUD_RATE => Ok(divisor as u16),
+            _ => Err("baud rate has to divide 115200"),
+        }
+    }
+
+    fn fifo_control(&self) -> u8 {
+        let trigger = match self.fifo_trigger {
+            FifoTrigger::Disabled => return FIFO_CLEAR_RECEIVE | FIFO_CLEAR_TRANSMIT,
+            FifoTrigger::Bytes1 => 0b00,
+            FifoTrigger::Bytes4 => 0b01,
+            FifoTrigger::Bytes8 => 0b10,
+            FifoTrigger::Bytes14 => 0b11,
+        };
+        FIFO_ENABLE | FIFO_CLEAR_RECEIVE | FIFO_CLEAR_TRANSMIT | trigger << 6
+    }
 }
 
-#[doc(hidden)]
-pub fn _print(args: ::core::fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+impl Default for LineConfig {
+    fn default() -> Self {
+        LineConfig::DEFAULT
+    }
+}
+
+/// What a serial port is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// `serial_print!` and the kernel log.
+    Log,
+    /// The shell.
+    Console,
+    Debugger,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Log, Role::Console, Role::Debugger];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Log => "log",
+            Role::Console => "console",
+            Role::Debugger => "debugger",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Role> {
+        Role::ALL.iter().copied().find(|role| role.name() == name)
+    }
+}
+
+pub struct Uart {
+    base: u16,
+}
+
+impl Uart {
+    /// # Safety
+    ///
+    /// Writing to the ports from `base` to `base + 7` must not have effects other than on a UART
+    /// that may be there.
+    pub const unsafe fn new(base: u16) -> Uart {
+        Uart { base }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        let mut port: Port<u8> = Port::new(self.base + register);
+        unsafe { port.read() }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        let mut port: Port<u8> = Port::new(self.base + register);
+        unsafe { port.write(value) };
+    }
+
+    /// Checks that there is a UART: the scratch register has to keep what is written to it and a
+    /// byte sent in loopback mode has to come back.
+    pub fn probe(&mut self) -> bool {
+        for &value in &[0x5a, 0xa5] {
+            self.write(SCRATCH, value);
+            if self.read(SCRATCH) != value {
+                return false;
+            }
+        }
+
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(MODEM_CONTROL, MODEM_CONTROL_LOOPBACK);
+        while self.try_receive().is_some() {}
+        self.write(DATA, 0xae);
+        let echoed = (0..TIMEOUT).find_map(|_| self.try_receive()) == Some(0xae);
+        self.write(MODEM_CONTROL, MODEM_CONTROL_NORMAL);
+        echoed
+    }
+
+    /// Sets the line up as `config` says and turns on the receive interrupt.
+    pub fn configure(&mut self, config: &LineConfig) -> Result<(), &'static str> {
+        let line_control = config.line_control()?;
+        let divisor = config.divisor()?;
+
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, LINE_CONTROL_DIVISOR_LATCH);
+        self.write(DIVISOR_LOW, divisor as u8);
+        self.write(DIVISOR_HIGH, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, line_control);
+        self.write(FIFO_CONTROL, config.fifo_control());
+        self.write(MODEM_CONTROL, MODEM_CONTROL_NORMAL);
+        self.write(INTERRUPT_ENABLE, INTERRUPT_DATA_RECEIVED);
+        Ok(())
+    }
+
+    /// Sends `byte` once the transmitter has room for it. Gives up after a while rather than hang
+    /// on a port that stopped working.
+    pub fn send(&mut self, byte: u8) {
+        for _ in 0..TIMEOUT {
+            if self.read(LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY != 0 {
+                self.write(DATA, byte);
+                return;
+            }
+        }
+    }
+
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.read(LINE_STATUS) & LINE_STATUS_DATA_READY != 0 {
+            Some(self.read(DATA))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+static UARTS: [Mutex<Uart>; 4] = unsafe {
+    [
+        Mutex::new(Uart::new(ComPort::Com1.base())),
+        Mutex::new(Uart::new(ComPort::Com2.base())),
+        Mutex::new(Uart::new(ComPort::Com3.base())),
+        Mutex::new(Uart::new(ComPort::Com4.base())),
+    ]
+};
+static PRESENT: [AtomicBool; 4] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+static INPUTS: [Mutex<RingBuffer<u8, INPUT_SIZE>>; 4] = [
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+];
+static INIT: Once<()> = Once::new();
+
+/// The port of each `Role`, or `NO_PORT`.
+static ROLES: [AtomicU8; 3] = [
+    AtomicU8::new(ComPort::Com1 as u8),
+    AtomicU8::new(ComPort::Com1 as u8),
+    AtomicU8::new(NO_PORT),
+];
+const NO_PORT: u8 = u8::MAX;
+
+/// Probes the COM ports and sets the ones that are there to `LineConfig::DEFAULT`, with their
+/// interrupts on. Happens on first use of a port if it wasn't called before.
+pub fn init() {
+    INIT.call_once(|| {
+        interrupts::without_interrupts(|| {
+            for &port in &ComPort::ALL {
+                let mut uart = UARTS[port as usize].lock();
+                let present = uart.probe() && uart.configure(&LineConfig::DEFAULT).is_ok();
+                PRESENT[port as usize].store(present, Ordering::SeqCst);
+            }
+            // COM1 and COM3 share IRQ 4, COM2 and COM4 share IRQ 3
+            unsafe {
+                let mut pics = PICS.lock();
+                let [primary, secondary] = pics.read_masks();
+                pics.write_masks(primary & !(1 << 3 | 1 << 4), secondary);
+            }
+        });
+    });
+}
+
+pub fn is_present(port: ComPort) -> bool {
+    init();
+    PRESENT[port as usize].load(Ordering::SeqCst)
+}
+
+/// Changes the line settings of `port`.
+pub fn configure(port: ComPort, config: &LineConfig) -> Result<(), &'static str> {
+    if !is_present(port) {
+        return Err("no UART at that port");
+    }
+    interrupts::without_interrupts(|| UARTS[port as usize].lock().configure(config))
+}
+
+/// Makes `port` carry `role` from now on, or nothing if `port` is `None`. A port can have several
+/// roles.
+pub fn set_role(role: Role, port: Option<ComPort>) -> Result<(), &'static str> {
+    let value = match port {
+        Some(port) if !is_present(port) => return Err("no UART at that port"),
+        Some(port) => port as u8,
+        None => NO_PORT,
+    };
+    ROLES[role as usize].store(value, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn role_port(role: Role) -> Option<ComPort> {
+    ComPort::ALL
+        .get(ROLES[role as usize].load(Ordering::SeqCst) as usize)
+        .copied()
+}
+
+/// Runs `f` on the port carrying `role`, if there is one.
+pub fn with_role<R>(role: Role, f: impl FnOnce(&mut Uart) -> R) -> Option<R> {
+    let port = role_port(role).filter(|&port| is_present(port))?;
+    Some(interrupts::without_interrupts(|| {
+        f(&mut UARTS[port as usize].lock())
+    }))
+}
+
+/// Takes the oldest byte received on `port`.
+pub fn read_byte(port: ComPort) -> Option<u8> {
+    interrupts::without_interrupts(|| INPUTS[port as usize].lock().pop())
+}
 
+/// Sends `bytes` on `port` as they are.
+pub fn write_bytes(port: ComPort, bytes: &[u8]) -> Result<(), &'static str> {
+    if !is_present(port) {
+        return Err("no UART at that port");
+    }
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
+        let mut uart = UARTS[port as usize].lock();
+        for &byte in bytes {
+            uart.send(byte);
+        }
+    });
+    Ok(())
+}
+
+/// Takes the oldest character typed on the console port's terminal, like `keyboard::read_char`.
+/// Enter gives `\n` and both Backspace and Delete give `\u{8}`. Only ASCII is passed on.
+pub fn read_char() -> Option<char> {
+    let port = role_port(Role::Console)?;
+    while let Some(byte) = read_byte(port) {
+        match byte {
+            b'\r' => return Some('\n'),
+            0x08 | 0x7f => return Some('\u{8}'),
+            byte if byte.is_ascii() => return Some(byte as char),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Moves everything waiting in the receive FIFOs into the input buffers, called from the IRQ 3
+/// and 4 handlers. Returns true if the debugger sent a break (Ctrl+C), which isn't buffered.
+pub(crate) fn receive_pending() -> bool {
+    let debugger = role_port(Role::Debugger);
+    let mut stop = false;
+    for &port in &ComPort::ALL {
+        if !PRESENT[port as usize].load(Ordering::SeqCst) {
+            continue;
+        }
+        // everything else takes the port with interrupts off, so this only fails mid-probe
+        let (mut uart, mut input) = match (
+            UARTS[port as usize].try_lock(),
+            INPUTS[port as usize].try_lock(),
+        ) {
+            (Some(uart), Some(input)) => (uart, input),
+            _ => continue,
+        };
+        while let Some(byte) = uart.try_receive() {
+            if byte == BREAK && debugger == Some(port) {
+                stop = true;
+            } else {
+                input.push(byte);
+            }
+        }
+    }
+    stop
+}
+
+/// Writes to a serial terminal, which wants `\r\n` line endings and has to be told to erase the
+/// character before the cursor.
+struct Terminal<'a>(&'a mut Uart);
+
+impl fmt::Write for Terminal<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\n' => self.0.write_str("\r\n")?,
+                '\u{8}' => self.0.write_str("\u{8} \u{8}")?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints to the terminal on the console port, see `Terminal`.
+pub fn print_terminal(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    with_role(Role::Console, |uart| {
+        Terminal(uart)
             .write_fmt(args)
             .expect("Printing to serial failed");
     });
 }
 
-/// Prints to the host through the serial interface.
+#[doc(hidden)]
+pub fn _print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    with_role(Role::Log, |uart| {
+        uart.write_fmt(args).expect("Printing to serial failed");
+    });
+}
+
+/// Prints to the host through the serial port carrying the log.
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {
@@ -31,7 +476,7 @@ macro_rules! serial_print {
     };
 }
 
-/// Prints to the host through the serial interface, appending a newline.
+/// Prints to the host through the serial port carrying the log, appending a newline.
 #[macro_export]
 macro_rules! serial_println {
     () => ($crate::serial_print!("\n"));
@@ -39,3 +484,45 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Makes it look like `bytes` were received on `port`.
+#[cfg(test)]
+pub(crate) fn feed_input(port: ComPort, bytes: &[u8]) {
+    interrupts::without_interrupts(|| {
+        let mut input = INPUTS[port as usize].lock();
+        for &byte in bytes {
+            input.push(byte);
+        }
+    });
+}
+
+#[test_case]
+fn test_read_char() {
+    feed_input(ComPort::Com1, b"ls\x7f\xe9\r");
+    assert_eq!(read_char(), Some('l'));
+    assert_eq!(read_char(), Some('s'));
+    assert_eq!(read_char(), Some('\u{8}'));
+    assert_eq!(read_char(), Some('\n'));
+    assert_eq!(read_char(), None);
+}
+
+#[test_case]
+fn test_line_config() {
+    let config = LineConfig {
+        baud_rate: 9600,
+        data_bits: 7,
+        parity: Parity::Even,
+        stop_bits: StopBits::Two,
+        fifo_trigger: FifoTrigger::Bytes4,
+    };
+    assert_eq!(config.divisor(), Ok(12));
+    assert_eq!(config.line_control(), Ok(0b0001_1110));
+    assert_eq!(config.fifo_control(), 0b0100_0111);
+    assert!(LineConfig {
+        baud_rate: 100_000,
+        ..config
+    }
+    .divisor()
+    .is_err());
+    assert!(is_present(ComPort::Com1));
+}
@@ -0,0 +1,519 @@
+//! A small command line for poking at the kernel, run as a `Program` on its own console. It can
+//! be typed at on the keyboard or on a terminal attached to COM1, and answers on both.
+
+use crate::block;
+use crate::fat;
+use crate::gdb;
+use crate::keyboard::{self, Layout, ScancodeSet};
+use crate::logger::{self, Sink};
+use crate::monitor;
+use crate::pci::{self, Bar};
+use crate::program::{self, program_handler, Program};
+use crate::serial::{self, ComPort, Role};
+use crate::snake::SnakeGame;
+use crate::vfs::{self, Kind, OpenFlags};
+use crate::vga_buffer;
+use core::fmt;
+use core::str::SplitWhitespace;
+use pc_keyboard::DecodedKey;
+
+/// Like `print!`, but to both places the shell is used from.
+macro_rules! out {
+    ($($arg:tt)*) => ($crate::shell::print(format_args!($($arg)*)));
+}
+
+/// Like `println!`, but to both places the shell is used from.
+macro_rules! outln {
+    () => (out!("\n"));
+    ($($arg:tt)*) => (out!("{}\n", format_args!($($arg)*)));
+}
+
+/// Longest command line in bytes, further characters are ignored.
+const LINE_LENGTH: usize = 128;
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(SplitWhitespace) -> Result<(), &'static str>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "lists the commands",
+        run: help,
+    },
+    Command {
+        name: "clear",
+        help: "clears the screen",
+        run: clear,
+    },
+    Command {
+        name: "layout",
+        help: "[us|uk|de|dvorak|azerty|jis] [set1|set2], shows or sets the keyboard layout",
+        run: layout,
+    },
+    Command {
+        name: "serial",
+        help: "[log|console|debugger com1-4|none], shows the COM ports or picks one for a role",
+        run: serial_roles,
+    },
+    Command {
+        name: "dmesg",
+        help: "[clear], shows the latest log messages",
+        run: dmesg,
+    },
+    Command {
+        name: "loglevel",
+        help: "[module] [off|error|warn|info|debug|trace|default], shows or sets log filters",
+        run: loglevel,
+    },
+    Command {
+        name: "logsink",
+        help: "[serial|vga|dmesg level], shows or sets the levels the log outputs get",
+        run: logsink,
+    },
+    Command {
+        name: "lspci",
+        help: "[-v], lists the PCI devices, with their BARs and capabilities if -v",
+        run: lspci,
+    },
+    Command {
+        name: "lsblk",
+        help: "lists the disks",
+        run: lsblk,
+    },
+    Command {
+        name: "ls",
+        help: "[path], lists a directory",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        help: "path, shows a file",
+        run: cat,
+    },
+    Command {
+        name: "write",
+        help: "path text, replaces a file with a line of text",
+        run: write,
+    },
+    Command {
+        name: "mkdir",
+        help: "path, makes a directory",
+        run: mkdir,
+    },
+    Command {
+        name: "rm",
+        help: "path, removes a file or empty directory",
+        run: rm,
+    },
+    Command {
+        name: "mount",
+        help: "[disk path], shows the mounts or mounts a FAT disk",
+        run: mount,
+    },
+    Command {
+        name: "umount",
+        help: "path, unmounts a disk",
+        run: umount,
+    },
+    Command {
+        name: "gdb",
+        help: "stops the kernel until gdb attaches on the debugger port",
+        run: debug,
+    },
+    Command {
+        name: "monitor",
+        help: "stops the kernel in the monitor",
+        run: enter_monitor,
+    },
+    Command {
+        name: "snake",
+        help: "plays snake",
+        run: snake,
+    },
+];
+
+pub struct Shell;
+
+impl Program for Shell {
+    fn run(&mut self) -> Result<(), &'static str> {
+        let mut line = [0; LINE_LENGTH];
+        loop {
+            out!("> ");
+            let len = read_line(&mut line);
+            // only whole characters are ever stored
+            let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+            if let Err(err) = execute(line) {
+                outln!("error: {}", err);
+            }
+        }
+    }
+}
+
+/// Runs the command on `line`, if there is one.
+pub fn execute(line: &str) -> Result<(), &'static str> {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    let command = COMMANDS
+        .iter()
+        .find(|command| command.name == name)
+        .ok_or("unknown command, try help")?;
+    (command.run)(words)
+}
+
+/// Prints to the console and to the serial terminal.
+fn print(args: fmt::Arguments) {
+    vga_buffer::_print(args);
+    serial::print_terminal(args);
+}
+
+/// Takes the oldest key typed on the keyboard or on the serial terminal, which only types
+/// characters.
+pub fn read_key() -> Option<DecodedKey> {
+    keyboard::read_key().or_else(|| serial::read_char().map(DecodedKey::Unicode))
+}
+
+/// Reads a line typed on the keyboard or the serial terminal into `line`, echoing it, and returns
+/// its length.
+fn read_line(line: &mut [u8; LINE_LENGTH]) -> usize {
+    let mut len = 0;
+    loop {
+        let c = match read_key() {
+            Some(DecodedKey::Unicode(c)) => c,
+            Some(_) => continue,
+            None => {
+                x86_64::instructions::hlt();
+                continue;
+            }
+        };
+        match c {
+            '\n' => {
+                outln!();
+                return len;
+            }
+            '\u{8}' if len > 0 => {
+                // drop the whole last character, not just its last byte
+                len -= 1;
+                while len > 0 && line[len] & 0xc0 == 0x80 {
+                    len -= 1;
+                }
+                out!("\u{8}");
+            }
+            c if !c.is_control() && len + c.len_utf8() <= LINE_LENGTH => {
+                c.encode_utf8(&mut line[len..]);
+                len += c.len_utf8();
+                out!("{}", c);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn help(_args: SplitWhitespace) -> Result<(), &'static str> {
+    for command in COMMANDS {
+        outln!("{:8} {}", command.name, command.help);
+    }
+    Ok(())
+}
+
+fn clear(_args: SplitWhitespace) -> Result<(), &'static str> {
+    vga_buffer::output().lock().clear();
+    Ok(())
+}
+
+fn layout(args: SplitWhitespace) -> Result<(), &'static str> {
+    for arg in args {
+        if let Some(layout) = Layout::from_name(arg) {
+            keyboard::set_layout(layout);
+        } else if let Some(set) = ScancodeSet::from_name(arg) {
+            keyboard::set_scancode_set(set)?;
+        } else {
+            return Err("unknown layout or scancode set");
+        }
+    }
+    let (layout, set) = keyboard::layout();
+    outln!("{} {}", layout.name(), set.name());
+    Ok(())
+}
+
+fn serial_roles(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    if let Some(role) = args.next() {
+        let role = Role::from_name(role).ok_or("unknown role")?;
+        let port = match args.next().ok_or("which port?")? {
+            "none" => None,
+            port => Some(ComPort::from_name(port).ok_or("unknown port")?),
+        };
+        serial::set_role(role, port)?;
+    }
+    for &port in &ComPort::ALL {
+        if !serial::is_present(port) {
+            continue;
+        }
+        out!("{}", port.name());
+        for &role in &Role::ALL {
+            if serial::role_port(role) == Some(port) {
+                out!(" {}", role.name());
+            }
+        }
+        outln!();
+    }
+    Ok(())
+}
+
+fn dmesg(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    match args.next() {
+        None => logger::dmesg(|entry| outln!("{}", entry)),
+        Some("clear") => logger::clear_dmesg(),
+        Some(_) => return Err("dmesg only knows clear"),
+    }
+    Ok(())
+}
+
+fn loglevel(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    match (args.next(), args.next()) {
+        (None, _) => {}
+        (Some(level), None) => logger::set_level(logger::parse_level(level)?),
+        (Some(module), Some("default")) => logger::set_module_level(module, None)?,
+        (Some(module), Some(level)) => {
+            logger::set_module_level(module, Some(logger::parse_level(level)?))?
+        }
+    }
+    outln!("default {}", logger::level());
+    logger::module_levels(|module, level| outln!("{} {}", module, level));
+    Ok(())
+}
+
+fn logsink(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    if let Some(sink) = args.next() {
+        let sink = Sink::from_name(sink).ok_or("unknown log output")?;
+        let level = logger::parse_level(args.next().ok_or("which level?")?)?;
+        logger::set_sink_level(sink, level);
+    }
+    for &sink in &Sink::ALL {
+        outln!("{} {}", sink.name(), logger::sink_level(sink));
+    }
+    Ok(())
+}
+
+fn lspci(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    let verbose = match args.next() {
+        None => false,
+        Some("-v") => true,
+        Some(_) => return Err("lspci only knows -v"),
+    };
+    for device in pci::devices() {
+        outln!(
+            "{} {:04x}:{:04x} {} (rev {:02x})",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            pci::class_name(device.class, device.subclass),
+            device.revision
+        );
+        if !verbose {
+            continue;
+        }
+        outln!(
+            "  class {:02x}:{:02x}:{:02x}, driver {}",
+            device.class,
+            device.subclass,
+            device.prog_if,
+            pci::driver_of(device.address).unwrap_or("none")
+        );
+        match device.interrupt_pin {
+            0 => {}
+            pin @ 1..=4 => outln!(
+                "  interrupt pin {}, line {}",
+                (b'A' + pin - 1) as char,
+                device.interrupt_line
+            ),
+            // not one of INTA# to INTD#, a broken device
+            pin => outln!(
+                "  interrupt pin {:#04x}, line {}",
+                pin,
+                device.interrupt_line
+            ),
+        }
+        for (index, bar) in device.bars.iter().enumerate() {
+            match *bar {
+                Some(Bar::Memory {
+                    addr,
+                    size,
+                    prefetchable,
+                    is_64_bit,
+                }) => outln!(
+                    "  BAR{} memory at {:#x}, size {:#x}{}{}",
+                    index,
+                    addr,
+                    size,
+                    if is_64_bit { ", 64 bit" } else { "" },
+                    if prefetchable { ", prefetchable" } else { "" }
+                ),
+                Some(Bar::Io { port, size }) => {
+                    outln!("  BAR{} I/O ports at {:#x}, size {:#x}", index, port, size)
+                }
+                None => {}
+            }
+        }
+        for capability in device.capabilities.iter().flatten() {
+            outln!(
+                "  capability {:02x} {} at {:#x}",
+                capability.id,
+                pci::capability_name(capability.id),
+                capability.offset
+            );
+        }
+    }
+    Ok(())
+}
+
+fn lsblk(_args: SplitWhitespace) -> Result<(), &'static str> {
+    for device in block::devices() {
+        let size = device.block_count() * device.block_size() as u64;
+        outln!(
+            "{:8} {:>10} blocks of {:>4} bytes, {} MiB",
+            device.name(),
+            device.block_count(),
+            device.block_size(),
+            size >> 20
+        );
+    }
+    Ok(())
+}
+
+fn ls(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    let path = args.next().unwrap_or("/");
+    let fs = vfs::resolve(path)?.fs();
+    program::with_files(|files| {
+        let fd = files.open(path, OpenFlags::READ)?;
+        let result = (|| {
+            while let Some(entry) = files.read_dir(fd)? {
+                match entry.kind {
+                    Kind::Directory => outln!("{}/", entry.name()),
+                    Kind::File => {
+                        let size = fs.stat(entry.inode)?.size;
+                        outln!("{:24} {:>10}", entry.name(), size)
+                    }
+                    _ => outln!("{}", entry.name()),
+                }
+            }
+            Ok(())
+        })();
+        files.close(fd)?;
+        result
+    })
+}
+
+fn cat(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    let path = args.next().ok_or("which file?")?;
+    program::with_files(|files| {
+        let fd = files.open(path, OpenFlags::READ)?;
+        let mut buf = [0; 512];
+        let result = (|| loop {
+            let len = files.read(fd, &mut buf)?;
+            if len == 0 {
+                return Ok(());
+            }
+            let mut rest = &buf[..len];
+            while !rest.is_empty() {
+                match core::str::from_utf8(rest) {
+                    Ok(valid) => {
+                        out!("{}", valid);
+                        break;
+                    }
+                    Err(err) => {
+                        let (valid, invalid) = rest.split_at(err.valid_up_to());
+                        out!("{}", core::str::from_utf8(valid).unwrap_or(""));
+                        out!("{}", char::REPLACEMENT_CHARACTER);
+                        rest = &invalid[err.error_len().unwrap_or(invalid.len())..];
+                    }
+                }
+            }
+        })();
+        files.close(fd)?;
+        result
+    })
+}
+
+fn write(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    let path = args.next().ok_or("which file?")?;
+    program::with_files(|files| {
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let fd = files.open(path, flags)?;
+        let result = (|| {
+            for (i, word) in args.enumerate() {
+                if i > 0 {
+                    files.write(fd, b" ")?;
+                }
+                files.write(fd, word.as_bytes())?;
+            }
+            files.write(fd, b"\n").map(|_| ())
+        })();
+        files.close(fd)?;
+        result
+    })
+}
+
+fn mkdir(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    vfs::create(args.next().ok_or("which directory?")?, Kind::Directory).map(|_| ())
+}
+
+fn rm(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    vfs::remove(args.next().ok_or("which file?")?)
+}
+
+fn mount(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    if let Some(name) = args.next() {
+        let path = args.next().ok_or("where to?")?;
+        let device = block::find(name).ok_or("no such disk, see lsblk")?;
+        vfs::mount(path, fat::mount(device)?)?;
+    }
+    vfs::mounts(|point, fs| outln!("{:24} {}", point, fs.type_name()));
+    Ok(())
+}
+
+fn umount(mut args: SplitWhitespace) -> Result<(), &'static str> {
+    vfs::unmount(args.next().ok_or("which mount?")?)
+}
+
+fn debug(_args: SplitWhitespace) -> Result<(), &'static str> {
+    let port = serial::role_port(Role::Debugger)
+        .filter(|&port| serial::is_present(port))
+        .ok_or("no debugger port, see serial")?;
+    // gdb would take the log and the console output for garbled packets
+    if Role::ALL
+        .iter()
+        .any(|&role| role != Role::Debugger && serial::role_port(role) == Some(port))
+    {
+        return Err("the debugger port can't be used for anything else");
+    }
+    outln!("waiting for gdb on {}", port.name());
+    gdb::breakpoint();
+    Ok(())
+}
+
+fn enter_monitor(_args: SplitWhitespace) -> Result<(), &'static str> {
+    monitor::enter();
+    Ok(())
+}
+
+fn snake(_args: SplitWhitespace) -> Result<(), &'static str> {
+    program_handler(&mut SnakeGame)
+}
+
+#[test_case]
+fn test_execute() {
+    assert_eq!(execute("   "), Ok(()));
+    assert_eq!(execute("help"), Ok(()));
+    assert!(execute("no-such-command").is_err());
+    assert!(execute("layout qwertz").is_err());
+    assert!(execute("loglevel ps2 loud").is_err());
+    assert!(execute("lspci -x").is_err());
+    assert!(execute("cat").is_err());
+    assert!(execute("mount nodisk /mnt").is_err());
+}
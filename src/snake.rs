@@ -1,10 +1,12 @@
-use crate::interrupts::{LASTPRESSED, STOPWATCH};
+use crate::interrupts::STOPWATCH;
+use crate::keyboard;
 use crate::program::Program;
-use crate::vga_buffer::WRITER;
+use crate::shell;
+use crate::vga_buffer;
 use crate::{print, println};
 use core::mem::MaybeUninit;
 use fixed_slice_vec::FixedSliceVec;
-use pc_keyboard::DecodedKey;
+use pc_keyboard::{DecodedKey, KeyCode};
 use rand::rngs::SmallRng;
 use rand::RngCore;
 use rand::SeedableRng;
@@ -60,17 +62,31 @@ impl Program for SnakeGame {
         let mut eating = false;
         let mut death = false;
 
+        keyboard::clear_events();
+        // frames are drawn into the back buffer and presented once they are complete
+        vga_buffer::output().lock().set_auto_present(false);
+
         // main game loop
         loop {
-            if let DecodedKey::Unicode(val) = *LASTPRESSED.lock() {
-                let new_direction = match val {
-                    'w' => Direction::Up,
-                    'a' => Direction::Left,
-                    's' => Direction::Down,
-                    'd' => Direction::Right,
-                    _ => direction.clone(),
+            // takes the last direction pressed since the previous tile, wasd or the arrow keys
+            let moving = direction.clone();
+            while let Some(key) = shell::read_key() {
+                let new_direction = match key {
+                    DecodedKey::RawKey(KeyCode::ArrowUp) | DecodedKey::Unicode('w') => {
+                        Direction::Up
+                    }
+                    DecodedKey::RawKey(KeyCode::ArrowLeft) | DecodedKey::Unicode('a') => {
+                        Direction::Left
+                    }
+                    DecodedKey::RawKey(KeyCode::ArrowDown) | DecodedKey::Unicode('s') => {
+                        Direction::Down
+                    }
+                    DecodedKey::RawKey(KeyCode::ArrowRight) | DecodedKey::Unicode('d') => {
+                        Direction::Right
+                    }
+                    _ => continue,
                 };
-                if new_direction != direction.get_opposite() {
+                if new_direction != moving.get_opposite() {
                     direction = new_direction;
                 }
             }
@@ -145,6 +161,7 @@ impl Program for SnakeGame {
 
             // displays bottom border
             println!("{empty:->width$}", empty = "", width = GAME_LENGTH as usize);
+            vga_buffer::output().lock().present_on_retrace();
 
             // sleeps for the time per tile
             let start_time = *STOPWATCH.lock();
@@ -154,7 +171,7 @@ impl Program for SnakeGame {
                         break;
                     }
                 } else {
-                    println!("lock is locked");
+                    log::trace!("stopwatch is locked, retrying");
                 }
                 for _ in 0..100000 {}
             }
@@ -163,11 +180,14 @@ impl Program for SnakeGame {
             // breaking the loop
             if death {
                 println!("You Died");
+                let mut writer = vga_buffer::output().lock();
+                writer.set_auto_present(true);
+                writer.present();
                 break;
             }
 
             // clears the screen for the next iteration of the loop
-            WRITER.lock().clear();
+            vga_buffer::output().lock().clear();
         }
         Ok(())
     }
@@ -0,0 +1,377 @@
+//! A file system in RAM for scratch files, mounted at /tmp. Unlike ramfs the file contents are
+//! kept in frames taken as they are needed, so files can be up to `MAX_FILE_SIZE` bytes. Each file
+//! has an index frame with the physical addresses of its data frames, parts of a file that were
+//! never written have no frame and read as zeroes.
+//!
+//! Frames of removed or truncated files go on a free list that all tmpfs instances share, as the
+//! frame allocator can't take them back.
+
+use crate::memory;
+use crate::vfs::{DirEntry, FileSystem, Inode, Kind, Stat};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+const MAX_NODES: usize = 256;
+const MAX_NAME_LENGTH: usize = 64;
+const PAGE_SIZE: usize = 4096;
+/// How many data frames the index frame of a file has room for.
+const PAGES_PER_FILE: usize = PAGE_SIZE / 8;
+pub const MAX_FILE_SIZE: u64 = (PAGES_PER_FILE * PAGE_SIZE) as u64;
+/// The root directory isn't in the table, node `i` has inode `i + 1`.
+const ROOT: Inode = 0;
+
+pub static TMPFS: TmpFs = TmpFs::new();
+
+/// The first frame of the free list, 0 if it is empty. Each free frame starts with the address of
+/// the next one. Frame 0 is never handed out by the frame allocator, so 0 can mean none here and
+/// in the index frames.
+static FREE_FRAMES: Mutex<u64> = Mutex::new(0);
+
+fn frame_bytes(addr: u64) -> &'static mut [u8; PAGE_SIZE] {
+    unsafe { &mut *memory::phys_to_virt(PhysAddr::new(addr)).as_mut_ptr() }
+}
+
+fn index_entries(addr: u64) -> &'static mut [u64; PAGES_PER_FILE] {
+    unsafe { &mut *memory::phys_to_virt(PhysAddr::new(addr)).as_mut_ptr() }
+}
+
+/// Takes a frame off the free list or from the frame allocator and zeroes it.
+fn allocate() -> Result<u64, &'static str> {
+    let mut free = FREE_FRAMES.lock();
+    let addr = match *free {
+        0 => memory::allocate_frame()
+            .ok_or("out of memory")?
+            .start_address()
+            .as_u64(),
+        addr => {
+            *free = index_entries(addr)[0];
+            addr
+        }
+    };
+    frame_bytes(addr).fill(0);
+    Ok(addr)
+}
+
+fn free(addr: u64) {
+    let mut free = FREE_FRAMES.lock();
+    index_entries(addr)[0] = *free;
+    *free = addr;
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    used: bool,
+    parent: Inode,
+    directory: bool,
+    name: [u8; MAX_NAME_LENGTH],
+    name_len: usize,
+    size: u64,
+    /// The index frame, 0 until something is written.
+    index: u64,
+}
+
+impl Node {
+    const EMPTY: Node = Node {
+        used: false,
+        parent: ROOT,
+        directory: false,
+        name: [0; MAX_NAME_LENGTH],
+        name_len: 0,
+        size: 0,
+        index: 0,
+    };
+
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    fn kind(&self) -> Kind {
+        if self.directory {
+            Kind::Directory
+        } else {
+            Kind::File
+        }
+    }
+
+    /// The data frame for page `page` of the file, allocated if `create` is set.
+    fn page(&mut self, page: usize, create: bool) -> Result<Option<u64>, &'static str> {
+        if self.index == 0 {
+            if !create {
+                return Ok(None);
+            }
+            self.index = allocate()?;
+        }
+        let entry = &mut index_entries(self.index)[page];
+        if *entry == 0 {
+            if !create {
+                return Ok(None);
+            }
+            *entry = allocate()?;
+        }
+        Ok(Some(*entry))
+    }
+
+    /// Gives back the frames past `size` bytes and zeroes the rest of the last page, so that the
+    /// file reads as zeroes there if it grows again.
+    fn shrink(&mut self, size: u64) {
+        if self.index == 0 {
+            return;
+        }
+        let entries = index_entries(self.index);
+        let keep = (size as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+        for entry in entries[keep..].iter_mut().filter(|entry| **entry != 0) {
+            free(*entry);
+            *entry = 0;
+        }
+        let in_page = size as usize % PAGE_SIZE;
+        if in_page != 0 && entries[keep - 1] != 0 {
+            frame_bytes(entries[keep - 1])[in_page..].fill(0);
+        }
+        if keep == 0 {
+            free(self.index);
+            self.index = 0;
+        }
+    }
+}
+
+pub struct TmpFs {
+    nodes: Mutex<[Node; MAX_NODES]>,
+}
+
+impl TmpFs {
+    pub const fn new() -> Self {
+        TmpFs {
+            nodes: Mutex::new([Node::EMPTY; MAX_NODES]),
+        }
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The node with `inode`, if it is a file or directory that exists.
+fn node(nodes: &mut [Node; MAX_NODES], inode: Inode) -> Result<&mut Node, &'static str> {
+    inode
+        .checked_sub(1)
+        .and_then(move |i| nodes.get_mut(i as usize))
+        .filter(|node| node.used)
+        .ok_or("no such file")
+}
+
+fn file(nodes: &mut [Node; MAX_NODES], inode: Inode) -> Result<&mut Node, &'static str> {
+    let node = node(nodes, inode)?;
+    if node.directory {
+        return Err("is a directory");
+    }
+    Ok(node)
+}
+
+fn is_dir(nodes: &mut [Node; MAX_NODES], inode: Inode) -> bool {
+    inode == ROOT || node(nodes, inode).is_ok_and(|node| node.directory)
+}
+
+fn find(nodes: &[Node; MAX_NODES], dir: Inode, name: &str) -> Option<Inode> {
+    nodes
+        .iter()
+        .position(|node| node.used && node.parent == dir && node.name() == name)
+        .map(|i| i as Inode + 1)
+}
+
+impl FileSystem for TmpFs {
+    fn type_name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Inode {
+        ROOT
+    }
+
+    fn stat(&self, inode: Inode) -> Result<Stat, &'static str> {
+        if inode == ROOT {
+            return Ok(Stat {
+                kind: Kind::Directory,
+                size: 0,
+                inode,
+            });
+        }
+        let mut nodes = self.nodes.lock();
+        let node = node(&mut nodes, inode)?;
+        Ok(Stat {
+            kind: node.kind(),
+            size: node.size,
+            inode,
+        })
+    }
+
+    fn lookup(&self, dir: Inode, name: &str) -> Result<Inode, &'static str> {
+        let mut nodes = self.nodes.lock();
+        if !is_dir(&mut nodes, dir) {
+            return Err("not a directory");
+        }
+        find(&nodes, dir, name).ok_or("no such file")
+    }
+
+    fn read_dir(&self, dir: Inode, index: &mut u64) -> Result<Option<DirEntry>, &'static str> {
+        let mut nodes = self.nodes.lock();
+        if !is_dir(&mut nodes, dir) {
+            return Err("not a directory");
+        }
+        let start = (*index as usize).min(MAX_NODES);
+        match nodes[start..]
+            .iter()
+            .position(|node| node.used && node.parent == dir)
+        {
+            Some(i) => {
+                let i = start + i;
+                *index = i as u64 + 1;
+                let node = &nodes[i];
+                DirEntry::new(node.name(), i as Inode + 1, node.kind()).map(Some)
+            }
+            None => {
+                *index = MAX_NODES as u64;
+                Ok(None)
+            }
+        }
+    }
+
+    fn read(&self, inode: Inode, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let mut nodes = self.nodes.lock();
+        let node = file(&mut nodes, inode)?;
+        let len = buf.len().min(node.size.saturating_sub(offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let at = offset as usize + done;
+            let in_page = at % PAGE_SIZE;
+            let part = (PAGE_SIZE - in_page).min(len - done);
+            let dest = &mut buf[done..done + part];
+            match node.page(at / PAGE_SIZE, false)? {
+                Some(addr) => dest.copy_from_slice(&frame_bytes(addr)[in_page..in_page + part]),
+                None => dest.fill(0),
+            }
+            done += part;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, inode: Inode, offset: u64, data: &[u8]) -> Result<usize, &'static str> {
+        let mut nodes = self.nodes.lock();
+        let node = file(&mut nodes, inode)?;
+        if offset
+            .checked_add(data.len() as u64)
+            .map_or(true, |end| end > MAX_FILE_SIZE)
+        {
+            return Err("tmpfs files are at most 2MiB");
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let at = offset as usize + done;
+            let in_page = at % PAGE_SIZE;
+            let part = (PAGE_SIZE - in_page).min(data.len() - done);
+            // what fit before memory ran out stays written
+            let addr = match node.page(at / PAGE_SIZE, true) {
+                Ok(addr) => addr.unwrap(),
+                Err(err) if done == 0 => return Err(err),
+                Err(_) => break,
+            };
+            frame_bytes(addr)[in_page..in_page + part].copy_from_slice(&data[done..done + part]);
+            done += part;
+        }
+        node.size = node.size.max(offset + done as u64);
+        Ok(done)
+    }
+
+    fn truncate(&self, inode: Inode, size: u64) -> Result<(), &'static str> {
+        let mut nodes = self.nodes.lock();
+        let node = file(&mut nodes, inode)?;
+        if size > MAX_FILE_SIZE {
+            return Err("tmpfs files are at most 2MiB");
+        }
+        if size < node.size {
+            node.shrink(size);
+        }
+        node.size = size;
+        Ok(())
+    }
+
+    fn create(&self, dir: Inode, name: &str, kind: Kind) -> Result<Inode, &'static str> {
+        let directory = match kind {
+            Kind::File => false,
+            Kind::Directory => true,
+            _ => return Err("tmpfs only has files and directories"),
+        };
+        if name.len() > MAX_NAME_LENGTH {
+            return Err("tmpfs names are at most 64 bytes");
+        }
+        let mut nodes = self.nodes.lock();
+        if !is_dir(&mut nodes, dir) {
+            return Err("not a directory");
+        }
+        if find(&nodes, dir, name).is_some() {
+            return Err("the file exists already");
+        }
+        let i = nodes
+            .iter()
+            .position(|node| !node.used)
+            .ok_or("tmpfs is full")?;
+        let node = &mut nodes[i];
+        *node = Node::EMPTY;
+        node.used = true;
+        node.parent = dir;
+        node.directory = directory;
+        node.name[..name.len()].copy_from_slice(name.as_bytes());
+        node.name_len = name.len();
+        Ok(i as Inode + 1)
+    }
+
+    fn remove(&self, dir: Inode, name: &str) -> Result<(), &'static str> {
+        let mut nodes = self.nodes.lock();
+        let inode = find(&nodes, dir, name).ok_or("no such file")?;
+        if nodes.iter().any(|node| node.used && node.parent == inode) {
+            return Err("the directory isn't empty");
+        }
+        let node = node(&mut nodes, inode)?;
+        node.shrink(0);
+        node.used = false;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_tmpfs() {
+    static FS: TmpFs = TmpFs::new();
+    let dir = FS.create(ROOT, "levels", Kind::Directory).unwrap();
+    let file = FS.create(dir, "1", Kind::File).unwrap();
+    assert!(FS.write(dir, 0, b"x").is_err());
+
+    // across a page boundary, with a hole before it
+    let at = 3 * PAGE_SIZE as u64 - 2;
+    assert_eq!(FS.write(file, at, b"wall"), Ok(4));
+    assert_eq!(FS.stat(file).unwrap().size, at + 4);
+    let mut buf = [1; 8];
+    assert_eq!(FS.read(file, 100, &mut buf), Ok(8));
+    assert_eq!(buf, [0; 8]);
+    assert_eq!(FS.read(file, at - 2, &mut buf), Ok(6));
+    assert_eq!(&buf[..6], b"\0\0wall");
+    assert!(FS.write(file, MAX_FILE_SIZE - 1, b"ab").is_err());
+
+    // shrinking zeroes what was cut off, in case the file grows again
+    FS.truncate(file, at + 1).unwrap();
+    FS.truncate(file, at + 4).unwrap();
+    assert_eq!(FS.read(file, at, &mut buf), Ok(4));
+    assert_eq!(&buf[..4], b"w\0\0\0");
+
+    // the frames of removed files are used again
+    FS.remove(dir, "1").unwrap();
+    let free = *FREE_FRAMES.lock();
+    assert_ne!(free, 0);
+    let file = FS.create(dir, "2", Kind::File).unwrap();
+    FS.write(file, 0, b"x").unwrap();
+    assert_ne!(*FREE_FRAMES.lock(), free);
+    assert!(FS.remove(ROOT, "levels").is_err());
+    FS.remove(dir, "2").unwrap();
+    FS.remove(ROOT, "levels").unwrap();
+    assert!(FS.stat(file).is_err());
+}
@@ -0,0 +1,651 @@
+//! The virtual file system: one tree of paths over all the mounted file systems, and tables of
+//! open files that programs read and write through.
+//!
+//! File systems implement `FileSystem` and name their files with inode numbers of their own. A
+//! `Vnode` is a file system together with one of its inode numbers. Paths are always absolute,
+//! "." and ".." are worked out from the path itself before any file system sees it.
+
+use crate::devfs;
+use crate::ramfs;
+use crate::tmpfs;
+use core::fmt;
+use core::ops::BitOr;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+pub const MAX_NAME_LENGTH: usize = 255;
+pub const MAX_PATH_LENGTH: usize = 256;
+const MAX_MOUNTS: usize = 8;
+/// How many files each `FdTable` can have open.
+pub const MAX_OPEN_FILES: usize = 16;
+
+static MOUNTS: Mutex<[Option<Mount>; MAX_MOUNTS]> = Mutex::new([None; MAX_MOUNTS]);
+static INIT: Once<()> = Once::new();
+
+pub type Inode = u64;
+/// An index into an `FdTable`.
+pub type Fd = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub kind: Kind,
+    /// In bytes, 0 for directories and character devices.
+    pub size: u64,
+    pub inode: Inode,
+}
+
+#[derive(Clone, Copy)]
+pub struct DirEntry {
+    name: [u8; MAX_NAME_LENGTH],
+    len: usize,
+    pub inode: Inode,
+    pub kind: Kind,
+}
+
+impl DirEntry {
+    pub fn new(name: &str, inode: Inode, kind: Kind) -> Result<Self, &'static str> {
+        if name.len() > MAX_NAME_LENGTH {
+            return Err("file name too long");
+        }
+        let mut entry = DirEntry {
+            name: [0; MAX_NAME_LENGTH],
+            len: name.len(),
+            inode,
+            kind,
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(entry)
+    }
+
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DirEntry")
+            .field("name", &self.name())
+            .field("inode", &self.inode)
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+/// What a file system does for the VFS. Inodes given to it are ones it handed out, but may be of
+/// files that were removed since.
+pub trait FileSystem: Sync {
+    /// Like "fat", for the list of mounts.
+    fn type_name(&self) -> &'static str;
+
+    fn root(&self) -> Inode;
+
+    fn stat(&self, inode: Inode) -> Result<Stat, &'static str>;
+
+    /// The entry called `name` in directory `dir`, never "." or "..".
+    fn lookup(&self, dir: Inode, name: &str) -> Result<Inode, &'static str>;
+
+    /// The first entry of directory `dir` from `*index` on, moving `*index` past it. What the
+    /// index means is up to the file system, listing starts at 0. "." and ".." aren't listed.
+    fn read_dir(&self, dir: Inode, index: &mut u64) -> Result<Option<DirEntry>, &'static str>;
+
+    /// Reads from byte `offset` of a file into `buf`, returns how much was read, 0 at the end.
+    fn read(&self, inode: Inode, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str>;
+
+    fn write(&self, _inode: Inode, _offset: u64, _data: &[u8]) -> Result<usize, &'static str> {
+        Err("read only file system")
+    }
+
+    fn truncate(&self, _inode: Inode, _size: u64) -> Result<(), &'static str> {
+        Err("read only file system")
+    }
+
+    fn create(&self, _dir: Inode, _name: &str, _kind: Kind) -> Result<Inode, &'static str> {
+        Err("read only file system")
+    }
+
+    /// Removes a file, or a directory if it's empty.
+    fn remove(&self, _dir: Inode, _name: &str) -> Result<(), &'static str> {
+        Err("read only file system")
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/// A file or directory of a mounted file system.
+#[derive(Clone, Copy)]
+pub struct Vnode {
+    fs: &'static dyn FileSystem,
+    inode: Inode,
+}
+
+impl Vnode {
+    pub fn fs(&self) -> &'static dyn FileSystem {
+        self.fs
+    }
+
+    pub fn inode(&self) -> Inode {
+        self.inode
+    }
+
+    pub fn stat(&self) -> Result<Stat, &'static str> {
+        self.fs.stat(self.inode)
+    }
+}
+
+/// An absolute path without "." and ".." parts, empty ones or a trailing slash.
+#[derive(Clone, Copy)]
+pub struct Path {
+    bytes: [u8; MAX_PATH_LENGTH],
+    len: usize,
+}
+
+impl Path {
+    pub fn new(path: &str) -> Result<Path, &'static str> {
+        if !path.starts_with('/') {
+            return Err("paths have to start with /");
+        }
+        let mut normal = Path {
+            bytes: [0; MAX_PATH_LENGTH],
+            len: 0,
+        };
+        for name in path.split('/') {
+            match name {
+                "" | "." => {}
+                ".." => normal.pop(),
+                name => normal.push(name)?,
+            }
+        }
+        Ok(normal)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self.len {
+            0 => "/",
+            len => core::str::from_utf8(&self.bytes[..len]).unwrap_or("/"),
+        }
+    }
+
+    /// The path of the directory this is in and the name it has there, `None` for "/".
+    pub fn split_last(&self) -> Option<(Path, &str)> {
+        if self.len == 0 {
+            return None;
+        }
+        let slash = self.as_str().rfind('/')?;
+        let mut parent = *self;
+        parent.len = slash;
+        Some((parent, &self.as_str()[slash + 1..]))
+    }
+
+    /// Whether this is `other` or inside it.
+    fn starts_with(&self, other: &Path) -> bool {
+        let (path, other) = (&self.bytes[..self.len], &other.bytes[..other.len]);
+        path.starts_with(other) && (path.len() == other.len() || path[other.len()] == b'/')
+    }
+
+    fn push(&mut self, name: &str) -> Result<(), &'static str> {
+        if name.len() > MAX_NAME_LENGTH {
+            return Err("file name too long");
+        }
+        let end = self.len + 1 + name.len();
+        if end > MAX_PATH_LENGTH {
+            return Err("path too long");
+        }
+        self.bytes[self.len] = b'/';
+        self.bytes[self.len + 1..end].copy_from_slice(name.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+
+    fn pop(&mut self) {
+        self.len = self.bytes[..self.len]
+            .iter()
+            .rposition(|&byte| byte == b'/')
+            .unwrap_or(0);
+    }
+}
+
+impl PartialEq for Path {
+    fn eq(&self, other: &Path) -> bool {
+        self.bytes[..self.len] == other.bytes[..other.len]
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Mount {
+    point: Path,
+    fs: &'static dyn FileSystem,
+}
+
+/// Mounts a RAM file system at / with the devices in /dev, scratch files in /tmp, and an empty
+/// /mnt to mount disks at.
+pub fn init() {
+    INIT.call_once(|| {
+        if let Err(err) = mount_root() {
+            log::error!("no root file system: {}", err);
+        }
+    });
+}
+
+fn mount_root() -> Result<(), &'static str> {
+    mount("/", &ramfs::ROOT_FS)?;
+    create("/dev", Kind::Directory)?;
+    create("/mnt", Kind::Directory)?;
+    create("/tmp", Kind::Directory)?;
+    mount("/dev", &devfs::DEVFS)?;
+    mount("/tmp", &tmpfs::TMPFS)
+}
+
+/// Makes the root of `fs` appear at `point`, which has to be an existing directory unless it is
+/// "/".
+pub fn mount(point: &str, fs: &'static dyn FileSystem) -> Result<(), &'static str> {
+    let point = Path::new(point)?;
+    if point.len != 0 && resolve_path(&point)?.stat()?.kind != Kind::Directory {
+        return Err("not a directory");
+    }
+    interrupts::without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        if mounts.iter().flatten().any(|mount| mount.point == point) {
+            return Err("something is mounted there already");
+        }
+        let slot = mounts
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("too many mounts")?;
+        *slot = Some(Mount { point, fs });
+        Ok(())
+    })
+}
+
+/// Flushes and takes away the file system mounted at `point`. Files still open on it keep
+/// working, as file systems are never freed.
+pub fn unmount(point: &str) -> Result<(), &'static str> {
+    let point = Path::new(point)?;
+    let fs = interrupts::without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        if mounts
+            .iter()
+            .flatten()
+            .any(|mount| mount.point != point && mount.point.starts_with(&point))
+        {
+            return Err("something else is mounted inside it");
+        }
+        let slot = mounts
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|mount| mount.point == point))
+            .ok_or("nothing is mounted there")?;
+        Ok(slot.take().unwrap().fs)
+    })?;
+    fs.flush()
+}
+
+/// Calls `f` with the mount points and their file systems, in the order they were mounted in.
+pub fn mounts(mut f: impl FnMut(&Path, &'static dyn FileSystem)) {
+    let mounts = interrupts::without_interrupts(|| *MOUNTS.lock());
+    for mount in mounts.iter().flatten() {
+        f(&mount.point, mount.fs);
+    }
+}
+
+/// The file system of the innermost mount point that `path` is inside, and the rest of the path.
+fn mount_of(path: &Path) -> Result<(&'static dyn FileSystem, &str), &'static str> {
+    let (fs, len) = interrupts::without_interrupts(|| {
+        MOUNTS
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|mount| path.starts_with(&mount.point))
+            .max_by_key(|mount| mount.point.len)
+            .map(|mount| (mount.fs, mount.point.len))
+    })
+    .ok_or("nothing is mounted at /")?;
+    Ok((fs, &path.as_str()[len..]))
+}
+
+fn is_mount_point(path: &Path) -> bool {
+    interrupts::without_interrupts(|| {
+        MOUNTS
+            .lock()
+            .iter()
+            .flatten()
+            .any(|mount| mount.point == *path)
+    })
+}
+
+pub fn resolve(path: &str) -> Result<Vnode, &'static str> {
+    resolve_path(&Path::new(path)?)
+}
+
+fn resolve_path(path: &Path) -> Result<Vnode, &'static str> {
+    let (fs, rest) = mount_of(path)?;
+    let mut inode = fs.root();
+    for name in rest.split('/').filter(|name| !name.is_empty()) {
+        inode = fs.lookup(inode, name)?;
+    }
+    Ok(Vnode { fs, inode })
+}
+
+pub fn stat(path: &str) -> Result<Stat, &'static str> {
+    resolve(path)?.stat()
+}
+
+/// Makes an empty file or directory at `path`.
+pub fn create(path: &str, kind: Kind) -> Result<Vnode, &'static str> {
+    let path = Path::new(path)?;
+    if is_mount_point(&path) {
+        return Err("the file exists already");
+    }
+    let (parent, name) = path.split_last().ok_or("the file exists already")?;
+    let dir = resolve_path(&parent)?;
+    let inode = dir.fs.create(dir.inode, name, kind)?;
+    Ok(Vnode { fs: dir.fs, inode })
+}
+
+/// Removes the file or empty directory at `path`.
+pub fn remove(path: &str) -> Result<(), &'static str> {
+    let path = Path::new(path)?;
+    if is_mount_point(&path) {
+        return Err("can't remove a mount point");
+    }
+    let (parent, name) = path.split_last().ok_or("can't remove a mount point")?;
+    let dir = resolve_path(&parent)?;
+    dir.fs.remove(dir.inode, name)
+}
+
+/// Writes everything the mounted file systems only have in memory to their devices.
+pub fn sync() -> Result<(), &'static str> {
+    let mut result = Ok(());
+    mounts(|_, fs| {
+        if let Err(err) = fs.flush() {
+            result = Err(err);
+        }
+    });
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u8);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Makes the file if it doesn't exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Empties the file when it's opened for writing.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Writes always go to the end of the file.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+#[derive(Clone, Copy)]
+struct OpenFile {
+    vnode: Vnode,
+    kind: Kind,
+    flags: OpenFlags,
+    /// Where in the file the next read or write is, or the index for `read_dir` in a directory.
+    offset: u64,
+}
+
+/// The files a program has open, by file descriptor.
+pub struct FdTable {
+    files: [Option<OpenFile>; MAX_OPEN_FILES],
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        FdTable {
+            files: [None; MAX_OPEN_FILES],
+        }
+    }
+
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Fd, &'static str> {
+        let fd = self
+            .files
+            .iter()
+            .position(Option::is_none)
+            .ok_or("too many open files")?;
+        let vnode = match resolve(path) {
+            Err(_) if flags.contains(OpenFlags::CREATE) => create(path, Kind::File)?,
+            result => result?,
+        };
+        let kind = vnode.stat()?.kind;
+        let writes = flags.contains(OpenFlags::WRITE);
+        if kind == Kind::Directory && writes {
+            return Err("is a directory");
+        }
+        if writes && flags.contains(OpenFlags::TRUNCATE) && kind != Kind::BlockDevice {
+            vnode.fs.truncate(vnode.inode, 0)?;
+        }
+        self.files[fd] = Some(OpenFile {
+            vnode,
+            kind,
+            flags,
+            offset: 0,
+        });
+        Ok(fd)
+    }
+
+    /// Reads from where `fd` is at into `buf` and moves it past what was read.
+    pub fn read(&mut self, fd: Fd, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let file = self.file(fd)?;
+        if !file.flags.contains(OpenFlags::READ) {
+            return Err("the file isn't open for reading");
+        }
+        if file.kind == Kind::Directory {
+            return Err("is a directory");
+        }
+        let len = file.vnode.fs.read(file.vnode.inode, file.offset, buf)?;
+        file.offset += len as u64;
+        Ok(len)
+    }
+
+    /// Writes `data` where `fd` is at, or at the end if it was opened to append, and moves it
+    /// past what was written.
+    pub fn write(&mut self, fd: Fd, data: &[u8]) -> Result<usize, &'static str> {
+        let file = self.file(fd)?;
+        if !file.flags.contains(OpenFlags::WRITE) {
+            return Err("the file isn't open for writing");
+        }
+        if file.flags.contains(OpenFlags::APPEND) {
+            file.offset = file.vnode.stat()?.size;
+        }
+        let len = file.vnode.fs.write(file.vnode.inode, file.offset, data)?;
+        file.offset += len as u64;
+        Ok(len)
+    }
+
+    /// Moves where `fd` reads and writes from, and returns where that is now.
+    pub fn seek(&mut self, fd: Fd, to: SeekFrom) -> Result<u64, &'static str> {
+        let file = self.file(fd)?;
+        if file.kind == Kind::Directory {
+            return Err("is a directory");
+        }
+        let (base, delta) = match to {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (file.offset, delta),
+            SeekFrom::End(delta) => (file.vnode.stat()?.size, delta),
+        };
+        file.offset = base
+            .checked_add_signed(delta)
+            .ok_or("can't seek before the start of the file")?;
+        Ok(file.offset)
+    }
+
+    /// The next entry of the directory open as `fd`.
+    pub fn read_dir(&mut self, fd: Fd) -> Result<Option<DirEntry>, &'static str> {
+        let file = self.file(fd)?;
+        if file.kind != Kind::Directory {
+            return Err("not a directory");
+        }
+        file.vnode.fs.read_dir(file.vnode.inode, &mut file.offset)
+    }
+
+    pub fn stat(&mut self, fd: Fd) -> Result<Stat, &'static str> {
+        self.file(fd)?.vnode.stat()
+    }
+
+    /// Closes `fd`, flushing its file system if it was written to.
+    pub fn close(&mut self, fd: Fd) -> Result<(), &'static str> {
+        let file = self
+            .files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or("bad file descriptor")?;
+        if file.flags.contains(OpenFlags::WRITE) {
+            file.vnode.fs.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn close_all(&mut self) {
+        for fd in 0..MAX_OPEN_FILES {
+            if self.files[fd].is_none() {
+                continue;
+            }
+            if let Err(err) = self.close(fd) {
+                log::warn!("closing file {} failed: {}", fd, err);
+            }
+        }
+    }
+
+    fn file(&mut self, fd: Fd) -> Result<&mut OpenFile, &'static str> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::as_mut)
+            .ok_or("bad file descriptor")
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_paths() {
+    assert_eq!(Path::new("/").unwrap().as_str(), "/");
+    assert_eq!(Path::new("//a/./b/").unwrap().as_str(), "/a/b");
+    assert_eq!(Path::new("/a/../../b/..").unwrap().as_str(), "/");
+    assert!(Path::new("a/b").is_err());
+    let path = Path::new("/mnt/disk/file").unwrap();
+    let (parent, name) = path.split_last().unwrap();
+    assert_eq!((parent.as_str(), name), ("/mnt/disk", "file"));
+    assert_eq!(
+        Path::new("/a").unwrap().split_last().unwrap().0.as_str(),
+        "/"
+    );
+    assert!(Path::new("/").unwrap().split_last().is_none());
+    assert!(path.starts_with(&Path::new("/mnt").unwrap()));
+    assert!(!path.starts_with(&Path::new("/mn").unwrap()));
+}
+
+#[test_case]
+fn test_files() {
+    init();
+    let mut files = FdTable::new();
+    let fd = files
+        .open("/notes.txt", OpenFlags::WRITE | OpenFlags::CREATE)
+        .unwrap();
+    assert_eq!(files.write(fd, b"hello world"), Ok(11));
+    assert!(files.read(fd, &mut [0; 4]).is_err());
+    files.close(fd).unwrap();
+    assert!(files.close(fd).is_err());
+
+    let fd = files.open("/dev/../notes.txt", OpenFlags::READ).unwrap();
+    assert_eq!(files.seek(fd, SeekFrom::End(-5)), Ok(6));
+    let mut buf = [0; 16];
+    assert_eq!(files.read(fd, &mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"world");
+    assert_eq!(files.read(fd, &mut buf), Ok(0));
+    assert!(files.seek(fd, SeekFrom::Current(-20)).is_err());
+    files.close(fd).unwrap();
+
+    let fd = files.open("/", OpenFlags::READ).unwrap();
+    let mut names = 0;
+    while let Some(entry) = files.read_dir(fd).unwrap() {
+        assert!(matches!(entry.name(), "dev" | "mnt" | "tmp" | "notes.txt"));
+        names += 1;
+    }
+    assert_eq!(names, 4);
+    files.close(fd).unwrap();
+
+    let fd = files.open("/dev/zero", OpenFlags::READ).unwrap();
+    buf = [1; 16];
+    assert_eq!(files.read(fd, &mut buf), Ok(16));
+    assert_eq!(buf, [0; 16]);
+    files.close_all();
+    assert!(files.read(fd, &mut buf).is_err());
+
+    assert!(remove("/dev").is_err());
+    remove("/notes.txt").unwrap();
+    assert!(stat("/notes.txt").is_err());
+}
+
+#[test_case]
+fn test_mounts() {
+    use crate::fat::{self, FatType};
+
+    init();
+    crate::ahci::init();
+    let device = crate::block::find("sdb").expect("no disk for FAT tests");
+    fat::format(device, FatType::Fat16, "").unwrap();
+    create("/mnt/disk", Kind::Directory).unwrap();
+    mount("/mnt/disk", fat::mount(device).unwrap()).unwrap();
+    assert!(mount("/mnt/disk", &ramfs::ROOT_FS).is_err());
+
+    let mut files = FdTable::new();
+    create("/mnt/disk/Saved Games", Kind::Directory).unwrap();
+    let fd = files
+        .open(
+            "/mnt/disk/saved games/snake.txt",
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::APPEND,
+        )
+        .unwrap();
+    files.write(fd, b"12").unwrap();
+    files.seek(fd, SeekFrom::Start(0)).unwrap();
+    files.write(fd, b"34").unwrap();
+    files.close(fd).unwrap();
+    assert_eq!(stat("/mnt/disk/Saved Games/Snake.txt").unwrap().size, 4);
+    assert!(unmount("/mnt").is_err());
+    assert!(remove("/mnt/disk").is_err());
+
+    unmount("/mnt/disk").unwrap();
+    assert!(stat("/mnt/disk/Saved Games").is_err());
+    remove("/mnt/disk").unwrap();
+}
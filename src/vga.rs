@@ -0,0 +1,258 @@
+//! Low level access to the VGA registers and to the font stored in plane 2.
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+const ATTRIBUTE_INDEX: u16 = 0x3c0;
+const MISC_OUTPUT_WRITE: u16 = 0x3c2;
+const SEQUENCER_INDEX: u16 = 0x3c4;
+const DAC_READ_INDEX: u16 = 0x3c7;
+const DAC_WRITE_INDEX: u16 = 0x3c8;
+const DAC_DATA: u16 = 0x3c9;
+pub(crate) const GRAPHICS_INDEX: u16 = 0x3ce;
+const CRTC_INDEX: u16 = 0x3d4;
+const INPUT_STATUS: u16 = 0x3da;
+
+const SEQ_MAP_MASK: u8 = 0x02;
+const SEQ_MEMORY_MODE: u8 = 0x04;
+const GC_READ_MAP: u8 = 0x04;
+const GC_MODE: u8 = 0x05;
+const GC_MISC: u8 = 0x06;
+const CRTC_END_HORIZONTAL_BLANKING: u8 = 0x03;
+const CRTC_VERTICAL_RETRACE_END: u8 = 0x11;
+const CRTC_MAX_SCAN_LINE: u8 = 0x09;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+
+/// Number of glyphs in a VGA font.
+pub const FONT_GLYPHS: usize = 256;
+/// Every glyph takes up a 32 byte slot in plane 2, whatever its height.
+const GLYPH_SLOT: usize = 32;
+/// Where plane 2 shows up while it is mapped for font access.
+const FONT_MEMORY: usize = 0xa0000;
+
+/// Number of colours in the DAC palette.
+pub const PALETTE_SIZE: usize = 256;
+
+/// The complete register state of a video mode, in the layout of the well known VGA mode dumps.
+pub(crate) struct ModeRegisters {
+    pub misc: u8,
+    pub sequencer: [u8; 5],
+    pub crtc: [u8; 25],
+    pub graphics: [u8; 9],
+    pub attribute: [u8; 21],
+}
+
+/// Standard 80x25 text mode with 9 pixel wide characters.
+pub(crate) const TEXT_80X25: ModeRegisters = ModeRegisters {
+    misc: 0x67,
+    sequencer: [0x03, 0x00, 0x03, 0x00, 0x02],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x55, 0x81, 0xbf, 0x1f, 0x00, 0x4f, 0x0d, 0x0e, 0x00, 0x00, 0x00,
+        0x50, 0x9c, 0x0e, 0x8f, 0x28, 0x1f, 0x96, 0xb9, 0xa3, 0xff,
+    ],
+    graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x0e, 0x00, 0xff],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e,
+        0x3f, 0x0c, 0x00, 0x0f, 0x08, 0x00,
+    ],
+};
+
+/// A bitmap font with one byte per row and `height` rows per glyph. Glyphs are stored one after
+/// another starting from glyph 0, so a font may cover fewer than all 256 characters.
+pub struct Font<'a> {
+    pub height: usize,
+    pub glyphs: &'a [u8],
+}
+
+/// Writes `value` to register `index` of the register group at `index_port`, the data port is
+/// always the one after it.
+pub(crate) fn write_register(index_port: u16, index: u8, value: u8) {
+    let mut index_reg: Port<u8> = Port::new(index_port);
+    let mut data_reg: Port<u8> = Port::new(index_port + 1);
+    unsafe {
+        index_reg.write(index);
+        data_reg.write(value);
+    }
+}
+
+pub(crate) fn read_register(index_port: u16, index: u8) -> u8 {
+    let mut index_reg: Port<u8> = Port::new(index_port);
+    let mut data_reg: Port<u8> = Port::new(index_port + 1);
+    unsafe {
+        index_reg.write(index);
+        data_reg.read()
+    }
+}
+
+/// Programs every VGA register from `regs`, switching to the mode they describe.
+pub(crate) fn set_registers(regs: &ModeRegisters) {
+    let mut misc: PortWriteOnly<u8> = PortWriteOnly::new(MISC_OUTPUT_WRITE);
+    let mut attribute: PortWriteOnly<u8> = PortWriteOnly::new(ATTRIBUTE_INDEX);
+    let mut input_status: PortReadOnly<u8> = PortReadOnly::new(INPUT_STATUS);
+
+    interrupts::without_interrupts(|| unsafe {
+        misc.write(regs.misc);
+        for (index, &value) in regs.sequencer.iter().enumerate() {
+            write_register(SEQUENCER_INDEX, index as u8, value);
+        }
+
+        // the timing registers are write protected until bit 7 of the retrace end is cleared
+        let blanking = read_register(CRTC_INDEX, CRTC_END_HORIZONTAL_BLANKING);
+        write_register(CRTC_INDEX, CRTC_END_HORIZONTAL_BLANKING, blanking | 0x80);
+        let retrace = read_register(CRTC_INDEX, CRTC_VERTICAL_RETRACE_END);
+        write_register(CRTC_INDEX, CRTC_VERTICAL_RETRACE_END, retrace & !0x80);
+        for (index, &value) in regs.crtc.iter().enumerate() {
+            let value = match index as u8 {
+                CRTC_END_HORIZONTAL_BLANKING => value | 0x80,
+                CRTC_VERTICAL_RETRACE_END => value & !0x80,
+                _ => value,
+            };
+            write_register(CRTC_INDEX, index as u8, value);
+        }
+
+        for (index, &value) in regs.graphics.iter().enumerate() {
+            write_register(GRAPHICS_INDEX, index as u8, value);
+        }
+
+        // reading the input status resets the attribute controller's index/data flip-flop
+        for (index, &value) in regs.attribute.iter().enumerate() {
+            input_status.read();
+            attribute.write(index as u8);
+            attribute.write(value);
+        }
+        // hand the palette back to the display, which also unblanks it
+        input_status.read();
+        attribute.write(0x20);
+    });
+}
+
+/// Sets DAC colour `index` to the given 6 bit red, green and blue levels.
+pub fn set_palette(index: u8, red: u8, green: u8, blue: u8) {
+    let mut write_index: PortWriteOnly<u8> = PortWriteOnly::new(DAC_WRITE_INDEX);
+    let mut data: Port<u8> = Port::new(DAC_DATA);
+    interrupts::without_interrupts(|| unsafe {
+        write_index.write(index);
+        data.write(red & 0x3f);
+        data.write(green & 0x3f);
+        data.write(blue & 0x3f);
+    });
+}
+
+/// Copies the whole DAC palette into `colours` as red, green, blue triples.
+pub fn read_palette(colours: &mut [u8; PALETTE_SIZE * 3]) {
+    let mut read_index: PortWriteOnly<u8> = PortWriteOnly::new(DAC_READ_INDEX);
+    let mut data: Port<u8> = Port::new(DAC_DATA);
+    interrupts::without_interrupts(|| unsafe {
+        read_index.write(0);
+        for level in colours.iter_mut() {
+            *level = data.read();
+        }
+    });
+}
+
+/// Loads a whole palette previously saved with `read_palette`.
+pub fn write_palette(colours: &[u8; PALETTE_SIZE * 3]) {
+    let mut write_index: PortWriteOnly<u8> = PortWriteOnly::new(DAC_WRITE_INDEX);
+    let mut data: Port<u8> = Port::new(DAC_DATA);
+    interrupts::without_interrupts(|| unsafe {
+        write_index.write(0);
+        for &level in colours.iter() {
+            data.write(level);
+        }
+    });
+}
+
+/// Maps plane 2 at 0xa0000 for the duration of `f` and then restores the text mode mapping.
+fn with_font_plane<R>(f: impl FnOnce(*mut u8) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        write_register(SEQUENCER_INDEX, SEQ_MAP_MASK, 0x04);
+        write_register(SEQUENCER_INDEX, SEQ_MEMORY_MODE, 0x07);
+        write_register(GRAPHICS_INDEX, GC_READ_MAP, 0x02);
+        write_register(GRAPHICS_INDEX, GC_MODE, 0x00);
+        write_register(GRAPHICS_INDEX, GC_MISC, 0x04);
+
+        let result = f(FONT_MEMORY as *mut u8);
+
+        write_register(SEQUENCER_INDEX, SEQ_MAP_MASK, 0x03);
+        write_register(SEQUENCER_INDEX, SEQ_MEMORY_MODE, 0x03);
+        write_register(GRAPHICS_INDEX, GC_READ_MAP, 0x00);
+        write_register(GRAPHICS_INDEX, GC_MODE, 0x10);
+        write_register(GRAPHICS_INDEX, GC_MISC, 0x0e);
+        result
+    })
+}
+
+/// Uploads `font` into the VGA font memory, replacing the glyphs it covers.
+pub fn load_font(font: &Font) {
+    assert!(
+        font.height > 0 && font.height <= GLYPH_SLOT,
+        "bad glyph height"
+    );
+    with_font_plane(|plane| {
+        for (glyph, rows) in font
+            .glyphs
+            .chunks(font.height)
+            .take(FONT_GLYPHS)
+            .enumerate()
+        {
+            for (row, &bits) in rows.iter().enumerate() {
+                unsafe { plane.add(glyph * GLYPH_SLOT + row).write_volatile(bits) };
+            }
+        }
+    });
+}
+
+/// Replaces the glyph of character `index` with `rows`, e.g. to draw custom game tiles.
+pub fn load_glyph(index: u8, rows: &[u8]) {
+    assert!(rows.len() <= GLYPH_SLOT, "bad glyph height");
+    with_font_plane(|plane| {
+        for (row, &bits) in rows.iter().enumerate() {
+            unsafe {
+                plane
+                    .add(index as usize * GLYPH_SLOT + row)
+                    .write_volatile(bits)
+            };
+        }
+    });
+}
+
+/// Copies the first `height` rows of every glyph of the loaded font into `glyphs`.
+pub fn read_font(height: usize, glyphs: &mut [u8]) {
+    assert!(
+        glyphs.len() >= FONT_GLYPHS * height,
+        "font buffer too small"
+    );
+    with_font_plane(|plane| {
+        for glyph in 0..FONT_GLYPHS {
+            for row in 0..height {
+                glyphs[glyph * height + row] =
+                    unsafe { plane.add(glyph * GLYPH_SLOT + row).read_volatile() };
+            }
+        }
+    });
+}
+
+/// Sets how many scan lines each text row is tall and moves the cursor to the bottom two of them.
+pub fn set_char_height(height: u8) {
+    interrupts::without_interrupts(|| {
+        let max_scan_line = read_register(CRTC_INDEX, CRTC_MAX_SCAN_LINE);
+        write_register(
+            CRTC_INDEX,
+            CRTC_MAX_SCAN_LINE,
+            (max_scan_line & 0xe0) | (height - 1),
+        );
+        let cursor_start = read_register(CRTC_INDEX, CRTC_CURSOR_START);
+        write_register(
+            CRTC_INDEX,
+            CRTC_CURSOR_START,
+            (cursor_start & 0xe0) | (height - 2),
+        );
+        let cursor_end = read_register(CRTC_INDEX, CRTC_CURSOR_END);
+        write_register(
+            CRTC_INDEX,
+            CRTC_CURSOR_END,
+            (cursor_end & 0xe0) | (height - 1),
+        );
+    });
+}
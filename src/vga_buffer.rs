@@ -1,16 +1,37 @@
+use crate::cp437;
+use crate::framebuffer;
+use crate::vga::{self, Font, FONT_GLYPHS};
+use core::cmp;
 use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::PortReadOnly;
+
+pub const CONSOLE_COUNT: usize = 4;
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        colour_code: ColourCode::new(Colour::Yellow, Colour::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    /// The virtual consoles, each with its own screen contents, cursor and colour. Only the active
+    /// console is drawn to VGA memory, the others keep rendering into their back buffers.
+    pub static ref CONSOLES: [Mutex<Writer>; CONSOLE_COUNT] = [
+        Mutex::new(Writer::new(0)),
+        Mutex::new(Writer::new(1)),
+        Mutex::new(Writer::new(2)),
+        Mutex::new(Writer::new(3)),
+    ];
 }
 
+/// The console currently shown on screen.
+static ACTIVE_CONSOLE: AtomicUsize = AtomicUsize::new(0);
+/// The console that `print!` writes to.
+static OUTPUT_CONSOLE: AtomicUsize = AtomicUsize::new(0);
+/// Bumped on every console switch so the newly shown console knows to repaint the whole screen.
+static SWITCH_GENERATION: AtomicUsize = AtomicUsize::new(0);
+/// The cell the mouse pointer is drawn in as `row * MAX_BUFFER_WIDTH + col`, or `NO_POINTER`.
+static POINTER_CELL: AtomicUsize = AtomicUsize::new(NO_POINTER);
+const NO_POINTER: usize = usize::MAX;
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -41,6 +62,11 @@ impl ColourCode {
     fn new(foreground: Colour, background: Colour) -> ColourCode {
         ColourCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// Swaps the foreground and background colours.
+    fn inverted(self) -> ColourCode {
+        ColourCode(self.0.rotate_left(4))
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -50,49 +76,198 @@ struct ScreenChar {
     colour_code: ColourCode,
 }
 
-pub const BUFFER_HEIGHT: usize = 25;
-pub const BUFFER_WIDTH: usize = 80;
+impl ScreenChar {
+    const BLANK: ScreenChar = ScreenChar {
+        ascii_character: b' ',
+        colour_code: ColourCode(0),
+    };
+}
+
+/// The largest console dimensions, the dimensions in use are those of the current `TextMode` or
+/// of the framebuffer console.
+pub const MAX_BUFFER_HEIGHT: usize = 50;
+pub const MAX_BUFFER_WIDTH: usize = 128;
+
+type Line = [ScreenChar; MAX_BUFFER_WIDTH];
 
+/// VGA text memory, rows are `width` cells apart.
 #[repr(transparent)]
 struct Buffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    chars: [Volatile<ScreenChar>; MAX_BUFFER_WIDTH * MAX_BUFFER_HEIGHT],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextMode {
+    /// The standard mode with the 8x16 font.
+    Text80x25,
+    /// The same timings with an 8x8 font, which is squashed from the 8x16 one.
+    Text80x50,
+}
+
+impl TextMode {
+    pub fn columns(self) -> usize {
+        80
+    }
+
+    pub fn rows(self) -> usize {
+        match self {
+            TextMode::Text80x25 => 25,
+            TextMode::Text80x50 => 50,
+        }
+    }
+
+    fn char_height(self) -> usize {
+        match self {
+            TextMode::Text80x25 => 16,
+            TextMode::Text80x50 => 8,
+        }
+    }
+}
+
+static TEXT_MODE: Mutex<TextMode> = Mutex::new(TextMode::Text80x25);
+/// The 8x16 font as it was when the last 8x16 mode was left, to be restored on the way back.
+static FONT_8X16: Mutex<[u8; FONT_GLYPHS * 16]> = Mutex::new([0; FONT_GLYPHS * 16]);
+
+static mut BACK_BUFFERS: [[Line; MAX_BUFFER_HEIGHT]; CONSOLE_COUNT] =
+    [[[ScreenChar::BLANK; MAX_BUFFER_WIDTH]; MAX_BUFFER_HEIGHT]; CONSOLE_COUNT];
+
+/// Number of lines kept after they scroll off the top of the screen.
+pub const SCROLLBACK_LINES: usize = 500;
+
+const EMPTY_SCROLLBACK: Scrollback = Scrollback::new();
+static mut SCROLLBACKS: [Scrollback; CONSOLE_COUNT] = [EMPTY_SCROLLBACK; CONSOLE_COUNT];
+
+/// Ring of the lines that have scrolled off the top of the screen, oldest lines are overwritten
+/// once it is full.
+struct Scrollback {
+    lines: [Line; SCROLLBACK_LINES],
+    next: usize,
+    len: usize,
+}
+
+impl Scrollback {
+    const fn new() -> Scrollback {
+        Scrollback {
+            lines: [[ScreenChar::BLANK; MAX_BUFFER_WIDTH]; SCROLLBACK_LINES],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, line: &Line) {
+        self.lines[self.next] = *line;
+        self.next = (self.next + 1) % SCROLLBACK_LINES;
+        if self.len < SCROLLBACK_LINES {
+            self.len += 1;
+        }
+    }
+
+    /// Returns the line that scrolled off `age` lines ago, where an age of 1 is the most recent.
+    fn line(&self, age: usize) -> &Line {
+        &self.lines[(self.next + SCROLLBACK_LINES - age) % SCROLLBACK_LINES]
+    }
+
+    /// Takes the most recent line back out of the ring.
+    fn pop(&mut self) -> Option<Line> {
+        if self.len == 0 {
+            return None;
+        }
+        let line = *self.line(1);
+        self.next = (self.next + SCROLLBACK_LINES - 1) % SCROLLBACK_LINES;
+        self.len -= 1;
+        Some(line)
+    }
 }
 
+/// Port of the VGA input status register, bit 3 is set during vertical retrace.
+const INPUT_STATUS_PORT: u16 = 0x3da;
+const VERTICAL_RETRACE: u8 = 1 << 3;
+
+/// Text console that draws into an off-screen back buffer. Rows that change are marked dirty and
+/// only those rows are copied into VGA memory by `present`, and only while the console is active.
 pub struct Writer {
+    index: usize,
+    presented_generation: usize,
     column_position: usize,
     colour_code: ColourCode,
+    width: usize,
+    height: usize,
+    back_buffer: &'static mut [Line; MAX_BUFFER_HEIGHT],
+    dirty_rows: u64,
+    auto_present: bool,
+    scrollback: &'static mut Scrollback,
+    /// How many lines the view is scrolled up into the scrollback, 0 shows the live screen.
+    scroll_offset: usize,
     buffer: &'static mut Buffer,
 }
 
 impl Writer {
+    fn new(index: usize) -> Writer {
+        Writer {
+            index,
+            presented_generation: usize::MAX,
+            column_position: 0,
+            colour_code: ColourCode::new(Colour::Yellow, Colour::Black),
+            width: TextMode::Text80x25.columns(),
+            height: TextMode::Text80x25.rows(),
+            back_buffer: unsafe { &mut BACK_BUFFERS[index] },
+            dirty_rows: 0,
+            auto_present: true,
+            scrollback: unsafe { &mut SCROLLBACKS[index] },
+            scroll_offset: 0,
+            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
+        self.scroll_to_bottom();
         match byte {
             b'\n' => self.new_line(),
             byte => {
-                if self.column_position >= BUFFER_WIDTH {
+                if self.column_position >= self.width {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.height - 1;
                 let col = self.column_position;
 
                 let colour_code = self.colour_code;
-                self.buffer.chars[row][col].write(ScreenChar {
+                self.back_buffer[row][col] = ScreenChar {
                     ascii_character: byte,
                     colour_code,
-                });
+                };
+                self.mark_dirty(row);
                 self.column_position += 1;
             }
         }
     }
 
+    /// Moves back a column and blanks it, within the current line.
+    pub fn backspace(&mut self) {
+        self.scroll_to_bottom();
+        if self.column_position > 0 {
+            self.column_position -= 1;
+            let row = self.height - 1;
+            self.back_buffer[row][self.column_position] = ScreenChar {
+                ascii_character: b' ',
+                colour_code: self.colour_code,
+            };
+            self.mark_dirty(row);
+        }
+    }
+
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ascii byte
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
-            }
+        for c in s.chars() {
+            self.write_char(c);
+        }
+    }
+
+    /// Writes `c` using its code page 437 glyph, or ■ if the VGA font has no glyph for it.
+    pub fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.write_byte(b'\n'),
+            '\u{8}' => self.backspace(),
+            c => self.write_byte(cp437::encode(c).unwrap_or(cp437::REPLACEMENT)),
         }
     }
 
@@ -101,19 +276,157 @@ impl Writer {
         //    ascii_character: b' ',
         //    colour_code: ColourCode::new(Colour::Yellow, Colour::Black),
         //};
-        for row in 0..BUFFER_HEIGHT {
+        for row in 0..self.height {
             self.clear_row(row);
         }
     }
 
-    fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_active(&self) -> bool {
+        ACTIVE_CONSOLE.load(Ordering::SeqCst) == self.index
+    }
+
+    /// Copies every dirty row of the back buffer into VGA memory, or draws it on the framebuffer
+    /// console if there is one. Does nothing while another console is shown, the rows stay dirty
+    /// until this console is switched to.
+    pub fn present(&mut self) {
+        if !self.is_active() {
+            return;
+        }
+        let generation = SWITCH_GENERATION.load(Ordering::SeqCst);
+        if generation != self.presented_generation {
+            self.presented_generation = generation;
+            self.dirty_rows = u64::MAX;
+        }
+        let pointer = POINTER_CELL.load(Ordering::SeqCst);
+        for row in 0..self.height {
+            if self.dirty_rows & (1 << row) == 0 {
+                continue;
+            }
+            let line = *self.view_row(row);
+            for (col, character) in line[..self.width].iter().enumerate() {
+                let mut character = *character;
+                if row * MAX_BUFFER_WIDTH + col == pointer {
+                    character.colour_code = character.colour_code.inverted();
+                }
+                match framebuffer::console() {
+                    Some(framebuffer) => framebuffer.draw_cell(
+                        col,
+                        row,
+                        character.ascii_character,
+                        character.colour_code.0,
+                    ),
+                    None => self.buffer.chars[row * self.width + col].write(character),
+                }
+            }
+        }
+        self.dirty_rows = 0;
+    }
+
+    /// Draws the mouse pointer as an inverted cell at `row`, `col`, or hides it. The pointer stays
+    /// at that place on screen whichever console is shown.
+    pub fn show_pointer(&mut self, cell: Option<(usize, usize)>) {
+        let new = match cell {
+            Some((row, col)) if row < self.height && col < self.width => {
+                row * MAX_BUFFER_WIDTH + col
+            }
+            _ => NO_POINTER,
+        };
+        let old = POINTER_CELL.swap(new, Ordering::SeqCst);
+        for &cell in &[old, new] {
+            if cell != NO_POINTER && cell / MAX_BUFFER_WIDTH < self.height {
+                self.mark_dirty(cell / MAX_BUFFER_WIDTH);
+            }
+        }
+        self.present();
+    }
+
+    /// Moves the view `lines` lines back into the scrollback.
+    pub fn scroll_up(&mut self, lines: usize) {
+        let offset = usize::min(self.scroll_offset + lines, self.scrollback.len);
+        self.set_scroll_offset(offset);
+    }
+
+    /// Moves the view `lines` lines towards the live screen.
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.set_scroll_offset(self.scroll_offset.saturating_sub(lines));
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.set_scroll_offset(0);
+    }
+
+    fn set_scroll_offset(&mut self, offset: usize) {
+        if offset != self.scroll_offset {
+            self.scroll_offset = offset;
+            self.dirty_rows = u64::MAX;
+        }
+    }
+
+    /// The row that is shown at `row` on screen with the current scroll offset applied.
+    fn view_row(&self, row: usize) -> &Line {
+        if row >= self.scroll_offset {
+            &self.back_buffer[row - self.scroll_offset]
+        } else {
+            self.scrollback.line(self.scroll_offset - row)
+        }
+    }
+
+    /// Waits for the start of the next vertical retrace before presenting so the copy doesn't
+    /// tear halfway through a frame.
+    pub fn present_on_retrace(&mut self) {
+        wait_for_retrace();
+        self.present();
+    }
+
+    /// Whether `print!` presents the back buffer after every write. Programs that draw whole
+    /// frames turn this off and call `present` themselves.
+    pub fn set_auto_present(&mut self, auto_present: bool) {
+        self.auto_present = auto_present;
+    }
+
+    /// Adapts the console to new text mode dimensions, keeping the bottom rows in place. Rows that
+    /// no longer fit go into the scrollback and new rows at the top are refilled from it.
+    fn resize(&mut self, width: usize, height: usize) {
+        match height.cmp(&self.height) {
+            cmp::Ordering::Less => {
+                let excess = self.height - height;
+                for row in 0..excess {
+                    self.scrollback.push(&self.back_buffer[row]);
+                }
+                self.back_buffer.copy_within(excess..self.height, 0);
+            }
+            cmp::Ordering::Greater => {
+                let extra = height - self.height;
+                self.back_buffer.copy_within(0..self.height, extra);
+                for row in (0..extra).rev() {
+                    self.back_buffer[row] = self
+                        .scrollback
+                        .pop()
+                        .unwrap_or([ScreenChar::BLANK; MAX_BUFFER_WIDTH]);
+                }
             }
+            cmp::Ordering::Equal => {}
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
+        self.width = width;
+        self.height = height;
+        self.column_position = usize::min(self.column_position, width);
+        self.scroll_offset = 0;
+        self.dirty_rows = u64::MAX;
+    }
+
+    fn new_line(&mut self) {
+        self.scrollback.push(&self.back_buffer[0]);
+        self.back_buffer.copy_within(1..self.height, 0);
+        self.dirty_rows = u64::MAX;
+        self.clear_row(self.height - 1);
         self.column_position = 0;
     }
 
@@ -122,9 +435,195 @@ impl Writer {
             ascii_character: b' ',
             colour_code: self.colour_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        self.back_buffer[row] = [blank; MAX_BUFFER_WIDTH];
+        self.mark_dirty(row);
+    }
+
+    fn mark_dirty(&mut self, row: usize) {
+        self.dirty_rows |= 1 << row;
+    }
+
+    /// The character and colour attribute shown at `row`, `col`, taking scrolling into account.
+    pub fn cell(&self, row: usize, col: usize) -> (u8, u8) {
+        let character = self.view_row(row)[col];
+        (character.ascii_character, character.colour_code.0)
+    }
+
+    /// Writes what the console shows as a screenshot frame, see `screenshot_to_serial`.
+    pub fn write_screenshot(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(
+            out,
+            "-----BEGIN SCREENSHOT {}x{}-----",
+            self.width, self.height
+        )?;
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let (character, attribute) = self.cell(row, col);
+                write!(out, "{:02x}{:02x}", character, attribute)?;
+            }
+            writeln!(out)?;
+        }
+        writeln!(out, "-----END SCREENSHOT-----")
+    }
+
+    /// Reads back what VGA memory shows at `row`, `col`.
+    #[cfg(test)]
+    fn screen_char(&self, row: usize, col: usize) -> ScreenChar {
+        self.buffer.chars[row * self.width + col].read()
+    }
+}
+
+/// The console that `print!` currently writes to.
+pub fn output() -> &'static Mutex<Writer> {
+    &CONSOLES[output_console()]
+}
+
+/// Sends `print!` output to console `index`.
+pub fn set_output_console(index: usize) {
+    assert!(index < CONSOLE_COUNT, "no console {}", index);
+    OUTPUT_CONSOLE.store(index, Ordering::SeqCst);
+}
+
+pub fn output_console() -> usize {
+    OUTPUT_CONSOLE.load(Ordering::SeqCst)
+}
+
+pub fn active_console() -> usize {
+    ACTIVE_CONSOLE.load(Ordering::SeqCst)
+}
+
+/// Shows console `index` on screen.
+///
+/// Safe to call from interrupt handlers: if the console is locked by the interrupted code it is
+/// repainted by that code's next `present` instead.
+pub fn switch_console(index: usize) {
+    assert!(index < CONSOLE_COUNT, "no console {}", index);
+    if ACTIVE_CONSOLE.swap(index, Ordering::SeqCst) == index {
+        return;
+    }
+    SWITCH_GENERATION.fetch_add(1, Ordering::SeqCst);
+    if let Some(mut writer) = CONSOLES[index].try_lock() {
+        writer.present();
+    }
+}
+
+pub fn text_mode() -> TextMode {
+    *TEXT_MODE.lock()
+}
+
+/// Reprograms the VGA for `mode` and resizes every console to match. Does nothing once the
+/// consoles have moved to the framebuffer.
+///
+/// Must not be called while holding a console lock.
+pub fn set_text_mode(mode: TextMode) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut current = TEXT_MODE.lock();
+        if *current == mode || framebuffer::console().is_some() {
+            return;
+        }
+
+        let mut font_8x16 = FONT_8X16.lock();
+        if current.char_height() == 16 {
+            // keep any custom glyphs that were loaded into the 8x16 font
+            vga::read_font(16, &mut *font_8x16);
         }
+        load_text_font(mode, &font_8x16);
+        vga::set_char_height(mode.char_height() as u8);
+        *current = mode;
+        resize_consoles(mode.columns(), mode.rows());
+    });
+}
+
+/// Resizes every console to `columns` by `rows`, as far as the maximum allows, and repaints.
+///
+/// Must not be called while holding a console lock.
+pub(crate) fn resize_consoles(columns: usize, rows: usize) {
+    let columns = usize::min(columns, MAX_BUFFER_WIDTH);
+    let rows = usize::min(rows, MAX_BUFFER_HEIGHT);
+    for console in CONSOLES.iter() {
+        console.lock().resize(columns, rows);
+    }
+    SWITCH_GENERATION.fetch_add(1, Ordering::SeqCst);
+    CONSOLES[active_console()].lock().present();
+}
+
+fn load_text_font(mode: TextMode, font_8x16: &[u8; FONT_GLYPHS * 16]) {
+    match mode.char_height() {
+        16 => vga::load_font(&Font {
+            height: 16,
+            glyphs: font_8x16,
+        }),
+        _ => {
+            // fold each pair of rows together so thin lines survive
+            let mut font_8x8 = [0; FONT_GLYPHS * 8];
+            for (glyph, rows) in font_8x8.chunks_mut(8).enumerate() {
+                for (row, bits) in rows.iter_mut().enumerate() {
+                    let source = glyph * 16 + row * 2;
+                    *bits = font_8x16[source] | font_8x16[source + 1];
+                }
+            }
+            vga::load_font(&Font {
+                height: 8,
+                glyphs: &font_8x8,
+            });
+        }
+    }
+}
+
+/// Saves the font before a graphics mode overwrites plane 2.
+pub(crate) fn save_text_font() {
+    if TEXT_MODE.lock().char_height() == 16 {
+        vga::read_font(16, &mut *FONT_8X16.lock());
+    }
+}
+
+/// Copies the 8x16 text font, including any custom glyphs, into `font`.
+pub(crate) fn copy_text_font(font: &mut [u8; FONT_GLYPHS * 16]) {
+    save_text_font();
+    font.copy_from_slice(&*FONT_8X16.lock());
+}
+
+/// Puts the VGA back into the current text mode after a graphics mode and repaints the active
+/// console.
+pub(crate) fn restore_text_mode() {
+    let mode = *TEXT_MODE.lock();
+    vga::set_registers(&vga::TEXT_80X25);
+    load_text_font(mode, &FONT_8X16.lock());
+    vga::set_char_height(mode.char_height() as u8);
+    SWITCH_GENERATION.fetch_add(1, Ordering::SeqCst);
+    if let Some(mut writer) = CONSOLES[active_console()].try_lock() {
+        writer.present();
+    }
+}
+
+/// Sends what is on screen to the serial port carrying the log, so it can be turned back into
+/// text or an ANSI coloured dump with `scripts/screenshot.py`.
+///
+/// The frame is a `-----BEGIN SCREENSHOT <columns>x<rows>-----` line, then one line per row with
+/// four hex digits per cell (the code page 437 character, then the colour attribute), and finally
+/// an `-----END SCREENSHOT-----` line.
+pub fn screenshot_to_serial() {
+    use crate::serial::{self, Role};
+
+    // the console may be locked by the code that was interrupted for the hotkey
+    if let Some(writer) = CONSOLES[active_console()].try_lock() {
+        serial::with_role(Role::Log, |uart| {
+            writer
+                .write_screenshot(uart)
+                .expect("Printing to serial failed")
+        });
+    }
+}
+
+/// Spins until the display enters its next vertical retrace.
+pub fn wait_for_retrace() {
+    let mut status: PortReadOnly<u8> = PortReadOnly::new(INPUT_STATUS_PORT);
+    unsafe {
+        // let a retrace that is already in progress finish first
+        while status.read() & VERTICAL_RETRACE != 0 {}
+        while status.read() & VERTICAL_RETRACE == 0 {}
     }
 }
 
@@ -152,7 +651,55 @@ pub fn _print(args: fmt::Arguments) {
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        let mut writer = output().lock();
+        writer.write_fmt(args).unwrap();
+        if writer.auto_present {
+            writer.present();
+        }
+    });
+}
+
+/// Writes UTF-8 `bytes` to console `index`, with ■ for what isn't valid UTF-8.
+pub fn write_console(index: usize, bytes: &[u8]) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = CONSOLES[index].lock();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            match core::str::from_utf8(rest) {
+                Ok(valid) => {
+                    writer.write_string(valid);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, invalid) = rest.split_at(err.valid_up_to());
+                    writer.write_string(core::str::from_utf8(valid).unwrap_or(""));
+                    writer.write_byte(cp437::REPLACEMENT);
+                    // a sequence cut off at the end has no error length
+                    rest = &invalid[err.error_len().unwrap_or(invalid.len())..];
+                }
+            }
+        }
+        if writer.auto_present {
+            writer.present();
+        }
+    });
+}
+
+/// Like `_print`, but to console `index`, and gives up instead of waiting if it is in use, for
+/// code that may have interrupted whoever holds it.
+pub fn try_print_console(index: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(mut writer) = CONSOLES[index].try_lock() {
+            writer.write_fmt(args).unwrap();
+            if writer.auto_present {
+                writer.present();
+            }
+        }
     });
 }
 
@@ -175,11 +722,172 @@ fn test_println_output() {
 
     let s = "Some test string that fits on a single line";
     interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
+        let mut writer = CONSOLES[0].lock();
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+        writer.present();
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.screen_char(writer.height() - 2, i);
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    });
+}
+
+#[test_case]
+fn test_present_skips_clean_rows() {
+    use x86_64::instructions::interrupts;
+
+    let marker = ScreenChar {
+        ascii_character: b'#',
+        colour_code: ColourCode::new(Colour::White, Colour::Black),
+    };
+    interrupts::without_interrupts(|| {
+        let mut writer = CONSOLES[0].lock();
+        writer.write_byte(b'\n');
+        writer.present();
+        writer.buffer.chars[0].write(marker);
+        writer.write_byte(b'x');
+        writer.present();
+        assert_eq!(writer.screen_char(0, 0), marker);
+        let (row, col) = (writer.height() - 1, writer.column_position - 1);
+        assert_eq!(writer.screen_char(row, col).ascii_character, b'x');
+    });
+}
+
+#[test_case]
+fn test_scrollback() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    let s = "scrolled off the top";
+    interrupts::without_interrupts(|| {
+        let mut writer = CONSOLES[0].lock();
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+        let height = writer.height();
+        for _ in 0..height {
+            writer.write_byte(b'\n');
+        }
+        writer.scroll_up(height);
+        writer.present();
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.screen_char(writer.height() - 2, i);
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+
+        writer.write_byte(b'x');
+        assert_eq!(writer.scroll_offset, 0);
+        writer.present();
+    });
+}
+
+#[test_case]
+fn test_inactive_console_renders_off_screen() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    let s = "only on the second console";
+    interrupts::without_interrupts(|| {
+        let mut writer = CONSOLES[1].lock();
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+        writer.present();
+        let shown = s.chars().enumerate().all(|(i, c)| {
+            let screen_char = writer.screen_char(writer.height() - 2, i);
+            char::from(screen_char.ascii_character) == c
+        });
+        assert!(!shown);
+
+        drop(writer);
+        switch_console(1);
+        let writer = CONSOLES[1].lock();
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.screen_char(writer.height() - 2, i);
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+        drop(writer);
+        switch_console(0);
+    });
+}
+
+#[test_case]
+fn test_println_cp437() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = CONSOLES[0].lock();
+        writeln!(writer, "\n£5 ╔═╗ €").expect("writeln failed");
+        writer.present();
+        let expected = [0x9c, b'5', b' ', 0xc9, 0xcd, 0xbb, b' ', cp437::REPLACEMENT];
+        for (i, &byte) in expected.iter().enumerate() {
+            let screen_char = writer.screen_char(writer.height() - 2, i);
+            assert_eq!(screen_char.ascii_character, byte);
+        }
+    });
+}
+
+#[test_case]
+fn test_text_mode_switch() {
+    use core::fmt::Write;
+
+    let s = "survives a mode switch";
+    {
+        let mut writer = CONSOLES[0].lock();
         writeln!(writer, "\n{}", s).expect("writeln failed");
+    }
+    set_text_mode(TextMode::Text80x50);
+    {
+        let writer = CONSOLES[0].lock();
+        assert_eq!(writer.height(), 50);
         for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            let screen_char = writer.screen_char(writer.height() - 2, i);
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
+    }
+    set_text_mode(TextMode::Text80x25);
+    assert_eq!(CONSOLES[0].lock().height(), 25);
+}
+
+#[test_case]
+fn test_screenshot() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    /// Counts the lines written to it and keeps line `target`.
+    struct Capture {
+        target: usize,
+        lines: usize,
+        text: [u8; MAX_BUFFER_WIDTH * 4],
+        len: usize,
+    }
+
+    impl fmt::Write for Capture {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for byte in s.bytes() {
+                if byte == b'\n' {
+                    self.lines += 1;
+                } else if self.lines == self.target {
+                    self.text[self.len] = byte;
+                    self.len += 1;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut writer = CONSOLES[0].lock();
+        writeln!(writer, "\nshot").expect("writeln failed");
+        // line 0 is the header, so row n is line n + 1
+        let mut capture = Capture {
+            target: writer.height() - 1,
+            lines: 0,
+            text: [0; MAX_BUFFER_WIDTH * 4],
+            len: 0,
+        };
+        writer
+            .write_screenshot(&mut capture)
+            .expect("screenshot failed");
+        assert_eq!(capture.lines, writer.height() + 2);
+        assert_eq!(capture.len, writer.width() * 4);
+        assert_eq!(&capture.text[..16], b"730e680e6f0e740e");
     });
 }
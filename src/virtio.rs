@@ -0,0 +1,502 @@
+//! What virtio devices have in common: finding their registers on PCI, agreeing on features, and
+//! the split virtqueues they exchange buffers with the driver through.
+//!
+//! Both the legacy transport, with the registers in an I/O port BAR, and the modern one, with
+//! them in memory BARs that vendor capabilities point at, are supported. Transitional devices,
+//! which have both, are driven through the modern one. Queues are polled, the devices are asked
+//! not to interrupt.
+
+use crate::memory;
+use crate::pci::{self, Bar, Device};
+use core::sync::atomic::{fence, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+/// Transitional devices have ids from here on, in the order of their device types.
+pub const LEGACY_DEVICE_ID: u16 = 0x1000;
+/// Modern devices have this plus their device type as id.
+pub const MODERN_DEVICE_ID: u16 = 0x1040;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// the registers of the legacy transport
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+/// Where the device specific registers start while MSI-X is off.
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+// the common configuration registers of the modern transport
+const DEVICE_FEATURE_SELECT: u64 = 0;
+const DEVICE_FEATURE: u64 = 4;
+const DRIVER_FEATURE_SELECT: u64 = 8;
+const DRIVER_FEATURE: u64 = 12;
+const DEVICE_STATUS: u64 = 20;
+const QUEUE_SELECT: u64 = 22;
+const QUEUE_SIZE: u64 = 24;
+const QUEUE_ENABLE: u64 = 28;
+const QUEUE_NOTIFY_OFF: u64 = 30;
+const QUEUE_DESC: u64 = 32;
+const QUEUE_DRIVER: u64 = 40;
+const QUEUE_DEVICE: u64 = 48;
+
+// the kinds of vendor capability that point at the modern transport's registers
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_DEVICE_CFG: u8 = 4;
+
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+const AVAIL_NO_INTERRUPT: u16 = 1;
+
+/// Queues are made this long if the device lets the driver choose.
+const MAX_QUEUE_SIZE: u16 = 128;
+const PAGE_SIZE: usize = 4096;
+
+/// How the registers of a virtio device are reached.
+#[derive(Debug, Clone, Copy)]
+pub enum Transport {
+    Legacy {
+        io: u16,
+    },
+    Modern {
+        common: VirtAddr,
+        notify: VirtAddr,
+        notify_multiplier: u32,
+        /// The device specific registers, if the device has any.
+        device: Option<VirtAddr>,
+    },
+}
+
+impl Transport {
+    /// Finds the registers of `device`, and lets it decode its BARs and do DMA.
+    pub fn new(device: &Device) -> Result<Transport, &'static str> {
+        device.enable();
+        let mut common = None;
+        let mut notify = None;
+        let mut config = None;
+        for capability in device.capabilities.iter().flatten() {
+            if capability.id != pci::CAPABILITY_VENDOR {
+                continue;
+            }
+            let offset = u16::from(capability.offset);
+            let kind = (device.read_config(offset) >> 24) as u8;
+            let bar = device.read_config(offset + 4) as u8;
+            let start = device.read_config(offset + 8);
+            let length = device.read_config(offset + 12);
+            let region = || map_region(device, bar, start, length);
+            match kind {
+                CAP_COMMON_CFG => common = Some(region()?),
+                CAP_NOTIFY_CFG => notify = Some((region()?, device.read_config(offset + 16))),
+                CAP_DEVICE_CFG => config = Some(region()?),
+                _ => {}
+            }
+        }
+        match (common, notify, device.bars[0]) {
+            (Some(common), Some((notify, notify_multiplier)), _) => Ok(Transport::Modern {
+                common,
+                notify,
+                notify_multiplier,
+                device: config,
+            }),
+            (_, _, Some(Bar::Io { port, .. })) => Ok(Transport::Legacy { io: port }),
+            _ => Err("virtio device has no registers"),
+        }
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern { .. })
+    }
+
+    pub fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io } => unsafe { Port::new(io + LEGACY_STATUS).read() },
+            Transport::Modern { common, .. } => read(common + DEVICE_STATUS),
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { io } => unsafe { Port::new(io + LEGACY_STATUS).write(status) },
+            Transport::Modern { common, .. } => write(common + DEVICE_STATUS, status),
+        }
+    }
+
+    /// Resets the device and agrees with it on the features in `supported` that it has, which
+    /// are returned. Queues are set up after this, then `driver_ok` lets the device start.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, &'static str> {
+        self.set_status(0);
+        // a modern device can take a while to reset
+        for _ in 0..100_000 {
+            if self.status() == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let offered = self.device_features();
+        let mut features = offered & supported;
+        if self.is_modern() {
+            if offered & FEATURE_VERSION_1 == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err("modern virtio device without VERSION_1");
+            }
+            features |= FEATURE_VERSION_1;
+        }
+        self.set_driver_features(features);
+        if self.is_modern() {
+            self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err("virtio device refused the features");
+            }
+        }
+        Ok(features)
+    }
+
+    /// Tells the device that the driver has set it up.
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                Port::new(io + LEGACY_DEVICE_CONFIG + offset).read()
+            },
+            Transport::Modern { device, .. } => {
+                device.map_or(0, |device| read(device + u64::from(offset)))
+            }
+        }
+    }
+
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        u64::from(self.config_u32(offset + 4)) << 32 | u64::from(self.config_u32(offset))
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy { io } => {
+                u64::from(unsafe { Port::<u32>::new(io + LEGACY_DEVICE_FEATURES).read() })
+            }
+            Transport::Modern { common, .. } => {
+                write::<u32>(common + DEVICE_FEATURE_SELECT, 1);
+                let high: u32 = read(common + DEVICE_FEATURE);
+                write::<u32>(common + DEVICE_FEATURE_SELECT, 0);
+                let low: u32 = read(common + DEVICE_FEATURE);
+                u64::from(high) << 32 | u64::from(low)
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                Port::new(io + LEGACY_DRIVER_FEATURES).write(features as u32)
+            },
+            Transport::Modern { common, .. } => {
+                write::<u32>(common + DRIVER_FEATURE_SELECT, 0);
+                write(common + DRIVER_FEATURE, features as u32);
+                write::<u32>(common + DRIVER_FEATURE_SELECT, 1);
+                write(common + DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+
+    /// The longest queue `index` can be, 0 if the device doesn't have that queue.
+    fn queue_max_size(&self, index: u16) -> u16 {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                Port::new(io + LEGACY_QUEUE_SELECT).write(index);
+                Port::new(io + LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => {
+                write(common + QUEUE_SELECT, index);
+                read(common + QUEUE_SIZE)
+            }
+        }
+    }
+
+    /// Gives queue `index` to the device, and returns its notification offset.
+    fn setup_queue(&self, queue: &Virtqueue) -> u16 {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                Port::new(io + LEGACY_QUEUE_SELECT).write(queue.index);
+                let frame = (queue.phys.as_u64() / PAGE_SIZE as u64) as u32;
+                Port::new(io + LEGACY_QUEUE_ADDRESS).write(frame);
+                0
+            },
+            Transport::Modern { common, .. } => {
+                write(common + QUEUE_SELECT, queue.index);
+                write(common + QUEUE_SIZE, queue.size);
+                let (avail, used, _) = layout(queue.size);
+                write_u64(common + QUEUE_DESC, queue.phys.as_u64());
+                write_u64(common + QUEUE_DRIVER, queue.phys.as_u64() + avail as u64);
+                write_u64(common + QUEUE_DEVICE, queue.phys.as_u64() + used as u64);
+                write::<u16>(common + QUEUE_ENABLE, 1);
+                read(common + QUEUE_NOTIFY_OFF)
+            }
+        }
+    }
+
+    fn notify(&self, queue: u16, notify_offset: u16) {
+        match *self {
+            Transport::Legacy { io } => unsafe { Port::new(io + LEGACY_QUEUE_NOTIFY).write(queue) },
+            Transport::Modern {
+                notify,
+                notify_multiplier,
+                ..
+            } => write(
+                notify + u64::from(notify_offset) * u64::from(notify_multiplier),
+                queue,
+            ),
+        }
+    }
+}
+
+/// A piece of memory a request is made of.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes it, as opposed to reads it.
+    pub device_writes: bool,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A split virtqueue: a descriptor table with the buffers, the ring of requests the driver makes
+/// available and the ring of ones the device has used. All three are in one piece of physical
+/// memory laid out like the legacy transport needs.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    phys: PhysAddr,
+    virt: VirtAddr,
+    notify_offset: u16,
+    /// The first descriptor that isn't part of a request, the others are chained to it.
+    free_head: u16,
+    free: u16,
+    next_available: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    /// Sets up queue `index` of the device. Its memory is never given back.
+    pub fn new(transport: &Transport, index: u16) -> Result<Virtqueue, &'static str> {
+        let max_size = transport.queue_max_size(index);
+        if max_size == 0 {
+            return Err("virtio device has no such queue");
+        }
+        // the legacy transport can't be told about a smaller queue
+        let size = if transport.is_modern() {
+            max_size.min(MAX_QUEUE_SIZE)
+        } else {
+            max_size
+        };
+        let mut queue = Virtqueue::allocate(index, size)?;
+        queue.notify_offset = transport.setup_queue(&queue);
+        Ok(queue)
+    }
+
+    fn allocate(index: u16, size: u16) -> Result<Virtqueue, &'static str> {
+        let (_, _, total) = layout(size);
+        let frame = memory::allocate_frames(total / PAGE_SIZE).ok_or("out of memory")?;
+        let phys = frame.start_address();
+        let virt = memory::phys_to_virt(phys);
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, total) };
+        let queue = Virtqueue {
+            index,
+            size,
+            phys,
+            virt,
+            notify_offset: 0,
+            free_head: 0,
+            free: size,
+            next_available: 0,
+            last_used: 0,
+        };
+        for i in 0..size {
+            queue.set_descriptor(
+                i,
+                Descriptor {
+                    next: i + 1,
+                    ..Descriptor::default()
+                },
+            );
+        }
+        let (avail, _, _) = layout(size);
+        write(virt + avail as u64, AVAIL_NO_INTERRUPT);
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Makes `buffers` available to the device as one request, which `pop_used` returns the
+    /// first descriptor of once the device is done with it. The device has to be notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, &'static str> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free) {
+            return Err("virtqueue is full");
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut descriptor = self.descriptor(index);
+            descriptor.addr = buffer.addr.as_u64();
+            descriptor.len = buffer.len;
+            descriptor.flags = if buffer.device_writes { DESC_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                descriptor.flags |= DESC_NEXT;
+            }
+            self.set_descriptor(index, descriptor);
+            index = descriptor.next;
+        }
+        self.free_head = index;
+        self.free -= buffers.len() as u16;
+
+        let (avail, _, _) = layout(self.size);
+        let slot = usize::from(self.next_available % self.size);
+        write(self.virt + (avail + 4 + slot * 2) as u64, head);
+        self.next_available = self.next_available.wrapping_add(1);
+        // the device mustn't see the new index before the descriptors and the ring entry
+        fence(Ordering::SeqCst);
+        write(self.virt + (avail + 2) as u64, self.next_available);
+        Ok(head)
+    }
+
+    pub fn notify(&self, transport: &Transport) {
+        fence(Ordering::SeqCst);
+        transport.notify(self.index, self.notify_offset);
+    }
+
+    /// Takes the next request the device is done with, as its first descriptor and the number
+    /// of bytes the device wrote, and frees its descriptors.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let (_, used, _) = layout(self.size);
+        let used_index: u16 = read(self.virt + (used + 2) as u64);
+        if used_index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = usize::from(self.last_used % self.size);
+        let element = self.virt + (used + 4 + slot * 8) as u64;
+        let head = read::<u32>(element) as u16;
+        let len = read::<u32>(element + 4u64);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut last = head;
+        let mut count = 1;
+        loop {
+            let descriptor = self.descriptor(last);
+            if descriptor.flags & DESC_NEXT == 0 {
+                break;
+            }
+            last = descriptor.next;
+            count += 1;
+        }
+        let mut descriptor = self.descriptor(last);
+        descriptor.next = self.free_head;
+        self.set_descriptor(last, descriptor);
+        self.free_head = head;
+        self.free += count;
+        Some((head, len))
+    }
+
+    fn descriptor(&self, index: u16) -> Descriptor {
+        read(self.virt + usize::from(index) as u64 * 16)
+    }
+
+    fn set_descriptor(&self, index: u16, descriptor: Descriptor) {
+        write(self.virt + usize::from(index) as u64 * 16, descriptor)
+    }
+}
+
+/// Where the available and used rings start in a queue of `size`, and its size in bytes.
+fn layout(size: u16) -> (usize, usize, usize) {
+    let size = usize::from(size);
+    let avail = 16 * size;
+    let used = align_up(avail + 6 + 2 * size);
+    (avail, used, align_up(used + 6 + 8 * size))
+}
+
+fn align_up(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+fn map_region(device: &Device, bar: u8, start: u32, length: u32) -> Result<VirtAddr, &'static str> {
+    match device.bars.get(usize::from(bar)).copied().flatten() {
+        Some(Bar::Memory { addr, .. }) => memory::map_mmio(addr + u64::from(start), length.into()),
+        _ => Err("virtio capability points at a missing BAR"),
+    }
+}
+
+fn read<T: Copy>(addr: VirtAddr) -> T {
+    unsafe { addr.as_ptr::<T>().read_volatile() }
+}
+
+fn write<T: Copy>(addr: VirtAddr, value: T) {
+    unsafe { addr.as_mut_ptr::<T>().write_volatile(value) }
+}
+
+fn write_u64(addr: VirtAddr, value: u64) {
+    write(addr, value as u32);
+    write(addr + 4u64, (value >> 32) as u32);
+}
+
+#[test_case]
+fn test_virtqueue() {
+    let mut queue = Virtqueue::allocate(0, 4).unwrap();
+    let buffer = |addr: u64, device_writes| Buffer {
+        addr: PhysAddr::new(addr),
+        len: 16,
+        device_writes,
+    };
+    let first = queue
+        .add(&[buffer(0x1000, false), buffer(0x2000, true)])
+        .unwrap();
+    let second = queue.add(&[buffer(0x3000, false)]).unwrap();
+    assert_eq!(queue.free, 1);
+    assert!(queue.add(&[buffer(0, false), buffer(0, false)]).is_err());
+    assert_eq!(queue.pop_used(), None);
+
+    let (avail, used, _) = layout(4);
+    assert_eq!(read::<u16>(queue.virt + (avail + 2) as u64), 2);
+    assert_eq!(read::<u16>(queue.virt + (avail + 4) as u64), first);
+    let descriptor = queue.descriptor(first);
+    assert_eq!(descriptor.flags, DESC_NEXT);
+    assert_eq!(queue.descriptor(descriptor.next).flags, DESC_WRITE);
+    assert_eq!(queue.descriptor(descriptor.next).addr, 0x2000);
+
+    // pretend to be the device, done with the second request before the first
+    write(queue.virt + (used + 4) as u64, u32::from(second));
+    write(queue.virt + (used + 8) as u64, 0u32);
+    write(queue.virt + (used + 12) as u64, u32::from(first));
+    write(queue.virt + (used + 16) as u64, 16u32);
+    write(queue.virt + (used + 2) as u64, 2u16);
+    assert_eq!(queue.pop_used(), Some((second, 0)));
+    assert_eq!(queue.pop_used(), Some((first, 16)));
+    assert_eq!(queue.pop_used(), None);
+    assert_eq!(queue.free, 4);
+    assert!(queue.add(&[buffer(0, false); 4]).is_ok());
+}
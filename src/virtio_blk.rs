@@ -0,0 +1,286 @@
+//! A driver for virtio block devices, registered as the block devices vda, vdb and so on.
+//!
+//! Requests go through one virtqueue, a sector header, the data and a status byte each. The data
+//! is copied through a DMA buffer of the driver's own, so buffers from anywhere can be used.
+
+use crate::block::{self, BlockDevice};
+use crate::memory;
+use crate::pci::{self, Device, Driver, Matcher};
+use crate::virtio::{self, Buffer, Transport, Virtqueue};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const SECTOR_SIZE: usize = 512;
+
+const DEVICE_TYPE: u16 = 2;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+const CONFIG_CAPACITY: u16 = 0;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// The DMA buffer has the request header and status in its first frame, then this much data.
+const DATA_FRAMES: usize = 4;
+const DATA_SIZE: usize = DATA_FRAMES * 4096;
+const STATUS_OFFSET: u64 = 16;
+const DATA_OFFSET: u64 = 4096;
+/// How often the used ring is looked at before giving up on a request.
+const POLL_LIMIT: u32 = 10_000_000;
+
+const NAMES: [&str; 4] = ["vda", "vdb", "vdc", "vdd"];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_DISK: Once<Disk> = Once::new();
+static DISKS: [Once<Disk>; 4] = [NO_DISK; 4];
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+static INIT: Once<()> = Once::new();
+
+static DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    matchers: &[
+        Matcher::Id {
+            vendor_id: virtio::VENDOR_ID,
+            device_id: virtio::LEGACY_DEVICE_ID + DEVICE_TYPE - 1,
+        },
+        Matcher::Id {
+            vendor_id: virtio::VENDOR_ID,
+            device_id: virtio::MODERN_DEVICE_ID + DEVICE_TYPE,
+        },
+    ],
+    probe,
+};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+pub struct Disk {
+    name: &'static str,
+    transport: Transport,
+    sectors: u64,
+    features: u64,
+    /// The queue and the DMA buffer, which are used by one request at a time.
+    queue: Mutex<(Virtqueue, PhysAddr)>,
+}
+
+impl Disk {
+    pub fn is_read_only(&self) -> bool {
+        self.features & FEATURE_READ_ONLY != 0
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// Sends a request for `len` bytes of data at `sector` and waits for it. The data is in the
+    /// DMA buffer, `with_data` is called with it before the request for writes and after it for
+    /// reads.
+    fn request(
+        &self,
+        kind: u32,
+        sector: u64,
+        len: usize,
+        with_data: impl FnOnce(&mut [u8]),
+    ) -> Result<(), &'static str> {
+        let mut queue = self.queue.lock();
+        let (queue, dma) = &mut *queue;
+        let virt = memory::phys_to_virt(*dma);
+        let data = unsafe {
+            core::slice::from_raw_parts_mut((virt + DATA_OFFSET).as_mut_ptr::<u8>(), len)
+        };
+        let mut with_data = Some(with_data);
+        if kind != REQUEST_IN {
+            (with_data.take().unwrap())(data);
+        }
+        let header = RequestHeader {
+            kind,
+            reserved: 0,
+            sector,
+        };
+        unsafe {
+            virt.as_mut_ptr::<RequestHeader>().write_volatile(header);
+            status(virt).write_volatile(0xff);
+        }
+
+        let header = Buffer {
+            addr: *dma,
+            len: core::mem::size_of::<RequestHeader>() as u32,
+            device_writes: false,
+        };
+        let data_buffer = Buffer {
+            addr: *dma + DATA_OFFSET,
+            len: len as u32,
+            device_writes: kind == REQUEST_IN,
+        };
+        let status_buffer = Buffer {
+            addr: *dma + STATUS_OFFSET,
+            len: 1,
+            device_writes: true,
+        };
+        if len == 0 {
+            queue.add(&[header, status_buffer])?;
+        } else {
+            queue.add(&[header, data_buffer, status_buffer])?;
+        }
+        queue.notify(&self.transport);
+        let mut done = false;
+        for _ in 0..POLL_LIMIT {
+            if queue.pop_used().is_some() {
+                done = true;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        if !done {
+            // the descriptors stay in use, the device could still write to the buffer
+            return Err("virtio disk timed out");
+        }
+        match unsafe { status(virt).read_volatile() } {
+            STATUS_OK => {}
+            STATUS_UNSUPPORTED => return Err("virtio disk doesn't support the request"),
+            _ => return Err("virtio disk error"),
+        }
+        if let Some(with_data) = with_data {
+            with_data(data);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(DATA_SIZE).enumerate() {
+            let sector = lba + (i * DATA_SIZE / SECTOR_SIZE) as u64;
+            self.request(REQUEST_IN, sector, chunk.len(), |data| {
+                chunk.copy_from_slice(data)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(self, lba, buf.len())?;
+        if self.is_read_only() {
+            return Err("virtio disk is read only");
+        }
+        for (i, chunk) in buf.chunks(DATA_SIZE).enumerate() {
+            let sector = lba + (i * DATA_SIZE / SECTOR_SIZE) as u64;
+            self.request(REQUEST_OUT, sector, chunk.len(), |data| {
+                data.copy_from_slice(chunk)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), &'static str> {
+        if self.features & FEATURE_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(REQUEST_FLUSH, 0, 0, |_| {})
+    }
+}
+
+/// Looks for virtio block devices on PCI and registers them as block devices.
+pub fn init() {
+    INIT.call_once(|| {
+        if let Err(err) = pci::register_driver(&DRIVER) {
+            log::warn!("no virtio-blk driver: {}", err);
+        }
+    });
+}
+
+/// The disk registered as `NAMES[index]`.
+pub fn disk(index: usize) -> Option<&'static Disk> {
+    DISKS.get(index)?.r#try()
+}
+
+fn probe(device: &'static Device) -> Result<(), &'static str> {
+    let index = DISK_COUNT.load(Ordering::Relaxed);
+    if index == DISKS.len() {
+        return Err("too many virtio disks");
+    }
+    let transport = Transport::new(device)?;
+    let features = transport.negotiate(FEATURE_READ_ONLY | FEATURE_FLUSH)?;
+    let queue = Virtqueue::new(&transport, 0)?;
+    let dma = memory::allocate_frames(1 + DATA_FRAMES)
+        .ok_or("out of memory")?
+        .start_address();
+    transport.driver_ok();
+    let sectors = transport.config_u64(CONFIG_CAPACITY);
+
+    DISK_COUNT.store(index + 1, Ordering::Relaxed);
+    let disk = DISKS[index].call_once(|| Disk {
+        name: NAMES[index],
+        transport,
+        sectors,
+        features,
+        queue: Mutex::new((queue, dma)),
+    });
+    log::info!(
+        "{}: {} sectors over the {} transport{}",
+        disk.name,
+        sectors,
+        if transport.is_modern() {
+            "modern"
+        } else {
+            "legacy"
+        },
+        if disk.is_read_only() {
+            ", read only"
+        } else {
+            ""
+        }
+    );
+    block::register(disk)
+}
+
+fn status(dma: VirtAddr) -> *mut u8 {
+    (dma + STATUS_OFFSET).as_mut_ptr()
+}
+
+#[test_case]
+fn test_read_write() {
+    init();
+    // the tests attach a legacy only and a modern only disk, 34 sectors take three requests
+    let disks = || (0..NAMES.len()).filter_map(disk);
+    assert!(disks().any(|disk| disk.transport().is_modern()));
+    assert!(disks().any(|disk| !disk.transport().is_modern()));
+
+    for disk in disks() {
+        let lba = disk.block_count() - 34;
+        let mut data = [0; 34 * SECTOR_SIZE];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = (i * 3 + lba as usize) as u8;
+        }
+        disk.write_blocks(lba, &data).unwrap();
+        disk.flush().unwrap();
+        let mut read = [0; 34 * SECTOR_SIZE];
+        disk.read_blocks(lba, &mut read).unwrap();
+        assert!(read[..] == data[..]);
+        assert!(disk.read_blocks(disk.block_count(), &mut read).is_err());
+    }
+}